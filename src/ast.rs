@@ -3,94 +3,516 @@
 use crate::{text::node_text, typ::Type};
 use internment::Intern;
 use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ops::Range, sync::Arc};
 use tree_sitter::{Node, Tree};
 
-// TODO: Incremental reparsing
-
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct SyntaxError;
 
 type Result<T> = std::result::Result<T, SyntaxError>;
 
-#[derive(Debug)]
+/// A byte range relative to the start of the enclosing function, so that the
+/// function's AST stays the same when code above it is edited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    fn new(node: Node, function_start: usize) -> Self {
+        Self {
+            start: node.start_byte() - function_start,
+            end: node.end_byte() - function_start,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct File {
-    functions: Vec<Function>,
+    functions: Vec<Arc<Function>>,
+    structs: Vec<Arc<Struct>>,
+    enums: Vec<Arc<Enum>>,
 }
 
 impl File {
     pub fn parse(tree: &Tree, text: &Rope) -> Self {
         Self {
-            functions: tree
-                .root_node()
-                .children(&mut tree.walk())
-                .filter(|child| !child.is_extra())
-                .map(|child| Function::parse(child, text))
+            functions: function_nodes(tree)
+                .into_iter()
+                .map(|node| Arc::new(Function::parse(node, text)))
+                .collect(),
+            structs: struct_nodes(tree)
+                .into_iter()
+                .map(|node| Arc::new(Struct::parse(node, text)))
+                .collect(),
+            enums: enum_nodes(tree)
+                .into_iter()
+                .map(|node| Arc::new(Enum::parse(node, text)))
+                .collect(),
+        }
+    }
+
+    /// Parses the file again after an edit, reusing the ASTs of functions that
+    /// don't touch any of the `changed` byte ranges. `old_tree` is the tree
+    /// this AST was parsed from, edited to line up with the new text. Structs
+    /// and enums are cheap to parse, so they are always parsed again.
+    pub fn reparse(
+        &self,
+        old_tree: &Tree,
+        tree: &Tree,
+        text: &Rope,
+        changed: &[Range<usize>],
+    ) -> Self {
+        let old_functions = function_nodes(old_tree)
+            .into_iter()
+            .map(|node| node.byte_range())
+            .zip(&self.functions)
+            .collect::<HashMap<_, _>>();
+
+        Self {
+            functions: function_nodes(tree)
+                .into_iter()
+                .map(|node| {
+                    let range = node.byte_range();
+                    let is_unchanged = !changed.iter().any(|changed| {
+                        changed.start <= range.end && range.start <= changed.end
+                    });
+                    match old_functions.get(&range) {
+                        Some(&function) if is_unchanged => Arc::clone(function),
+                        _ => Arc::new(Function::parse(node, text)),
+                    }
+                })
+                .collect(),
+            structs: struct_nodes(tree)
+                .into_iter()
+                .map(|node| Arc::new(Struct::parse(node, text)))
                 .collect(),
+            enums: enum_nodes(tree)
+                .into_iter()
+                .map(|node| Arc::new(Enum::parse(node, text)))
+                .collect(),
+        }
+    }
+
+    pub fn functions(&self) -> &[Arc<Function>] {
+        &self.functions
+    }
+
+    /// Finds the first function with the given name.
+    pub fn function(&self, name: Intern<str>) -> Option<&Arc<Function>> {
+        self.functions
+            .iter()
+            .find(|function| function.signature.name == Ok(name))
+    }
+
+    pub fn structs(&self) -> &[Arc<Struct>] {
+        &self.structs
+    }
+
+    pub fn enums(&self) -> &[Arc<Enum>] {
+        &self.enums
+    }
+
+    /// The first struct or enum with each name. Structs hide enums with the
+    /// same name.
+    pub fn type_definitions(&self) -> Types {
+        let mut types = Types::default();
+        for definition in &self.structs {
+            if let Ok(name) = definition.name {
+                types
+                    .structs
+                    .entry(name)
+                    .or_insert_with(|| Arc::clone(definition));
+            }
+        }
+        for definition in &self.enums {
+            if let Ok(name) = definition.name {
+                if !types.structs.contains_key(&name) {
+                    types
+                        .enums
+                        .entry(name)
+                        .or_insert_with(|| Arc::clone(definition));
+                }
+            }
         }
+        types
     }
 }
 
-#[derive(Debug)]
-struct Function {
-    signature: FunctionSignature,
-    body: Result<Block>,
+/// Identifies a top-level definition. Functions have a separate namespace
+/// from structs and enums.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Item {
+    Function(Intern<str>),
+    Struct(Intern<str>),
+    Enum(Intern<str>),
+}
+
+/// Finds the syntax node of the first function with the given name. Spans in
+/// the function's AST are relative to the start of this node.
+pub fn function_node<'tree>(
+    tree: &'tree Tree,
+    text: &Rope,
+    name: Intern<str>,
+) -> Option<Node<'tree>> {
+    find_by_name(function_nodes(tree), text, name)
+}
+
+/// Like `function_node`, but for structs and enums too.
+pub fn item_node<'tree>(
+    tree: &'tree Tree,
+    text: &Rope,
+    item: Item,
+) -> Option<Node<'tree>> {
+    match item {
+        Item::Function(name) => function_node(tree, text, name),
+        Item::Struct(name) => find_by_name(struct_nodes(tree), text, name),
+        Item::Enum(name) => find_by_name(enum_nodes(tree), text, name),
+    }
+}
+
+fn find_by_name<'tree>(
+    nodes: Vec<Node<'tree>>,
+    text: &Rope,
+    name: Intern<str>,
+) -> Option<Node<'tree>> {
+    nodes.into_iter().find(|node| {
+        node.child_by_field_name("name")
+            .filter(|node| node.kind() == "identifier")
+            .is_some_and(|node| *node_text(node, text) == *name)
+    })
+}
+
+/// The top-level nodes that aren't struct or enum definitions. Anything that
+/// isn't a function is parsed as an invalid one.
+fn function_nodes(tree: &Tree) -> Vec<Node<'_>> {
+    top_level_nodes(tree)
+        .into_iter()
+        .filter(|node| {
+            !matches!(node.kind(), "struct_definition" | "enum_definition")
+        })
+        .collect()
+}
+
+fn struct_nodes(tree: &Tree) -> Vec<Node<'_>> {
+    top_level_nodes(tree)
+        .into_iter()
+        .filter(|node| node.kind() == "struct_definition")
+        .collect()
+}
+
+fn enum_nodes(tree: &Tree) -> Vec<Node<'_>> {
+    top_level_nodes(tree)
+        .into_iter()
+        .filter(|node| node.kind() == "enum_definition")
+        .collect()
+}
+
+fn top_level_nodes(tree: &Tree) -> Vec<Node<'_>> {
+    tree.root_node()
+        .children(&mut tree.walk())
+        .filter(|child| !child.is_extra())
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub signature: FunctionSignature,
+    pub name_span: Result<Span>,
+    /// Whether the function is defined outside of Gneiss and called with the
+    /// C ABI.
+    pub is_extern: bool,
+    /// Whether C code can call the function by its name.
+    pub is_export: bool,
+    /// Absent if the definition ends with `;` instead.
+    pub body: Option<Result<Block>>,
 }
 
 impl Function {
     fn parse(node: Node, text: &Rope) -> Self {
-        let name = node
+        let start = node.start_byte();
+        let name_node = node
             .child_by_field_name("name")
-            .filter(|node| node.kind() == "identifier")
+            .filter(|node| node.kind() == "identifier");
+        let name = name_node
             .map(|node| (&*node_text(node, text)).into())
             .ok_or(SyntaxError);
+        let linkage = node.child(0).map(|child| child.kind());
+        let is_extern = linkage == Some("extern");
+        let is_export = linkage == Some("export");
         let parameters = node
             .child_by_field_name("parameters")
-            .map(FunctionParameters::parse)
-            .ok_or(SyntaxError);
+            .ok_or(SyntaxError)
+            .and_then(|node| FunctionParameters::parse(node, text, start));
         let return_type = node
             .child_by_field_name("return_type")
             .ok_or(SyntaxError)
             .and_then(|node| Type::parse(node, text));
         let body = node
             .child_by_field_name("body")
-            .ok_or(SyntaxError)
-            .and_then(|node| Block::parse(node, text));
+            .map(|node| Block::parse(node, text, start));
         Self {
             signature: FunctionSignature {
                 name,
                 parameters,
                 return_type,
             },
+            name_span: name_node
+                .map(|node| Span::new(node, start))
+                .ok_or(SyntaxError),
+            is_extern,
+            is_export,
             body,
         }
     }
+
+    /// The names of the functions called by this one, without duplicates.
+    pub fn callees(&self) -> Vec<Intern<str>> {
+        let mut callees = Vec::new();
+        self.for_each_expr(&mut |expr| {
+            if let Expr::FunctionCall { name: Ok(name), .. } = expr {
+                if !callees.contains(name) {
+                    callees.push(*name);
+                }
+            }
+        });
+        callees
+    }
+
+    /// The names of the variables whose address is taken with `&`, without
+    /// duplicates.
+    pub fn addressed_variables(&self) -> Vec<Intern<str>> {
+        let mut names = Vec::new();
+        self.for_each_expr(&mut |expr| {
+            if let Expr::AddressOf {
+                value: Ok(value), ..
+            } = expr
+            {
+                if let Some(Expr::Identifier { name, .. }) = value.place_root()
+                {
+                    if !names.contains(name) {
+                        names.push(*name);
+                    }
+                }
+            }
+        });
+        names
+    }
+
+    /// Calls `f` on every expression in the body, outer ones first.
+    fn for_each_expr(&self, f: &mut impl FnMut(&Expr)) {
+        if let Some(Ok(body)) = &self.body {
+            body.for_each_expr(f);
+        }
+    }
 }
 
-#[derive(Debug)]
-struct FunctionSignature {
-    name: Result<Intern<str>>,
-    parameters: Result<FunctionParameters>,
-    return_type: Result<Type>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionSignature {
+    pub name: Result<Intern<str>>,
+    pub parameters: Result<FunctionParameters>,
+    pub return_type: Result<Type>,
 }
 
-#[derive(Debug)]
-struct FunctionParameters(Vec<(Expr, Type)>);
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionParameters(pub Vec<(Expr, Type)>);
 
 impl FunctionParameters {
-    fn parse(node: Node) -> Self {
-        // TODO
-        Self(Vec::new())
+    fn parse(node: Node, text: &Rope, function_start: usize) -> Result<Self> {
+        node.named_children(&mut node.walk())
+            .filter(|child| !child.is_extra())
+            .map(|child| {
+                if child.kind() != "parameter" {
+                    return Err(SyntaxError);
+                }
+                let pattern = child
+                    .child_by_field_name("pattern")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| Expr::parse(node, text, function_start))?;
+                let typ = child
+                    .child_by_field_name("type")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| Type::parse(node, text))?;
+                Ok((pattern, typ))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+}
+
+/// Struct and enum definitions by name. They share a namespace, so a name
+/// is in at most one of the maps.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Types {
+    pub structs: HashMap<Intern<str>, Arc<Struct>>,
+    pub enums: HashMap<Intern<str>, Arc<Enum>>,
+}
+
+impl Types {
+    pub fn contains(&self, name: Intern<str>) -> bool {
+        self.structs.contains_key(&name) || self.enums.contains_key(&name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Struct {
+    pub name: Result<Intern<str>>,
+    pub name_span: Result<Span>,
+    /// Whether the fields are laid out without padding.
+    pub is_packed: bool,
+    pub fields: Result<Vec<Field>>,
+}
+
+impl Struct {
+    fn parse(node: Node, text: &Rope) -> Self {
+        let start = node.start_byte();
+        let name_node = node
+            .child_by_field_name("name")
+            .filter(|node| node.kind() == "identifier");
+        let fields = node
+            .child_by_field_name("fields")
+            .ok_or(SyntaxError)
+            .and_then(|node| {
+                node.named_children(&mut node.walk())
+                    .filter(|child| !child.is_extra())
+                    .map(|child| Field::parse(child, text, start))
+                    .collect()
+            });
+        Self {
+            name: name_node
+                .ok_or(SyntaxError)
+                .and_then(|node| parse_identifier(node, text)),
+            name_span: name_node
+                .map(|node| Span::new(node, start))
+                .ok_or(SyntaxError),
+            is_packed: node
+                .child(0)
+                .is_some_and(|child| child.kind() == "packed"),
+            fields,
+        }
+    }
+
+    /// The index and definition of the field with the given name.
+    pub fn field(&self, name: Intern<str>) -> Option<(usize, &Field)> {
+        self.fields
+            .as_ref()
+            .ok()?
+            .iter()
+            .enumerate()
+            .find(|(_, field)| field.name == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: Intern<str>,
+    /// Relative to the start of the struct definition.
+    pub span: Span,
+    pub typ: Type,
+}
+
+impl Field {
+    fn parse(node: Node, text: &Rope, struct_start: usize) -> Result<Self> {
+        if node.kind() != "field_declaration" {
+            return Err(SyntaxError);
+        }
+        let name_node = node.child_by_field_name("name").ok_or(SyntaxError)?;
+        Ok(Self {
+            name: parse_identifier(name_node, text)?,
+            span: Span::new(name_node, struct_start),
+            typ: node
+                .child_by_field_name("type")
+                .ok_or(SyntaxError)
+                .and_then(|node| Type::parse(node, text))?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Enum {
+    pub name: Result<Intern<str>>,
+    pub name_span: Result<Span>,
+    pub variants: Result<Vec<Variant>>,
+}
+
+impl Enum {
+    fn parse(node: Node, text: &Rope) -> Self {
+        let start = node.start_byte();
+        let name_node = node
+            .child_by_field_name("name")
+            .filter(|node| node.kind() == "identifier");
+        let variants = node
+            .child_by_field_name("variants")
+            .ok_or(SyntaxError)
+            .and_then(|node| {
+                node.named_children(&mut node.walk())
+                    .filter(|child| !child.is_extra())
+                    .map(|child| Variant::parse(child, text, start))
+                    .collect()
+            });
+        Self {
+            name: name_node
+                .ok_or(SyntaxError)
+                .and_then(|node| parse_identifier(node, text)),
+            name_span: name_node
+                .map(|node| Span::new(node, start))
+                .ok_or(SyntaxError),
+            variants,
+        }
+    }
+
+    /// The index and definition of the variant with the given name.
+    pub fn variant(&self, name: Intern<str>) -> Option<(usize, &Variant)> {
+        self.variants
+            .as_ref()
+            .ok()?
+            .iter()
+            .enumerate()
+            .find(|(_, variant)| variant.name == name)
     }
 }
 
-#[derive(Debug)]
-struct Block {
-    statements: Vec<Result<Statement>>,
-    result: Option<Result<Box<Expr>>>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    pub name: Intern<str>,
+    /// Relative to the start of the enum definition.
+    pub span: Span,
+    /// The types of the values that the variant holds.
+    pub payload: Vec<Type>,
+}
+
+impl Variant {
+    fn parse(node: Node, text: &Rope, enum_start: usize) -> Result<Self> {
+        if node.kind() != "variant_declaration" {
+            return Err(SyntaxError);
+        }
+        let name_node = node.child_by_field_name("name").ok_or(SyntaxError)?;
+        let payload = match node.child_by_field_name("payload") {
+            Some(node) => node
+                .named_children(&mut node.walk())
+                .filter(|child| !child.is_extra())
+                .map(|child| Type::parse(child, text))
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            name: parse_identifier(name_node, text)?,
+            span: Span::new(name_node, enum_start),
+            payload,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub statements: Vec<Result<Statement>>,
+    pub result: Option<Result<Box<Expr>>>,
 }
 
 impl Block {
-    fn parse(node: Node, text: &Rope) -> Result<Self> {
+    fn parse(node: Node, text: &Rope, function_start: usize) -> Result<Self> {
         if node.kind() != "block" {
             return Err(SyntaxError);
         }
@@ -103,62 +525,168 @@ impl Block {
                         && Some(child.id())
                             != result_node.as_ref().map(Node::id)
                 })
-                .map(|node| Statement::parse(node, text))
+                .map(|node| Statement::parse(node, text, function_start))
                 .collect(),
-            result: result_node
-                .map(|node| Expr::parse(node, text).map(Box::new)),
+            result: result_node.map(|node| {
+                Expr::parse(node, text, function_start).map(Box::new)
+            }),
         })
     }
+
+    fn for_each_expr(&self, f: &mut impl FnMut(&Expr)) {
+        for statement in self.statements.iter().flatten() {
+            match statement {
+                Statement::Expr(expr) => {
+                    if let Ok(expr) = expr {
+                        expr.for_each_expr(f);
+                    }
+                }
+                Statement::Let { pattern, value }
+                | Statement::Assign {
+                    target: pattern,
+                    value,
+                    ..
+                } => {
+                    for expr in [pattern, value].into_iter().flatten() {
+                        expr.for_each_expr(f);
+                    }
+                }
+            }
+        }
+        if let Some(Ok(result)) = &self.result {
+            result.for_each_expr(f);
+        }
+    }
 }
 
-#[derive(Debug)]
-enum Statement {
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
     Expr(Result<Expr>),
     Let {
         pattern: Result<Expr>,
         value: Result<Expr>,
     },
+    /// `target = value;`, where only a dereference is a valid target.
+    Assign {
+        target: Result<Expr>,
+        value: Result<Expr>,
+        span: Span,
+    },
 }
 
 impl Statement {
-    fn parse(node: Node, text: &Rope) -> Result<Self> {
+    fn parse(node: Node, text: &Rope, function_start: usize) -> Result<Self> {
         match node.kind() {
             "expression_statement" => node
                 .child(0)
-                .map(|node| Expr::parse(node, text))
+                .map(|node| Expr::parse(node, text, function_start))
                 .map(Self::Expr)
                 .ok_or(SyntaxError),
             "let_declaration" => Ok(Self::Let {
                 pattern: node
                     .child_by_field_name("pattern")
                     .ok_or(SyntaxError)
-                    .and_then(|node| Expr::parse(node, text)),
+                    .and_then(|node| Expr::parse(node, text, function_start)),
+                value: node
+                    .child_by_field_name("value")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| Expr::parse(node, text, function_start)),
+            }),
+            "assignment_statement" => Ok(Self::Assign {
+                target: node
+                    .child_by_field_name("target")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| Expr::parse(node, text, function_start)),
                 value: node
                     .child_by_field_name("value")
                     .ok_or(SyntaxError)
-                    .and_then(|node| Expr::parse(node, text)),
+                    .and_then(|node| Expr::parse(node, text, function_start)),
+                span: Span::new(node, function_start),
             }),
             _ => Err(SyntaxError),
         }
     }
 }
 
-#[derive(Debug)]
-enum Expr {
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
     Block(Block),
-    Identifier(Intern<str>),
+    Identifier {
+        name: Intern<str>,
+        span: Span,
+    },
     FunctionCall {
         name: Result<Intern<str>>,
         arguments: Result<FunctionArguments>,
+        span: Span,
     },
     IntLiteral(IntLiteral),
+    Binary {
+        operator: BinaryOperator,
+        left: Result<Box<Expr>>,
+        right: Result<Box<Expr>>,
+        span: Span,
+    },
+    /// `&value` or `&mut value`.
+    AddressOf {
+        is_mutable: bool,
+        value: Result<Box<Expr>>,
+        span: Span,
+    },
+    /// `*pointer`.
+    Dereference {
+        pointer: Result<Box<Expr>>,
+        span: Span,
+    },
+    /// `value as typ`.
+    Cast {
+        value: Result<Box<Expr>>,
+        typ: Result<Type>,
+        span: Span,
+    },
+    /// `Name { field: value, ... }`.
+    StructLiteral {
+        name: Result<Intern<str>>,
+        fields: Result<Vec<FieldInitializer>>,
+        span: Span,
+    },
+    /// `value.field`.
+    Field {
+        value: Result<Box<Expr>>,
+        field: Result<Intern<str>>,
+        span: Span,
+    },
+    /// `Name::Variant` or `Name::Variant(value, ...)`.
+    Variant {
+        name: Result<Intern<str>>,
+        variant: Result<Intern<str>>,
+        payload: FunctionArguments,
+        span: Span,
+    },
+    /// `match value { pattern => result, ... }`.
+    Match {
+        value: Result<Box<Expr>>,
+        arms: Result<Vec<MatchArm>>,
+        span: Span,
+    },
 }
 
 impl Expr {
-    fn parse(node: Node, text: &Rope) -> Result<Self> {
+    fn parse(node: Node, text: &Rope, function_start: usize) -> Result<Self> {
+        let operand = |field| {
+            node.child_by_field_name(field)
+                .ok_or(SyntaxError)
+                .and_then(|node| Expr::parse(node, text, function_start))
+                .map(Box::new)
+        };
         match node.kind() {
-            "identifier" => parse_identifier(node, text).map(Self::Identifier),
-            "block" => Block::parse(node, text).map(Self::Block),
+            "identifier" => Ok(Self::Identifier {
+                name: parse_identifier(node, text)?,
+                span: Span::new(node, function_start),
+            }),
+            "block" => {
+                Block::parse(node, text, function_start).map(Self::Block)
+            }
             "function_call" => Ok(Self::FunctionCall {
                 name: node
                     .child_by_field_name("name")
@@ -166,20 +694,344 @@ impl Expr {
                     .and_then(|node| parse_identifier(node, text)),
                 arguments: node
                     .child_by_field_name("arguments")
-                    .map(|node| FunctionArguments::parse(node, text))
+                    .map(|node| {
+                        FunctionArguments::parse(node, text, function_start)
+                    })
                     .ok_or(SyntaxError),
+                span: Span::new(node, function_start),
             }),
             "number" => IntLiteral::parse(node, text).map(Self::IntLiteral),
+            "binary_expression" => Ok(Self::Binary {
+                operator: node
+                    .child_by_field_name("operator")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| BinaryOperator::parse(node.kind()))?,
+                left: operand("left"),
+                right: operand("right"),
+                span: Span::new(node, function_start),
+            }),
+            "address_of_expression" => Ok(Self::AddressOf {
+                is_mutable: node
+                    .child(1)
+                    .is_some_and(|child| child.kind() == "mut"),
+                value: operand("value"),
+                span: Span::new(node, function_start),
+            }),
+            "dereference_expression" => Ok(Self::Dereference {
+                pointer: operand("pointer"),
+                span: Span::new(node, function_start),
+            }),
+            "cast_expression" => Ok(Self::Cast {
+                value: operand("value"),
+                typ: node
+                    .child_by_field_name("type")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| Type::parse(node, text)),
+                span: Span::new(node, function_start),
+            }),
+            "struct_expression" => Ok(Self::StructLiteral {
+                name: node
+                    .child_by_field_name("name")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| parse_identifier(node, text)),
+                fields: node
+                    .child_by_field_name("fields")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| {
+                        node.named_children(&mut node.walk())
+                            .filter(|child| !child.is_extra())
+                            .map(|child| {
+                                FieldInitializer::parse(
+                                    child,
+                                    text,
+                                    function_start,
+                                )
+                            })
+                            .collect()
+                    }),
+                span: Span::new(node, function_start),
+            }),
+            "field_expression" => Ok(Self::Field {
+                value: operand("value"),
+                field: node
+                    .child_by_field_name("field")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| parse_identifier(node, text)),
+                span: Span::new(node, function_start),
+            }),
+            "variant_expression" => Ok(Self::Variant {
+                name: node
+                    .child_by_field_name("name")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| parse_identifier(node, text)),
+                variant: node
+                    .child_by_field_name("variant")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| parse_identifier(node, text)),
+                payload: node
+                    .child_by_field_name("payload")
+                    .map_or(FunctionArguments(Vec::new()), |node| {
+                        FunctionArguments::parse(node, text, function_start)
+                    }),
+                span: Span::new(node, function_start),
+            }),
+            "match_expression" => Ok(Self::Match {
+                value: operand("value"),
+                arms: node
+                    .child_by_field_name("arms")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| {
+                        node.named_children(&mut node.walk())
+                            .filter(|child| !child.is_extra())
+                            .map(|child| {
+                                MatchArm::parse(child, text, function_start)
+                            })
+                            .collect()
+                    }),
+                span: Span::new(node, function_start),
+            }),
+            "parenthesized_expression" => node
+                .named_child(0)
+                .filter(|child| !child.is_extra())
+                .ok_or(SyntaxError)
+                .and_then(|node| Expr::parse(node, text, function_start)),
             _ => Err(SyntaxError),
         }
     }
+
+    fn for_each_expr(&self, f: &mut impl FnMut(&Expr)) {
+        f(self);
+        match self {
+            Self::Block(block) => block.for_each_expr(f),
+            Self::FunctionCall { arguments, .. } => {
+                for argument in arguments
+                    .iter()
+                    .flat_map(|arguments| &arguments.0)
+                    .flatten()
+                {
+                    argument.for_each_expr(f);
+                }
+            }
+            Self::Variant { payload, .. } => {
+                for value in payload.0.iter().flatten() {
+                    value.for_each_expr(f);
+                }
+            }
+            Self::Match { value, arms, .. } => {
+                if let Ok(value) = value {
+                    value.for_each_expr(f);
+                }
+                for arm in arms.iter().flatten() {
+                    if let Ok(value) = &arm.value {
+                        value.for_each_expr(f);
+                    }
+                }
+            }
+            Self::Binary { left, right, .. } => {
+                for operand in [left, right].into_iter().flatten() {
+                    operand.for_each_expr(f);
+                }
+            }
+            Self::AddressOf { value: operand, .. }
+            | Self::Dereference {
+                pointer: operand, ..
+            }
+            | Self::Cast { value: operand, .. }
+            | Self::Field { value: operand, .. } => {
+                if let Ok(operand) = operand {
+                    operand.for_each_expr(f);
+                }
+            }
+            Self::StructLiteral { fields, .. } => {
+                for field in fields.iter().flatten() {
+                    if let Ok(value) = &field.value {
+                        value.for_each_expr(f);
+                    }
+                }
+            }
+            Self::Identifier { .. } | Self::IntLiteral(_) => {}
+        }
+    }
+
+    /// The expression that a chain of field accesses like `x.a.b` starts
+    /// from, which is `self` if it isn't a field access, or `None` if the
+    /// chain contains a syntax error.
+    pub fn place_root(&self) -> Option<&Expr> {
+        match self {
+            Self::Field { value, .. } => value.as_ref().ok()?.place_root(),
+            _ => Some(self),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldInitializer {
+    pub name: Intern<str>,
+    pub span: Span,
+    pub value: Result<Expr>,
+}
+
+impl FieldInitializer {
+    fn parse(node: Node, text: &Rope, function_start: usize) -> Result<Self> {
+        if node.kind() != "field_initializer" {
+            return Err(SyntaxError);
+        }
+        let name_node = node.child_by_field_name("name").ok_or(SyntaxError)?;
+        Ok(Self {
+            name: parse_identifier(name_node, text)?,
+            span: Span::new(node, function_start),
+            value: node
+                .child_by_field_name("value")
+                .ok_or(SyntaxError)
+                .and_then(|node| Expr::parse(node, text, function_start)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchArm {
+    pub pattern: Result<Pattern>,
+    pub pattern_span: Span,
+    pub value: Result<Expr>,
 }
 
-#[derive(Debug)]
-struct FunctionArguments(Vec<Result<Expr>>);
+impl MatchArm {
+    fn parse(node: Node, text: &Rope, function_start: usize) -> Result<Self> {
+        if node.kind() != "match_arm" {
+            return Err(SyntaxError);
+        }
+        let pattern_node =
+            node.child_by_field_name("pattern").ok_or(SyntaxError)?;
+        Ok(Self {
+            pattern: Pattern::parse(pattern_node, text),
+            pattern_span: Span::new(pattern_node, function_start),
+            value: node
+                .child_by_field_name("value")
+                .ok_or(SyntaxError)
+                .and_then(|node| Expr::parse(node, text, function_start)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    /// `_`, which matches anything.
+    Wildcard,
+    /// A name, which matches anything and is bound to the value.
+    Binding(Intern<str>),
+    IntLiteral(IntLiteral),
+    /// `start..=end`.
+    Range(IntLiteral, IntLiteral),
+    /// `Name::Variant` or `Name::Variant(binding, ...)`, where each binding
+    /// is a name for a value of the payload or `None` for `_`.
+    Variant {
+        name: Intern<str>,
+        variant: Intern<str>,
+        bindings: Vec<Option<Intern<str>>>,
+    },
+}
+
+impl Pattern {
+    fn parse(node: Node, text: &Rope) -> Result<Self> {
+        let binding = |node| {
+            parse_identifier(node, text)
+                .map(|name| (&*name != "_").then_some(name))
+        };
+        let literal = |field| {
+            node.child_by_field_name(field)
+                .ok_or(SyntaxError)
+                .and_then(|node| IntLiteral::parse(node, text))
+        };
+        match node.kind() {
+            "identifier" => Ok(match binding(node)? {
+                Some(name) => Self::Binding(name),
+                None => Self::Wildcard,
+            }),
+            "number" => IntLiteral::parse(node, text).map(Self::IntLiteral),
+            "range_pattern" => {
+                Ok(Self::Range(literal("start")?, literal("end")?))
+            }
+            "variant_pattern" => Ok(Self::Variant {
+                name: node
+                    .child_by_field_name("name")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| parse_identifier(node, text))?,
+                variant: node
+                    .child_by_field_name("variant")
+                    .ok_or(SyntaxError)
+                    .and_then(|node| parse_identifier(node, text))?,
+                bindings: match node.child_by_field_name("bindings") {
+                    Some(node) => node
+                        .named_children(&mut node.walk())
+                        .filter(|child| !child.is_extra())
+                        .map(|child| {
+                            if child.kind() == "identifier" {
+                                binding(child)
+                            } else {
+                                Err(SyntaxError)
+                            }
+                        })
+                        .collect::<Result<_>>()?,
+                    None => Vec::new(),
+                },
+            }),
+            _ => Err(SyntaxError),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl BinaryOperator {
+    fn parse(token: &str) -> Result<Self> {
+        Ok(match token {
+            "+" => Self::Add,
+            "-" => Self::Sub,
+            "*" => Self::Mul,
+            "/" => Self::Div,
+            "%" => Self::Rem,
+            "&" => Self::And,
+            "|" => Self::Or,
+            "^" => Self::Xor,
+            "<<" => Self::Shl,
+            ">>" => Self::Shr,
+            _ => return Err(SyntaxError),
+        })
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+            Self::And => "&",
+            Self::Or => "|",
+            Self::Xor => "^",
+            Self::Shl => "<<",
+            Self::Shr => ">>",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionArguments(pub Vec<Result<Expr>>);
 
 impl FunctionArguments {
-    fn parse(node: Node, text: &Rope) -> Self {
+    fn parse(node: Node, text: &Rope, function_start: usize) -> Self {
         Self(
             node.named_children(&mut node.walk())
                 .filter(|child| child.is_extra() == child.is_error())
@@ -187,7 +1039,7 @@ impl FunctionArguments {
                     if node.is_error() {
                         Err(SyntaxError)
                     } else {
-                        Expr::parse(node, text)
+                        Expr::parse(node, text, function_start)
                     }
                 })
                 .collect(),
@@ -195,8 +1047,8 @@ impl FunctionArguments {
     }
 }
 
-#[derive(Debug)]
-enum IntLiteral {
+#[derive(Clone, Debug, PartialEq)]
+pub enum IntLiteral {
     U8(Result<u8>),
     U16(Result<u16>),
     U32(Result<u32>),
@@ -239,6 +1091,33 @@ impl IntLiteral {
             _ => panic!("invalid integer literal type suffix"),
         })
     }
+
+    /// The value of the literal, or an error if it doesn't fit its type.
+    pub fn value(&self) -> Result<i128> {
+        Ok(match *self {
+            Self::U8(value) => value?.into(),
+            Self::U16(value) => value?.into(),
+            Self::U32(value) => value?.into(),
+            Self::U64(value) => value?.into(),
+            Self::I8(value) => value?.into(),
+            Self::I16(value) => value?.into(),
+            Self::I32(value) => value?.into(),
+            Self::I64(value) => value?.into(),
+        })
+    }
+
+    pub fn typ(&self) -> Type {
+        match self {
+            Self::U8(_) => Type::U8,
+            Self::U16(_) => Type::U16,
+            Self::U32(_) => Type::U32,
+            Self::U64(_) => Type::U64,
+            Self::I8(_) => Type::I8,
+            Self::I16(_) => Type::I16,
+            Self::I32(_) => Type::I32,
+            Self::I64(_) => Type::I64,
+        }
+    }
 }
 
 fn parse_identifier(node: Node, text: &Rope) -> Result<Intern<str>> {
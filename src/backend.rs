@@ -0,0 +1,92 @@
+//! Code generation from the IR.
+
+pub mod c;
+pub mod dwarf;
+pub mod elf;
+pub mod llvm;
+pub mod wasm;
+pub mod x86_64;
+
+use crate::ir::Program;
+use internment::Intern;
+
+/// Turns a Gneiss identifier, which may contain `-`, `@` and any Unicode
+/// letters, into a symbol name that is valid in C and assemblers. The mapping
+/// is injective: literal underscores are doubled, so a single underscore
+/// always starts an escape sequence.
+pub fn mangle(name: &str) -> String {
+    let mut mangled = String::from("gn_");
+    for c in name.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => mangled.push(c),
+            '_' => mangled.push_str("__"),
+            '-' => mangled.push_str("_h"),
+            '@' => mangled.push_str("_a"),
+            _ => mangled.push_str(&format!("_u{:x}_", u32::from(c))),
+        }
+    }
+    mangled
+}
+
+/// The symbol of the function called `name`. `extern` and `export` functions
+/// keep their name so that C code and the linker find them.
+fn symbol(program: &Program, name: Intern<str>) -> String {
+    let is_c_function = program.extern_function(name).is_some()
+        || program
+            .function(name)
+            .is_some_and(|function| function.is_export);
+    if is_c_function {
+        name.to_string()
+    } else {
+        mangle(&name)
+    }
+}
+
+/// Appends `value` in the unsigned LEB128 encoding, seven bits at a time.
+fn uleb(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Appends `value` in the signed LEB128 encoding.
+fn sleb(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0)
+            || (value == -1 && byte & 0x40 != 0);
+        if done {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mangled_names_are_valid_c_identifiers() {
+        assert_eq!(mangle("main"), "gn_main");
+        assert_eq!(mangle("kebab-case"), "gn_kebab_hcase");
+        assert_eq!(mangle("@builtin"), "gn__abuiltin");
+        assert_eq!(mangle("snake_case"), "gn_snake__case");
+        assert_eq!(mangle("äö"), "gn__ue4__uf6_");
+    }
+
+    #[test]
+    fn mangling_is_injective() {
+        assert_ne!(mangle("a-b"), mangle("a_hb"));
+        assert_ne!(mangle("a_b"), mangle("a__b"));
+        assert_ne!(mangle("@a"), mangle("_aa"));
+    }
+}
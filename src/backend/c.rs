@@ -0,0 +1,898 @@
+//! Translates the IR into portable C that only depends on `<stdint.h>`. Blocks
+//! become labels, block parameters become local variables that are assigned
+//! before jumping, and arithmetic is done on unsigned types so that it wraps
+//! around like in the IR instead of overflowing. Programs that make system
+//! calls also need the `syscall` function of the C library on Linux.
+//!
+//! Pointers and struct and enum values are `uint64_t` addresses, and memory
+//! is accessed with `memcpy` from `<string.h>` so that neither alignment nor
+//! the aliasing rules of C get in the way. Stack slots are arrays of bytes
+//! with the size and alignment of their layout.
+
+use super::{mangle, symbol};
+use crate::{
+    ast::BinaryOperator,
+    ir::{Function, Instruction, Program, Terminator, Value},
+    layout::Layout,
+    opt::OptLevel,
+    typ::Type,
+};
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fmt::{self, Write as _},
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+pub fn emit(program: &Program) -> String {
+    generate(program, true)
+}
+
+/// A header that declares the `export` functions of `program` for C code that
+/// links with it. `name` determines the include guard.
+pub fn emit_header(program: &Program, name: &str) -> String {
+    let mut out = String::new();
+    write_header(&mut out, program, name)
+        .expect("writing to a `String` can't fail");
+    out
+}
+
+/// The kind of library that `build_library` creates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LibraryKind {
+    /// An archive of objects, linked into the program that uses it.
+    Static,
+    /// A shared object, loaded when the program that uses it starts.
+    Shared,
+}
+
+/// Compiles `program` into an executable with the system C compiler, which is
+/// `cc` unless the `CC` environment variable says otherwise. `linker_args`
+/// come last, so that libraries can provide the `extern` functions.
+pub fn build_executable(
+    program: &Program,
+    output: &Path,
+    opt_level: OptLevel,
+    linker_args: &[OsString],
+) -> Result<(), String> {
+    let mut args = vec![OsStr::new("-o"), output.as_os_str()];
+    args.extend(linker_args.iter().map(OsString::as_os_str));
+    run_compiler(&emit(program), opt_level, &args)
+}
+
+/// Compiles `program` into a library without a C entry point. Static
+/// libraries are archived with `ar`, or with the `AR` environment variable if
+/// it is set, and `linker_args` only apply to shared ones.
+pub fn build_library(
+    program: &Program,
+    output: &Path,
+    opt_level: OptLevel,
+    kind: LibraryKind,
+    linker_args: &[OsString],
+) -> Result<(), String> {
+    let code = generate(program, false);
+    match kind {
+        LibraryKind::Shared => {
+            let mut args = ["-shared", "-fPIC", "-o"].map(OsStr::new).to_vec();
+            args.push(output.as_os_str());
+            args.extend(linker_args.iter().map(OsString::as_os_str));
+            run_compiler(&code, opt_level, &args)
+        }
+        LibraryKind::Static => {
+            let object = temporary_object();
+            let result = run_compiler(
+                &code,
+                opt_level,
+                &[
+                    OsStr::new("-c"),
+                    OsStr::new("-fPIC"),
+                    OsStr::new("-o"),
+                    object.as_os_str(),
+                ],
+            )
+            .and_then(|()| archive(&object, output));
+            std::fs::remove_file(&object).ok();
+            result
+        }
+    }
+}
+
+/// Links a relocatable object into an executable with the system C compiler,
+/// whose C runtime calls `main`.
+pub fn link(
+    object: &[u8],
+    output: &Path,
+    linker_args: &[OsString],
+) -> Result<(), String> {
+    let compiler = compiler();
+    let path = temporary_object();
+    std::fs::write(&path, object)
+        .map_err(|err| format!("failed to write {}: {err}", path.display()))?;
+    let status = Command::new(&compiler)
+        .arg(&path)
+        .arg("-o")
+        .arg(output)
+        .args(linker_args)
+        .status();
+    // The object is only needed while linking.
+    std::fs::remove_file(&path).ok();
+    let status =
+        status.map_err(|err| format!("failed to run `{compiler}`: {err}"))?;
+    if !status.success() {
+        return Err(format!("`{compiler}` failed with {status}"));
+    }
+    Ok(())
+}
+
+fn compiler() -> String {
+    std::env::var("CC").unwrap_or_else(|_| "cc".into())
+}
+
+fn temporary_object() -> PathBuf {
+    std::env::temp_dir().join(format!("gneiss-{}.o", std::process::id()))
+}
+
+/// Compiles `code`, which the compiler reads from standard input, passing
+/// `args` after the input.
+fn run_compiler(
+    code: &str,
+    opt_level: OptLevel,
+    args: &[&OsStr],
+) -> Result<(), String> {
+    let compiler = compiler();
+    let opt_flag = match opt_level {
+        OptLevel::O0 => "-O0",
+        OptLevel::O1 => "-O1",
+        OptLevel::O2 => "-O2",
+    };
+    let mut child = Command::new(&compiler)
+        .args(["-x", "c", "-", opt_flag])
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to run `{compiler}`: {err}"))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(code.as_bytes())
+        .map_err(|err| format!("failed to write to `{compiler}`: {err}"))?;
+    let status = child
+        .wait()
+        .map_err(|err| format!("failed to wait for `{compiler}`: {err}"))?;
+    if !status.success() {
+        return Err(format!("`{compiler}` failed with {status}"));
+    }
+    Ok(())
+}
+
+/// Creates a static library that contains `object`.
+fn archive(object: &Path, output: &Path) -> Result<(), String> {
+    let archiver = std::env::var("AR").unwrap_or_else(|_| "ar".into());
+    // `ar` adds to existing archives, which could still contain the objects
+    // of an earlier build.
+    std::fs::remove_file(output).ok();
+    let status = Command::new(&archiver)
+        .arg("rcs")
+        .arg(output)
+        .arg(object)
+        .status()
+        .map_err(|err| format!("failed to run `{archiver}`: {err}"))?;
+    if !status.success() {
+        return Err(format!("`{archiver}` failed with {status}"));
+    }
+    Ok(())
+}
+
+/// Translates `program`, with a C `main` function that calls the Gneiss one
+/// if `has_entry_point` is set.
+fn generate(program: &Program, has_entry_point: bool) -> String {
+    let mut out = String::new();
+    write_program(&mut out, program, has_entry_point)
+        .expect("writing to a `String` can't fail");
+    out
+}
+
+fn write_header(
+    out: &mut String,
+    program: &Program,
+    name: &str,
+) -> fmt::Result {
+    let mut guard = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect::<String>();
+    if !guard.starts_with(|c: char| c.is_ascii_uppercase()) {
+        guard.insert_str(0, "GNEISS_");
+    }
+    writeln!(out, "#ifndef {guard}_H")?;
+    writeln!(out, "#define {guard}_H")?;
+    writeln!(out)?;
+    writeln!(out, "#include <stdint.h>")?;
+    writeln!(out)?;
+    writeln!(out, "#ifdef __cplusplus")?;
+    writeln!(out, "extern \"C\" {{")?;
+    writeln!(out, "#endif")?;
+    writeln!(out)?;
+    for function in &program.functions {
+        if function.is_export {
+            write_prototype(
+                out,
+                function.return_type,
+                &function.name,
+                function
+                    .parameters
+                    .iter()
+                    .map(|&parameter| function.typ(parameter)),
+            )?;
+        }
+    }
+    writeln!(out)?;
+    writeln!(out, "#ifdef __cplusplus")?;
+    writeln!(out, "}}")?;
+    writeln!(out, "#endif")?;
+    writeln!(out)?;
+    writeln!(out, "#endif")
+}
+
+fn write_program(
+    out: &mut String,
+    program: &Program,
+    has_entry_point: bool,
+) -> fmt::Result {
+    writeln!(out, "#include <stdint.h>")?;
+    if program.uses_memory() {
+        writeln!(out, "#include <string.h>")?;
+    }
+    if program.uses_syscalls() {
+        writeln!(out, "#include <errno.h>")?;
+        writeln!(out)?;
+        writeln!(out, "long syscall(long number, ...);")?;
+    }
+    writeln!(out)?;
+    for function in &program.externs {
+        write_prototype(
+            out,
+            function.return_type,
+            &function.name,
+            function.parameter_types.iter().copied(),
+        )?;
+    }
+    for function in &program.functions {
+        write_signature(out, program, function)?;
+        writeln!(out, ";")?;
+    }
+    for function in &program.functions {
+        writeln!(out)?;
+        write_function(out, program, function)?;
+    }
+
+    // The C entry point calls the Gneiss one and turns its result into the
+    // exit status.
+    if let Some(main) = program
+        .function("main".into())
+        .filter(|main| has_entry_point && main.parameters.is_empty())
+    {
+        let name = mangle(&main.name);
+        writeln!(out)?;
+        writeln!(out, "int main(void) {{")?;
+        if main.return_type == Type::Unit {
+            writeln!(out, "    {name}();")?;
+            writeln!(out, "    return 0;")?;
+        } else {
+            writeln!(out, "    return (int){name}();")?;
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+fn write_signature(
+    out: &mut String,
+    program: &Program,
+    function: &Function,
+) -> fmt::Result {
+    write!(
+        out,
+        "{} {}(",
+        c_type(function.return_type),
+        symbol(program, function.name)
+    )?;
+    if function.parameters.is_empty() {
+        out.push_str("void");
+    }
+    for (i, &parameter) in function.parameters.iter().enumerate() {
+        if i != 0 {
+            out.push_str(", ");
+        }
+        write!(out, "{} {parameter}", c_type(function.typ(parameter)))?;
+    }
+    out.push(')');
+    Ok(())
+}
+
+/// Declares a function that is defined in another translation unit, without
+/// parameter names. Unlike in definitions, pointers keep their types so that
+/// C code sees the real signature.
+fn write_prototype(
+    out: &mut String,
+    return_type: Type,
+    name: &str,
+    mut parameter_types: impl Iterator<Item = Type>,
+) -> fmt::Result {
+    let return_type = declared_type(return_type);
+    out.push_str(&return_type);
+    if !return_type.ends_with('*') {
+        out.push(' ');
+    }
+    write!(out, "{name}(")?;
+    match parameter_types.next() {
+        Some(typ) => out.push_str(&declared_type(typ)),
+        None => out.push_str("void"),
+    }
+    for typ in parameter_types {
+        write!(out, ", {}", declared_type(typ))?;
+    }
+    writeln!(out, ");")
+}
+
+fn write_function(
+    out: &mut String,
+    program: &Program,
+    function: &Function,
+) -> fmt::Result {
+    write_signature(out, program, function)?;
+    writeln!(out, " {{")?;
+
+    let mut locals =
+        function
+            .blocks
+            .iter()
+            .flat_map(|block| {
+                block.parameters.iter().copied().chain(
+                    block.instructions.iter().filter_map(Instruction::dest),
+                )
+            })
+            .collect::<Vec<_>>();
+    locals.sort();
+    for local in locals {
+        write!(out, "    {} {local};", c_type(function.typ(local)))?;
+        match function.values[local.0].name {
+            Some(name) => writeln!(out, " // {name}")?,
+            None => writeln!(out)?,
+        }
+    }
+    for instruction in function
+        .blocks
+        .iter()
+        .flat_map(|block| block.instructions.iter())
+    {
+        if let Instruction::StackSlot { dest } = instruction {
+            let layout = function
+                .typ(*dest)
+                .pointee()
+                .and_then(|typ| Layout::of(typ, &program.types))
+                .expect("stack slots have a layout");
+            // Arrays can't be empty, but `unit` still needs an address.
+            writeln!(
+                out,
+                "    _Alignas({}) uint8_t {dest}_slot[{}];",
+                layout.align,
+                layout.size.max(1),
+            )?;
+        }
+    }
+
+    let targets = function
+        .blocks
+        .iter()
+        .flat_map(|block| block.terminator.successors())
+        .collect::<HashSet<_>>();
+    for id in function.block_ids() {
+        if targets.contains(&id) {
+            writeln!(out, "{id}:")?;
+        }
+        let block = function.block(id);
+        for instruction in &block.instructions {
+            write_instruction(out, program, function, instruction)?;
+        }
+        match &block.terminator {
+            Terminator::Return(Some(value)) => {
+                writeln!(out, "    return {value};")?
+            }
+            Terminator::Return(None) => writeln!(out, "    return;")?,
+            Terminator::Jump(target, arguments) => {
+                let parameters = &function.block(*target).parameters;
+                write_parallel_copy(out, function, parameters, arguments)?;
+                writeln!(out, "    goto {target};")?;
+            }
+            Terminator::Branch {
+                value,
+                low,
+                high,
+                then,
+                otherwise,
+            } => {
+                let condition = range_condition(function, *value, *low, *high);
+                writeln!(out, "    if ({condition}) goto {then};")?;
+                writeln!(out, "    goto {otherwise};")?;
+            }
+        }
+    }
+    writeln!(out, "}}")
+}
+
+/// A C expression that checks whether `value` lies within `low..=high`,
+/// leaving out comparisons with the limits of its type that are always true.
+fn range_condition(
+    function: &Function,
+    value: Value,
+    low: u64,
+    high: u64,
+) -> String {
+    let typ = function.typ(value);
+    if low == high {
+        return format!("{value} == {}", constant(typ, low));
+    }
+    let (min, max) = typ.range().expect("branches are on integers");
+    let mut comparisons = Vec::new();
+    if typ.to_i128(low) != min {
+        comparisons.push(format!("{value} >= {}", constant(typ, low)));
+    }
+    if typ.to_i128(high) != max {
+        comparisons.push(format!("{value} <= {}", constant(typ, high)));
+    }
+    if comparisons.is_empty() {
+        "1".to_owned()
+    } else {
+        comparisons.join(" && ")
+    }
+}
+
+fn write_instruction(
+    out: &mut String,
+    program: &Program,
+    function: &Function,
+    instruction: &Instruction,
+) -> fmt::Result {
+    match instruction {
+        Instruction::Const { dest, value } => writeln!(
+            out,
+            "    {dest} = {};",
+            constant(function.typ(*dest), *value)
+        ),
+        Instruction::Copy { dest, source } => {
+            writeln!(out, "    {dest} = {source};")
+        }
+        Instruction::Binary {
+            dest,
+            operator,
+            left,
+            right,
+        } => writeln!(
+            out,
+            "    {dest} = {};",
+            binary(function.typ(*dest), *operator, *left, *right)
+        ),
+        Instruction::Call {
+            dest,
+            callee,
+            arguments,
+        } => {
+            // `extern` functions are declared with pointer types, which
+            // addresses have to be converted to and from.
+            let extern_function = program.extern_function(*callee);
+            out.push_str("    ");
+            if let Some(dest) = dest {
+                write!(out, "{dest} = ")?;
+                if extern_function.is_some_and(|function| {
+                    matches!(function.return_type, Type::Pointer { .. })
+                }) {
+                    out.push_str("(uint64_t)");
+                }
+            }
+            write!(out, "{}(", symbol(program, *callee))?;
+            for (i, argument) in arguments.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(", ");
+                }
+                if let Some(typ @ Type::Pointer { .. }) =
+                    extern_function.map(|function| function.parameter_types[i])
+                {
+                    write!(out, "({})", declared_type(typ))?;
+                }
+                write!(out, "{argument}")?;
+            }
+            writeln!(out, ");")
+        }
+        Instruction::Syscall { dest, arguments } => {
+            write!(out, "    {dest} = syscall(")?;
+            for (i, argument) in arguments.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(", ");
+                }
+                write!(out, "{argument}")?;
+            }
+            writeln!(out, ");")?;
+            // The C library reports errors in `errno`, while the kernel
+            // returns them as negative numbers.
+            writeln!(out, "    if ({dest} == -1) {dest} = -errno;")
+        }
+        Instruction::StackSlot { dest } => {
+            writeln!(out, "    {dest} = (uint64_t)(uintptr_t)&{dest}_slot;")
+        }
+        Instruction::Load { dest, address } => writeln!(
+            out,
+            "    memcpy(&{dest}, (void *)(uintptr_t){address}, \
+             sizeof {dest});"
+        ),
+        Instruction::Store { address, value } => writeln!(
+            out,
+            "    memcpy((void *)(uintptr_t){address}, &{value}, \
+             sizeof {value});"
+        ),
+        Instruction::Cast { dest, source } => writeln!(
+            out,
+            "    {dest} = ({}){source};",
+            c_type(function.typ(*dest))
+        ),
+    }
+}
+
+/// Assigns `arguments` to `parameters` as if all assignments happened at once,
+/// since an argument may be a parameter that is assigned earlier.
+fn write_parallel_copy(
+    out: &mut String,
+    function: &Function,
+    parameters: &[Value],
+    arguments: &[Value],
+) -> fmt::Result {
+    match (parameters, arguments) {
+        ([], []) => Ok(()),
+        ([parameter], [argument]) => {
+            writeln!(out, "    {parameter} = {argument};")
+        }
+        _ => {
+            writeln!(out, "    {{")?;
+            for (i, argument) in arguments.iter().enumerate() {
+                let typ = c_type(function.typ(*argument));
+                writeln!(out, "        {typ} t{i} = {argument};")?;
+            }
+            for (i, parameter) in parameters.iter().enumerate() {
+                writeln!(out, "        {parameter} = t{i};")?;
+            }
+            writeln!(out, "    }}")
+        }
+    }
+}
+
+fn c_type(typ: Type) -> &'static str {
+    match typ {
+        Type::Unit => "void",
+        Type::I8 => "int8_t",
+        Type::I16 => "int16_t",
+        Type::I32 => "int32_t",
+        Type::I64 => "int64_t",
+        Type::U8 => "uint8_t",
+        Type::U16 => "uint16_t",
+        Type::U32 => "uint32_t",
+        Type::U64 | Type::Pointer { .. } | Type::Named(_) => "uint64_t",
+    }
+}
+
+/// The type of a parameter or return value in a declaration, where pointers
+/// are `T *`, or `const T *` if they can't be written through. Structs and
+/// enums aren't declared, so pointers to them are `void *`.
+fn declared_type(typ: Type) -> String {
+    let Type::Pointer {
+        pointee,
+        is_mutable,
+    } = typ
+    else {
+        return c_type(typ).into();
+    };
+    let mut declared = match *pointee {
+        Type::Named(_) => "void".into(),
+        pointee => declared_type(pointee),
+    };
+    if !is_mutable {
+        if declared.ends_with('*') {
+            declared.push_str("const");
+        } else {
+            declared.insert_str(0, "const ");
+        }
+    }
+    if !declared.ends_with('*') {
+        declared.push(' ');
+    }
+    declared.push('*');
+    declared
+}
+
+fn constant(typ: Type, value: u64) -> String {
+    match typ {
+        Type::U64 | Type::Pointer { .. } => format!("UINT64_C({value})"),
+        Type::I64 if value == 1 << 63 => "INT64_MIN".into(),
+        Type::I64 => format!("INT64_C({})", typ.to_i128(value)),
+        _ => format!("({}){}", c_type(typ), typ.to_i128(value)),
+    }
+}
+
+/// The operands are converted to at least 32 bits before the operation,
+/// because C would otherwise promote small unsigned types to `int`, whose
+/// overflow is undefined. Shifting a signed number left is undefined too if
+/// it is negative or a bit reaches the sign bit, so only right shifts are
+/// done in the signed type. Converting the result back to a signed type and
+/// shifting negative numbers right are implementation-defined, but behave as
+/// two's complement on every compiler worth supporting.
+fn binary(
+    typ: Type,
+    operator: BinaryOperator,
+    left: Value,
+    right: Value,
+) -> String {
+    let bits = typ.bits().expect("values aren't `unit`");
+    let (unsigned, signed) = if bits <= 32 {
+        ("uint32_t", "int32_t")
+    } else {
+        ("uint64_t", "int64_t")
+    };
+    let symbol = operator.symbol();
+    let expression = match operator {
+        BinaryOperator::Shl | BinaryOperator::Shr => {
+            let left_type =
+                if operator == BinaryOperator::Shr && typ.is_signed() {
+                    signed
+                } else {
+                    unsigned
+                };
+            format!(
+                "({left_type}){left} {symbol} (({unsigned}){right} & {})",
+                bits - 1
+            )
+        }
+        BinaryOperator::Div | BinaryOperator::Rem if typ.is_signed() => {
+            format!("({signed}){left} {symbol} ({signed}){right}")
+        }
+        _ => format!("({unsigned}){left} {symbol} ({unsigned}){right}"),
+    };
+    format!("({})({expression})", c_type(typ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower::lower_text;
+
+    #[test]
+    fn emits_c() {
+        let program = lower_text(
+            "
+fn main() -> i8 {
+    let x = the-answer() + 1_i8;
+    x >> 2_i8
+}
+fn the-answer() -> i8 { 42_i8 }
+",
+            OptLevel::O1,
+        );
+        assert_eq!(
+            emit(&program),
+            "\
+#include <stdint.h>
+
+int8_t gn_main(void);
+int8_t gn_the_hanswer(void);
+
+int8_t gn_main(void) {
+    int8_t v0;
+    int8_t v1;
+    int8_t v2; // x
+    int8_t v4;
+    int8_t v5;
+    v0 = gn_the_hanswer();
+    v1 = (int8_t)1;
+    v2 = (int8_t)((uint32_t)v0 + (uint32_t)v1);
+    v4 = (int8_t)2;
+    v5 = (int8_t)((int32_t)v2 >> ((uint32_t)v4 & 7));
+    return v5;
+}
+
+int8_t gn_the_hanswer(void) {
+    int8_t v0;
+    v0 = (int8_t)42;
+    return v0;
+}
+
+int main(void) {
+    return (int)gn_main();
+}
+"
+        );
+    }
+
+    #[test]
+    fn signed_left_shifts_are_unsigned() {
+        let program =
+            lower_text("fn main() -> i32 { -1_i32 << 3_i32 }", OptLevel::O0);
+        let c = emit(&program);
+        assert!(
+            c.contains(" = (int32_t)((uint32_t)v0 << ((uint32_t)v1 & 31));"),
+            "{c}"
+        );
+    }
+
+    #[test]
+    fn executables_exit_with_the_result_of_main() {
+        if Command::new("cc").arg("--version").output().is_err() {
+            eprintln!("skipping because `cc` isn't available");
+            return;
+        }
+        // 242 * 3 wraps around to 214 in a `u8`.
+        let program = lower_text(
+            "
+fn main() -> u8 { (the-answer() + 200_u8) * 3_u8 }
+fn the-answer() -> u8 { 42_u8 }
+",
+            OptLevel::O0,
+        );
+        let path = std::env::temp_dir()
+            .join(format!("gneiss-c-test-{}", std::process::id()));
+        build_executable(&program, &path, OptLevel::O0, &[]).unwrap();
+        let status = Command::new(&path).status().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status.code(), Some(214));
+    }
+
+    #[test]
+    fn extern_functions_are_called_by_their_c_name() {
+        let program = lower_text(
+            "
+extern fn abs(x: i32) -> i32;
+fn main() -> i32 { abs(-7_i32) }
+",
+            OptLevel::O0,
+        );
+        let c = emit(&program);
+        assert!(c.contains("\nint32_t abs(int32_t);\n"), "{c}");
+        assert!(c.contains(" = abs(v0);"), "{c}");
+        if Command::new("cc").arg("--version").output().is_err() {
+            eprintln!("skipping because `cc` isn't available");
+            return;
+        }
+        let path = std::env::temp_dir()
+            .join(format!("gneiss-c-extern-test-{}", std::process::id()));
+        build_executable(&program, &path, OptLevel::O0, &[]).unwrap();
+        let status = Command::new(&path).status().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(status.code(), Some(7));
+    }
+
+    #[test]
+    fn pointers_read_and_write_memory() {
+        let program = lower_text(
+            "
+fn main() -> u16 {
+    let x = 196608_u64;
+    let p = &mut x;
+    *p = *p + 1_u64;
+    second(&x as *u16) + (*p as u16)
+}
+fn second(p: *u16) -> u16 { *(p + 1_i64) }
+",
+            OptLevel::O1,
+        );
+        let c = emit(&program);
+        assert!(c.contains("\n#include <string.h>\n"), "{c}");
+        if Command::new("cc").arg("--version").output().is_err() {
+            eprintln!("skipping because `cc` isn't available");
+            return;
+        }
+        let path = std::env::temp_dir()
+            .join(format!("gneiss-c-pointer-test-{}", std::process::id()));
+        build_executable(&program, &path, OptLevel::O2, &[]).unwrap();
+        let status = Command::new(&path).status().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // `x` is 0x30001, whose second 16 bits are 3.
+        assert_eq!(status.code(), Some(4));
+    }
+
+    #[test]
+    fn declarations_keep_pointer_types() {
+        let program = lower_text(
+            "
+extern fn fill(p: *mut u8, n: u64) -> *mut u8;
+export fn first(p: *u8, names: **mut u8, n: u64) -> u64 {
+    fill(*names, n);
+    *p as u64
+}
+",
+            OptLevel::O0,
+        );
+        let header = emit_header(&program, "first");
+        assert!(
+            header.contains(
+                "\nuint64_t first(const uint8_t *, uint8_t *const *, \
+                 uint64_t);\n"
+            ),
+            "{header}"
+        );
+        let c = emit(&program);
+        assert!(c.contains("\nuint8_t *fill(uint8_t *, uint64_t);\n"), "{c}");
+        assert!(c.contains(" = (uint64_t)fill((uint8_t *)v"), "{c}");
+    }
+
+    #[test]
+    fn libraries_export_functions_to_c() {
+        let program = lower_text(
+            "
+export fn add_one(x: u8) -> u8 { plus(x, 1_u8) }
+fn plus(x: u8, y: u8) -> u8 { x + y }
+fn main() -> u8 { 0_u8 }
+",
+            OptLevel::O0,
+        );
+        let header = emit_header(&program, "add-one");
+        assert_eq!(
+            header,
+            "\
+#ifndef ADD_ONE_H
+#define ADD_ONE_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern \"C\" {
+#endif
+
+uint8_t add_one(uint8_t);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
+"
+        );
+        if Command::new("cc").arg("--version").output().is_err() {
+            eprintln!("skipping because `cc` isn't available");
+            return;
+        }
+        let directory = std::env::temp_dir()
+            .join(format!("gneiss-c-library-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let library = directory.join("libadd.a");
+        build_library(
+            &program,
+            &library,
+            OptLevel::O0,
+            LibraryKind::Static,
+            &[],
+        )
+        .unwrap();
+        std::fs::write(directory.join("add-one.h"), header).unwrap();
+        std::fs::write(
+            directory.join("main.c"),
+            "#include \"add-one.h\"\nint main(void) { return add_one(41); }\n",
+        )
+        .unwrap();
+        // The library has no `main` of its own to clash with this one.
+        let executable = directory.join("program");
+        let status = Command::new("cc")
+            .arg(directory.join("main.c"))
+            .arg(&library)
+            .arg("-o")
+            .arg(&executable)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let status = Command::new(&executable).status().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(status.code(), Some(42));
+    }
+}
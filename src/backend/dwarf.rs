@@ -0,0 +1,519 @@
+//! Writes DWARF debug information, version 4, so that debuggers like GDB and
+//! LLDB can map machine code back to lines of Gneiss and show the values of
+//! parameters and `let` bindings. `.debug_info` describes each function and
+//! its variables, `.debug_line` maps addresses to lines and columns, and
+//! `.debug_abbrev` declares the attributes that `.debug_info` uses.
+
+use super::{
+    elf::{DebugRelocation, DebugSection, DebugTarget, Symbol},
+    sleb, uleb,
+};
+use crate::{ast::Span, ir, typ::Type};
+use internment::Intern;
+use ropey::Rope;
+use std::{collections::HashMap, path::Path};
+
+/// The source file that a program was compiled from.
+pub struct Source<'a> {
+    pub path: &'a Path,
+    /// The directory that the compiler ran in, which `path` may be relative
+    /// to.
+    pub directory: &'a Path,
+    pub text: &'a Rope,
+    /// Where each function starts in `text`, which is what the spans in its IR
+    /// are relative to.
+    pub function_starts: HashMap<Intern<str>, usize>,
+}
+
+impl Source<'_> {
+    /// The line and column of the byte at `offset` in `function`, counting
+    /// from one.
+    fn position(&self, function: Intern<str>, offset: usize) -> (u64, u64) {
+        let byte = self.function_starts[&function] + offset;
+        let line = self.text.byte_to_line(byte);
+        let column = byte - self.text.line_to_byte(line);
+        (line as u64 + 1, column as u64 + 1)
+    }
+}
+
+/// What a backend knows about the code it generated for a function.
+pub struct Function<'a> {
+    pub ir: &'a ir::Function,
+    /// Where the code for each instruction with a source position starts,
+    /// relative to the start of the function, in order.
+    pub lines: Vec<(u64, Span)>,
+    /// Where each value is kept, indexed by `Value`. A value keeps its
+    /// location for its whole lifetime, so debuggers show whatever is left
+    /// there once it is dead.
+    pub locations: Vec<Option<Location>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// A register, by its DWARF number.
+    Register(u16),
+    /// Memory at an offset from the address in a register.
+    Memory { base: u16, offset: i64 },
+}
+
+const DW_TAG_FORMAL_PARAMETER: u64 = 0x05;
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_TAG_BASE_TYPE: u64 = 0x24;
+const DW_TAG_SUBPROGRAM: u64 = 0x2e;
+const DW_TAG_VARIABLE: u64 = 0x34;
+
+const DW_AT_LOCATION: u64 = 0x02;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_BYTE_SIZE: u64 = 0x0b;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_AT_LANGUAGE: u64 = 0x13;
+const DW_AT_COMP_DIR: u64 = 0x1b;
+const DW_AT_PRODUCER: u64 = 0x25;
+const DW_AT_DECL_FILE: u64 = 0x3a;
+const DW_AT_DECL_LINE: u64 = 0x3b;
+const DW_AT_ENCODING: u64 = 0x3e;
+const DW_AT_EXTERNAL: u64 = 0x3f;
+const DW_AT_TYPE: u64 = 0x49;
+const DW_AT_LINKAGE_NAME: u64 = 0x6e;
+
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_REF4: u64 = 0x13;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+const DW_FORM_EXPRLOC: u64 = 0x18;
+const DW_FORM_FLAG_PRESENT: u64 = 0x19;
+
+const DW_ATE_ADDRESS: u8 = 0x01;
+const DW_ATE_SIGNED: u8 = 0x05;
+const DW_ATE_UNSIGNED: u8 = 0x08;
+const DW_OP_REGX: u8 = 0x90;
+const DW_OP_BREGX: u8 = 0x92;
+
+/// Debuggers don't know Gneiss, and C is the language whose expressions are
+/// closest to it.
+const DW_LANG_C99: u16 = 0x0c;
+
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_COLUMN: u8 = 0x05;
+const DW_LNS_SET_PROLOGUE_END: u8 = 0x0a;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+/// The number of operands of each standard opcode of the line number program,
+/// starting with opcode 1.
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+/// The codes of the abbreviations, each of which is the tag of an entry in
+/// `.debug_info`, whether it has children and the forms of its attributes.
+const COMPILE_UNIT: u64 = 1;
+const BASE_TYPE: u64 = 2;
+const SUBPROGRAM: u64 = 3;
+/// A function that returns `unit`, which has no type.
+const UNIT_SUBPROGRAM: u64 = 4;
+const FORMAL_PARAMETER: u64 = 5;
+const VARIABLE: u64 = 6;
+
+type Abbreviation = (u64, u64, bool, &'static [(u64, u64)]);
+
+const ABBREVIATIONS: [Abbreviation; 6] = [
+    (
+        COMPILE_UNIT,
+        DW_TAG_COMPILE_UNIT,
+        true,
+        &[
+            (DW_AT_PRODUCER, DW_FORM_STRING),
+            (DW_AT_LANGUAGE, DW_FORM_DATA2),
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_COMP_DIR, DW_FORM_STRING),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            // The size of the code rather than where it ends.
+            (DW_AT_HIGH_PC, DW_FORM_DATA8),
+            (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        ],
+    ),
+    (
+        BASE_TYPE,
+        DW_TAG_BASE_TYPE,
+        false,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_ENCODING, DW_FORM_DATA1),
+            (DW_AT_BYTE_SIZE, DW_FORM_DATA1),
+        ],
+    ),
+    (
+        SUBPROGRAM,
+        DW_TAG_SUBPROGRAM,
+        true,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_LINKAGE_NAME, DW_FORM_STRING),
+            (DW_AT_DECL_FILE, DW_FORM_DATA1),
+            (DW_AT_DECL_LINE, DW_FORM_UDATA),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA8),
+            (DW_AT_EXTERNAL, DW_FORM_FLAG_PRESENT),
+            (DW_AT_TYPE, DW_FORM_REF4),
+        ],
+    ),
+    (
+        UNIT_SUBPROGRAM,
+        DW_TAG_SUBPROGRAM,
+        true,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_LINKAGE_NAME, DW_FORM_STRING),
+            (DW_AT_DECL_FILE, DW_FORM_DATA1),
+            (DW_AT_DECL_LINE, DW_FORM_UDATA),
+            (DW_AT_LOW_PC, DW_FORM_ADDR),
+            (DW_AT_HIGH_PC, DW_FORM_DATA8),
+            (DW_AT_EXTERNAL, DW_FORM_FLAG_PRESENT),
+        ],
+    ),
+    (
+        FORMAL_PARAMETER,
+        DW_TAG_FORMAL_PARAMETER,
+        false,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+        ],
+    ),
+    (
+        VARIABLE,
+        DW_TAG_VARIABLE,
+        false,
+        &[
+            (DW_AT_NAME, DW_FORM_STRING),
+            (DW_AT_TYPE, DW_FORM_REF4),
+            (DW_AT_LOCATION, DW_FORM_EXPRLOC),
+        ],
+    ),
+];
+
+/// Describes `functions`, which are in a `.text` section of `text_size`
+/// bytes, as a single compilation unit.
+pub fn sections(
+    source: &Source,
+    functions: &[(&Symbol, Function)],
+    text_size: u64,
+) -> Vec<DebugSection> {
+    vec![
+        debug_abbrev(),
+        debug_info(source, functions, text_size),
+        debug_line(source, functions),
+    ]
+}
+
+fn debug_abbrev() -> DebugSection {
+    let mut abbrev = Writer::new(".debug_abbrev");
+    for (code, tag, has_children, attributes) in ABBREVIATIONS {
+        abbrev.uleb(code);
+        abbrev.uleb(tag);
+        abbrev.u8(u8::from(has_children));
+        for &(name, form) in attributes {
+            abbrev.uleb(name);
+            abbrev.uleb(form);
+        }
+        abbrev.bytes(&[0, 0]);
+    }
+    abbrev.u8(0);
+    abbrev.finish()
+}
+
+fn debug_info(
+    source: &Source,
+    functions: &[(&Symbol, Function)],
+    text_size: u64,
+) -> DebugSection {
+    let mut info = Writer::new(".debug_info");
+    // The length is filled in at the end.
+    info.u32(0);
+    info.u16(4);
+    info.section_offset(".debug_abbrev", 0);
+    // The size of an address.
+    info.u8(8);
+
+    info.uleb(COMPILE_UNIT);
+    info.string(concat!("gneiss ", env!("CARGO_PKG_VERSION")));
+    info.u16(DW_LANG_C99);
+    info.string(&source.path.to_string_lossy());
+    info.string(&source.directory.to_string_lossy());
+    info.text_address(0);
+    info.u64(text_size);
+    info.section_offset(".debug_line", 0);
+
+    // References to types are offsets from the start of the unit, which is
+    // the start of the section.
+    let mut types = Vec::<(Type, u32)>::new();
+    let used_types = functions.iter().flat_map(|(_, function)| {
+        let values = variables(function).map(|(value, _, _)| value);
+        std::iter::once(function.ir.return_type)
+            .chain(values.map(|value| function.ir.typ(value)))
+    });
+    for typ in used_types {
+        let Some(bits) = typ.bits() else {
+            continue;
+        };
+        if types.iter().any(|&(other, _)| other == typ) {
+            continue;
+        }
+        types.push((typ, info.offset()));
+        info.uleb(BASE_TYPE);
+        info.string(&typ.to_string());
+        // Pointers and struct values are plain addresses rather than
+        // pointer and structure types, which would need entries for what
+        // they point to and for the fields.
+        info.u8(match typ {
+            Type::Pointer { .. } | Type::Named(_) => DW_ATE_ADDRESS,
+            _ if typ.is_signed() => DW_ATE_SIGNED,
+            _ => DW_ATE_UNSIGNED,
+        });
+        info.u8((bits / 8) as u8);
+    }
+    let type_offset = |typ: Type| {
+        types
+            .iter()
+            .find(|&&(other, _)| other == typ)
+            .map(|&(_, offset)| offset)
+            .expect("every type has an entry")
+    };
+
+    for (symbol, function) in functions {
+        let name = function.ir.name;
+        let return_type = function.ir.return_type;
+        info.uleb(if return_type == Type::Unit {
+            UNIT_SUBPROGRAM
+        } else {
+            SUBPROGRAM
+        });
+        info.string(&name);
+        info.string(&symbol.name);
+        info.u8(1);
+        info.uleb(source.position(name, 0).0);
+        info.text_address(symbol.offset);
+        info.u64(symbol.size);
+        if return_type != Type::Unit {
+            info.u32(type_offset(return_type));
+        }
+
+        for (value, name, location) in variables(function) {
+            info.uleb(if function.ir.parameters.contains(&value) {
+                FORMAL_PARAMETER
+            } else {
+                VARIABLE
+            });
+            info.string(&name);
+            info.u32(type_offset(function.ir.typ(value)));
+            let mut expression = Vec::new();
+            match location {
+                Location::Register(register) => {
+                    expression.push(DW_OP_REGX);
+                    uleb(&mut expression, u64::from(register));
+                }
+                Location::Memory { base, offset } => {
+                    expression.push(DW_OP_BREGX);
+                    uleb(&mut expression, u64::from(base));
+                    sleb(&mut expression, offset);
+                }
+            }
+            info.uleb(expression.len() as u64);
+            info.bytes(&expression);
+        }
+        // The end of the function's children.
+        info.u8(0);
+    }
+    // The end of the unit's children.
+    info.u8(0);
+
+    info.patch_length(0);
+    info.finish()
+}
+
+/// The named values of `function` that have a location, which are its
+/// parameters and `let` bindings.
+fn variables<'a>(
+    function: &'a Function,
+) -> impl Iterator<Item = (ir::Value, Intern<str>, Location)> + 'a {
+    function
+        .ir
+        .values
+        .iter()
+        .enumerate()
+        .filter_map(|(i, info)| {
+            Some((ir::Value(i), info.name?, function.locations[i]?))
+        })
+}
+
+fn debug_line(
+    source: &Source,
+    functions: &[(&Symbol, Function)],
+) -> DebugSection {
+    let mut line = Writer::new(".debug_line");
+    // The length of the unit and of the header are filled in later.
+    line.u32(0);
+    line.u16(4);
+    line.u32(0);
+    let header_start = line.offset();
+    // The size of the smallest instruction, the number of operations per
+    // instruction, whether rows start statements by default, and the
+    // parameters of special opcodes, which aren't used.
+    line.bytes(&[1, 1, 1, -5_i8 as u8, 14]);
+    line.u8(STANDARD_OPCODE_LENGTHS.len() as u8 + 1);
+    line.bytes(&STANDARD_OPCODE_LENGTHS);
+    // No include directories besides the compilation directory, and the
+    // source file without a modification time or size.
+    line.u8(0);
+    line.string(&source.path.to_string_lossy());
+    line.bytes(&[0, 0, 0]);
+    line.u8(0);
+    line.patch_length(header_start - 4);
+
+    for (symbol, function) in functions {
+        let name = function.ir.name;
+        // The prologue belongs to the line where the function starts.
+        let (start_line, start_column) = source.position(name, 0);
+        let mut rows = vec![(0, start_line, start_column)];
+        for &(address, span) in &function.lines {
+            let (line, column) = source.position(name, span.start);
+            let last = rows.last_mut().expect("there is a first row");
+            if last.0 == address {
+                // Instructions that produced no code.
+                *last = (address, line, column);
+            } else if (last.1, last.2) != (line, column) {
+                rows.push((address, line, column));
+            }
+        }
+
+        line.u8(0);
+        line.uleb(9);
+        line.u8(DW_LNE_SET_ADDRESS);
+        line.text_address(symbol.offset);
+        let (mut address, mut current_line, mut column) = (0, 1, 0);
+        for (i, &(row_address, row_line, row_column)) in rows.iter().enumerate()
+        {
+            if i == 1 {
+                line.u8(DW_LNS_SET_PROLOGUE_END);
+            }
+            if row_address != address {
+                line.u8(DW_LNS_ADVANCE_PC);
+                line.uleb(row_address - address);
+                address = row_address;
+            }
+            if row_line != current_line {
+                line.u8(DW_LNS_ADVANCE_LINE);
+                line.sleb(row_line as i64 - current_line as i64);
+                current_line = row_line;
+            }
+            if row_column != column {
+                line.u8(DW_LNS_SET_COLUMN);
+                line.uleb(row_column);
+                column = row_column;
+            }
+            line.u8(DW_LNS_COPY);
+        }
+        line.u8(DW_LNS_ADVANCE_PC);
+        line.uleb(symbol.size - address);
+        line.bytes(&[0, 1, DW_LNE_END_SEQUENCE]);
+    }
+
+    line.patch_length(0);
+    line.finish()
+}
+
+/// Builds the contents of a debug section and the relocations in it.
+struct Writer {
+    section: DebugSection,
+}
+
+impl Writer {
+    fn new(name: &'static str) -> Self {
+        Self {
+            section: DebugSection {
+                name,
+                contents: Vec::new(),
+                relocations: Vec::new(),
+            },
+        }
+    }
+
+    fn offset(&self) -> u32 {
+        u32::try_from(self.section.contents.len())
+            .expect("debug sections are smaller than 4 GiB")
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.section.contents.extend(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.section.contents.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn uleb(&mut self, value: u64) {
+        uleb(&mut self.section.contents, value);
+    }
+
+    fn sleb(&mut self, value: i64) {
+        sleb(&mut self.section.contents, value);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.bytes(string.as_bytes());
+        self.u8(0);
+    }
+
+    /// The address of the byte at `offset` in `.text`.
+    fn text_address(&mut self, offset: u64) {
+        self.relocate(DebugTarget::Text, offset);
+        self.u64(0);
+    }
+
+    /// The offset of a byte in another debug section.
+    fn section_offset(&mut self, section: &'static str, offset: u64) {
+        self.relocate(DebugTarget::Section(section), offset);
+        self.u32(0);
+    }
+
+    fn relocate(&mut self, target: DebugTarget, addend: u64) {
+        self.section.relocations.push(DebugRelocation {
+            offset: u64::from(self.offset()),
+            target,
+            addend,
+        });
+    }
+
+    /// Fills in the 32-bit length at `offset` with the size of everything
+    /// after it.
+    fn patch_length(&mut self, offset: u32) {
+        let length = self.offset() - offset - 4;
+        let offset = offset as usize;
+        self.section.contents[offset..offset + 4]
+            .copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn finish(self) -> DebugSection {
+        self.section
+    }
+}
@@ -0,0 +1,621 @@
+//! Writes machine code into ELF files for x86-64: relocatable object files for
+//! a linker, or static executables for Linux that need no linker at all. Both
+//! can carry debug information in sections that aren't loaded into memory.
+
+/// Machine code together with the functions it defines and the places where
+/// it refers to other functions.
+#[derive(Debug, Default)]
+pub struct Object {
+    pub text: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    pub debug_sections: Vec<DebugSection>,
+}
+
+/// A function defined in `.text`.
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A 32-bit field in `.text` that holds the address of `symbol` relative to
+/// the end of the field, like the operand of a `call`.
+#[derive(Debug)]
+pub struct Relocation {
+    pub offset: u64,
+    pub symbol: String,
+}
+
+/// A section of debug information, like `.debug_info`.
+#[derive(Debug)]
+pub struct DebugSection {
+    pub name: &'static str,
+    pub contents: Vec<u8>,
+    pub relocations: Vec<DebugRelocation>,
+}
+
+/// A field in a debug section that refers to code or to another debug
+/// section, whose final value depends on where the linker puts them.
+#[derive(Debug)]
+pub struct DebugRelocation {
+    pub offset: u64,
+    pub target: DebugTarget,
+    /// The offset within the target.
+    pub addend: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum DebugTarget {
+    /// A 64-bit address in `.text`.
+    Text,
+    /// A 32-bit offset into the debug section with the given name.
+    Section(&'static str),
+}
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const R_X86_64_64: u64 = 1;
+const R_X86_64_PLT32: u64 = 4;
+const R_X86_64_32: u64 = 10;
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+/// Section indices of relocatable files, in the order of the section headers.
+/// The debug sections come last, each followed by its relocations if it has
+/// any. Executables have `.text` first too.
+const TEXT: u16 = 1;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+const SHSTRTAB: u16 = 5;
+const FIRST_DEBUG_SECTION: u16 = 7;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: u16 = 56;
+const SECTION_HEADER_SIZE: u16 = 64;
+const SYMBOL_SIZE: u64 = 24;
+const RELOCATION_SIZE: u64 = 24;
+
+/// Where executables are loaded. The whole file is mapped, so the code follows
+/// the headers at the same offset in the file and in memory.
+const BASE_ADDRESS: u64 = 0x40_0000;
+const PROGRAM_HEADER_COUNT: u16 = 2;
+const EXECUTABLE_TEXT_OFFSET: u64 =
+    HEADER_SIZE as u64 + (PROGRAM_HEADER_SIZE * PROGRAM_HEADER_COUNT) as u64;
+
+impl Object {
+    pub fn write_relocatable(&self) -> Vec<u8> {
+        // Functions that are called but not defined here are left for the
+        // linker to find.
+        let mut undefined = Vec::new();
+        for relocation in &self.relocations {
+            let name = relocation.symbol.as_str();
+            if !self.symbols.iter().any(|symbol| symbol.name == name)
+                && !undefined.contains(&name)
+            {
+                undefined.push(name);
+            }
+        }
+
+        let mut debug_indices = Vec::new();
+        let mut next_index = FIRST_DEBUG_SECTION;
+        for section in &self.debug_sections {
+            debug_indices.push(next_index);
+            next_index += if section.relocations.is_empty() { 1 } else { 2 };
+        }
+
+        // The null symbol comes first, then a local symbol for `.text` and
+        // each debug section that relocations can refer to, and then the
+        // global ones.
+        let mut strtab = StringTable::default();
+        let mut symtab = vec![0; SYMBOL_SIZE as usize];
+        for index in [TEXT].into_iter().chain(debug_indices.iter().copied()) {
+            symtab.extend(0_u32.to_le_bytes());
+            symtab.push(STT_SECTION);
+            symtab.push(0);
+            symtab.extend(index.to_le_bytes());
+            symtab.extend([0; 16]);
+        }
+        let first_global = symtab.len() as u64 / SYMBOL_SIZE;
+        let debug_section_symbol = |name: &str| {
+            let index = self
+                .debug_sections
+                .iter()
+                .position(|section| section.name == name)
+                .expect("relocations refer to sections in the object");
+            // After the null symbol and the one for `.text`.
+            index as u64 + 2
+        };
+        let symbol_index = |name: &str| {
+            let index = self
+                .symbols
+                .iter()
+                .map(|symbol| symbol.name.as_str())
+                .chain(undefined.iter().copied())
+                .position(|other| other == name)
+                .expect("every referenced symbol is in the table");
+            first_global + index as u64
+        };
+        self.write_symbols(&mut symtab, &mut strtab, 0);
+        for name in &undefined {
+            symtab.extend(strtab.add(name).to_le_bytes());
+            symtab.push(STB_GLOBAL << 4);
+            symtab.extend([0; 19]);
+        }
+
+        let mut rela = Vec::new();
+        for relocation in &self.relocations {
+            rela.extend(relocation.offset.to_le_bytes());
+            let info = symbol_index(&relocation.symbol) << 32 | R_X86_64_PLT32;
+            rela.extend(info.to_le_bytes());
+            // The field is relative to its end rather than its start.
+            rela.extend((-4_i64).to_le_bytes());
+        }
+        let debug_relas = self
+            .debug_sections
+            .iter()
+            .map(|section| {
+                let mut rela = Vec::new();
+                for relocation in &section.relocations {
+                    rela.extend(relocation.offset.to_le_bytes());
+                    let info = match relocation.target {
+                        DebugTarget::Text => 1 << 32 | R_X86_64_64,
+                        DebugTarget::Section(name) => {
+                            debug_section_symbol(name) << 32 | R_X86_64_32
+                        }
+                    };
+                    rela.extend(info.to_le_bytes());
+                    rela.extend(relocation.addend.to_le_bytes());
+                }
+                rela
+            })
+            .collect::<Vec<_>>();
+
+        let mut sections = vec![
+            (
+                ".text".to_owned(),
+                SectionHeader {
+                    typ: SHT_PROGBITS,
+                    flags: SHF_ALLOC | SHF_EXECINSTR,
+                    align: 16,
+                    ..SectionHeader::default()
+                },
+                self.text.as_slice(),
+            ),
+            (
+                ".rela.text".to_owned(),
+                SectionHeader {
+                    typ: SHT_RELA,
+                    flags: SHF_INFO_LINK,
+                    link: SYMTAB,
+                    info: u32::from(TEXT),
+                    align: 8,
+                    entry_size: RELOCATION_SIZE,
+                    ..SectionHeader::default()
+                },
+                &rela,
+            ),
+            (
+                ".symtab".to_owned(),
+                SectionHeader {
+                    typ: SHT_SYMTAB,
+                    link: STRTAB,
+                    info: first_global as u32,
+                    align: 8,
+                    entry_size: SYMBOL_SIZE,
+                    ..SectionHeader::default()
+                },
+                &symtab,
+            ),
+            (
+                ".strtab".to_owned(),
+                SectionHeader {
+                    typ: SHT_STRTAB,
+                    ..SectionHeader::default()
+                },
+                &strtab.bytes,
+            ),
+            (
+                ".shstrtab".to_owned(),
+                SectionHeader {
+                    typ: SHT_STRTAB,
+                    ..SectionHeader::default()
+                },
+                // Filled in below, once it contains every name.
+                &[],
+            ),
+            // An empty `.note.GNU-stack` tells the linker that the stack
+            // doesn't need to be executable.
+            (
+                ".note.GNU-stack".to_owned(),
+                SectionHeader {
+                    typ: SHT_PROGBITS,
+                    ..SectionHeader::default()
+                },
+                &[],
+            ),
+        ];
+        for ((section, rela), index) in self
+            .debug_sections
+            .iter()
+            .zip(&debug_relas)
+            .zip(debug_indices)
+        {
+            sections.push((
+                section.name.to_owned(),
+                SectionHeader {
+                    typ: SHT_PROGBITS,
+                    align: 1,
+                    ..SectionHeader::default()
+                },
+                &section.contents,
+            ));
+            if !rela.is_empty() {
+                sections.push((
+                    format!(".rela{}", section.name),
+                    SectionHeader {
+                        typ: SHT_RELA,
+                        flags: SHF_INFO_LINK,
+                        link: SYMTAB,
+                        info: u32::from(index),
+                        align: 8,
+                        entry_size: RELOCATION_SIZE,
+                        ..SectionHeader::default()
+                    },
+                    rela,
+                ));
+            }
+        }
+
+        let mut shstrtab = StringTable::default();
+        let sections = sections
+            .into_iter()
+            .map(|(name, header, contents)| {
+                let header = SectionHeader {
+                    name: shstrtab.add(&name),
+                    ..header
+                };
+                (header, contents)
+            })
+            .collect::<Vec<_>>();
+
+        let mut file = vec![0; HEADER_SIZE];
+        let mut headers = vec![SectionHeader::default()];
+        for (header, contents) in sections {
+            let contents = if headers.len() == usize::from(SHSTRTAB) {
+                &shstrtab.bytes
+            } else {
+                contents
+            };
+            while !(file.len() as u64).is_multiple_of(header.align.max(1)) {
+                file.push(0);
+            }
+            headers.push(SectionHeader {
+                offset: file.len() as u64,
+                size: contents.len() as u64,
+                ..header
+            });
+            file.extend(contents);
+        }
+
+        let section_headers_offset = write_section_headers(&mut file, &headers);
+        write_header(
+            &mut file,
+            ET_REL,
+            0,
+            0,
+            section_headers_offset,
+            &headers,
+            SHSTRTAB,
+        );
+        file
+    }
+
+    /// Links the object into a static executable that starts at the symbol
+    /// `entry`. Every function that is called has to be defined here.
+    pub fn write_executable(&self, entry: &str) -> Result<Vec<u8>, String> {
+        let text_address = BASE_ADDRESS + EXECUTABLE_TEXT_OFFSET;
+        let address = |name: &str| {
+            self.symbols
+                .iter()
+                .find(|symbol| symbol.name == name)
+                .map(|symbol| text_address + symbol.offset)
+        };
+
+        let mut text = self.text.clone();
+        for relocation in &self.relocations {
+            let target = address(&relocation.symbol).ok_or_else(|| {
+                format!("undefined reference to `{}`", relocation.symbol)
+            })?;
+            let offset = relocation.offset as usize;
+            // The field is relative to its end rather than its start.
+            let end = text_address + relocation.offset + 4;
+            let relative = i32::try_from(target as i64 - end as i64)
+                .expect("code is smaller than 2 GiB");
+            text[offset..offset + 4].copy_from_slice(&relative.to_le_bytes());
+        }
+        let entry = address(entry)
+            .ok_or_else(|| format!("the entry point `{entry}` is missing"))?;
+
+        let mut file = vec![0; HEADER_SIZE];
+        let size = EXECUTABLE_TEXT_OFFSET + text.len() as u64;
+        // A single readable and executable segment maps the headers and the
+        // code.
+        ProgramHeader {
+            typ: PT_LOAD,
+            flags: PF_R | PF_X,
+            offset: 0,
+            address: BASE_ADDRESS,
+            size,
+            align: 0x1000,
+        }
+        .write(&mut file);
+        // Without this, the kernel would make the stack executable.
+        ProgramHeader {
+            typ: PT_GNU_STACK,
+            flags: PF_R | PF_W,
+            offset: 0,
+            address: 0,
+            size: 0,
+            align: 16,
+        }
+        .write(&mut file);
+        file.extend(&text);
+
+        // The sections after the code are only there for debuggers and
+        // tools like `objdump`, since there is nothing left to link.
+        let mut shstrtab = StringTable::default();
+        let mut headers = vec![
+            SectionHeader::default(),
+            SectionHeader {
+                name: shstrtab.add(".text"),
+                typ: SHT_PROGBITS,
+                flags: SHF_ALLOC | SHF_EXECINSTR,
+                address: text_address,
+                offset: EXECUTABLE_TEXT_OFFSET,
+                size: text.len() as u64,
+                align: 16,
+                ..SectionHeader::default()
+            },
+        ];
+        for section in &self.debug_sections {
+            let mut contents = section.contents.clone();
+            for relocation in &section.relocations {
+                let offset = relocation.offset as usize;
+                match relocation.target {
+                    DebugTarget::Text => {
+                        let address = text_address + relocation.addend;
+                        contents[offset..offset + 8]
+                            .copy_from_slice(&address.to_le_bytes());
+                    }
+                    // Each debug section only has the contents from this
+                    // object, so offsets into them stay the same.
+                    DebugTarget::Section(_) => {
+                        let addend = u32::try_from(relocation.addend)
+                            .expect("debug sections are smaller than 4 GiB");
+                        contents[offset..offset + 4]
+                            .copy_from_slice(&addend.to_le_bytes());
+                    }
+                }
+            }
+            headers.push(SectionHeader {
+                name: shstrtab.add(section.name),
+                typ: SHT_PROGBITS,
+                offset: file.len() as u64,
+                size: contents.len() as u64,
+                align: 1,
+                ..SectionHeader::default()
+            });
+            file.extend(contents);
+        }
+
+        let mut strtab = StringTable::default();
+        let mut symtab = vec![0; SYMBOL_SIZE as usize];
+        self.write_symbols(&mut symtab, &mut strtab, text_address);
+        while !file.len().is_multiple_of(8) {
+            file.push(0);
+        }
+        let symtab_index = headers.len() as u32;
+        headers.push(SectionHeader {
+            name: shstrtab.add(".symtab"),
+            typ: SHT_SYMTAB,
+            offset: file.len() as u64,
+            size: symtab.len() as u64,
+            link: symtab_index + 1,
+            // Every symbol but the null one is global.
+            info: 1,
+            align: 8,
+            entry_size: SYMBOL_SIZE,
+            ..SectionHeader::default()
+        });
+        file.extend(symtab);
+        headers.push(SectionHeader {
+            name: shstrtab.add(".strtab"),
+            typ: SHT_STRTAB,
+            offset: file.len() as u64,
+            size: strtab.bytes.len() as u64,
+            ..SectionHeader::default()
+        });
+        file.extend(strtab.bytes);
+        let names = u16::try_from(headers.len()).expect("few sections");
+        let name = shstrtab.add(".shstrtab");
+        headers.push(SectionHeader {
+            name,
+            typ: SHT_STRTAB,
+            offset: file.len() as u64,
+            size: shstrtab.bytes.len() as u64,
+            ..SectionHeader::default()
+        });
+        file.extend(shstrtab.bytes);
+
+        let section_headers_offset = write_section_headers(&mut file, &headers);
+        write_header(
+            &mut file,
+            ET_EXEC,
+            entry,
+            PROGRAM_HEADER_COUNT,
+            section_headers_offset,
+            &headers,
+            names,
+        );
+        Ok(file)
+    }
+
+    /// Appends a global symbol for each function, whose value is its offset
+    /// in `.text` plus `base`.
+    fn write_symbols(
+        &self,
+        symtab: &mut Vec<u8>,
+        strtab: &mut StringTable,
+        base: u64,
+    ) {
+        for symbol in &self.symbols {
+            symtab.extend(strtab.add(&symbol.name).to_le_bytes());
+            symtab.push(STB_GLOBAL << 4 | STT_FUNC);
+            symtab.push(0);
+            symtab.extend(TEXT.to_le_bytes());
+            symtab.extend((base + symbol.offset).to_le_bytes());
+            symtab.extend(symbol.size.to_le_bytes());
+        }
+    }
+}
+
+/// Appends the section headers, aligned to 8 bytes, and returns their offset.
+fn write_section_headers(file: &mut Vec<u8>, headers: &[SectionHeader]) -> u64 {
+    while !file.len().is_multiple_of(8) {
+        file.push(0);
+    }
+    let offset = file.len() as u64;
+    for header in headers {
+        header.write(file);
+    }
+    offset
+}
+
+fn write_header(
+    file: &mut [u8],
+    typ: u16,
+    entry: u64,
+    program_header_count: u16,
+    section_headers_offset: u64,
+    section_headers: &[SectionHeader],
+    section_names: u16,
+) {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    // 64-bit, little-endian, version 1, System V ABI.
+    header.extend(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    // The type of file, for x86-64, version 1.
+    header.extend(typ.to_le_bytes());
+    header.extend(62_u16.to_le_bytes());
+    header.extend(1_u32.to_le_bytes());
+    header.extend(entry.to_le_bytes());
+    // Program headers follow this header if there are any.
+    let program_headers_offset = if program_header_count == 0 {
+        0
+    } else {
+        HEADER_SIZE as u64
+    };
+    header.extend(program_headers_offset.to_le_bytes());
+    header.extend(section_headers_offset.to_le_bytes());
+    // Flags, the size of this header and the size and number of program
+    // headers.
+    header.extend(0_u32.to_le_bytes());
+    header.extend((HEADER_SIZE as u16).to_le_bytes());
+    header.extend(PROGRAM_HEADER_SIZE.to_le_bytes());
+    header.extend(program_header_count.to_le_bytes());
+    header.extend(SECTION_HEADER_SIZE.to_le_bytes());
+    let section_count =
+        u16::try_from(section_headers.len()).expect("few sections");
+    header.extend(section_count.to_le_bytes());
+    header.extend(section_names.to_le_bytes());
+    file[..HEADER_SIZE].copy_from_slice(&header);
+}
+
+/// A segment of an executable.
+struct ProgramHeader {
+    typ: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    size: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    fn write(&self, file: &mut Vec<u8>) {
+        file.extend(self.typ.to_le_bytes());
+        file.extend(self.flags.to_le_bytes());
+        file.extend(self.offset.to_le_bytes());
+        file.extend(self.address.to_le_bytes());
+        // The physical address, which nothing uses.
+        file.extend(self.address.to_le_bytes());
+        // The size in the file and in memory.
+        file.extend(self.size.to_le_bytes());
+        file.extend(self.size.to_le_bytes());
+        file.extend(self.align.to_le_bytes());
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entry_size: u64,
+}
+
+impl SectionHeader {
+    fn write(&self, file: &mut Vec<u8>) {
+        file.extend(self.name.to_le_bytes());
+        file.extend(self.typ.to_le_bytes());
+        file.extend(self.flags.to_le_bytes());
+        file.extend(self.address.to_le_bytes());
+        file.extend(self.offset.to_le_bytes());
+        file.extend(self.size.to_le_bytes());
+        file.extend(self.link.to_le_bytes());
+        file.extend(self.info.to_le_bytes());
+        file.extend(self.align.to_le_bytes());
+        file.extend(self.entry_size.to_le_bytes());
+    }
+}
+
+/// Null-terminated strings that are referred to by their offsets.
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        // Offset zero is the empty string.
+        Self { bytes: vec![0] }
+    }
+}
+
+impl StringTable {
+    fn add(&mut self, string: &str) -> u32 {
+        let offset = u32::try_from(self.bytes.len()).expect("small table");
+        self.bytes.extend(string.bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
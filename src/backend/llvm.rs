@@ -0,0 +1,590 @@
+//! Translates the IR into textual LLVM IR, so that `clang` or `llc` can
+//! optimize it further and compile it for targets we don't support ourselves.
+//! Block parameters become phi nodes. LLVM has no instructions for constants
+//! and copies, so constants are written where they are used and copies become
+//! no-op bitcasts that keep the names of `let` bindings visible. System calls
+//! become inline assembly for x86-64 Linux.
+//!
+//! Pointers are `i64`s like in the other backends and become opaque `ptr`s
+//! with `inttoptr` right before memory is accessed, which needs LLVM 15 or
+//! newer. Stack slots are all allocated at the start of the entry block.
+
+use super::{mangle, symbol};
+use crate::{
+    ast::BinaryOperator,
+    ir::{
+        BlockId, ExternFunction, Function, Instruction, Program, Terminator,
+        Value,
+    },
+    layout::Layout,
+    typ::Type,
+};
+use std::{
+    cmp::Ordering,
+    fmt::{self, Write as _},
+};
+
+pub fn emit(program: &Program) -> String {
+    let mut out = String::new();
+    write_program(&mut out, program).expect("writing to a `String` can't fail");
+    out
+}
+
+fn write_program(out: &mut String, program: &Program) -> fmt::Result {
+    for function in &program.externs {
+        write_declaration(out, function)?;
+    }
+    for (i, function) in program.functions.iter().enumerate() {
+        if i != 0 || !program.externs.is_empty() {
+            writeln!(out)?;
+        }
+        FunctionWriter::new(program, function).write(out)?;
+    }
+
+    // The C entry point calls the Gneiss one and turns its result into the
+    // exit status.
+    if let Some(main) = program
+        .function("main".into())
+        .filter(|main| main.parameters.is_empty())
+    {
+        let name = mangle(&main.name);
+        writeln!(out)?;
+        writeln!(out, "define i32 @main() {{")?;
+        match main.return_type {
+            Type::Unit => {
+                writeln!(out, "  call void @{name}()")?;
+                writeln!(out, "  ret i32 0")?;
+            }
+            typ => {
+                let llvm_type = llvm_type(typ);
+                writeln!(out, "  %result = call {llvm_type} @{name}()")?;
+                let cast = match typ.bits() {
+                    Some(64) => "trunc",
+                    Some(32) => "bitcast",
+                    _ if typ.is_signed() => "sext",
+                    _ => "zext",
+                };
+                writeln!(out, "  %status = {cast} {llvm_type} %result to i32")?;
+                writeln!(out, "  ret i32 %status")?;
+            }
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+/// Declares a function from a C library. The C ABI expects 8- and 16-bit
+/// integers to be extended to 32 bits, which LLVM only does when asked.
+fn write_declaration(
+    out: &mut String,
+    function: &ExternFunction,
+) -> fmt::Result {
+    out.push_str("declare ");
+    if let Some(attribute) = extension(function.return_type) {
+        write!(out, "{attribute} ")?;
+    }
+    write!(
+        out,
+        "{} @{}(",
+        llvm_type(function.return_type),
+        function.name
+    )?;
+    for (i, &typ) in function.parameter_types.iter().enumerate() {
+        if i != 0 {
+            out.push_str(", ");
+        }
+        out.push_str(llvm_type(typ));
+        if let Some(attribute) = extension(typ) {
+            write!(out, " {attribute}")?;
+        }
+    }
+    writeln!(out, ")")
+}
+
+/// The attribute that extends an 8- or 16-bit integer at C calls.
+fn extension(typ: Type) -> Option<&'static str> {
+    match typ.bits()? {
+        8 | 16 if typ.is_signed() => Some("signext"),
+        8 | 16 => Some("zeroext"),
+        _ => None,
+    }
+}
+
+struct FunctionWriter<'a> {
+    program: &'a Program,
+    function: &'a Function,
+    /// The value of each constant, indexed by `Value`.
+    constants: Vec<Option<u64>>,
+    /// The jumps to each block, with the block they come from and their
+    /// arguments, indexed by `BlockId`.
+    incoming: Vec<Vec<(BlockId, &'a [Value])>>,
+}
+
+impl<'a> FunctionWriter<'a> {
+    fn new(program: &'a Program, function: &'a Function) -> Self {
+        let mut constants = vec![None; function.values.len()];
+        let mut incoming = vec![Vec::new(); function.blocks.len()];
+        for id in function.block_ids() {
+            let block = function.block(id);
+            for instruction in &block.instructions {
+                if let Instruction::Const { dest, value } = *instruction {
+                    constants[dest.0] = Some(value);
+                }
+            }
+            if let Terminator::Jump(target, arguments) = &block.terminator {
+                incoming[target.0].push((id, arguments.as_slice()));
+            }
+        }
+        Self {
+            program,
+            function,
+            constants,
+            incoming,
+        }
+    }
+
+    fn write(&self, out: &mut String) -> fmt::Result {
+        let function = self.function;
+        // Calls between Gneiss functions agree on the extension without it.
+        let attribute = |typ| extension(typ).filter(|_| function.is_export);
+        out.push_str("define ");
+        if let Some(attribute) = attribute(function.return_type) {
+            write!(out, "{attribute} ")?;
+        }
+        write!(
+            out,
+            "{} @{}(",
+            llvm_type(function.return_type),
+            symbol(self.program, function.name)
+        )?;
+        for (i, &parameter) in function.parameters.iter().enumerate() {
+            if i != 0 {
+                out.push_str(", ");
+            }
+            let typ = function.typ(parameter);
+            out.push_str(llvm_type(typ));
+            if let Some(attribute) = attribute(typ) {
+                write!(out, " {attribute}")?;
+            }
+            write!(out, " %{parameter}")?;
+        }
+        writeln!(out, ") {{")?;
+
+        for id in function.block_ids() {
+            if id != Function::ENTRY {
+                writeln!(out)?;
+            }
+            writeln!(out, "{id}:")?;
+            if id == Function::ENTRY {
+                self.write_allocas(out)?;
+            }
+            let block = function.block(id);
+            for (i, &parameter) in block.parameters.iter().enumerate() {
+                write!(
+                    out,
+                    "  %{parameter} = phi {} ",
+                    llvm_type(function.typ(parameter))
+                )?;
+                for (j, &(predecessor, arguments)) in
+                    self.incoming[id.0].iter().enumerate()
+                {
+                    if j != 0 {
+                        out.push_str(", ");
+                    }
+                    write!(
+                        out,
+                        "[ {}, %{predecessor} ]",
+                        self.operand(arguments[i])
+                    )?;
+                }
+                self.write_name(out, parameter)?;
+            }
+            for (i, instruction) in block.instructions.iter().enumerate() {
+                self.write_instruction(out, (id, i), instruction)?;
+            }
+            match &block.terminator {
+                Terminator::Return(Some(value)) => writeln!(
+                    out,
+                    "  ret {} {}",
+                    llvm_type(function.typ(*value)),
+                    self.operand(*value)
+                )?,
+                Terminator::Return(None) => writeln!(out, "  ret void")?,
+                Terminator::Jump(target, _) => {
+                    writeln!(out, "  br label %{target}")?
+                }
+                &Terminator::Branch {
+                    value,
+                    low,
+                    high,
+                    then,
+                    otherwise,
+                } => {
+                    // Subtracting `low` turns the range into `0..=high - low`
+                    // for either signedness, so one unsigned comparison does.
+                    let typ = function.typ(value);
+                    let llvm_type = llvm_type(typ);
+                    writeln!(
+                        out,
+                        "  %{id}.offset = sub {llvm_type} {}, {}",
+                        self.operand(value),
+                        literal(typ, low)
+                    )?;
+                    writeln!(
+                        out,
+                        "  %{id}.in = icmp ule {llvm_type} %{id}.offset, {}",
+                        literal(typ, high.wrapping_sub(low))
+                    )?;
+                    writeln!(
+                        out,
+                        "  br i1 %{id}.in, label %{then}, label %{otherwise}"
+                    )?
+                }
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_allocas(&self, out: &mut String) -> fmt::Result {
+        let instructions = self
+            .function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.instructions);
+        for instruction in instructions {
+            if let Instruction::StackSlot { dest } = instruction {
+                let layout = self
+                    .function
+                    .typ(*dest)
+                    .pointee()
+                    .and_then(|typ| Layout::of(typ, &self.program.types))
+                    .expect("stack slots have a layout");
+                // `unit` still needs an address.
+                writeln!(
+                    out,
+                    "  %{dest}.slot = alloca [{} x i8], align {}",
+                    layout.size.max(1),
+                    layout.align,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the instruction at `location`, which is a block and an index in
+    /// it, for naming temporaries of instructions without a result.
+    fn write_instruction(
+        &self,
+        out: &mut String,
+        location: (BlockId, usize),
+        instruction: &Instruction,
+    ) -> fmt::Result {
+        let function = self.function;
+        match instruction {
+            Instruction::Const { .. } => Ok(()),
+            Instruction::Copy { dest, source } => {
+                let typ = llvm_type(function.typ(*dest));
+                write!(
+                    out,
+                    "  %{dest} = bitcast {typ} {} to {typ}",
+                    self.operand(*source)
+                )?;
+                self.write_name(out, *dest)
+            }
+            Instruction::Binary {
+                dest,
+                operator,
+                left,
+                right,
+            } => {
+                let typ = function.typ(*dest);
+                let llvm_type = llvm_type(typ);
+                let mut right = self.operand(*right);
+                if matches!(operator, BinaryOperator::Shl | BinaryOperator::Shr)
+                {
+                    // LLVM shifts by the width or more produce poison, while
+                    // the IR takes the shift amount modulo the width.
+                    let bits = typ.bits().expect("values aren't `unit`");
+                    writeln!(
+                        out,
+                        "  %{dest}.amount = and {llvm_type} {right}, {}",
+                        bits - 1
+                    )?;
+                    right = format!("%{dest}.amount");
+                }
+                write!(
+                    out,
+                    "  %{dest} = {} {llvm_type} {}, {right}",
+                    opcode(*operator, typ.is_signed()),
+                    self.operand(*left)
+                )?;
+                self.write_name(out, *dest)
+            }
+            Instruction::Call {
+                dest,
+                callee,
+                arguments,
+            } => {
+                out.push_str("  ");
+                let return_type = match dest {
+                    Some(dest) => {
+                        write!(out, "%{dest} = ")?;
+                        function.typ(*dest)
+                    }
+                    None => Type::Unit,
+                };
+                write!(
+                    out,
+                    "call {} @{}(",
+                    llvm_type(return_type),
+                    symbol(self.program, *callee)
+                )?;
+                for (i, &argument) in arguments.iter().enumerate() {
+                    if i != 0 {
+                        out.push_str(", ");
+                    }
+                    write!(
+                        out,
+                        "{} {}",
+                        llvm_type(function.typ(argument)),
+                        self.operand(argument)
+                    )?;
+                }
+                out.push(')');
+                match dest {
+                    Some(dest) => self.write_name(out, *dest),
+                    None => writeln!(out),
+                }
+            }
+            Instruction::Syscall { dest, arguments } => {
+                write!(
+                    out,
+                    "  %{dest} = call i64 asm sideeffect \"syscall\", \"={{rax}}"
+                )?;
+                for register in SYSCALL_REGISTERS.iter().take(arguments.len()) {
+                    write!(out, ",{{{register}}}")?;
+                }
+                // The kernel overwrites `rcx` and `r11`.
+                out.push_str(",~{rcx},~{r11},~{memory}\"(");
+                for (i, &argument) in arguments.iter().enumerate() {
+                    if i != 0 {
+                        out.push_str(", ");
+                    }
+                    write!(out, "i64 {}", self.operand(argument))?;
+                }
+                out.push(')');
+                self.write_name(out, *dest)
+            }
+            Instruction::StackSlot { dest } => {
+                write!(out, "  %{dest} = ptrtoint ptr %{dest}.slot to i64")?;
+                self.write_name(out, *dest)
+            }
+            Instruction::Load { dest, address } => {
+                writeln!(
+                    out,
+                    "  %{dest}.address = inttoptr i64 {} to ptr",
+                    self.operand(*address)
+                )?;
+                write!(
+                    out,
+                    "  %{dest} = load {}, ptr %{dest}.address, align 1",
+                    llvm_type(function.typ(*dest))
+                )?;
+                self.write_name(out, *dest)
+            }
+            Instruction::Store { address, value } => {
+                let (id, i) = location;
+                writeln!(
+                    out,
+                    "  %{id}.{i}.address = inttoptr i64 {} to ptr",
+                    self.operand(*address)
+                )?;
+                writeln!(
+                    out,
+                    "  store {} {}, ptr %{id}.{i}.address, align 1",
+                    llvm_type(function.typ(*value)),
+                    self.operand(*value)
+                )
+            }
+            Instruction::Cast { dest, source } => {
+                let (from, to) = (function.typ(*source), function.typ(*dest));
+                let cast = match from.bits().cmp(&to.bits()) {
+                    Ordering::Greater => "trunc",
+                    Ordering::Equal => "bitcast",
+                    Ordering::Less if from.is_signed() => "sext",
+                    Ordering::Less => "zext",
+                };
+                write!(
+                    out,
+                    "  %{dest} = {cast} {} {} to {}",
+                    llvm_type(from),
+                    self.operand(*source),
+                    llvm_type(to)
+                )?;
+                self.write_name(out, *dest)
+            }
+        }
+    }
+
+    /// Ends the line with a comment naming the `let` binding or parameter
+    /// that holds `value`, if any.
+    fn write_name(&self, out: &mut String, value: Value) -> fmt::Result {
+        match self.function.values[value.0].name {
+            Some(name) => writeln!(out, " ; {name}"),
+            None => writeln!(out),
+        }
+    }
+
+    /// Constants are written as literals, everything else as a register.
+    fn operand(&self, value: Value) -> String {
+        match self.constants[value.0] {
+            Some(constant) => literal(self.function.typ(value), constant),
+            None => format!("%{value}"),
+        }
+    }
+}
+
+/// LLVM reads integer literals as signed, whatever the type.
+fn literal(typ: Type, constant: u64) -> String {
+    let bits = typ.bits().expect("values aren't `unit`");
+    let shift = 64 - bits;
+    (((constant << shift) as i64) >> shift).to_string()
+}
+
+/// The registers that hold the number of a system call and its arguments.
+const SYSCALL_REGISTERS: [&str; 7] =
+    ["rax", "rdi", "rsi", "rdx", "r10", "r8", "r9"];
+
+fn llvm_type(typ: Type) -> &'static str {
+    match typ {
+        Type::Unit => "void",
+        Type::I8 | Type::U8 => "i8",
+        Type::I16 | Type::U16 => "i16",
+        Type::I32 | Type::U32 => "i32",
+        Type::I64 | Type::U64 | Type::Pointer { .. } | Type::Named(_) => "i64",
+    }
+}
+
+fn opcode(operator: BinaryOperator, is_signed: bool) -> &'static str {
+    match (operator, is_signed) {
+        (BinaryOperator::Add, _) => "add",
+        (BinaryOperator::Sub, _) => "sub",
+        (BinaryOperator::Mul, _) => "mul",
+        (BinaryOperator::Div, true) => "sdiv",
+        (BinaryOperator::Div, false) => "udiv",
+        (BinaryOperator::Rem, true) => "srem",
+        (BinaryOperator::Rem, false) => "urem",
+        (BinaryOperator::And, _) => "and",
+        (BinaryOperator::Or, _) => "or",
+        (BinaryOperator::Xor, _) => "xor",
+        (BinaryOperator::Shl, _) => "shl",
+        (BinaryOperator::Shr, true) => "ashr",
+        (BinaryOperator::Shr, false) => "lshr",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::Types,
+        ir::{lower::lower_text, Block},
+        opt,
+    };
+
+    fn emit_text(text: &str, level: opt::OptLevel) -> String {
+        emit(&lower_text(text, level))
+    }
+
+    #[test]
+    fn emits_llvm_ir() {
+        assert_eq!(
+            emit_text(
+                "
+fn main() -> u8 {
+    let x = the-answer() / 200_u8;
+    x >> 9_u8
+}
+fn the-answer() -> u8 { 42_u8 }
+fn nothing() -> unit { the-answer(); }
+",
+                opt::OptLevel::O0,
+            ),
+            "\
+define i8 @gn_main() {
+bb0:
+  %v0 = call i8 @gn_the_hanswer()
+  %v2 = udiv i8 %v0, -56
+  %v3 = bitcast i8 %v2 to i8 ; x
+  %v5.amount = and i8 9, 7
+  %v5 = lshr i8 %v3, %v5.amount
+  ret i8 %v5
+}
+
+define i8 @gn_the_hanswer() {
+bb0:
+  ret i8 42
+}
+
+define void @gn_nothing() {
+bb0:
+  %v0 = call i8 @gn_the_hanswer()
+  ret void
+}
+
+define i32 @main() {
+  %result = call i8 @gn_main()
+  %status = zext i8 %result to i32
+  ret i32 %status
+}
+"
+        );
+    }
+
+    #[test]
+    fn block_parameters_become_phi_nodes() {
+        let mut function = Function::new("f", Type::I8, &[]);
+        let one = function.new_value(Type::I8, None);
+        let y = function.new_value(Type::I8, Some("y".into()));
+        let sum = function.new_value(Type::I8, None);
+        function.blocks = vec![
+            Block {
+                parameters: Vec::new(),
+                instructions: vec![Instruction::Const {
+                    dest: one,
+                    value: 1,
+                }],
+                terminator: Terminator::Jump(BlockId(1), vec![one]),
+            },
+            Block {
+                parameters: vec![y],
+                instructions: vec![Instruction::Binary {
+                    dest: sum,
+                    operator: BinaryOperator::Add,
+                    left: y,
+                    right: y,
+                }],
+                terminator: Terminator::Return(Some(sum)),
+            },
+        ];
+        let program = Program {
+            functions: vec![function.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        assert_eq!(
+            emit(&program),
+            "\
+define i8 @gn_f() {
+bb0:
+  br label %bb1
+
+bb1:
+  %v1 = phi i8 [ 1, %bb0 ] ; y
+  %v2 = add i8 %v1, %v1
+  ret i8 %v2
+}
+"
+        );
+    }
+}
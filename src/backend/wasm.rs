@@ -0,0 +1,1111 @@
+//! Translates the IR into a WebAssembly module, either in the binary format or
+//! in the text format. WebAssembly only has 32- and 64-bit integers, so 8- and
+//! 16-bit values live in `i32` locals. They are kept in a canonical form,
+//! sign-extended if their type is signed and zero-extended otherwise, by
+//! masking or sign-extending the result of every operation that could leave
+//! the range of the type.
+//!
+//! Functions with more than one block dispatch to the next block through a
+//! `br_table` inside a loop, which works for any control-flow graph.
+//!
+//! Pointers are `i64` addresses into a linear memory that the module exports
+//! as `memory`, which only exists if the program uses pointers. Stack slots
+//! live in a stack at the top of the memory that grows down from the global
+//! `$sp`, and every function with stack slots moves it for its frame.
+//!
+//! WebAssembly can't make system calls or call `extern` functions, so programs
+//! that do are rejected before they get here.
+
+use super::{mangle, sleb, uleb};
+use crate::{
+    ast::BinaryOperator,
+    ir::{BlockId, Function, Instruction, Program, Terminator, Value},
+    layout::Layout,
+    typ::Type,
+};
+use internment::Intern;
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+};
+
+/// The number of 64 KiB pages of memory.
+const MEMORY_PAGES: u32 = 16;
+
+/// The initial value of `$sp`, which is the end of the memory.
+const STACK_TOP: i32 = MEMORY_PAGES as i32 * 65536;
+
+/// The index of `$sp`, which is the only global.
+const STACK_POINTER: u32 = 0;
+
+/// A module in the binary format.
+pub fn emit_wasm(program: &Program) -> Vec<u8> {
+    let functions = compile_program(program);
+    let uses_memory = program.uses_memory();
+
+    let mut types = Vec::new();
+    uleb(&mut types, functions.len() as u64);
+    for function in &functions {
+        types.push(0x60);
+        uleb(&mut types, function.parameter_count as u64);
+        for (_, typ) in &function.locals[..function.parameter_count] {
+            types.push(typ.byte());
+        }
+        uleb(&mut types, function.result.iter().count() as u64);
+        types.extend(function.result.map(ValType::byte));
+    }
+
+    let mut declarations = Vec::new();
+    uleb(&mut declarations, functions.len() as u64);
+    for index in 0..functions.len() {
+        uleb(&mut declarations, index as u64);
+    }
+
+    let mut memories = Vec::new();
+    let mut globals = Vec::new();
+    if uses_memory {
+        // One memory with a minimum size and no maximum.
+        memories.extend([0x01, 0x00]);
+        uleb(&mut memories, u64::from(MEMORY_PAGES));
+        // One mutable `i32`.
+        globals.extend([0x01, 0x7f, 0x01, 0x41]);
+        sleb(&mut globals, i64::from(STACK_TOP));
+        globals.push(0x0b);
+    }
+
+    let mut exports = Vec::new();
+    let exported = functions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, function)| Some((index, function.export?)))
+        .collect::<Vec<_>>();
+    uleb(
+        &mut exports,
+        (exported.len() + usize::from(uses_memory)) as u64,
+    );
+    for (index, name) in exported {
+        uleb(&mut exports, name.len() as u64);
+        exports.extend(name.bytes());
+        exports.push(0x00);
+        uleb(&mut exports, index as u64);
+    }
+    if uses_memory {
+        uleb(&mut exports, "memory".len() as u64);
+        exports.extend(b"memory");
+        exports.extend([0x02, 0x00]);
+    }
+
+    let mut code = Vec::new();
+    uleb(&mut code, functions.len() as u64);
+    for function in &functions {
+        let body = function.encode();
+        uleb(&mut code, body.len() as u64);
+        code.extend(body);
+    }
+
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    for (id, contents) in [
+        (1, types),
+        (3, declarations),
+        (5, memories),
+        (6, globals),
+        (7, exports),
+        (10, code),
+    ] {
+        if contents.is_empty() {
+            continue;
+        }
+        module.push(id);
+        uleb(&mut module, contents.len() as u64);
+        module.extend(contents);
+    }
+    module
+}
+
+/// A module in the text format.
+pub fn emit_wat(program: &Program) -> String {
+    let mut out = String::new();
+    write_module(&mut out, &compile_program(program), program.uses_memory())
+        .expect("writing to a `String` can't fail");
+    out
+}
+
+fn write_module(
+    out: &mut String,
+    functions: &[WasmFunction],
+    uses_memory: bool,
+) -> fmt::Result {
+    writeln!(out, "(module")?;
+    if uses_memory {
+        writeln!(out, "  (memory (export \"memory\") {MEMORY_PAGES})")?;
+        writeln!(out, "  (global $sp (mut i32) (i32.const {STACK_TOP}))")?;
+    }
+    for function in functions {
+        write!(out, "  (func ${}", function.name)?;
+        if let Some(export) = function.export {
+            write!(out, " (export \"{export}\")")?;
+        }
+        for (name, typ) in &function.locals[..function.parameter_count] {
+            write!(out, " (param ${name} {})", typ.name())?;
+        }
+        if let Some(result) = function.result {
+            write!(out, " (result {})", result.name())?;
+        }
+        writeln!(out)?;
+        for (name, typ) in &function.locals[function.parameter_count..] {
+            writeln!(out, "    (local ${name} {})", typ.name())?;
+        }
+
+        let mut depth = 2;
+        for op in &function.body {
+            if matches!(op, Op::End) {
+                depth -= 1;
+            }
+            write!(out, "{:width$}", "", width = depth * 2)?;
+            match op {
+                Op::Block => writeln!(out, "block")?,
+                Op::Loop => writeln!(out, "loop")?,
+                Op::End => writeln!(out, "end")?,
+                Op::Br(depth) => writeln!(out, "br {depth}")?,
+                Op::BrTable(labels) => {
+                    out.push_str("br_table");
+                    for label in labels {
+                        write!(out, " {label}")?;
+                    }
+                    writeln!(out)?;
+                }
+                Op::Return => writeln!(out, "return")?,
+                Op::Unreachable => writeln!(out, "unreachable")?,
+                Op::Call(index) => {
+                    writeln!(out, "call ${}", functions[*index as usize].name)?
+                }
+                Op::LocalGet(index) => writeln!(
+                    out,
+                    "local.get ${}",
+                    function.locals[*index as usize].0
+                )?,
+                Op::LocalSet(index) => writeln!(
+                    out,
+                    "local.set ${}",
+                    function.locals[*index as usize].0
+                )?,
+                Op::I32Const(value) => writeln!(out, "i32.const {value}")?,
+                Op::I64Const(value) => writeln!(out, "i64.const {value}")?,
+                Op::GlobalGet(_) => writeln!(out, "global.get $sp")?,
+                Op::GlobalSet(_) => writeln!(out, "global.set $sp")?,
+                Op::Numeric(numeric) | Op::Memory(numeric) => {
+                    writeln!(out, "{}", numeric.name)?
+                }
+            }
+            if matches!(op, Op::Block | Op::Loop) {
+                depth += 1;
+            }
+        }
+        writeln!(out, "  )")?;
+    }
+    writeln!(out, ")")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn of(typ: Type) -> Option<Self> {
+        match typ.bits()? {
+            64 => Some(Self::I64),
+            _ => Some(Self::I32),
+        }
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            Self::I32 => 0x7f,
+            Self::I64 => 0x7e,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::I32 => "i32",
+            Self::I64 => "i64",
+        }
+    }
+}
+
+/// An instruction without immediates that operates on the stack.
+#[derive(Clone, Copy)]
+struct Numeric {
+    name: &'static str,
+    opcode: u8,
+}
+
+const I32_AND: Numeric = Numeric {
+    name: "i32.and",
+    opcode: 0x71,
+};
+const I32_EXTEND8_S: Numeric = Numeric {
+    name: "i32.extend8_s",
+    opcode: 0xc0,
+};
+const I32_EXTEND16_S: Numeric = Numeric {
+    name: "i32.extend16_s",
+    opcode: 0xc1,
+};
+const I32_ADD: Numeric = Numeric {
+    name: "i32.add",
+    opcode: 0x6a,
+};
+const I32_SUB: Numeric = Numeric {
+    name: "i32.sub",
+    opcode: 0x6b,
+};
+const I32_LE_U: Numeric = Numeric {
+    name: "i32.le_u",
+    opcode: 0x4d,
+};
+const I64_LE_U: Numeric = Numeric {
+    name: "i64.le_u",
+    opcode: 0x58,
+};
+const SELECT: Numeric = Numeric {
+    name: "select",
+    opcode: 0x1b,
+};
+const I32_WRAP_I64: Numeric = Numeric {
+    name: "i32.wrap_i64",
+    opcode: 0xa7,
+};
+const I64_EXTEND_I32_S: Numeric = Numeric {
+    name: "i64.extend_i32_s",
+    opcode: 0xac,
+};
+const I64_EXTEND_I32_U: Numeric = Numeric {
+    name: "i64.extend_i32_u",
+    opcode: 0xad,
+};
+
+enum Op {
+    Block,
+    Loop,
+    End,
+    Br(u32),
+    /// The last label is the default.
+    BrTable(Vec<u32>),
+    Return,
+    Unreachable,
+    Call(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    I32Const(i32),
+    I64Const(i64),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Numeric(Numeric),
+    /// A load or store of the address on the stack, without an offset or an
+    /// alignment hint.
+    Memory(Numeric),
+}
+
+struct WasmFunction {
+    name: String,
+    export: Option<Intern<str>>,
+    /// The parameters followed by the other locals, with their names in the
+    /// text format.
+    locals: Vec<(String, ValType)>,
+    parameter_count: usize,
+    result: Option<ValType>,
+    body: Vec<Op>,
+}
+
+impl WasmFunction {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let locals = &self.locals[self.parameter_count..];
+        uleb(&mut bytes, locals.len() as u64);
+        for (_, typ) in locals {
+            uleb(&mut bytes, 1);
+            bytes.push(typ.byte());
+        }
+        for op in &self.body {
+            match op {
+                Op::Block => bytes.extend([0x02, 0x40]),
+                Op::Loop => bytes.extend([0x03, 0x40]),
+                Op::End => bytes.push(0x0b),
+                Op::Br(depth) => {
+                    bytes.push(0x0c);
+                    uleb(&mut bytes, u64::from(*depth));
+                }
+                Op::BrTable(labels) => {
+                    let (default, labels) =
+                        labels.split_last().expect("there is a default label");
+                    bytes.push(0x0e);
+                    uleb(&mut bytes, labels.len() as u64);
+                    for &label in labels {
+                        uleb(&mut bytes, u64::from(label));
+                    }
+                    uleb(&mut bytes, u64::from(*default));
+                }
+                Op::Return => bytes.push(0x0f),
+                Op::Unreachable => bytes.push(0x00),
+                Op::Call(index) => {
+                    bytes.push(0x10);
+                    uleb(&mut bytes, u64::from(*index));
+                }
+                Op::LocalGet(index) => {
+                    bytes.push(0x20);
+                    uleb(&mut bytes, u64::from(*index));
+                }
+                Op::LocalSet(index) => {
+                    bytes.push(0x21);
+                    uleb(&mut bytes, u64::from(*index));
+                }
+                Op::I32Const(value) => {
+                    bytes.push(0x41);
+                    sleb(&mut bytes, i64::from(*value));
+                }
+                Op::I64Const(value) => {
+                    bytes.push(0x42);
+                    sleb(&mut bytes, *value);
+                }
+                Op::GlobalGet(index) => {
+                    bytes.push(0x23);
+                    uleb(&mut bytes, u64::from(*index));
+                }
+                Op::GlobalSet(index) => {
+                    bytes.push(0x24);
+                    uleb(&mut bytes, u64::from(*index));
+                }
+                Op::Numeric(numeric) => bytes.push(numeric.opcode),
+                Op::Memory(numeric) => bytes.extend([numeric.opcode, 0, 0]),
+            }
+        }
+        bytes.push(0x0b);
+        bytes
+    }
+}
+
+fn compile_program(program: &Program) -> Vec<WasmFunction> {
+    let indices = program
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(index, function)| {
+            let index = u32::try_from(index).expect("too many functions");
+            (function.name, index)
+        })
+        .collect::<HashMap<_, _>>();
+    program
+        .functions
+        .iter()
+        .map(|function| {
+            FunctionCompiler::new(program, function, &indices).compile()
+        })
+        .collect()
+}
+
+struct FunctionCompiler<'a> {
+    function: &'a Function,
+    indices: &'a HashMap<Intern<str>, u32>,
+    /// The local that holds each value, indexed by `Value`.
+    value_locals: Vec<Option<u32>>,
+    /// The offset from `$sp` of the memory that each `StackSlot` instruction
+    /// points its result to, indexed by `Value`.
+    stack_slots: Vec<Option<i32>>,
+    /// The size of the function's part of the stack in bytes.
+    frame_size: i32,
+    locals: Vec<(String, ValType)>,
+    body: Vec<Op>,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn new(
+        program: &Program,
+        function: &'a Function,
+        indices: &'a HashMap<Intern<str>, u32>,
+    ) -> Self {
+        let mut defined = function
+            .blocks
+            .iter()
+            .flat_map(|block| {
+                block.parameters.iter().copied().chain(
+                    block.instructions.iter().filter_map(Instruction::dest),
+                )
+            })
+            .collect::<Vec<_>>();
+        defined.sort();
+
+        let mut stack_slots = vec![None; function.values.len()];
+        let mut frame_size = 0;
+        for instruction in
+            function.blocks.iter().flat_map(|block| &block.instructions)
+        {
+            if let Instruction::StackSlot { dest } = *instruction {
+                // Alignments are at most 8, so every slot starts at a
+                // multiple of 8.
+                let size = function
+                    .typ(dest)
+                    .pointee()
+                    .and_then(|typ| Layout::of(typ, &program.types))
+                    .expect("stack slots have a layout")
+                    .size;
+                stack_slots[dest.0] = Some(frame_size);
+                // Even `unit` gets an address of its own.
+                frame_size += size.max(1).next_multiple_of(8) as i32;
+            }
+        }
+
+        let mut compiler = Self {
+            function,
+            indices,
+            value_locals: vec![None; function.values.len()],
+            stack_slots,
+            frame_size,
+            locals: Vec::new(),
+            body: Vec::new(),
+        };
+        for value in function.parameters.iter().chain(&defined) {
+            let typ = ValType::of(function.typ(*value))
+                .expect("values aren't `unit`");
+            let local = compiler.new_local(value.to_string(), typ);
+            compiler.value_locals[value.0] = Some(local);
+        }
+        compiler
+    }
+
+    fn new_local(&mut self, name: String, typ: ValType) -> u32 {
+        self.locals.push((name, typ));
+        u32::try_from(self.locals.len() - 1).expect("too many locals")
+    }
+
+    fn compile(mut self) -> WasmFunction {
+        let function = self.function;
+        self.adjust_stack_pointer(I32_SUB);
+        let block_count = function.blocks.len();
+        if block_count == 1 {
+            self.compile_block(Function::ENTRY, None);
+        } else {
+            let next = self.new_local("next".into(), ValType::I32);
+            self.body.push(Op::Loop);
+            self.body.extend((0..block_count).map(|_| Op::Block));
+            self.body.push(Op::LocalGet(next));
+            self.body
+                .push(Op::BrTable((0..block_count as u32).collect::<Vec<_>>()));
+            for id in function.block_ids() {
+                self.body.push(Op::End);
+                let loop_depth = (block_count - 1 - id.0) as u32;
+                self.compile_block(id, Some((next, loop_depth)));
+            }
+            self.body.push(Op::End);
+            self.body.push(Op::Unreachable);
+        }
+
+        WasmFunction {
+            name: mangle(&function.name),
+            export: (function.is_export || &*function.name == "main")
+                .then_some(function.name),
+            parameter_count: function.parameters.len(),
+            locals: self.locals,
+            result: ValType::of(function.return_type),
+            body: self.body,
+        }
+    }
+
+    /// `dispatch` is the local that selects the next block and the depth of
+    /// the dispatch loop, if the function has more than one block.
+    fn compile_block(&mut self, id: BlockId, dispatch: Option<(u32, u32)>) {
+        let function = self.function;
+        let block = function.block(id);
+        for instruction in &block.instructions {
+            self.compile_instruction(instruction);
+        }
+        match &block.terminator {
+            Terminator::Return(value) => {
+                if let Some(value) = value {
+                    self.get(*value);
+                }
+                self.adjust_stack_pointer(I32_ADD);
+                self.body.push(Op::Return);
+            }
+            Terminator::Jump(target, arguments) => {
+                // All arguments are on the stack before any parameter is
+                // assigned, since an argument may be one of the parameters.
+                for &argument in arguments {
+                    self.get(argument);
+                }
+                for &parameter in
+                    function.block(*target).parameters.iter().rev()
+                {
+                    self.set(parameter);
+                }
+                let (next, loop_depth) =
+                    dispatch.expect("functions with jumps have several blocks");
+                self.body.push(Op::I32Const(target.0 as i32));
+                self.body.push(Op::LocalSet(next));
+                self.body.push(Op::Br(loop_depth));
+            }
+            &Terminator::Branch {
+                value,
+                low,
+                high,
+                then,
+                otherwise,
+            } => {
+                self.body.push(Op::I32Const(then.0 as i32));
+                self.body.push(Op::I32Const(otherwise.0 as i32));
+                // Subtracting `low` turns the range into `0..=high - low`
+                // for either signedness, so one unsigned comparison does.
+                let typ = function.typ(value);
+                let width = typ.truncate(high.wrapping_sub(low));
+                self.get(value);
+                if ValType::of(typ) == Some(ValType::I64) {
+                    self.body.push(Op::I64Const(low as i64));
+                    self.body
+                        .push(Op::Numeric(numeric(typ, BinaryOperator::Sub)));
+                    self.body.push(Op::I64Const(width as i64));
+                    self.body.push(Op::Numeric(I64_LE_U));
+                } else {
+                    self.body.push(Op::I32Const(typ.to_i128(low) as i32));
+                    self.body.push(Op::Numeric(I32_SUB));
+                    self.body.push(Op::I32Const(width as i32));
+                    self.body.push(Op::Numeric(I32_LE_U));
+                }
+                self.body.push(Op::Numeric(SELECT));
+                let (next, loop_depth) = dispatch
+                    .expect("functions with branches have several blocks");
+                self.body.push(Op::LocalSet(next));
+                self.body.push(Op::Br(loop_depth));
+            }
+        }
+    }
+
+    fn compile_instruction(&mut self, instruction: &Instruction) {
+        let function = self.function;
+        match instruction {
+            Instruction::Const { dest, value } => {
+                let typ = function.typ(*dest);
+                self.body.push(match ValType::of(typ) {
+                    Some(ValType::I64) => Op::I64Const(*value as i64),
+                    _ => Op::I32Const(typ.to_i128(*value) as i32),
+                });
+                self.set(*dest);
+            }
+            Instruction::Copy { dest, source } => {
+                self.get(*source);
+                self.set(*dest);
+            }
+            Instruction::Binary {
+                dest,
+                operator,
+                left,
+                right,
+            } => {
+                let typ = function.typ(*dest);
+                let bits = typ.bits().expect("values aren't `unit`");
+                self.get(*left);
+                self.get(*right);
+                // WebAssembly takes shift amounts modulo 32 or 64.
+                if matches!(operator, BinaryOperator::Shl | BinaryOperator::Shr)
+                    && bits < 32
+                {
+                    self.body.push(Op::I32Const(bits as i32 - 1));
+                    self.body.push(Op::Numeric(I32_AND));
+                }
+                self.body.push(Op::Numeric(numeric(typ, *operator)));
+                if !matches!(
+                    operator,
+                    BinaryOperator::And
+                        | BinaryOperator::Or
+                        | BinaryOperator::Xor
+                        | BinaryOperator::Shr
+                ) {
+                    self.canonicalize(typ);
+                }
+                self.set(*dest);
+            }
+            Instruction::Call {
+                dest,
+                callee,
+                arguments,
+            } => {
+                for &argument in arguments {
+                    self.get(argument);
+                }
+                self.body.push(Op::Call(self.indices[callee]));
+                if let Some(dest) = dest {
+                    self.set(*dest);
+                }
+            }
+            Instruction::Syscall { .. } => {
+                unreachable!("WebAssembly modules can't make system calls")
+            }
+            Instruction::StackSlot { dest } => {
+                let offset = self.stack_slots[dest.0].expect("slot has offset");
+                self.body.push(Op::GlobalGet(STACK_POINTER));
+                self.body.push(Op::I32Const(offset));
+                self.body.push(Op::Numeric(I32_ADD));
+                self.body.push(Op::Numeric(I64_EXTEND_I32_U));
+                self.set(*dest);
+            }
+            Instruction::Load { dest, address } => {
+                let typ = function.typ(*dest);
+                let (name, opcode) = match (typ.bits(), typ.is_signed()) {
+                    (Some(8), true) => ("i32.load8_s", 0x2c),
+                    (Some(8), false) => ("i32.load8_u", 0x2d),
+                    (Some(16), true) => ("i32.load16_s", 0x2e),
+                    (Some(16), false) => ("i32.load16_u", 0x2f),
+                    (Some(32), _) => ("i32.load", 0x28),
+                    _ => ("i64.load", 0x29),
+                };
+                self.get(*address);
+                self.body.push(Op::Numeric(I32_WRAP_I64));
+                self.body.push(Op::Memory(Numeric { name, opcode }));
+                self.set(*dest);
+            }
+            Instruction::Store { address, value } => {
+                let (name, opcode) = match function.typ(*value).bits() {
+                    Some(8) => ("i32.store8", 0x3a),
+                    Some(16) => ("i32.store16", 0x3b),
+                    Some(32) => ("i32.store", 0x36),
+                    _ => ("i64.store", 0x37),
+                };
+                self.get(*address);
+                self.body.push(Op::Numeric(I32_WRAP_I64));
+                self.get(*value);
+                self.body.push(Op::Memory(Numeric { name, opcode }));
+            }
+            Instruction::Cast { dest, source } => {
+                let (from, to) = (function.typ(*source), function.typ(*dest));
+                self.get(*source);
+                match (ValType::of(from), ValType::of(to)) {
+                    (Some(ValType::I64), Some(ValType::I64)) => {}
+                    (Some(ValType::I64), _) => {
+                        self.body.push(Op::Numeric(I32_WRAP_I64));
+                        self.canonicalize(to);
+                    }
+                    (_, Some(ValType::I64)) if from.is_signed() => {
+                        self.body.push(Op::Numeric(I64_EXTEND_I32_S));
+                    }
+                    (_, Some(ValType::I64)) => {
+                        self.body.push(Op::Numeric(I64_EXTEND_I32_U));
+                    }
+                    _ => self.canonicalize(to),
+                }
+                self.set(*dest);
+            }
+        }
+    }
+
+    /// Moves `$sp` past the function's frame with `operator`, which is
+    /// `i32.sub` when the function starts and `i32.add` when it returns.
+    fn adjust_stack_pointer(&mut self, operator: Numeric) {
+        if self.frame_size == 0 {
+            return;
+        }
+        self.body.push(Op::GlobalGet(STACK_POINTER));
+        self.body.push(Op::I32Const(self.frame_size));
+        self.body.push(Op::Numeric(operator));
+        self.body.push(Op::GlobalSet(STACK_POINTER));
+    }
+
+    /// Brings the `i32` on top of the stack back into the range of `typ`.
+    fn canonicalize(&mut self, typ: Type) {
+        match (typ.bits(), typ.is_signed()) {
+            (Some(8), true) => self.body.push(Op::Numeric(I32_EXTEND8_S)),
+            (Some(16), true) => self.body.push(Op::Numeric(I32_EXTEND16_S)),
+            (Some(bits @ (8 | 16)), false) => {
+                self.body.push(Op::I32Const((1 << bits) - 1));
+                self.body.push(Op::Numeric(I32_AND));
+            }
+            _ => {}
+        }
+    }
+
+    fn get(&mut self, value: Value) {
+        let local = self.value_locals[value.0].expect("value is defined");
+        self.body.push(Op::LocalGet(local));
+    }
+
+    fn set(&mut self, value: Value) {
+        let local = self.value_locals[value.0].expect("value is defined");
+        self.body.push(Op::LocalSet(local));
+    }
+}
+
+fn numeric(typ: Type, operator: BinaryOperator) -> Numeric {
+    let signed = typ.is_signed();
+    let (name, opcode) = match (ValType::of(typ), operator) {
+        (Some(ValType::I64), operator) => match operator {
+            BinaryOperator::Add => ("i64.add", 0x7c),
+            BinaryOperator::Sub => ("i64.sub", 0x7d),
+            BinaryOperator::Mul => ("i64.mul", 0x7e),
+            BinaryOperator::Div if signed => ("i64.div_s", 0x7f),
+            BinaryOperator::Div => ("i64.div_u", 0x80),
+            BinaryOperator::Rem if signed => ("i64.rem_s", 0x81),
+            BinaryOperator::Rem => ("i64.rem_u", 0x82),
+            BinaryOperator::And => ("i64.and", 0x83),
+            BinaryOperator::Or => ("i64.or", 0x84),
+            BinaryOperator::Xor => ("i64.xor", 0x85),
+            BinaryOperator::Shl => ("i64.shl", 0x86),
+            BinaryOperator::Shr if signed => ("i64.shr_s", 0x87),
+            BinaryOperator::Shr => ("i64.shr_u", 0x88),
+        },
+        (_, operator) => match operator {
+            BinaryOperator::Add => ("i32.add", 0x6a),
+            BinaryOperator::Sub => ("i32.sub", 0x6b),
+            BinaryOperator::Mul => ("i32.mul", 0x6c),
+            BinaryOperator::Div if signed => ("i32.div_s", 0x6d),
+            BinaryOperator::Div => ("i32.div_u", 0x6e),
+            BinaryOperator::Rem if signed => ("i32.rem_s", 0x6f),
+            BinaryOperator::Rem => ("i32.rem_u", 0x70),
+            BinaryOperator::And => ("i32.and", 0x71),
+            BinaryOperator::Or => ("i32.or", 0x72),
+            BinaryOperator::Xor => ("i32.xor", 0x73),
+            BinaryOperator::Shl => ("i32.shl", 0x74),
+            BinaryOperator::Shr if signed => ("i32.shr_s", 0x75),
+            BinaryOperator::Shr => ("i32.shr_u", 0x76),
+        },
+    };
+    Numeric { name, opcode }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::Types,
+        ir::{lower::lower_text, Block},
+        opt::OptLevel,
+    };
+    use wasmi::{Engine, Linker, Module, Store, WasmResults};
+
+    fn run<R: WasmResults>(program: &Program) -> R {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &emit_wasm(program)[..]).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        instance
+            .get_typed_func::<(), R>(&store, "main")
+            .unwrap()
+            .call(&mut store, ())
+            .unwrap()
+    }
+
+    fn block(instructions: Vec<Instruction>, terminator: Terminator) -> Block {
+        Block {
+            parameters: Vec::new(),
+            instructions,
+            terminator,
+        }
+    }
+
+    #[test]
+    fn small_integers_wrap_around() {
+        let mut square = Function::new("square", Type::U16, &[Type::U16]);
+        let x = square.parameters[0];
+        let result = square.new_value(Type::U16, None);
+        square.blocks.push(block(
+            vec![Instruction::Binary {
+                dest: result,
+                operator: BinaryOperator::Mul,
+                left: x,
+                right: x,
+            }],
+            Terminator::Return(Some(result)),
+        ));
+
+        let mut main = Function::new("main", Type::U16, &[]);
+        let argument = main.new_value(Type::U16, None);
+        let result = main.new_value(Type::U16, None);
+        main.blocks.push(block(
+            vec![
+                Instruction::Const {
+                    dest: argument,
+                    value: 300,
+                },
+                Instruction::Call {
+                    dest: Some(result),
+                    callee: "square".into(),
+                    arguments: vec![argument],
+                },
+            ],
+            Terminator::Return(Some(result)),
+        ));
+
+        // 300 * 300 wraps around to 24464 in a `u16`.
+        let program = Program {
+            functions: vec![main.into(), square.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        assert_eq!(run::<i32>(&program), 24464);
+    }
+
+    #[test]
+    fn jumps_pass_arguments_to_block_parameters() {
+        let mut main = Function::new("main", Type::I8, &[]);
+        let [a, b, c, d, product, e, f, difference, shifted] =
+            [(); 9].map(|()| main.new_value(Type::I8, None));
+        main.blocks = vec![
+            block(
+                vec![
+                    Instruction::Const {
+                        dest: a,
+                        value: 100,
+                    },
+                    Instruction::Const { dest: b, value: 3 },
+                ],
+                Terminator::Jump(BlockId(1), vec![a, b]),
+            ),
+            Block {
+                parameters: vec![c, d],
+                instructions: vec![Instruction::Binary {
+                    dest: product,
+                    operator: BinaryOperator::Mul,
+                    left: c,
+                    right: d,
+                }],
+                // The arguments are swapped.
+                terminator: Terminator::Jump(BlockId(2), vec![product, d]),
+            },
+            Block {
+                parameters: vec![e, f],
+                instructions: vec![
+                    Instruction::Binary {
+                        dest: difference,
+                        operator: BinaryOperator::Sub,
+                        left: f,
+                        right: e,
+                    },
+                    Instruction::Binary {
+                        dest: shifted,
+                        operator: BinaryOperator::Shr,
+                        left: difference,
+                        right: f,
+                    },
+                ],
+                terminator: Terminator::Return(Some(shifted)),
+            },
+        ];
+
+        // 100 * 3 wraps around to 44, 3 - 44 is -41 and shifting it right by
+        // 3 rounds towards negative infinity.
+        let program = Program {
+            functions: vec![main.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        assert_eq!(run::<i32>(&program), -6);
+    }
+
+    #[test]
+    fn branches_compare_according_to_signedness() {
+        // Returns 1 for -10..=-1 and 2 otherwise.
+        let mut classify = Function::new("classify", Type::U8, &[Type::I8]);
+        let x = classify.parameters[0];
+        let [inside, outside] =
+            [(); 2].map(|()| classify.new_value(Type::U8, None));
+        classify.blocks = vec![
+            block(
+                Vec::new(),
+                Terminator::Branch {
+                    value: x,
+                    low: Type::I8.truncate(-10_i64 as u64),
+                    high: Type::I8.truncate(-1_i64 as u64),
+                    then: BlockId(1),
+                    otherwise: BlockId(2),
+                },
+            ),
+            block(
+                vec![Instruction::Const {
+                    dest: inside,
+                    value: 1,
+                }],
+                Terminator::Return(Some(inside)),
+            ),
+            block(
+                vec![Instruction::Const {
+                    dest: outside,
+                    value: 2,
+                }],
+                Terminator::Return(Some(outside)),
+            ),
+        ];
+
+        let mut main = Function::new("main", Type::U8, &[]);
+        let mut instructions = Vec::new();
+        let mut total = None;
+        for argument in [-5_i64, 5, -128] {
+            let [value, result] =
+                [Type::I8, Type::U8].map(|typ| main.new_value(typ, None));
+            instructions.push(Instruction::Const {
+                dest: value,
+                value: Type::I8.truncate(argument as u64),
+            });
+            instructions.push(Instruction::Call {
+                dest: Some(result),
+                callee: "classify".into(),
+                arguments: vec![value],
+            });
+            total = Some(match total {
+                None => result,
+                Some(total) => {
+                    let sum = main.new_value(Type::U8, None);
+                    instructions.push(Instruction::Binary {
+                        dest: sum,
+                        operator: BinaryOperator::Add,
+                        left: total,
+                        right: result,
+                    });
+                    sum
+                }
+            });
+        }
+        main.blocks
+            .push(block(instructions, Terminator::Return(total)));
+
+        let program = Program {
+            functions: vec![main.into(), classify.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        assert_eq!(run::<i32>(&program), 1 + 2 + 2);
+    }
+
+    #[test]
+    fn memory_is_accessed_in_the_width_of_the_type() {
+        let mut main = Function::new("main", Type::I16, &[]);
+        let slot = main.new_value(Type::pointer(Type::U64, true), None);
+        let halves = main.new_value(Type::pointer(Type::I16, false), None);
+        let bytes = main.new_value(Type::pointer(Type::U8, true), None);
+        let [number, two] = [(); 2].map(|()| main.new_value(Type::U64, None));
+        let offset = main.new_value(Type::pointer(Type::I16, false), None);
+        let high_half = main.new_value(Type::pointer(Type::I16, false), None);
+        let seven = main.new_value(Type::U8, None);
+        let [low, high, sum, changed, result] =
+            [(); 5].map(|()| main.new_value(Type::I16, None));
+        main.blocks.push(block(
+            vec![
+                Instruction::StackSlot { dest: slot },
+                Instruction::Const {
+                    dest: number,
+                    value: 0xffff_0005,
+                },
+                Instruction::Store {
+                    address: slot,
+                    value: number,
+                },
+                Instruction::Cast {
+                    dest: halves,
+                    source: slot,
+                },
+                Instruction::Load {
+                    dest: low,
+                    address: halves,
+                },
+                Instruction::Const {
+                    dest: two,
+                    value: 2,
+                },
+                Instruction::Cast {
+                    dest: offset,
+                    source: two,
+                },
+                Instruction::Binary {
+                    dest: high_half,
+                    operator: BinaryOperator::Add,
+                    left: halves,
+                    right: offset,
+                },
+                Instruction::Load {
+                    dest: high,
+                    address: high_half,
+                },
+                Instruction::Binary {
+                    dest: sum,
+                    operator: BinaryOperator::Add,
+                    left: low,
+                    right: high,
+                },
+                Instruction::Cast {
+                    dest: bytes,
+                    source: slot,
+                },
+                Instruction::Const {
+                    dest: seven,
+                    value: 7,
+                },
+                Instruction::Store {
+                    address: bytes,
+                    value: seven,
+                },
+                Instruction::Load {
+                    dest: changed,
+                    address: halves,
+                },
+                Instruction::Binary {
+                    dest: result,
+                    operator: BinaryOperator::Add,
+                    left: sum,
+                    right: changed,
+                },
+            ],
+            Terminator::Return(Some(result)),
+        ));
+        let program = Program {
+            functions: vec![main.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        // 5 + -1, plus 7 after the low byte is overwritten.
+        assert_eq!(run::<i32>(&program), 11);
+    }
+
+    #[test]
+    fn emits_wat() {
+        let program = lower_text(
+            "
+fn main() -> i8 { the-answer() * 2_i8 }
+fn the-answer() -> i8 { 42_i8 }
+",
+            OptLevel::O0,
+        );
+        assert_eq!(
+            emit_wat(&program),
+            "\
+(module
+  (func $gn_main (export \"main\") (result i32)
+    (local $v0 i32)
+    (local $v1 i32)
+    (local $v2 i32)
+    call $gn_the_hanswer
+    local.set $v0
+    i32.const 2
+    local.set $v1
+    local.get $v0
+    local.get $v1
+    i32.mul
+    i32.extend8_s
+    local.set $v2
+    local.get $v2
+    return
+  )
+  (func $gn_the_hanswer (result i32)
+    (local $v0 i32)
+    i32.const 42
+    local.set $v0
+    local.get $v0
+    return
+  )
+)
+"
+        );
+        assert_eq!(run::<i32>(&program), 84);
+    }
+}
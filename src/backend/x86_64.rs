@@ -0,0 +1,1405 @@
+//! Generates x86-64 machine code following the System V ABI. Values live in
+//! the registers that the register allocator picks for them, or in stack slots
+//! if they were spilled, and instructions compute their results in the scratch
+//! registers `rax`, `rcx` and `rdx` before moving them to where they belong.
+//!
+//! The frame holds the saved callee-saved registers, then the spill slots,
+//! then the memory of `StackSlot` instructions in multiples of 8 bytes.
+//!
+//! Values are kept sign-extended or zero-extended to 64 bits depending on the
+//! signedness of their type, so that all arithmetic can be done on 64-bit
+//! registers. Callers and callees written in C only guarantee the low bits of
+//! arguments and return values, so those are extended again when they arrive.
+
+mod asm;
+mod regalloc;
+
+use super::{
+    dwarf::{self, Source},
+    elf::{Object, Relocation, Symbol},
+    mangle, symbol,
+};
+use crate::{
+    ast::{BinaryOperator, Span},
+    ir::{BlockId, Function, Instruction, Program, Terminator, Value},
+    layout::Layout,
+    typ::Type,
+};
+use asm::{Alu, Assembler, Code, Label, Reg, Shift};
+use regalloc::{Allocation, Location};
+use std::fmt::Write as _;
+
+/// The registers that hold the first arguments of a call.
+const ARGUMENT_REGISTERS: [Reg; 6] =
+    [Reg::Rdi, Reg::Rsi, Reg::Rdx, Reg::Rcx, Reg::R8, Reg::R9];
+
+/// The registers that hold the number of a system call and its arguments.
+const SYSCALL_REGISTERS: [Reg; 7] = [
+    Reg::Rax,
+    Reg::Rdi,
+    Reg::Rsi,
+    Reg::Rdx,
+    Reg::R10,
+    Reg::R8,
+    Reg::R9,
+];
+
+/// The number of the Linux system call that ends the process.
+const SYS_EXIT: u64 = 60;
+
+/// How the program is started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entry {
+    /// A C `main` function, called by the C runtime.
+    Main,
+    /// A `_start` function that the kernel jumps to when there is no C
+    /// runtime. It passes the result of `main` to the `exit` system call.
+    Start,
+}
+
+/// Compiles `program` into an object, with debug information if the source
+/// that it came from is given.
+pub fn compile(
+    program: &Program,
+    entry: Entry,
+    source: Option<&Source>,
+) -> Object {
+    let mut object = Object::default();
+    let mut debug = Vec::new();
+    for compiled in compile_functions(program, entry) {
+        let Compiled {
+            symbol: name,
+            code,
+            debug: function,
+        } = compiled;
+        // Functions are aligned like C compilers do, padded with `int3`.
+        while !object.text.len().is_multiple_of(16) {
+            object.text.push(0xcc);
+        }
+        let offset = object.text.len() as u64;
+        object.symbols.push(Symbol {
+            name,
+            offset,
+            size: code.bytes.len() as u64,
+        });
+        object.relocations.extend(code.calls.into_iter().map(
+            |(position, symbol)| Relocation {
+                offset: offset + position as u64,
+                symbol,
+            },
+        ));
+        object.text.extend(code.bytes);
+        debug.extend(
+            function.map(|function| (object.symbols.len() - 1, function)),
+        );
+    }
+    if let Some(source) = source {
+        let functions = debug
+            .into_iter()
+            .map(|(symbol, function)| (&object.symbols[symbol], function))
+            .collect::<Vec<_>>();
+        object.debug_sections =
+            dwarf::sections(source, &functions, object.text.len() as u64);
+    }
+    object
+}
+
+/// The same code as `compile` produces, as input for the GNU assembler. Each
+/// instruction that gives a `let` binding its value says where it is kept.
+pub fn emit_asm(program: &Program, entry: Entry) -> String {
+    let mut out = String::new();
+    out.push_str("    .intel_syntax noprefix\n");
+    out.push_str("    .text\n");
+    for Compiled {
+        symbol: name, code, ..
+    } in compile_functions(program, entry)
+    {
+        out.push('\n');
+        writeln!(out, "    .globl {name}").unwrap();
+        writeln!(out, "    .type {name}, @function").unwrap();
+        writeln!(out, "    .p2align 4, 0xcc").unwrap();
+        writeln!(out, "{name}:").unwrap();
+        for line in code.listing {
+            writeln!(out, "{line}").unwrap();
+        }
+        writeln!(out, "    .size {name}, .-{name}").unwrap();
+    }
+    out.push('\n');
+    out.push_str("    .section .note.GNU-stack,\"\",@progbits\n");
+    out
+}
+
+/// A function's symbol and code.
+struct Compiled<'a> {
+    symbol: String,
+    code: Code,
+    /// What debug information needs to know about the code, unless it is the
+    /// entry point, which has no source.
+    debug: Option<dwarf::Function<'a>>,
+}
+
+/// Compiles each function and the entry point.
+fn compile_functions(program: &Program, entry: Entry) -> Vec<Compiled<'_>> {
+    let mut functions = Vec::new();
+    for function in &program.functions {
+        let symbol = symbol(program, function.name);
+        let mut assembler = Assembler::new(&symbol);
+        let debug =
+            FunctionCompiler::new(program, function, &mut assembler).compile();
+        functions.push(Compiled {
+            symbol,
+            code: assembler.finish(),
+            debug: Some(debug),
+        });
+    }
+
+    // The entry point calls the Gneiss one, whose result has already been
+    // extended to 64 bits so that `eax` holds the exit status.
+    if let Some(main) = program
+        .function("main".into())
+        .filter(|main| main.parameters.is_empty())
+    {
+        let name = match entry {
+            Entry::Main => "main",
+            Entry::Start => "_start",
+        };
+        let mut assembler = Assembler::new(name);
+        match entry {
+            Entry::Main => {
+                assembler.push(Reg::Rbp);
+                assembler.mov(Reg::Rbp, Reg::Rsp);
+                assembler.call(mangle(&main.name));
+                if main.return_type == Type::Unit {
+                    assembler.mov_immediate(Reg::Rax, 0);
+                }
+                assembler.leave();
+                assembler.ret();
+            }
+            Entry::Start => {
+                // The kernel leaves the stack aligned to 16 bytes, and a
+                // zero `rbp` marks the outermost frame for debuggers.
+                assembler.alu(Alu::Xor, Reg::Rbp, Reg::Rbp);
+                assembler.call(mangle(&main.name));
+                if main.return_type == Type::Unit {
+                    assembler.mov_immediate(Reg::Rdi, 0);
+                } else {
+                    assembler.mov(Reg::Rdi, Reg::Rax);
+                }
+                assembler.mov_immediate(Reg::Rax, SYS_EXIT);
+                assembler.syscall();
+            }
+        }
+        functions.push(Compiled {
+            symbol: name.to_owned(),
+            code: assembler.finish(),
+            debug: None,
+        });
+    }
+    functions
+}
+
+struct FunctionCompiler<'a, 'asm> {
+    program: &'a Program,
+    function: &'a Function,
+    assembler: &'asm mut Assembler,
+    allocation: Allocation,
+    /// For the pointer that each `StackSlot` instruction defines, the number
+    /// of 8-byte words of memory in the frame up to and including its own.
+    memory: Vec<Option<usize>>,
+    labels: Vec<Label>,
+    /// Where the code of each instruction with a source position starts.
+    lines: Vec<(u64, Span)>,
+}
+
+impl<'a, 'asm> FunctionCompiler<'a, 'asm> {
+    fn new(
+        program: &'a Program,
+        function: &'a Function,
+        assembler: &'asm mut Assembler,
+    ) -> Self {
+        let labels = function
+            .block_ids()
+            .map(|_| assembler.new_label())
+            .collect();
+        let mut memory = vec![None; function.values.len()];
+        let mut words = 0;
+        for instruction in
+            function.blocks.iter().flat_map(|block| &block.instructions)
+        {
+            if let Instruction::StackSlot { dest } = *instruction {
+                // Alignments are at most 8, like those of the words.
+                let size = function
+                    .typ(dest)
+                    .pointee()
+                    .and_then(|typ| Layout::of(typ, &program.types))
+                    .expect("stack slots have a layout")
+                    .size;
+                // Even `unit` gets an address of its own.
+                words += size.div_ceil(8).max(1) as usize;
+                memory[dest.0] = Some(words);
+            }
+        }
+        Self {
+            program,
+            function,
+            assembler,
+            allocation: regalloc::allocate(function),
+            memory,
+            labels,
+            lines: Vec::new(),
+        }
+    }
+
+    fn compile(mut self) -> dwarf::Function<'a> {
+        let function = self.function;
+        let memory_words =
+            self.memory.iter().flatten().max().copied().unwrap_or(0);
+        let frame_size = (self.allocation.callee_saved.len()
+            + self.allocation.spill_slots
+            + memory_words)
+            * 8;
+        let frame_size = i32::try_from(frame_size.next_multiple_of(16))
+            .expect("frame fits in 2 GiB");
+        self.assembler.push(Reg::Rbp);
+        self.assembler.mov(Reg::Rbp, Reg::Rsp);
+        if frame_size != 0 {
+            self.assembler.adjust_stack(-frame_size);
+        }
+        for (i, &reg) in self.allocation.callee_saved.clone().iter().enumerate()
+        {
+            self.assembler.store(Reg::Rbp, slot(i), reg);
+        }
+
+        let moves = function
+            .parameters
+            .iter()
+            .zip(ARGUMENT_REGISTERS)
+            .map(|(&parameter, register)| {
+                (self.allocation.location(parameter), Location::Reg(register))
+            })
+            .collect();
+        self.parallel_move(moves);
+        let register_parameters =
+            function.parameters.len().min(ARGUMENT_REGISTERS.len());
+        for (i, &parameter) in function.parameters[register_parameters..]
+            .iter()
+            .enumerate()
+        {
+            // Above the saved `rbp` and the return address.
+            self.assembler.load(Reg::Rax, Reg::Rbp, 16 + 8 * i as i32);
+            self.store(parameter, Reg::Rax);
+        }
+        for &parameter in &function.parameters {
+            let typ = function.typ(parameter);
+            match self.allocation.location(parameter) {
+                Location::Reg(reg) => self.extend(reg, typ),
+                Location::Spilled(_) => {
+                    self.load(Reg::Rax, parameter);
+                    self.extend(Reg::Rax, typ);
+                    self.store(parameter, Reg::Rax);
+                }
+            }
+            self.describe(parameter, None);
+        }
+
+        for id in function.block_ids() {
+            self.assembler.bind(self.labels[id.0]);
+            let block = function.block(id);
+            for instruction in &block.instructions {
+                let start = self.assembler.offset();
+                let span = instruction
+                    .dest()
+                    .and_then(|dest| function.values[dest.0].span);
+                if let Some(span) = span {
+                    self.lines.push((start as u64, span));
+                }
+                self.compile_instruction(instruction);
+                if let Some(dest) = instruction.dest() {
+                    self.describe(dest, Some(start));
+                }
+            }
+            match &block.terminator {
+                Terminator::Return(value) => {
+                    if let Some(value) = value {
+                        self.load(Reg::Rax, *value);
+                    }
+                    for (i, &reg) in
+                        self.allocation.callee_saved.clone().iter().enumerate()
+                    {
+                        self.assembler.load(reg, Reg::Rbp, slot(i));
+                    }
+                    self.assembler.leave();
+                    self.assembler.ret();
+                }
+                Terminator::Jump(target, arguments) => {
+                    self.compile_jump(id, *target, arguments);
+                }
+                &Terminator::Branch {
+                    value,
+                    low,
+                    high,
+                    then,
+                    otherwise,
+                } => {
+                    // Subtracting `low` turns the range into `0..=high - low`
+                    // for either signedness, so one unsigned comparison does.
+                    let typ = function.typ(value);
+                    let low = typ.to_i128(low);
+                    let width = typ.to_i128(high) - low;
+                    self.load(Reg::Rax, value);
+                    self.assembler.mov_immediate(Reg::Rcx, low as u64);
+                    self.assembler.alu(Alu::Sub, Reg::Rax, Reg::Rcx);
+                    self.assembler.mov_immediate(Reg::Rcx, width as u64);
+                    self.assembler.alu(Alu::Cmp, Reg::Rax, Reg::Rcx);
+                    self.assembler.jbe(self.labels[then.0]);
+                    if otherwise.0 != id.0 + 1 {
+                        self.assembler.jmp(self.labels[otherwise.0]);
+                    }
+                }
+            }
+        }
+
+        let locations = self
+            .allocation
+            .locations
+            .iter()
+            .map(|location| {
+                location.map(|location| match location {
+                    Location::Reg(reg) => {
+                        dwarf::Location::Register(reg.dwarf_number())
+                    }
+                    Location::Spilled(slot) => dwarf::Location::Memory {
+                        base: Reg::Rbp.dwarf_number(),
+                        offset: i64::from(self.spill_slot(slot)),
+                    },
+                })
+            })
+            .collect();
+        dwarf::Function {
+            ir: function,
+            lines: self.lines,
+            locations,
+        }
+    }
+
+    fn compile_instruction(&mut self, instruction: &Instruction) {
+        match instruction {
+            Instruction::Const { dest, value } => {
+                let typ = self.function.typ(*dest);
+                let value = typ.to_i128(*value) as u64;
+                match self.allocation.location(*dest) {
+                    Location::Reg(reg) => {
+                        self.assembler.mov_immediate(reg, value)
+                    }
+                    Location::Spilled(_) => {
+                        self.assembler.mov_immediate(Reg::Rax, value);
+                        self.store(*dest, Reg::Rax);
+                    }
+                }
+            }
+            Instruction::Copy { dest, source } => self.copy(*dest, *source),
+            Instruction::Binary {
+                dest,
+                operator,
+                left,
+                right,
+            } => {
+                let typ = self.function.typ(*dest);
+                self.load(Reg::Rax, *left);
+                // Only division and shifts need the right operand in a
+                // particular register.
+                let right = match self.allocation.location(*right) {
+                    Location::Reg(reg)
+                        if !matches!(
+                            operator,
+                            BinaryOperator::Div
+                                | BinaryOperator::Rem
+                                | BinaryOperator::Shl
+                                | BinaryOperator::Shr
+                        ) =>
+                    {
+                        reg
+                    }
+                    _ => {
+                        self.load(Reg::Rcx, *right);
+                        Reg::Rcx
+                    }
+                };
+                self.compile_binary(typ, *operator, right);
+                self.extend(Reg::Rax, typ);
+                self.store(*dest, Reg::Rax);
+            }
+            Instruction::Call {
+                dest,
+                callee,
+                arguments,
+            } => {
+                let (register_arguments, stack_arguments) = arguments
+                    .split_at(arguments.len().min(ARGUMENT_REGISTERS.len()));
+                // The stack has to be aligned to 16 bytes at the call.
+                let padding = if stack_arguments.len().is_multiple_of(2) {
+                    0
+                } else {
+                    8
+                };
+                if padding != 0 {
+                    self.assembler.adjust_stack(-padding);
+                }
+                for &argument in stack_arguments.iter().rev() {
+                    self.push(self.allocation.location(argument));
+                }
+                self.move_to_registers(register_arguments, &ARGUMENT_REGISTERS);
+                self.assembler.call(symbol(self.program, *callee));
+                let cleanup = 8 * stack_arguments.len() as i32 + padding;
+                if cleanup != 0 {
+                    self.assembler.adjust_stack(cleanup);
+                }
+                if let Some(dest) = dest {
+                    self.extend(Reg::Rax, self.function.typ(*dest));
+                    self.store(*dest, Reg::Rax);
+                }
+            }
+            Instruction::Syscall { dest, arguments } => {
+                // The number moves into `rax` along with the arguments, since
+                // it may be allocated to one of their registers.
+                self.move_to_registers(arguments, &SYSCALL_REGISTERS);
+                self.assembler.syscall();
+                self.store(*dest, Reg::Rax);
+            }
+            Instruction::StackSlot { dest } => {
+                let offset = self.stack_slot(*dest);
+                self.assembler.lea(Reg::Rax, Reg::Rbp, offset);
+                self.store(*dest, Reg::Rax);
+            }
+            Instruction::Load { dest, address } => {
+                let typ = self.function.typ(*dest);
+                let bits = typ.bits().expect("values aren't `unit`");
+                self.load(Reg::Rcx, *address);
+                self.assembler.load_extended(
+                    Reg::Rax,
+                    Reg::Rcx,
+                    0,
+                    bits,
+                    typ.is_signed(),
+                );
+                self.store(*dest, Reg::Rax);
+            }
+            Instruction::Store { address, value } => {
+                let typ = self.function.typ(*value);
+                let bits = typ.bits().expect("values aren't `unit`");
+                self.load(Reg::Rcx, *address);
+                self.load(Reg::Rax, *value);
+                self.assembler.store_truncated(Reg::Rcx, 0, Reg::Rax, bits);
+            }
+            Instruction::Cast { dest, source } => {
+                // The source is already extended according to its own type.
+                self.load(Reg::Rax, *source);
+                self.extend(Reg::Rax, self.function.typ(*dest));
+                self.store(*dest, Reg::Rax);
+            }
+        }
+    }
+
+    /// Computes `rax = rax op right`, clobbering `rcx` and `rdx`. The right
+    /// operand of divisions and shifts has to be in `rcx`.
+    fn compile_binary(
+        &mut self,
+        typ: Type,
+        operator: BinaryOperator,
+        right: Reg,
+    ) {
+        let assembler = &mut *self.assembler;
+        let bits = typ.bits().expect("values aren't `unit`");
+        match operator {
+            BinaryOperator::Add => assembler.alu(Alu::Add, Reg::Rax, right),
+            BinaryOperator::Sub => assembler.alu(Alu::Sub, Reg::Rax, right),
+            BinaryOperator::And => assembler.alu(Alu::And, Reg::Rax, right),
+            BinaryOperator::Or => assembler.alu(Alu::Or, Reg::Rax, right),
+            BinaryOperator::Xor => assembler.alu(Alu::Xor, Reg::Rax, right),
+            BinaryOperator::Mul => assembler.imul(Reg::Rax, right),
+            BinaryOperator::Div | BinaryOperator::Rem => {
+                if typ.is_signed() {
+                    assembler.cqo();
+                    assembler.idiv(Reg::Rcx);
+                } else {
+                    assembler.alu(Alu::Xor, Reg::Rdx, Reg::Rdx);
+                    assembler.div(Reg::Rcx);
+                }
+                if operator == BinaryOperator::Rem {
+                    assembler.mov(Reg::Rax, Reg::Rdx);
+                }
+            }
+            BinaryOperator::Shl | BinaryOperator::Shr => {
+                // The processor only takes 64-bit shift amounts modulo the
+                // width of the register.
+                if bits < 64 {
+                    assembler.and_immediate(Reg::Rcx, bits as i8 - 1);
+                }
+                let shift = match operator {
+                    BinaryOperator::Shl => Shift::Shl,
+                    _ if typ.is_signed() => Shift::Sar,
+                    _ => Shift::Shr,
+                };
+                assembler.shift(shift, Reg::Rax);
+            }
+        }
+    }
+
+    fn compile_jump(
+        &mut self,
+        from: BlockId,
+        target: BlockId,
+        arguments: &[Value],
+    ) {
+        let parameters = &self.function.block(target).parameters;
+        let moves = parameters
+            .iter()
+            .zip(arguments)
+            .map(|(&parameter, &argument)| {
+                (
+                    self.allocation.location(parameter),
+                    self.allocation.location(argument),
+                )
+            })
+            .collect();
+        self.parallel_move(moves);
+        for &parameter in parameters {
+            self.describe(parameter, None);
+        }
+        if target.0 != from.0 + 1 {
+            self.assembler.jmp(self.labels[target.0]);
+        }
+    }
+
+    fn move_to_registers(&mut self, values: &[Value], registers: &[Reg]) {
+        let moves = values
+            .iter()
+            .zip(registers)
+            .map(|(&value, &register)| {
+                (Location::Reg(register), self.allocation.location(value))
+            })
+            .collect();
+        self.parallel_move(moves);
+    }
+
+    /// Performs moves from the second location of each pair to the first as
+    /// if they all happened at once, even though a source of one may be the
+    /// destination of another.
+    fn parallel_move(&mut self, mut moves: Vec<(Location, Location)>) {
+        moves.retain(|(dest, source)| dest != source);
+        // Moves between stack slots go through a scratch register that no
+        // move writes to.
+        let scratch = [Reg::Rax, Reg::Rcx]
+            .into_iter()
+            .find(|&reg| {
+                moves.iter().all(|&(dest, _)| dest != Location::Reg(reg))
+            })
+            .expect("no moves write to both `rax` and `rcx`");
+        // A move can go first if no other move still reads its destination.
+        while let Some(i) = moves.iter().position(|&(dest, _)| {
+            moves.iter().all(|&(_, source)| source != dest)
+        }) {
+            let (dest, source) = moves.remove(i);
+            self.move_location(dest, source, scratch);
+        }
+        // The remaining moves form cycles, which go through the stack.
+        for &(_, source) in &moves {
+            self.push(source);
+        }
+        for &(dest, _) in moves.iter().rev() {
+            match dest {
+                Location::Reg(reg) => self.assembler.pop(reg),
+                Location::Spilled(slot) => {
+                    let offset = self.spill_slot(slot);
+                    self.assembler.pop_memory(Reg::Rbp, offset);
+                }
+            }
+        }
+    }
+
+    /// Moves between two locations, using `scratch` if both are on the stack.
+    fn move_location(
+        &mut self,
+        dest: Location,
+        source: Location,
+        scratch: Reg,
+    ) {
+        match (dest, source) {
+            (Location::Reg(dest), Location::Reg(source)) => {
+                self.assembler.mov(dest, source);
+            }
+            (Location::Reg(dest), Location::Spilled(slot)) => {
+                let offset = self.spill_slot(slot);
+                self.assembler.load(dest, Reg::Rbp, offset);
+            }
+            (Location::Spilled(slot), Location::Reg(source)) => {
+                let offset = self.spill_slot(slot);
+                self.assembler.store(Reg::Rbp, offset, source);
+            }
+            (Location::Spilled(_), Location::Spilled(_)) => {
+                self.move_location(Location::Reg(scratch), source, scratch);
+                self.move_location(dest, Location::Reg(scratch), scratch);
+            }
+        }
+    }
+
+    /// Extends the low bits of `reg` to 64 bits according to `typ`.
+    fn extend(&mut self, reg: Reg, typ: Type) {
+        let bits = typ.bits().expect("values aren't `unit`");
+        if typ.is_signed() {
+            self.assembler.sign_extend(reg, bits);
+        } else {
+            self.assembler.zero_extend(reg, bits);
+        }
+    }
+
+    fn load(&mut self, reg: Reg, value: Value) {
+        let source = self.allocation.location(value);
+        self.parallel_move(vec![(Location::Reg(reg), source)]);
+    }
+
+    fn store(&mut self, value: Value, reg: Reg) {
+        let dest = self.allocation.location(value);
+        self.parallel_move(vec![(dest, Location::Reg(reg))]);
+    }
+
+    fn copy(&mut self, dest: Value, source: Value) {
+        self.parallel_move(vec![(
+            self.allocation.location(dest),
+            self.allocation.location(source),
+        )]);
+    }
+
+    fn push(&mut self, location: Location) {
+        match location {
+            Location::Reg(reg) => self.assembler.push(reg),
+            Location::Spilled(slot) => {
+                self.assembler.push_memory(Reg::Rbp, self.spill_slot(slot));
+            }
+        }
+    }
+
+    /// Says in the listing where the `let` binding that `value` belongs to
+    /// is kept. If the code from `start` gave it its value, the comment goes
+    /// on the last instruction, and otherwise on a line of its own.
+    fn describe(&mut self, value: Value, start: Option<usize>) {
+        let Some(name) = self.function.values[value.0].name else {
+            return;
+        };
+        let location = match self.allocation.location(value) {
+            Location::Reg(reg) => reg.to_string(),
+            Location::Spilled(slot) => {
+                format!("[rbp - {}]", -self.spill_slot(slot))
+            }
+        };
+        let text = format!("{location} = {name}");
+        match start {
+            Some(start) if start != self.assembler.offset() => {
+                self.assembler.annotate(&text);
+            }
+            _ => self.assembler.comment(&text),
+        }
+    }
+
+    fn spill_slot(&self, slot: usize) -> i32 {
+        self::slot(self.allocation.callee_saved.len() + slot)
+    }
+
+    /// The offset from `rbp` of the memory that a `StackSlot` instruction
+    /// defines `pointer` to point to.
+    fn stack_slot(&self, pointer: Value) -> i32 {
+        let words = self.memory[pointer.0].expect("value is a stack slot");
+        let before =
+            self.allocation.callee_saved.len() + self.allocation.spill_slots;
+        self::slot(before + words - 1)
+    }
+}
+
+/// The offset of the stack slot with the given index from `rbp`.
+fn slot(index: usize) -> i32 {
+    let index = i32::try_from(index).expect("frame fits in 2 GiB");
+    -8 * (index + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::Types,
+        ir::{Block, ExternFunction},
+    };
+    use std::{path::Path, process::Command};
+
+    /// Builds an executable from the given files with the C compiler and
+    /// returns its exit status.
+    fn build_and_run(name: &str, files: &[(&str, Vec<u8>)]) -> Option<i32> {
+        let directory = std::env::temp_dir()
+            .join(format!("gneiss-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let executable = directory.join("program");
+        let mut command = Command::new("cc");
+        for (file_name, contents) in files {
+            let path = directory.join(file_name);
+            std::fs::write(&path, contents).unwrap();
+            command.arg(path);
+        }
+        let linked = command.arg("-o").arg(&executable).status();
+        let status = match linked {
+            Ok(status) => {
+                assert!(status.success());
+                Command::new(&executable).status().unwrap().code()
+            }
+            Err(_) => {
+                eprintln!("skipping because `cc` isn't available");
+                None
+            }
+        };
+        std::fs::remove_dir_all(&directory).unwrap();
+        status
+    }
+
+    /// Runs `program` as a static executable and returns its exit status.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn run_static(
+        name: &str,
+        program: &Program,
+        source: Option<&Source>,
+    ) -> Option<i32> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = std::env::temp_dir()
+            .join(format!("gneiss-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let executable = directory.join("program");
+        let contents = compile(program, Entry::Start, source)
+            .write_executable("_start")
+            .unwrap();
+        std::fs::write(&executable, contents).unwrap();
+        std::fs::set_permissions(
+            &executable,
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let status = Command::new(&executable).status().unwrap().code();
+        std::fs::remove_dir_all(&directory).unwrap();
+        status
+    }
+
+    /// A function that keeps more values alive across a call than there are
+    /// registers, and returns 107.
+    fn register_pressure() -> Program {
+        let mut double = Function::new("double", Type::I32, &[Type::I32]);
+        let x = double.parameters[0];
+        let result = double.new_value(Type::I32, None);
+        double.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![Instruction::Binary {
+                dest: result,
+                operator: BinaryOperator::Add,
+                left: x,
+                right: x,
+            }],
+            terminator: Terminator::Return(Some(result)),
+        });
+
+        let mut main = Function::new("main", Type::I32, &[]);
+        let numbers = (1..=14)
+            .map(|i| {
+                let name = (i == 1).then(|| "first".into());
+                main.new_value(Type::I32, name)
+            })
+            .collect::<Vec<_>>();
+        let doubled = main.new_value(Type::I32, Some("doubled".into()));
+        let mut instructions = numbers
+            .iter()
+            .zip(1..)
+            .map(|(&dest, value)| Instruction::Const { dest, value })
+            .collect::<Vec<_>>();
+        instructions.push(Instruction::Call {
+            dest: Some(doubled),
+            callee: "double".into(),
+            arguments: vec![numbers[0]],
+        });
+        let mut total = doubled;
+        for &number in &numbers {
+            let dest = main.new_value(Type::I32, None);
+            instructions.push(Instruction::Binary {
+                dest,
+                operator: BinaryOperator::Add,
+                left: total,
+                right: number,
+            });
+            total = dest;
+        }
+        main.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions,
+            terminator: Terminator::Return(Some(total)),
+        });
+        Program {
+            functions: vec![main.into(), double.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        }
+    }
+
+    #[test]
+    fn objects_link_with_c() {
+        // Eight parameters, so that two are passed on the stack.
+        let mut sum = Function::new("sum-all", Type::I32, &[Type::I32; 8]);
+        let mut total = sum.parameters[0];
+        let mut instructions = Vec::new();
+        for &parameter in &sum.parameters.clone()[1..] {
+            let dest = sum.new_value(Type::I32, None);
+            instructions.push(Instruction::Binary {
+                dest,
+                operator: BinaryOperator::Add,
+                left: total,
+                right: parameter,
+            });
+            total = dest;
+        }
+        sum.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions,
+            terminator: Terminator::Return(Some(total)),
+        });
+
+        let mut main = Function::new("main", Type::I32, &[]);
+        let arguments = (1..=8)
+            .map(|_| main.new_value(Type::I32, None))
+            .collect::<Vec<_>>();
+        let [total, tripled, parameter, nine, quotient, square_root, square] =
+            [(); 7].map(|()| main.new_value(Type::I32, None));
+        let mut instructions = arguments
+            .iter()
+            .zip(1..)
+            .map(|(&dest, value)| Instruction::Const { dest, value })
+            .collect::<Vec<_>>();
+        instructions.extend([
+            Instruction::Call {
+                dest: Some(total),
+                callee: "sum-all".into(),
+                arguments,
+            },
+            Instruction::Call {
+                dest: Some(tripled),
+                callee: "triple".into(),
+                arguments: vec![total],
+            },
+        ]);
+        main.blocks = vec![
+            Block {
+                parameters: Vec::new(),
+                instructions,
+                terminator: Terminator::Jump(BlockId(1), vec![tripled]),
+            },
+            Block {
+                parameters: vec![parameter],
+                instructions: vec![
+                    Instruction::Const {
+                        dest: nine,
+                        value: 9,
+                    },
+                    Instruction::Binary {
+                        dest: quotient,
+                        operator: BinaryOperator::Div,
+                        left: parameter,
+                        right: nine,
+                    },
+                ],
+                terminator: Terminator::Jump(BlockId(2), vec![quotient]),
+            },
+            Block {
+                parameters: vec![square_root],
+                instructions: vec![Instruction::Binary {
+                    dest: square,
+                    operator: BinaryOperator::Mul,
+                    left: square_root,
+                    right: square_root,
+                }],
+                terminator: Terminator::Return(Some(square)),
+            },
+        ];
+
+        let c_source = "
+#include <stdint.h>
+int32_t gn_triple(int32_t x) { return x * 3; }
+";
+        let program = Program {
+            functions: vec![main.into(), sum.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        let files = [
+            (
+                "program.o",
+                compile(&program, Entry::Main, None).write_relocatable(),
+            ),
+            ("support.c", c_source.into()),
+        ];
+        // (1 + 2 + ... + 8) * 3 / 9 is 12, and 12 * 12 is 144.
+        if let Some(status) = build_and_run("link-with-c", &files) {
+            assert_eq!(status, 144);
+        }
+    }
+
+    #[test]
+    fn extern_functions_are_called_by_their_c_name() {
+        let mut main = Function::new("main", Type::I8, &[]);
+        let [five, negated, twelve, sum] =
+            [(); 4].map(|()| main.new_value(Type::I8, None));
+        main.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::Const {
+                    dest: five,
+                    value: 5,
+                },
+                Instruction::Call {
+                    dest: Some(negated),
+                    callee: "negate".into(),
+                    arguments: vec![five],
+                },
+                Instruction::Const {
+                    dest: twelve,
+                    value: 12,
+                },
+                Instruction::Binary {
+                    dest: sum,
+                    operator: BinaryOperator::Add,
+                    left: negated,
+                    right: twelve,
+                },
+            ],
+            terminator: Terminator::Return(Some(sum)),
+        });
+        let program = Program {
+            functions: vec![main.into()],
+            externs: vec![ExternFunction {
+                name: "negate".into(),
+                parameter_types: vec![Type::I8],
+                return_type: Type::I8,
+            }],
+            types: Types::default(),
+        };
+        // The C compiler may leave garbage above the low byte of the result.
+        let c_source = "
+#include <stdint.h>
+int8_t negate(int8_t x) { return -x; }
+";
+        let files = [
+            (
+                "program.o",
+                compile(&program, Entry::Main, None).write_relocatable(),
+            ),
+            ("support.c", c_source.into()),
+        ];
+        if let Some(status) = build_and_run("extern", &files) {
+            assert_eq!(status, 7);
+        }
+    }
+
+    #[test]
+    fn memory_is_accessed_in_the_width_of_the_type() {
+        let mut main = Function::new("main", Type::I16, &[]);
+        let slot = main.new_value(Type::pointer(Type::U64, true), None);
+        let halves = main.new_value(Type::pointer(Type::I16, false), None);
+        let bytes = main.new_value(Type::pointer(Type::U8, true), None);
+        let [number, two] = [(); 2].map(|()| main.new_value(Type::U64, None));
+        let offset = main.new_value(Type::pointer(Type::I16, false), None);
+        let high_half = main.new_value(Type::pointer(Type::I16, false), None);
+        let seven = main.new_value(Type::U8, None);
+        let [low, high, sum, changed, result] =
+            [(); 5].map(|()| main.new_value(Type::I16, None));
+        main.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::StackSlot { dest: slot },
+                Instruction::Const {
+                    dest: number,
+                    value: 0xffff_0005,
+                },
+                Instruction::Store {
+                    address: slot,
+                    value: number,
+                },
+                Instruction::Cast {
+                    dest: halves,
+                    source: slot,
+                },
+                Instruction::Load {
+                    dest: low,
+                    address: halves,
+                },
+                Instruction::Const {
+                    dest: two,
+                    value: 2,
+                },
+                Instruction::Cast {
+                    dest: offset,
+                    source: two,
+                },
+                Instruction::Binary {
+                    dest: high_half,
+                    operator: BinaryOperator::Add,
+                    left: halves,
+                    right: offset,
+                },
+                Instruction::Load {
+                    dest: high,
+                    address: high_half,
+                },
+                Instruction::Binary {
+                    dest: sum,
+                    operator: BinaryOperator::Add,
+                    left: low,
+                    right: high,
+                },
+                Instruction::Cast {
+                    dest: bytes,
+                    source: slot,
+                },
+                Instruction::Const {
+                    dest: seven,
+                    value: 7,
+                },
+                Instruction::Store {
+                    address: bytes,
+                    value: seven,
+                },
+                Instruction::Load {
+                    dest: changed,
+                    address: halves,
+                },
+                Instruction::Binary {
+                    dest: result,
+                    operator: BinaryOperator::Add,
+                    left: sum,
+                    right: changed,
+                },
+            ],
+            terminator: Terminator::Return(Some(result)),
+        });
+        let program = Program {
+            functions: vec![main.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        let files = [(
+            "program.o",
+            compile(&program, Entry::Main, None).write_relocatable(),
+        )];
+        // 5 + -1, plus 7 after the low byte is overwritten.
+        if let Some(status) = build_and_run("memory", &files) {
+            assert_eq!(status, 11);
+        }
+    }
+
+    #[test]
+    fn spilled_values_survive_calls() {
+        let program = register_pressure();
+        let files = [(
+            "program.o",
+            compile(&program, Entry::Main, None).write_relocatable(),
+        )];
+        // 2 + (1 + 2 + ... + 14)
+        if let Some(status) = build_and_run("spills", &files) {
+            assert_eq!(status, 107);
+        }
+    }
+
+    #[test]
+    fn assembly_matches_the_machine_code() {
+        let program = register_pressure();
+        let asm = emit_asm(&program, Entry::Main);
+        assert!(asm.contains("  # rbx = first\n"), "{asm}");
+        if let Some(status) =
+            build_and_run("assembly", &[("program.s", asm.into())])
+        {
+            assert_eq!(status, 107);
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn static_executables_return_syscall_results_from_main() {
+        let mut main = Function::new("main", Type::I64, &[]);
+        let [close, descriptor, result] =
+            [(); 3].map(|()| main.new_value(Type::I64, None));
+        main.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::Const {
+                    dest: close,
+                    value: 3,
+                },
+                Instruction::Const {
+                    dest: descriptor,
+                    value: u64::MAX,
+                },
+                Instruction::Syscall {
+                    dest: result,
+                    arguments: vec![close, descriptor],
+                },
+            ],
+            terminator: Terminator::Return(Some(result)),
+        });
+        let program = Program {
+            functions: vec![main.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        // Closing -1 fails with `EBADF`, which is 9, and the exit status
+        // keeps the low byte of -9.
+        assert_eq!(run_static("syscall-result", &program, None), Some(247));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn syscall_numbers_survive_moves_into_argument_registers() {
+        let mut main = Function::new("main", Type::I64, &[]);
+        let [dup2, old, new, result] =
+            [(); 4].map(|()| main.new_value(Type::I64, None));
+        main.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::Const {
+                    dest: dup2,
+                    value: 33,
+                },
+                Instruction::Const {
+                    dest: old,
+                    value: u64::MAX,
+                },
+                Instruction::Const {
+                    dest: new,
+                    value: 200,
+                },
+                Instruction::Syscall {
+                    dest: result,
+                    arguments: vec![dup2, old, new],
+                },
+            ],
+            terminator: Terminator::Return(Some(result)),
+        });
+        // The number sits in `rsi`, where the second argument goes.
+        assert_eq!(
+            regalloc::allocate(&main).location(dup2),
+            Location::Reg(Reg::Rsi)
+        );
+        let program = Program {
+            functions: vec![main.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        // Duplicating -1 fails with `EBADF`, while syscall 200, `tkill`,
+        // would fail with `EINVAL`.
+        assert_eq!(run_static("syscall-number", &program, None), Some(247));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn static_executables_can_exit_early() {
+        let mut main = Function::new("main", Type::Unit, &[]);
+        let [exit, status, result] =
+            [(); 3].map(|()| main.new_value(Type::I64, None));
+        main.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::Const {
+                    dest: exit,
+                    value: 60,
+                },
+                Instruction::Const {
+                    dest: status,
+                    value: 42,
+                },
+                Instruction::Syscall {
+                    dest: result,
+                    arguments: vec![exit, status],
+                },
+            ],
+            terminator: Terminator::Return(None),
+        });
+        let program = Program {
+            functions: vec![main.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        // Returning from `main` would exit with 0.
+        assert_eq!(run_static("exit-early", &program, None), Some(42));
+    }
+
+    #[test]
+    fn block_parameters_can_swap_registers() {
+        let mut main = Function::new("main", Type::I8, &[]);
+        let [ten, three, x, y, p, q, difference] =
+            [(); 7].map(|()| main.new_value(Type::I8, None));
+        main.blocks = vec![
+            Block {
+                parameters: Vec::new(),
+                instructions: vec![
+                    Instruction::Const {
+                        dest: ten,
+                        value: 10,
+                    },
+                    Instruction::Const {
+                        dest: three,
+                        value: 3,
+                    },
+                ],
+                terminator: Terminator::Jump(BlockId(1), vec![ten, three]),
+            },
+            Block {
+                parameters: vec![x, y],
+                instructions: Vec::new(),
+                terminator: Terminator::Jump(BlockId(2), vec![y, x]),
+            },
+            Block {
+                parameters: vec![p, q],
+                instructions: vec![Instruction::Binary {
+                    dest: difference,
+                    operator: BinaryOperator::Sub,
+                    left: p,
+                    right: q,
+                }],
+                terminator: Terminator::Return(Some(difference)),
+            },
+        ];
+        let program = Program {
+            functions: vec![main.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        let files = [(
+            "program.o",
+            compile(&program, Entry::Main, None).write_relocatable(),
+        )];
+        // 3 - 10 is -7, whose low byte is 249.
+        if let Some(status) = build_and_run("swap", &files) {
+            assert_eq!(status, 249);
+        }
+    }
+
+    #[test]
+    fn debug_information_survives_linking() {
+        let text = "\
+fn double(x: i32) -> i32 {
+    x + x
+}
+
+fn main() -> i32 {
+    let first = 1_i32;
+    let doubled = double(first);
+    doubled + 5_i32
+}
+";
+        let main_start = text.find("fn main").unwrap();
+        let span = |function_start: usize, code: &str| {
+            let start = text[function_start..].find(code).unwrap();
+            Some(Span {
+                start,
+                end: start + code.len(),
+            })
+        };
+
+        let mut double = Function::new("double", Type::I32, &[Type::I32]);
+        let x = double.parameters[0];
+        double.values[x.0].name = Some("x".into());
+        double.values[x.0].span = span(0, "x");
+        let sum = double.new_value(Type::I32, None);
+        double.values[sum.0].span = span(0, "x + x");
+        double.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![Instruction::Binary {
+                dest: sum,
+                operator: BinaryOperator::Add,
+                left: x,
+                right: x,
+            }],
+            terminator: Terminator::Return(Some(sum)),
+        });
+
+        let mut main = Function::new("main", Type::I32, &[]);
+        let [one, first, call, doubled, five, result] =
+            [(); 6].map(|()| main.new_value(Type::I32, None));
+        // Literals get the span of the `let` or expression around them.
+        main.values[one.0].span = span(main_start, "first");
+        main.values[first.0].name = Some("first".into());
+        main.values[first.0].span = span(main_start, "first");
+        main.values[call.0].span = span(main_start, "double(first)");
+        main.values[doubled.0].name = Some("doubled".into());
+        main.values[doubled.0].span = span(main_start, "doubled");
+        main.values[five.0].span = span(main_start, "doubled + 5_i32");
+        main.values[result.0].span = span(main_start, "doubled + 5_i32");
+        main.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::Const {
+                    dest: one,
+                    value: 1,
+                },
+                Instruction::Copy {
+                    dest: first,
+                    source: one,
+                },
+                Instruction::Call {
+                    dest: Some(call),
+                    callee: "double".into(),
+                    arguments: vec![first],
+                },
+                Instruction::Copy {
+                    dest: doubled,
+                    source: call,
+                },
+                Instruction::Const {
+                    dest: five,
+                    value: 5,
+                },
+                Instruction::Binary {
+                    dest: result,
+                    operator: BinaryOperator::Add,
+                    left: doubled,
+                    right: five,
+                },
+            ],
+            terminator: Terminator::Return(Some(result)),
+        });
+
+        let program = Program {
+            functions: vec![main.into(), double.into()],
+            externs: Vec::new(),
+            types: Types::default(),
+        };
+        let text = text.into();
+        let source = Source {
+            path: Path::new("test.gneiss"),
+            directory: Path::new("/"),
+            text: &text,
+            function_starts: [
+                ("double".into(), 0),
+                ("main".into(), main_start),
+            ]
+            .into(),
+        };
+        let object = compile(&program, Entry::Main, Some(&source));
+        let names = object
+            .debug_sections
+            .iter()
+            .map(|section| section.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [".debug_abbrev", ".debug_info", ".debug_line"]);
+
+        // The linker has to understand the relocations in the debug sections.
+        let files = [("program.o", object.write_relocatable())];
+        if let Some(status) = build_and_run("debug-info", &files) {
+            assert_eq!(status, 7);
+        }
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        assert_eq!(
+            run_static("debug-info-static", &program, Some(&source)),
+            Some(7)
+        );
+    }
+}
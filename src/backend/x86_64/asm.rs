@@ -0,0 +1,657 @@
+//! Encodes x86-64 instructions. Only the forms that the code generator needs
+//! are supported, all with 64-bit operands unless their name says otherwise.
+//! Next to the machine code, the assembler keeps a listing of the same
+//! instructions in the Intel syntax of the GNU assembler.
+
+use std::fmt;
+
+/// General-purpose registers, numbered like in their encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R8 = 8,
+    R9 = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+impl Reg {
+    fn low_bits(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn is_extended(self) -> bool {
+        self as u8 >= 8
+    }
+
+    /// The number of the register in DWARF debug information.
+    pub fn dwarf_number(self) -> u16 {
+        match self {
+            Self::Rax => 0,
+            Self::Rdx => 1,
+            Self::Rcx => 2,
+            Self::Rbx => 3,
+            Self::Rsi => 4,
+            Self::Rdi => 5,
+            Self::Rbp => 6,
+            Self::Rsp => 7,
+            // The others are numbered like in machine code.
+            _ => u16::from(self as u8),
+        }
+    }
+
+    /// The name of the low `bits` of the register in assembly.
+    fn name(self, bits: u32) -> &'static str {
+        const NAMES: [[&str; 16]; 4] = [
+            [
+                "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b",
+                "r9b", "r10b", "r11b", "r12b", "r13b", "r14b", "r15b",
+            ],
+            [
+                "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w",
+                "r10w", "r11w", "r12w", "r13w", "r14w", "r15w",
+            ],
+            [
+                "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d",
+                "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d",
+            ],
+            [
+                "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8",
+                "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+            ],
+        ];
+        let size = match bits {
+            8 => 0,
+            16 => 1,
+            32 => 2,
+            _ => 3,
+        };
+        NAMES[size][self as usize]
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name(64))
+    }
+}
+
+/// Operations that take a register and a register or memory operand and
+/// are encoded as a single opcode byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+/// Shifts by the amount in `cl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shift {
+    Shl,
+    Shr,
+    Sar,
+}
+
+/// A position in the code that jumps can target before it is known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Label(usize);
+
+impl Alu {
+    fn mnemonic(self) -> &'static str {
+        match self {
+            Self::Add => "add",
+            Self::Or => "or",
+            Self::And => "and",
+            Self::Sub => "sub",
+            Self::Xor => "xor",
+            Self::Cmp => "cmp",
+        }
+    }
+}
+
+/// The machine code of a function.
+pub struct Code {
+    pub bytes: Vec<u8>,
+    /// The offsets of `rel32` fields of calls, with the names of the called
+    /// symbols.
+    pub calls: Vec<(usize, String)>,
+    /// The instructions in assembly, one per line.
+    pub listing: Vec<String>,
+}
+
+pub struct Assembler {
+    /// Makes the names of labels in the listing unique.
+    name: String,
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// The offsets of `rel32` fields that refer to labels.
+    fixups: Vec<(usize, Label)>,
+    calls: Vec<(usize, String)>,
+    listing: Vec<String>,
+}
+
+impl Assembler {
+    /// An assembler for the function with the symbol `name`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            calls: Vec::new(),
+            listing: Vec::new(),
+        }
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// The number of bytes of code so far.
+    pub fn offset(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+        let name = self.label_name(label);
+        self.listing.push(format!("{name}:"));
+    }
+
+    /// Resolves jumps to labels.
+    pub fn finish(mut self) -> Code {
+        for &(offset, label) in &self.fixups {
+            let target = self.labels[label.0].expect("label is bound");
+            let relative = target as i64 - (offset as i64 + 4);
+            let relative =
+                i32::try_from(relative).expect("code is smaller than 2 GiB");
+            self.code[offset..offset + 4]
+                .copy_from_slice(&relative.to_le_bytes());
+        }
+        Code {
+            bytes: self.code,
+            calls: self.calls,
+            listing: self.listing,
+        }
+    }
+
+    /// Adds a line to the listing that only holds a comment.
+    pub fn comment(&mut self, text: &str) {
+        self.listing.push(format!("    # {text}"));
+    }
+
+    /// Adds a comment to the end of the last instruction in the listing.
+    pub fn annotate(&mut self, text: &str) {
+        let line = self.listing.last_mut().expect("an instruction was emitted");
+        line.push_str("  # ");
+        line.push_str(text);
+    }
+
+    fn label_name(&self, label: Label) -> String {
+        // Mangled names contain no dots.
+        format!(".L{}.{}", self.name, label.0)
+    }
+
+    fn list(&mut self, instruction: fmt::Arguments) {
+        self.listing.push(format!("    {instruction}"));
+    }
+
+    fn rex(&mut self, w: bool, reg: Reg, rm: Reg) {
+        let rex = 0x40
+            | u8::from(w) << 3
+            | u8::from(reg.is_extended()) << 2
+            | u8::from(rm.is_extended());
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    /// Emits an instruction with a register operand in the `reg` field and
+    /// another one in the `r/m` field.
+    fn register_form(&mut self, w: bool, opcode: &[u8], reg: Reg, rm: Reg) {
+        self.rex(w, reg, rm);
+        self.code.extend(opcode);
+        self.code
+            .push(0b1100_0000 | reg.low_bits() << 3 | rm.low_bits());
+    }
+
+    /// Emits an instruction with a register operand in the `reg` field and
+    /// the memory operand `[base + displacement]`.
+    fn memory_form(
+        &mut self,
+        w: bool,
+        opcode: &[u8],
+        reg: Reg,
+        base: Reg,
+        displacement: i32,
+    ) {
+        self.rex(w, reg, base);
+        self.code.extend(opcode);
+        self.code
+            .push(0b1000_0000 | reg.low_bits() << 3 | base.low_bits());
+        if base.low_bits() == Reg::Rsp.low_bits() {
+            // `rsp` and `r12` as base registers need a SIB byte.
+            self.code.push(0x24);
+        }
+        self.code.extend(displacement.to_le_bytes());
+    }
+
+    /// `mov dest, [base + displacement]`
+    pub fn load(&mut self, dest: Reg, base: Reg, displacement: i32) {
+        self.memory_form(true, &[0x8b], dest, base, displacement);
+        let memory = Memory::qword(base, displacement);
+        self.list(format_args!("mov {dest}, {memory}"));
+    }
+
+    /// `mov [base + displacement], source`
+    pub fn store(&mut self, base: Reg, displacement: i32, source: Reg) {
+        self.memory_form(true, &[0x89], source, base, displacement);
+        let memory = Memory::qword(base, displacement);
+        self.list(format_args!("mov {memory}, {source}"));
+    }
+
+    /// Loads `bits` bits from `[base + displacement]` into `dest`, extending
+    /// them to 64 bits.
+    pub fn load_extended(
+        &mut self,
+        dest: Reg,
+        base: Reg,
+        displacement: i32,
+        bits: u32,
+        is_signed: bool,
+    ) {
+        let memory = Memory::sized(bits, base, displacement);
+        // Writing a 32-bit register clears the upper half.
+        let (w, opcode, mnemonic, dest_bits) = match (bits, is_signed) {
+            (8, true) => (true, &[0x0f, 0xbe][..], "movsx", 64),
+            (8, false) => (false, &[0x0f, 0xb6][..], "movzx", 32),
+            (16, true) => (true, &[0x0f, 0xbf][..], "movsx", 64),
+            (16, false) => (false, &[0x0f, 0xb7][..], "movzx", 32),
+            (32, true) => (true, &[0x63][..], "movsxd", 64),
+            (32, false) => (false, &[0x8b][..], "mov", 32),
+            _ => return self.load(dest, base, displacement),
+        };
+        self.memory_form(w, opcode, dest, base, displacement);
+        self.list(format_args!(
+            "{mnemonic} {}, {memory}",
+            dest.name(dest_bits)
+        ));
+    }
+
+    /// Stores the low `bits` bits of `source` to `[base + displacement]`.
+    pub fn store_truncated(
+        &mut self,
+        base: Reg,
+        displacement: i32,
+        source: Reg,
+        bits: u32,
+    ) {
+        let memory = Memory::sized(bits, base, displacement);
+        match bits {
+            8 => {
+                // Without a REX prefix, the byte registers of `rsp`, `rbp`,
+                // `rsi` and `rdi` would be `ah`, `ch`, `dh` and `bh`.
+                let needs_rex = source.is_extended() || base.is_extended();
+                if !needs_rex && source.low_bits() >= 4 {
+                    self.code.push(0x40);
+                }
+                self.memory_form(false, &[0x88], source, base, displacement);
+            }
+            16 => {
+                self.code.push(0x66);
+                self.memory_form(false, &[0x89], source, base, displacement);
+            }
+            32 => self.memory_form(false, &[0x89], source, base, displacement),
+            _ => return self.store(base, displacement, source),
+        }
+        self.list(format_args!("mov {memory}, {}", source.name(bits)));
+    }
+
+    /// `lea dest, [base + displacement]`
+    pub fn lea(&mut self, dest: Reg, base: Reg, displacement: i32) {
+        self.memory_form(true, &[0x8d], dest, base, displacement);
+        let memory = Memory::qword(base, displacement);
+        self.list(format_args!("lea {dest}, {memory}"));
+    }
+
+    /// `push qword [base + displacement]`, which needs no REX.W prefix.
+    pub fn push_memory(&mut self, base: Reg, displacement: i32) {
+        self.memory_form(false, &[0xff], Reg::Rsi, base, displacement);
+        let memory = Memory::qword(base, displacement);
+        self.list(format_args!("push {memory}"));
+    }
+
+    /// `pop qword [base + displacement]`
+    pub fn pop_memory(&mut self, base: Reg, displacement: i32) {
+        self.memory_form(false, &[0x8f], Reg::Rax, base, displacement);
+        let memory = Memory::qword(base, displacement);
+        self.list(format_args!("pop {memory}"));
+    }
+
+    /// `mov dest, source`
+    pub fn mov(&mut self, dest: Reg, source: Reg) {
+        self.register_form(true, &[0x89], source, dest);
+        self.list(format_args!("mov {dest}, {source}"));
+    }
+
+    /// Loads a constant with the shortest encoding.
+    pub fn mov_immediate(&mut self, dest: Reg, value: u64) {
+        if let Ok(value) = u32::try_from(value) {
+            // Writing a 32-bit register clears the upper half.
+            self.rex(false, Reg::Rax, dest);
+            self.code.push(0xb8 + dest.low_bits());
+            self.code.extend(value.to_le_bytes());
+            self.list(format_args!("mov {}, {value}", dest.name(32)));
+        } else if let Ok(value) = i32::try_from(value as i64) {
+            self.rex(true, Reg::Rax, dest);
+            self.code.extend([0xc7, 0b1100_0000 | dest.low_bits()]);
+            self.code.extend(value.to_le_bytes());
+            self.list(format_args!("mov {dest}, {value}"));
+        } else {
+            self.rex(true, Reg::Rax, dest);
+            self.code.push(0xb8 + dest.low_bits());
+            self.code.extend(value.to_le_bytes());
+            self.list(format_args!("movabs {dest}, {value:#x}"));
+        }
+    }
+
+    /// `op dest, source`
+    pub fn alu(&mut self, op: Alu, dest: Reg, source: Reg) {
+        self.register_form(true, &[op as u8], source, dest);
+        self.list(format_args!("{} {dest}, {source}", op.mnemonic()));
+    }
+
+    /// `and dest, imm8`
+    pub fn and_immediate(&mut self, dest: Reg, value: i8) {
+        self.register_form(true, &[0x83], Reg::Rsp, dest);
+        self.code.push(value as u8);
+        self.list(format_args!("and {dest}, {value}"));
+    }
+
+    /// `add rsp, value` or `sub rsp, value` for negative values.
+    pub fn adjust_stack(&mut self, value: i32) {
+        let (extension, mnemonic, value) = if value < 0 {
+            (Reg::Rbp, "sub", -value)
+        } else {
+            (Reg::Rax, "add", value)
+        };
+        self.register_form(true, &[0x81], extension, Reg::Rsp);
+        self.code.extend(value.to_le_bytes());
+        self.list(format_args!("{mnemonic} rsp, {value}"));
+    }
+
+    /// `imul dest, source`
+    pub fn imul(&mut self, dest: Reg, source: Reg) {
+        self.register_form(true, &[0x0f, 0xaf], dest, source);
+        self.list(format_args!("imul {dest}, {source}"));
+    }
+
+    /// Sign-extends `rax` into `rdx` for a signed division.
+    pub fn cqo(&mut self) {
+        self.code.extend([0x48, 0x99]);
+        self.list(format_args!("cqo"));
+    }
+
+    /// `idiv divisor`, dividing `rdx:rax` as signed numbers.
+    pub fn idiv(&mut self, divisor: Reg) {
+        self.register_form(true, &[0xf7], Reg::Rdi, divisor);
+        self.list(format_args!("idiv {divisor}"));
+    }
+
+    /// `div divisor`, dividing `rdx:rax` as unsigned numbers.
+    pub fn div(&mut self, divisor: Reg) {
+        self.register_form(true, &[0xf7], Reg::Rsi, divisor);
+        self.list(format_args!("div {divisor}"));
+    }
+
+    /// Shifts `dest` by `cl`.
+    pub fn shift(&mut self, shift: Shift, dest: Reg) {
+        let (extension, mnemonic) = match shift {
+            Shift::Shl => (Reg::Rsp, "shl"),
+            Shift::Shr => (Reg::Rbp, "shr"),
+            Shift::Sar => (Reg::Rdi, "sar"),
+        };
+        self.register_form(true, &[0xd3], extension, dest);
+        self.list(format_args!("{mnemonic} {dest}, cl"));
+    }
+
+    /// Sign-extends the low `bits` of `reg` to 64 bits.
+    pub fn sign_extend(&mut self, reg: Reg, bits: u32) {
+        match bits {
+            8 => {
+                // Without a REX prefix, the byte registers of `rsp`, `rbp`,
+                // `rsi` and `rdi` would be `ah`, `ch`, `dh` and `bh`.
+                self.register_form(true, &[0x0f, 0xbe], reg, reg);
+                self.list(format_args!("movsx {reg}, {}", reg.name(8)));
+            }
+            16 => {
+                self.register_form(true, &[0x0f, 0xbf], reg, reg);
+                self.list(format_args!("movsx {reg}, {}", reg.name(16)));
+            }
+            32 => {
+                self.register_form(true, &[0x63], reg, reg);
+                self.list(format_args!("movsxd {reg}, {}", reg.name(32)));
+            }
+            _ => {}
+        }
+    }
+
+    /// Zero-extends the low `bits` of `reg` to 64 bits.
+    pub fn zero_extend(&mut self, reg: Reg, bits: u32) {
+        let name = reg.name(32);
+        match bits {
+            8 => {
+                self.code.push(
+                    0x40 | u8::from(reg.is_extended()) << 2
+                        | u8::from(reg.is_extended()),
+                );
+                self.code.extend([0x0f, 0xb6]);
+                self.code
+                    .push(0b1100_0000 | reg.low_bits() << 3 | reg.low_bits());
+                self.list(format_args!("movzx {name}, {}", reg.name(8)));
+            }
+            16 => {
+                self.register_form(false, &[0x0f, 0xb7], reg, reg);
+                self.list(format_args!("movzx {name}, {}", reg.name(16)));
+            }
+            32 => {
+                self.register_form(false, &[0x89], reg, reg);
+                self.list(format_args!("mov {name}, {name}"));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        self.rex(false, Reg::Rax, reg);
+        self.code.push(0x50 + reg.low_bits());
+        self.list(format_args!("push {reg}"));
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.rex(false, Reg::Rax, reg);
+        self.code.push(0x58 + reg.low_bits());
+        self.list(format_args!("pop {reg}"));
+    }
+
+    /// A call to `symbol`, which is filled in by a relocation.
+    pub fn call(&mut self, symbol: String) {
+        self.code.push(0xe8);
+        self.list(format_args!("call {symbol}"));
+        self.calls.push((self.code.len(), symbol));
+        self.code.extend([0; 4]);
+    }
+
+    pub fn jmp(&mut self, target: Label) {
+        self.code.push(0xe9);
+        self.fixups.push((self.code.len(), target));
+        self.code.extend([0; 4]);
+        let name = self.label_name(target);
+        self.list(format_args!("jmp {name}"));
+    }
+
+    /// Jumps if the last comparison found its first operand below or equal
+    /// to its second one as unsigned numbers.
+    pub fn jbe(&mut self, target: Label) {
+        self.code.extend([0x0f, 0x86]);
+        self.fixups.push((self.code.len(), target));
+        self.code.extend([0; 4]);
+        let name = self.label_name(target);
+        self.list(format_args!("jbe {name}"));
+    }
+
+    pub fn leave(&mut self) {
+        self.code.push(0xc9);
+        self.list(format_args!("leave"));
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xc3);
+        self.list(format_args!("ret"));
+    }
+
+    pub fn syscall(&mut self) {
+        self.code.extend([0x0f, 0x05]);
+        self.list(format_args!("syscall"));
+    }
+}
+
+/// A memory operand like `qword ptr [base + displacement]` in assembly.
+struct Memory {
+    bits: u32,
+    base: Reg,
+    displacement: i32,
+}
+
+impl Memory {
+    fn qword(base: Reg, displacement: i32) -> Self {
+        Self::sized(64, base, displacement)
+    }
+
+    fn sized(bits: u32, base: Reg, displacement: i32) -> Self {
+        Self {
+            bits,
+            base,
+            displacement,
+        }
+    }
+}
+
+impl fmt::Display for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = match self.bits {
+            8 => "byte",
+            16 => "word",
+            32 => "dword",
+            _ => "qword",
+        };
+        let Self {
+            base, displacement, ..
+        } = *self;
+        if displacement < 0 {
+            write!(f, "{size} ptr [{base} - {}]", -displacement)
+        } else {
+            write!(f, "{size} ptr [{base} + {displacement}]")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assemble(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut assembler = Assembler::new("test");
+        f(&mut assembler);
+        assembler.finish().bytes
+    }
+
+    #[test]
+    fn encodes_memory_operands() {
+        // mov rax, [rbp - 8]
+        assert_eq!(
+            assemble(|a| a.load(Reg::Rax, Reg::Rbp, -8)),
+            [0x48, 0x8b, 0x85, 0xf8, 0xff, 0xff, 0xff]
+        );
+        // mov [rsp + 16], r9
+        assert_eq!(
+            assemble(|a| a.store(Reg::Rsp, 16, Reg::R9)),
+            [0x4c, 0x89, 0x8c, 0x24, 0x10, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn encodes_sized_memory_operands() {
+        // movsx rax, word ptr [rcx + 0]
+        assert_eq!(
+            assemble(|a| a.load_extended(Reg::Rax, Reg::Rcx, 0, 16, true)),
+            [0x48, 0x0f, 0xbf, 0x81, 0x00, 0x00, 0x00, 0x00]
+        );
+        // movzx eax, byte ptr [rcx + 0]
+        assert_eq!(
+            assemble(|a| a.load_extended(Reg::Rax, Reg::Rcx, 0, 8, false)),
+            [0x0f, 0xb6, 0x81, 0x00, 0x00, 0x00, 0x00]
+        );
+        // mov byte ptr [rcx + 0], sil
+        assert_eq!(
+            assemble(|a| a.store_truncated(Reg::Rcx, 0, Reg::Rsi, 8)),
+            [0x40, 0x88, 0xb1, 0x00, 0x00, 0x00, 0x00]
+        );
+        // mov word ptr [r8 + 0], ax
+        assert_eq!(
+            assemble(|a| a.store_truncated(Reg::R8, 0, Reg::Rax, 16)),
+            [0x66, 0x41, 0x89, 0x80, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn encodes_register_operands() {
+        // add rax, rcx
+        assert_eq!(
+            assemble(|a| a.alu(Alu::Add, Reg::Rax, Reg::Rcx)),
+            [0x48, 0x01, 0xc8]
+        );
+        // imul r9, rcx
+        assert_eq!(
+            assemble(|a| a.imul(Reg::R9, Reg::Rcx)),
+            [0x4c, 0x0f, 0xaf, 0xc9]
+        );
+        // movsx rsi, sil
+        assert_eq!(
+            assemble(|a| a.sign_extend(Reg::Rsi, 8)),
+            [0x48, 0x0f, 0xbe, 0xf6]
+        );
+        // movzx esi, sil
+        assert_eq!(
+            assemble(|a| a.zero_extend(Reg::Rsi, 8)),
+            [0x40, 0x0f, 0xb6, 0xf6]
+        );
+    }
+
+    #[test]
+    fn jumps_are_relative_to_the_next_instruction() {
+        let code = assemble(|a| {
+            let label = a.new_label();
+            a.bind(label);
+            a.ret();
+            a.jmp(label);
+        });
+        assert_eq!(code, [0xc3, 0xe9, 0xfa, 0xff, 0xff, 0xff]);
+    }
+}
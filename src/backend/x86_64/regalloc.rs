@@ -0,0 +1,285 @@
+//! Linear-scan register allocation, as described in "Linear Scan Register
+//! Allocation" by Poletto and Sarkar. Blocks are laid out in order and each
+//! value gets a single interval from its first to its last position in that
+//! layout, including every block where it is live. Values whose intervals
+//! overlap get different registers, and when there are too few registers, the
+//! value that lives longest is spilled to the stack for its whole lifetime.
+//!
+//! Calls and system calls clobber the caller-saved registers, so values that
+//! live across them only get callee-saved registers, which the function saves
+//! and restores itself. `rax`, `rcx` and `rdx` are never allocated because
+//! instructions use them as scratch registers.
+
+use super::asm::Reg;
+use crate::ir::{cfg::Liveness, Function, Instruction, Value};
+
+/// Registers that calls preserve, in order of preference.
+const CALLEE_SAVED: [Reg; 5] =
+    [Reg::Rbx, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+/// All registers that values can be allocated to, in order of preference.
+/// The caller-saved ones come first because using them costs nothing until
+/// there is a call.
+const ALLOCATABLE: [Reg; 11] = [
+    Reg::Rsi,
+    Reg::Rdi,
+    Reg::R8,
+    Reg::R9,
+    Reg::R10,
+    Reg::R11,
+    Reg::Rbx,
+    Reg::R12,
+    Reg::R13,
+    Reg::R14,
+    Reg::R15,
+];
+
+/// Where a value lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Reg(Reg),
+    /// The index of a stack slot.
+    Spilled(usize),
+}
+
+#[derive(Debug)]
+pub struct Allocation {
+    /// Indexed by `Value`. Values that don't appear in the function have no
+    /// location.
+    pub locations: Vec<Option<Location>>,
+    /// The callee-saved registers that the function uses.
+    pub callee_saved: Vec<Reg>,
+    pub spill_slots: usize,
+}
+
+impl Allocation {
+    pub fn location(&self, value: Value) -> Location {
+        self.locations[value.0].expect("value appears in the function")
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Interval {
+    value: Value,
+    start: usize,
+    end: usize,
+    /// Whether a call happens while the value is live.
+    crosses_call: bool,
+}
+
+pub fn allocate(function: &Function) -> Allocation {
+    let mut intervals = intervals(function);
+    intervals.sort_by_key(|interval| (interval.start, interval.value));
+
+    let mut allocation = Allocation {
+        locations: vec![None; function.values.len()],
+        callee_saved: Vec::new(),
+        spill_slots: 0,
+    };
+    let mut active = Vec::<(Interval, Reg)>::new();
+    for interval in intervals {
+        // A value that is last used where another one is defined can share
+        // its register, since instructions read all their operands before
+        // they write their result. Values that are defined together, like
+        // the parameters of a block, can't.
+        active.retain(|(other, _)| {
+            other.end > interval.start
+                || other.end == interval.start && other.start == interval.start
+        });
+
+        let candidates = if interval.crosses_call {
+            &CALLEE_SAVED[..]
+        } else {
+            &ALLOCATABLE[..]
+        };
+        let free = candidates
+            .iter()
+            .find(|reg| active.iter().all(|(_, other)| other != *reg));
+        let location = match free {
+            Some(&reg) => {
+                active.push((interval, reg));
+                Location::Reg(reg)
+            }
+            None => {
+                // Spill whichever value lives longer, this one or one whose
+                // register it could take.
+                let longest = active
+                    .iter_mut()
+                    .filter(|(_, reg)| candidates.contains(reg))
+                    .max_by_key(|(other, _)| other.end)
+                    .filter(|(other, _)| other.end > interval.end);
+                let spilled = match longest {
+                    Some((other, reg)) => {
+                        let reg = *reg;
+                        let spilled = std::mem::replace(other, interval);
+                        allocation.locations[interval.value.0] =
+                            Some(Location::Reg(reg));
+                        spilled.value
+                    }
+                    None => interval.value,
+                };
+                allocation.locations[spilled.0] =
+                    Some(Location::Spilled(allocation.spill_slots));
+                allocation.spill_slots += 1;
+                continue;
+            }
+        };
+        allocation.locations[interval.value.0] = Some(location);
+    }
+
+    for reg in CALLEE_SAVED {
+        if allocation.locations.contains(&Some(Location::Reg(reg))) {
+            allocation.callee_saved.push(reg);
+        }
+    }
+    allocation
+}
+
+/// Numbers the positions in the function, with one for the start of each
+/// block, one for each instruction and one for each terminator, and finds the
+/// positions where each value is live.
+fn intervals(function: &Function) -> Vec<Interval> {
+    let liveness = Liveness::new(function);
+    let mut ranges = vec![None; function.values.len()];
+    let mut extend = |value: Value, position: usize| {
+        let range = ranges[value.0].get_or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+    let mut calls = Vec::new();
+    let mut position = 0;
+
+    for &parameter in &function.parameters {
+        extend(parameter, 0);
+    }
+    for id in function.block_ids() {
+        let block = function.block(id);
+        for &value in block.parameters.iter().chain(&liveness.live_in[id.0]) {
+            extend(value, position);
+        }
+        for instruction in &block.instructions {
+            position += 1;
+            for value in
+                instruction.operands().into_iter().chain(instruction.dest())
+            {
+                extend(value, position);
+            }
+            if matches!(
+                instruction,
+                Instruction::Call { .. } | Instruction::Syscall { .. }
+            ) {
+                calls.push(position);
+            }
+        }
+        position += 1;
+        for value in block
+            .terminator
+            .operands()
+            .into_iter()
+            .chain(liveness.live_out[id.0].iter().copied())
+        {
+            extend(value, position);
+        }
+        position += 1;
+    }
+
+    ranges
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, range)| {
+            let (start, end) = range?;
+            Some(Interval {
+                value: Value(i),
+                start,
+                end,
+                crosses_call: calls
+                    .iter()
+                    .any(|&call| start < call && call < end),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ir::{Block, Terminator},
+        typ::Type,
+    };
+
+    #[test]
+    fn values_live_across_calls_get_callee_saved_registers() {
+        let mut function = Function::new("f", Type::I64, &[]);
+        let [kept, temporary, result, sum] =
+            [(); 4].map(|()| function.new_value(Type::I64, None));
+        function.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::Const {
+                    dest: kept,
+                    value: 1,
+                },
+                Instruction::Const {
+                    dest: temporary,
+                    value: 2,
+                },
+                Instruction::Call {
+                    dest: Some(result),
+                    callee: "g".into(),
+                    arguments: vec![temporary],
+                },
+                Instruction::Binary {
+                    dest: sum,
+                    operator: crate::ast::BinaryOperator::Add,
+                    left: kept,
+                    right: result,
+                },
+            ],
+            terminator: Terminator::Return(Some(sum)),
+        });
+
+        let allocation = allocate(&function);
+        assert_eq!(allocation.location(kept), Location::Reg(Reg::Rbx));
+        assert_eq!(allocation.location(temporary), Location::Reg(Reg::Rsi));
+        assert_eq!(allocation.callee_saved, [Reg::Rbx]);
+        assert_eq!(allocation.spill_slots, 0);
+    }
+
+    #[test]
+    fn the_longest_lived_values_are_spilled() {
+        let mut function = Function::new("f", Type::I64, &[]);
+        let constants = ALLOCATABLE
+            .iter()
+            .map(|_| function.new_value(Type::I64, None))
+            .collect::<Vec<_>>();
+        let extra = function.new_value(Type::I64, None);
+        let mut instructions = constants
+            .iter()
+            .chain([&extra])
+            .map(|&dest| Instruction::Const { dest, value: 1 })
+            .collect::<Vec<_>>();
+        // The first constant is used last, so it is the one to spill.
+        let mut total = extra;
+        for &constant in constants.iter().rev() {
+            let dest = function.new_value(Type::I64, None);
+            instructions.push(Instruction::Binary {
+                dest,
+                operator: crate::ast::BinaryOperator::Add,
+                left: total,
+                right: constant,
+            });
+            total = dest;
+        }
+        function.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions,
+            terminator: Terminator::Return(Some(total)),
+        });
+
+        let allocation = allocate(&function);
+        assert_eq!(allocation.location(constants[0]), Location::Spilled(0));
+        assert_eq!(allocation.spill_slots, 1);
+        assert!(matches!(allocation.location(extra), Location::Reg(_)));
+    }
+}
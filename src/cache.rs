@@ -0,0 +1,319 @@
+//! A persistent cache for `gneiss compile`. Results are stored per function,
+//! keyed by a hash of everything they were computed from: the function's own
+//! source code and the signatures of the functions it calls. Editing a
+//! function therefore only invalidates its own entries, and those of its
+//! callers if its signature changed. Once the cache grows past `MAX_SIZE`,
+//! the entries that were used least recently are removed.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    cell::Cell,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Bumped whenever the format or meaning of cached values changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// The number of bytes that entries may take up in total.
+const MAX_SIZE: u64 = 64 << 20;
+
+/// A 128-bit FNV-1a hash. Unlike `std::hash::DefaultHasher`, it is guaranteed
+/// to stay the same across Rust versions, which matters for keys that are
+/// persisted.
+#[derive(Clone, Copy)]
+pub struct Key(u128);
+
+impl Key {
+    const OFFSET_BASIS: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    const PRIME: u128 = 0x0000_0000_0100_0000_0000_0000_0000_013b;
+
+    pub fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+            .bytes(env!("CARGO_PKG_VERSION").as_bytes())
+            .bytes(&FORMAT_VERSION.to_le_bytes())
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Self {
+        for &byte in bytes {
+            self.0 ^= u128::from(byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+        self
+    }
+
+    /// Adds a string to the key. The length is included so that different
+    /// ways of splitting the same text produce different keys.
+    pub fn str(self, s: &str) -> Self {
+        self.bytes(&s.len().to_le_bytes()).bytes(s.as_bytes())
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+pub struct Cache {
+    dir: PathBuf,
+    verbose: bool,
+    max_size: u64,
+    hits: Cell<usize>,
+    misses: Cell<usize>,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf, verbose: bool) -> Self {
+        Self {
+            dir,
+            verbose,
+            max_size: MAX_SIZE,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    /// Returns the value stored under `kind` and `key`, or computes and stores
+    /// it if there is none. `description` names the value in verbose output.
+    pub fn get_or_insert_with<T: Serialize + DeserializeOwned>(
+        &self,
+        kind: &str,
+        key: Key,
+        description: impl fmt::Display,
+        compute: impl FnOnce() -> T,
+    ) -> T {
+        let path = self.dir.join(kind).join(key.to_string());
+
+        if let Some(value) = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        {
+            // The modification time records when the entry was last used.
+            if let Err(err) = fs::File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_modified(SystemTime::now()))
+            {
+                eprintln!(
+                    "warning: failed to touch cache entry {}: {err}",
+                    path.display()
+                );
+            }
+            self.hits.set(self.hits.get() + 1);
+            if self.verbose {
+                eprintln!("cache hit: {description}");
+            }
+            return value;
+        }
+
+        self.misses.set(self.misses.get() + 1);
+        if self.verbose {
+            eprintln!("cache miss: {description}");
+        }
+        let value = compute();
+        if let Err(err) = write_atomically(&path, &value) {
+            eprintln!(
+                "warning: failed to write cache entry {}: {err}",
+                path.display()
+            );
+        }
+        value
+    }
+
+    /// Prints how many values were reused if verbose output is enabled, and
+    /// removes the least recently used entries if the cache is too large.
+    pub fn finish(&self) {
+        if self.verbose {
+            let hits = self.hits.get();
+            eprintln!(
+                "cache: reused {hits} of {} results from {}",
+                hits + self.misses.get(),
+                self.dir.display()
+            );
+        }
+        if let Err(err) = self.evict() {
+            eprintln!(
+                "warning: failed to clean up cache {}: {err}",
+                self.dir.display()
+            );
+        }
+    }
+
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        let Ok(kinds) = fs::read_dir(&self.dir) else {
+            // Nothing has been cached yet.
+            return Ok(());
+        };
+        for kind in kinds {
+            for entry in fs::read_dir(kind?.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                entries.push((metadata.modified()?, metadata.len(), entry));
+            }
+        }
+        let mut size: u64 = entries.iter().map(|&(_, len, _)| len).sum();
+        entries.sort_by_key(|&(modified, ..)| modified);
+        for (_, len, entry) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(entry.path())?;
+            size -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Serializes an `Intern<str>`, which `internment` only supports for sized
+/// types, as a string.
+pub mod interned {
+    use internment::Intern;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        s: &Intern<str>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Intern<str>, D::Error> {
+        String::deserialize(deserializer).map(|s| (&*s).into())
+    }
+}
+
+/// Like `interned`, for an `Option<Intern<str>>`.
+pub mod optional_interned {
+    use internment::Intern;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        s: &Option<Intern<str>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.as_deref().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Intern<str>>, D::Error> {
+        Option::<String>::deserialize(deserializer)
+            .map(|s| s.map(|s| (&*s).into()))
+    }
+}
+
+/// Writes to a temporary file first so that an interrupted compilation can't
+/// leave a truncated entry behind.
+fn write_atomically(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    let dir = path.parent().expect("cache entries are inside a directory");
+    fs::create_dir_all(dir)?;
+    let temp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&temp_path, serde_json::to_vec(value)?)?;
+    fs::rename(temp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_reused_until_the_key_changes() {
+        let dir = std::env::temp_dir()
+            .join(format!("gneiss-cache-test-{}", std::process::id()));
+        let cache = Cache::new(dir.clone(), false);
+        let key = Key::new().str("fn f() -> unit {}");
+
+        let value = cache.get_or_insert_with("test", key, "f", || vec![1, 2]);
+        assert_eq!(value, [1, 2]);
+        let value = cache.get_or_insert_with("test", key, "f", || vec![3]);
+        assert_eq!(value, [1, 2]);
+        assert_eq!((cache.hits.get(), cache.misses.get()), (1, 1));
+
+        let key = key.str("fn g() -> unit {}");
+        let value = cache.get_or_insert_with("test", key, "f", || vec![3]);
+        assert_eq!(value, [3]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let dir = std::env::temp_dir()
+            .join(format!("gneiss-eviction-test-{}", std::process::id()));
+        let mut cache = Cache::new(dir.clone(), false);
+        let [a, b, c] = ["a", "b", "c"].map(|s| Key::new().str(s));
+        cache.get_or_insert_with("test", a, "a", || 1);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cache.get_or_insert_with("test", b, "b", || 2);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cache.get_or_insert_with("test", a, "a", || 3);
+
+        // Each entry takes up one byte.
+        cache.max_size = 1;
+        cache.finish();
+        assert_eq!(cache.get_or_insert_with("test", a, "a", || 3), 1);
+        assert_eq!(cache.get_or_insert_with("test", b, "b", || 4), 4);
+        assert_eq!(cache.get_or_insert_with("test", c, "c", || 5), 5);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cached_functions_keep_their_names() {
+        use crate::{
+            ir::{Block, Function, Instruction, Terminator},
+            typ::Type,
+        };
+
+        let mut function = Function::new("f", Type::Named("Point".into()), &[]);
+        let point = function.new_value(Type::Named("Point".into()), None);
+        let pointer = function
+            .new_value(Type::pointer(Type::Named("Point".into()), true), None);
+        let cast =
+            function.new_value(Type::Named("Point".into()), Some("p".into()));
+        function.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: vec![
+                Instruction::Call {
+                    dest: Some(point),
+                    callee: "g".into(),
+                    arguments: Vec::new(),
+                },
+                Instruction::Cast {
+                    dest: pointer,
+                    source: point,
+                },
+                Instruction::Cast {
+                    dest: cast,
+                    source: pointer,
+                },
+            ],
+            terminator: Terminator::Return(Some(cast)),
+        });
+
+        let dir = std::env::temp_dir()
+            .join(format!("gneiss-function-test-{}", std::process::id()));
+        let cache = Cache::new(dir.clone(), false);
+        let key = Key::new().str("fn f() -> Point { g() as *Point as Point }");
+        cache.get_or_insert_with("test", key, "f", || function.clone());
+        let copy = cache.get_or_insert_with("test", key, "f", || {
+            Function::new("other", Type::Unit, &[])
+        });
+        assert_eq!(cache.hits.get(), 1);
+        assert_eq!(copy, function);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keys_depend_on_how_strings_are_split() {
+        let ab_c = Key::new().str("ab").str("c");
+        let a_bc = Key::new().str("a").str("bc");
+        assert_ne!(ab_c.to_string(), a_bc.to_string());
+    }
+}
@@ -0,0 +1,1293 @@
+//! Checks function bodies against the signatures of the functions they call
+//! and the types they use, and struct and enum definitions against each other.
+//! `match` expressions have to handle every value exactly once: an arm whose
+//! pattern only matches values that earlier arms already handle is an error,
+//! and so are values that no arm handles.
+
+use crate::{
+    ast::{
+        BinaryOperator, Block, Enum, Expr, Function, FunctionParameters,
+        FunctionSignature, Pattern, Span, Statement, Struct, SyntaxError,
+        Types, Variant,
+    },
+    intrinsics::Intrinsic,
+    typ::Type,
+};
+use internment::Intern;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Error {
+    pub message: String,
+    /// Relative to the start of the function being checked.
+    pub span: Span,
+}
+
+/// Checks the fields of a struct definition.
+pub fn check_struct(definition: &Struct, types: &Types) -> Vec<Error> {
+    let mut errors = Vec::new();
+    let Ok(fields) = &definition.fields else {
+        return errors;
+    };
+    for (i, field) in fields.iter().enumerate() {
+        if fields[..i].iter().any(|other| other.name == field.name) {
+            errors.push(Error {
+                message: format!("field `{}` is declared twice", field.name),
+                span: field.span,
+            });
+        }
+        if let Some(name) = unknown_type(field.typ, types) {
+            errors.push(Error {
+                message: format!("unknown type `{name}`"),
+                span: field.span,
+            });
+        }
+    }
+    let types_of_fields = fields.iter().map(|field| field.typ);
+    errors.extend(check_size(
+        definition.name,
+        definition.name_span,
+        types_of_fields,
+        types,
+    ));
+    errors
+}
+
+/// Checks the variants of an enum definition.
+pub fn check_enum(definition: &Enum, types: &Types) -> Vec<Error> {
+    let mut errors = Vec::new();
+    let Ok(variants) = &definition.variants else {
+        return errors;
+    };
+    for (i, variant) in variants.iter().enumerate() {
+        if variants[..i].iter().any(|other| other.name == variant.name) {
+            errors.push(Error {
+                message: format!(
+                    "variant `{}` is declared twice",
+                    variant.name
+                ),
+                span: variant.span,
+            });
+        }
+        for &typ in &variant.payload {
+            if let Some(name) = unknown_type(typ, types) {
+                errors.push(Error {
+                    message: format!("unknown type `{name}`"),
+                    span: variant.span,
+                });
+            }
+        }
+    }
+    let payloads = variants.iter().flat_map(|variant| &variant.payload);
+    errors.extend(check_size(
+        definition.name,
+        definition.name_span,
+        payloads.copied(),
+        types,
+    ));
+    errors
+}
+
+/// Reports a struct or enum named `name` that contains itself in one of the
+/// types `members`.
+fn check_size(
+    name: Result<Intern<str>, SyntaxError>,
+    span: Result<Span, SyntaxError>,
+    mut members: impl Iterator<Item = Type>,
+    types: &Types,
+) -> Option<Error> {
+    let (Ok(name), Ok(span)) = (name, span) else {
+        return None;
+    };
+    let mut visited = Vec::new();
+    members
+        .any(|typ| contains(typ, name, types, &mut visited))
+        .then(|| Error {
+            message: format!(
+                "`{name}` contains itself, so it would be infinitely large"
+            ),
+            span,
+        })
+}
+
+/// The name of a struct or enum that `typ` refers to but that doesn't exist,
+/// if any.
+fn unknown_type(typ: Type, types: &Types) -> Option<Intern<str>> {
+    match typ {
+        Type::Pointer { pointee, .. } => unknown_type(*pointee, types),
+        Type::Named(name) if !types.contains(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether a value of type `typ` contains a value of the type `name` in its
+/// fields or payloads, or in theirs and so on, without going through
+/// pointers. `visited` holds the types that have already been searched.
+fn contains(
+    typ: Type,
+    name: Intern<str>,
+    types: &Types,
+    visited: &mut Vec<Intern<str>>,
+) -> bool {
+    let Type::Named(inner) = typ else {
+        return false;
+    };
+    if inner == name {
+        return true;
+    }
+    if visited.contains(&inner) {
+        return false;
+    }
+    visited.push(inner);
+    let members = if let Some(definition) = types.structs.get(&inner) {
+        let fields = definition.fields.iter().flatten();
+        fields.map(|field| field.typ).collect()
+    } else if let Some(definition) = types.enums.get(&inner) {
+        let variants = definition.variants.iter().flatten();
+        variants
+            .flat_map(|variant| variant.payload.clone())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    members
+        .into_iter()
+        .any(|typ| contains(typ, name, types, visited))
+}
+
+/// Checks the body of `function`, looking up the functions it calls using
+/// `signature_of`.
+pub fn check_function(
+    function: &Function,
+    types: &Types,
+    signature_of: impl FnMut(Intern<str>) -> Option<Arc<FunctionSignature>>,
+) -> Vec<Error> {
+    let mut checker = Checker {
+        signature_of,
+        types,
+        scopes: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    // The keyword that makes the function visible to C, if any.
+    let linkage = if function.is_extern {
+        Some("extern")
+    } else if function.is_export {
+        Some("export")
+    } else {
+        None
+    };
+    if let (Ok(name), Ok(span)) =
+        (&function.signature.name, &function.name_span)
+    {
+        if Intrinsic::from_name(name).is_some() {
+            checker.error(
+                *span,
+                format!(
+                    "`{name}` is a built-in function and can't be redefined"
+                ),
+            );
+        }
+        if linkage.is_some() && !is_c_identifier(name) {
+            checker.error(*span, format!("`{name}` isn't a valid name in C"));
+        }
+        // The C entry point that calls the Gneiss one already has the name.
+        if function.is_export && &**name == "main" {
+            checker.error(*span, "`main` can't be `export`".to_owned());
+        }
+        if let Ok(return_type) = function.signature.return_type {
+            checker.check_type(return_type, *span);
+            if let Type::Named(_) = return_type {
+                if let Some(linkage) = linkage {
+                    checker.error(
+                        *span,
+                        format!(
+                            "`{linkage}` functions can't return structs or \
+                             enums"
+                        ),
+                    );
+                }
+                // Its result becomes the exit status.
+                if &**name == "main" {
+                    checker.error(
+                        *span,
+                        "`main` can't return a struct or enum".to_owned(),
+                    );
+                }
+            }
+        }
+        match (function.is_extern, &function.body) {
+            (true, Some(_)) => checker.error(
+                *span,
+                "`extern` functions can't have a body".to_owned(),
+            ),
+            (false, None) => checker.error(
+                *span,
+                format!("`{name}` needs a body unless it is `extern`"),
+            ),
+            _ => {}
+        }
+    }
+
+    let mut parameters = HashMap::new();
+    if let Ok(FunctionParameters(parameter_list)) =
+        &function.signature.parameters
+    {
+        for (pattern, typ) in parameter_list {
+            let Expr::Identifier { name, span } = pattern else {
+                continue;
+            };
+            checker.check_type(*typ, *span);
+            match (linkage, typ) {
+                (Some(linkage), Type::Unit) => checker.error(
+                    *span,
+                    format!(
+                        "`{linkage}` functions can't take `unit` parameters"
+                    ),
+                ),
+                (Some(linkage), Type::Named(_)) => checker.error(
+                    *span,
+                    format!(
+                        "`{linkage}` functions can't take struct or enum \
+                         parameters"
+                    ),
+                ),
+                _ => {}
+            }
+            parameters.insert(*name, Some(*typ));
+        }
+    }
+    checker.scopes.push(parameters);
+
+    let Some(Ok(body)) = &function.body else {
+        return checker.errors;
+    };
+
+    let body_type = checker.check_block(body);
+    if let (Some(found), Ok(expected), Ok(span)) = (
+        body_type,
+        &function.signature.return_type,
+        &function.name_span,
+    ) {
+        if found != *expected {
+            checker.error(
+                *span,
+                format!(
+                    "function returns `{expected}` but its body evaluates to \
+                     `{found}`"
+                ),
+            );
+        }
+    }
+
+    checker.errors
+}
+
+/// Whether C code can refer to a function by this name.
+fn is_c_identifier(name: &str) -> bool {
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+}
+
+struct Checker<'a, F> {
+    signature_of: F,
+    types: &'a Types,
+    /// Types of variables bound by `let`, or `None` if the type couldn't be
+    /// determined because of an earlier error.
+    scopes: Vec<HashMap<Intern<str>, Option<Type>>>,
+    errors: Vec<Error>,
+}
+
+impl<'a, F: FnMut(Intern<str>) -> Option<Arc<FunctionSignature>>>
+    Checker<'a, F>
+{
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(Error { message, span });
+    }
+
+    fn check_type(&mut self, typ: Type, span: Span) {
+        if let Some(name) = unknown_type(typ, self.types) {
+            self.error(span, format!("unknown type `{name}`"));
+        }
+    }
+
+    fn check_block(&mut self, block: &Block) -> Option<Type> {
+        self.scopes.push(HashMap::new());
+        for statement in block.statements.iter().flatten() {
+            match statement {
+                Statement::Expr(expr) => {
+                    if let Ok(expr) = expr {
+                        self.check_expr(expr);
+                    }
+                }
+                Statement::Let { pattern, value } => {
+                    let typ = value
+                        .as_ref()
+                        .ok()
+                        .and_then(|value| self.check_expr(value));
+                    if let Ok(Expr::Identifier { name, .. }) = pattern {
+                        self.scopes
+                            .last_mut()
+                            .expect("scope was just pushed")
+                            .insert(*name, typ);
+                    }
+                }
+                Statement::Assign {
+                    target,
+                    value,
+                    span,
+                } => {
+                    let Ok(target) = target else {
+                        continue;
+                    };
+                    let pointee = match target.place_root() {
+                        Some(Expr::Dereference { .. }) => {
+                            self.check_place(target, true, *span)
+                        }
+                        Some(_) => {
+                            self.check_expr(target);
+                            self.error(
+                                *span,
+                                "can only assign through pointers".to_owned(),
+                            );
+                            None
+                        }
+                        None => None,
+                    };
+                    let value = value
+                        .as_ref()
+                        .ok()
+                        .and_then(|value| self.check_expr(value));
+                    if let (Some(expected), Some(found)) = (pointee, value) {
+                        if expected != found {
+                            self.error(
+                                *span,
+                                format!(
+                                    "mismatched types `{expected}` and \
+                                     `{found}` for `=`"
+                                ),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        let typ = match &block.result {
+            None => Some(Type::Unit),
+            Some(Ok(result)) => self.check_expr(result),
+            Some(Err(_)) => None,
+        };
+        self.scopes.pop();
+        typ
+    }
+
+    /// Returns the type of `expr`, or `None` if it can't be determined because
+    /// of an error.
+    fn check_expr(&mut self, expr: &Expr) -> Option<Type> {
+        match expr {
+            Expr::Block(block) => self.check_block(block),
+            Expr::Identifier { name, span } => {
+                let typ = self
+                    .scopes
+                    .iter()
+                    .rev()
+                    .find_map(|scope| scope.get(name).copied());
+                if typ.is_none() {
+                    self.error(*span, format!("unknown variable `{name}`"));
+                }
+                typ.flatten()
+            }
+            Expr::FunctionCall {
+                name,
+                arguments,
+                span,
+            } => {
+                let argument_types = arguments
+                    .iter()
+                    .flat_map(|arguments| &arguments.0)
+                    .map(|argument| {
+                        argument
+                            .as_ref()
+                            .ok()
+                            .and_then(|argument| self.check_expr(argument))
+                    })
+                    .collect::<Vec<_>>();
+
+                let name = *name.as_ref().ok()?;
+                let Some(signature) = (self.signature_of)(name) else {
+                    self.error(*span, format!("unknown function `{name}`"));
+                    return None;
+                };
+
+                if let (Ok(parameters), Ok(_)) =
+                    (&signature.parameters, arguments)
+                {
+                    if parameters.0.len() == argument_types.len() {
+                        for ((_, expected), found) in
+                            parameters.0.iter().zip(argument_types)
+                        {
+                            if let Some(found) =
+                                found.filter(|found| found != expected)
+                            {
+                                self.error(
+                                    *span,
+                                    format!(
+                                        "expected argument of type \
+                                         `{expected}`, found `{found}`"
+                                    ),
+                                );
+                            }
+                        }
+                    } else {
+                        self.error(
+                            *span,
+                            format!(
+                                "`{name}` takes {} arguments but {} were given",
+                                parameters.0.len(),
+                                argument_types.len(),
+                            ),
+                        );
+                    }
+                }
+
+                signature.return_type.ok()
+            }
+            Expr::IntLiteral(literal) => Some(literal.typ()),
+            Expr::Binary {
+                operator,
+                left,
+                right,
+                span,
+            } => {
+                let left = self.check_operand(left);
+                let right = self.check_operand(right);
+                let is_offset = matches!(
+                    operator,
+                    BinaryOperator::Add | BinaryOperator::Sub
+                );
+                let operator = operator.symbol();
+                match (left, right) {
+                    (Some(typ @ (Type::Unit | Type::Named(_))), _)
+                    | (_, Some(typ @ (Type::Unit | Type::Named(_)))) => {
+                        self.error(
+                            *span,
+                            format!("`{operator}` can't be applied to `{typ}`"),
+                        );
+                        None
+                    }
+                    // Pointer arithmetic counts in elements, not bytes.
+                    (Some(pointer @ Type::Pointer { .. }), Some(offset))
+                        if is_offset && offset.is_integer() =>
+                    {
+                        Some(pointer)
+                    }
+                    (Some(left @ Type::Pointer { .. }), Some(right))
+                    | (Some(left), Some(right @ Type::Pointer { .. })) => {
+                        self.error(
+                            *span,
+                            format!(
+                                "`{operator}` can't be applied to `{left}` and \
+                                 `{right}`"
+                            ),
+                        );
+                        None
+                    }
+                    (Some(left), Some(right)) if left != right => {
+                        self.error(
+                            *span,
+                            format!(
+                                "mismatched types `{left}` and `{right}` for \
+                                 `{operator}`"
+                            ),
+                        );
+                        None
+                    }
+                    _ => left.or(right),
+                }
+            }
+            Expr::AddressOf {
+                is_mutable,
+                value,
+                span,
+            } => {
+                let value = value.as_ref().ok()?;
+                match value.place_root()? {
+                    Expr::Identifier { .. } | Expr::Dereference { .. } => self
+                        .check_place(value, *is_mutable, *span)
+                        .map(|typ| Type::pointer(typ, *is_mutable)),
+                    _ => {
+                        self.check_expr(value);
+                        self.error(
+                            *span,
+                            "can't take the address of a temporary".to_owned(),
+                        );
+                        None
+                    }
+                }
+            }
+            Expr::Dereference { pointer, span } => {
+                let pointer = self.check_operand(pointer);
+                self.dereference(pointer, *span)
+            }
+            Expr::Cast { value, typ, span } => {
+                let from = self.check_operand(value);
+                let to = *typ.as_ref().ok()?;
+                self.check_type(to, *span);
+                let is_number = |typ: Type| {
+                    typ.is_integer() || matches!(typ, Type::Pointer { .. })
+                };
+                if let Some(from) = from {
+                    if from != to && !(is_number(from) && is_number(to)) {
+                        self.error(
+                            *span,
+                            format!("can't cast `{from}` to `{to}`"),
+                        );
+                    }
+                }
+                Some(to)
+            }
+            Expr::StructLiteral { name, fields, span } => {
+                let fields = fields
+                    .iter()
+                    .flatten()
+                    .map(|field| {
+                        let typ = field
+                            .value
+                            .as_ref()
+                            .ok()
+                            .and_then(|value| self.check_expr(value));
+                        (field, typ)
+                    })
+                    .collect::<Vec<_>>();
+                let name = *name.as_ref().ok()?;
+                let types = self.types;
+                let Some(definition) = types.structs.get(&name) else {
+                    self.error(*span, format!("unknown struct `{name}`"));
+                    return None;
+                };
+                let mut initialized = Vec::new();
+                for (field, found) in fields {
+                    let Some((_, declared)) = definition.field(field.name)
+                    else {
+                        self.error(
+                            field.span,
+                            format!("`{name}` has no field `{}`", field.name),
+                        );
+                        continue;
+                    };
+                    if initialized.contains(&field.name) {
+                        self.error(
+                            field.span,
+                            format!(
+                                "field `{}` is initialized twice",
+                                field.name
+                            ),
+                        );
+                    }
+                    initialized.push(field.name);
+                    if let Some(found) =
+                        found.filter(|found| *found != declared.typ)
+                    {
+                        self.error(
+                            field.span,
+                            format!(
+                                "expected field `{}` of type `{}`, found \
+                                 `{found}`",
+                                field.name, declared.typ
+                            ),
+                        );
+                    }
+                }
+                for declared in definition.fields.iter().flatten() {
+                    if !initialized.contains(&declared.name) {
+                        self.error(
+                            *span,
+                            format!(
+                                "missing field `{}` in `{name}`",
+                                declared.name
+                            ),
+                        );
+                    }
+                }
+                Some(Type::Named(name))
+            }
+            Expr::Field { value, field, span } => {
+                let typ = self.check_operand(value)?;
+                self.field_type(typ, field, *span)
+            }
+            Expr::Variant {
+                name,
+                variant,
+                payload,
+                span,
+            } => {
+                let found = payload
+                    .0
+                    .iter()
+                    .map(|value| {
+                        value
+                            .as_ref()
+                            .ok()
+                            .and_then(|value| self.check_expr(value))
+                    })
+                    .collect::<Vec<_>>();
+                let name = *name.as_ref().ok()?;
+                let variant = *variant.as_ref().ok()?;
+                let (_, declared) = self.variant(name, variant, *span)?;
+                if declared.payload.len() == found.len() {
+                    for (expected, found) in declared.payload.iter().zip(found)
+                    {
+                        if let Some(found) =
+                            found.filter(|found| found != expected)
+                        {
+                            self.error(
+                                *span,
+                                format!(
+                                    "expected value of type `{expected}`, \
+                                     found `{found}`"
+                                ),
+                            );
+                        }
+                    }
+                } else {
+                    self.error(
+                        *span,
+                        format!(
+                            "`{name}::{variant}` takes {} values but {} were \
+                             given",
+                            declared.payload.len(),
+                            found.len(),
+                        ),
+                    );
+                }
+                Some(Type::Named(name))
+            }
+            Expr::Match { value, arms, span } => {
+                let scrutinee = self.check_operand(value);
+                let arms = arms.as_ref().ok()?;
+                if arms.is_empty() {
+                    self.error(
+                        *span,
+                        "`match` needs at least one arm".to_owned(),
+                    );
+                    return None;
+                }
+                // The cases are the values of integers and the indices of
+                // variants, and are only tracked while all patterns are
+                // valid.
+                let mut covered = scrutinee.map(|_| Cases::default());
+                let mut result = None;
+                let mut is_mismatched = false;
+                for arm in arms {
+                    let mut bindings = HashMap::new();
+                    let cases = arm.pattern.as_ref().ok().and_then(|pattern| {
+                        self.check_pattern(
+                            pattern,
+                            scrutinee,
+                            arm.pattern_span,
+                            &mut bindings,
+                        )
+                    });
+                    match (&mut covered, cases) {
+                        (Some(covered), Some((start, end))) => {
+                            if covered.contains(start, end) {
+                                self.error(
+                                    arm.pattern_span,
+                                    "unreachable pattern".to_owned(),
+                                );
+                            }
+                            covered.insert(start, end);
+                        }
+                        _ => covered = None,
+                    }
+                    self.scopes.push(bindings);
+                    let typ = arm
+                        .value
+                        .as_ref()
+                        .ok()
+                        .and_then(|value| self.check_expr(value));
+                    self.scopes.pop();
+                    match (result, typ) {
+                        (None, typ) => result = typ,
+                        (Some(first), Some(typ)) if first != typ => {
+                            self.error(
+                                *span,
+                                format!(
+                                    "mismatched types `{first}` and `{typ}` \
+                                     for `match` arms"
+                                ),
+                            );
+                            is_mismatched = true;
+                        }
+                        _ => {}
+                    }
+                }
+                if let (Some(covered), Some(scrutinee)) = (covered, scrutinee) {
+                    let (start, end) = self.domain(scrutinee);
+                    let missing = covered
+                        .missing(start, end)
+                        .into_iter()
+                        .flat_map(|(start, end)| {
+                            self.describe_cases(scrutinee, start, end)
+                        })
+                        .collect::<Vec<_>>();
+                    if !missing.is_empty() {
+                        self.error(
+                            *span,
+                            format!(
+                                "missing {} in `match`",
+                                missing.join(", ")
+                            ),
+                        );
+                    }
+                }
+                result.filter(|_| !is_mismatched)
+            }
+        }
+    }
+
+    /// Looks up the variant `variant` of the enum `name` and its index.
+    fn variant(
+        &mut self,
+        name: Intern<str>,
+        variant: Intern<str>,
+        span: Span,
+    ) -> Option<(usize, &'a Variant)> {
+        let types = self.types;
+        let Some(definition) = types.enums.get(&name) else {
+            self.error(span, format!("unknown enum `{name}`"));
+            return None;
+        };
+        let declared = definition.variant(variant);
+        if declared.is_none() {
+            self.error(span, format!("`{name}` has no variant `{variant}`"));
+        }
+        declared
+    }
+
+    /// Checks that `pattern` matches values of type `scrutinee` and adds the
+    /// variables it binds to `bindings`. Returns the cases that it matches,
+    /// unless it is invalid.
+    fn check_pattern(
+        &mut self,
+        pattern: &Pattern,
+        scrutinee: Option<Type>,
+        span: Span,
+        bindings: &mut HashMap<Intern<str>, Option<Type>>,
+    ) -> Option<(i128, i128)> {
+        let (found, cases) = match pattern {
+            Pattern::Wildcard => (None, None),
+            Pattern::Binding(name) => {
+                bindings.insert(*name, scrutinee);
+                (None, None)
+            }
+            Pattern::IntLiteral(literal) => {
+                let value = literal.value().ok()?;
+                (Some(literal.typ()), Some((value, value)))
+            }
+            Pattern::Range(start, end) => {
+                if start.typ() != end.typ() {
+                    self.error(
+                        span,
+                        format!(
+                            "mismatched types `{}` and `{}` for `..=`",
+                            start.typ(),
+                            end.typ()
+                        ),
+                    );
+                    return None;
+                }
+                let cases = (start.value().ok()?, end.value().ok()?);
+                (Some(start.typ()), Some(cases))
+            }
+            Pattern::Variant {
+                name,
+                variant,
+                bindings: names,
+            } => {
+                let declared = self.variant(*name, *variant, span);
+                let payload = declared.map(|(_, declared)| &declared.payload);
+                for (i, binding) in names.iter().enumerate() {
+                    if let Some(binding) = binding {
+                        let typ = payload.and_then(|payload| payload.get(i));
+                        bindings.insert(*binding, typ.copied());
+                    }
+                }
+                let (index, declared) = declared?;
+                if declared.payload.len() != names.len() {
+                    self.error(
+                        span,
+                        format!(
+                            "`{name}::{variant}` has {} values but the \
+                             pattern has {}",
+                            declared.payload.len(),
+                            names.len()
+                        ),
+                    );
+                    return None;
+                }
+                let index = index as i128;
+                (Some(Type::Named(*name)), Some((index, index)))
+            }
+        };
+        let scrutinee = scrutinee?;
+        if let Some(found) = found.filter(|found| *found != scrutinee) {
+            self.error(
+                span,
+                format!(
+                    "expected pattern of type `{scrutinee}`, found `{found}`"
+                ),
+            );
+            return None;
+        }
+        Some(cases.unwrap_or_else(|| self.domain(scrutinee)))
+    }
+
+    /// The cases of values of type `typ`: all values of an integer type, the
+    /// indices of the variants of an enum, or a single case for other types.
+    fn domain(&self, typ: Type) -> (i128, i128) {
+        match typ {
+            Type::Named(name) => match self.types.enums.get(&name) {
+                Some(definition) => {
+                    let variants = definition.variants.iter().flatten();
+                    (0, variants.count() as i128 - 1)
+                }
+                None => (0, 0),
+            },
+            typ => typ.range().unwrap_or((0, 0)),
+        }
+    }
+
+    /// Describes the cases from `start` to `end` of values of type `typ` the
+    /// way patterns would match them.
+    fn describe_cases(&self, typ: Type, start: i128, end: i128) -> Vec<String> {
+        if let Type::Named(name) = typ {
+            if let Some(definition) = self.types.enums.get(&name) {
+                let variants = definition.variants.iter().flatten();
+                return variants
+                    .skip(start as usize)
+                    .take((end - start + 1) as usize)
+                    .map(|variant| format!("`{name}::{}`", variant.name))
+                    .collect();
+            }
+        }
+        if !typ.is_integer() {
+            vec!["`_`".to_owned()]
+        } else if start == end {
+            vec![format!("`{start}_{typ}`")]
+        } else {
+            vec![format!("`{start}_{typ}..={end}_{typ}`")]
+        }
+    }
+
+    /// Checks a variable, a dereference or a field of one of those, whose
+    /// memory is written to if `is_mutable`.
+    fn check_place(
+        &mut self,
+        place: &Expr,
+        is_mutable: bool,
+        span: Span,
+    ) -> Option<Type> {
+        match place {
+            Expr::Dereference { pointer, .. } => {
+                let pointer = self.check_operand(pointer);
+                if is_mutable {
+                    self.check_mutable(pointer, span);
+                }
+                self.dereference(pointer, span)
+            }
+            Expr::Field {
+                value,
+                field,
+                span: field_span,
+            } => {
+                let value = value.as_ref().ok()?;
+                let typ = self.check_place(value, is_mutable, span)?;
+                self.field_type(typ, field, *field_span)
+            }
+            place => self.check_expr(place),
+        }
+    }
+
+    /// The type of the field of a value of type `typ`.
+    fn field_type(
+        &mut self,
+        typ: Type,
+        field: &Result<Intern<str>, SyntaxError>,
+        span: Span,
+    ) -> Option<Type> {
+        let field = *field.as_ref().ok()?;
+        let types = self.types;
+        let definition = match typ {
+            Type::Named(name) => match types.structs.get(&name) {
+                Some(definition) => Some(definition),
+                // Enums have no fields.
+                None if types.enums.contains_key(&name) => None,
+                // The unknown type has already been reported.
+                None => return None,
+            },
+            _ => None,
+        };
+        match definition.and_then(|definition| definition.field(field)) {
+            Some((_, declared)) => Some(declared.typ),
+            None => {
+                self.error(span, format!("`{typ}` has no field `{field}`"));
+                None
+            }
+        }
+    }
+
+    fn check_operand(
+        &mut self,
+        operand: &Result<Box<Expr>, SyntaxError>,
+    ) -> Option<Type> {
+        operand
+            .as_ref()
+            .ok()
+            .and_then(|operand| self.check_expr(operand))
+    }
+
+    fn check_mutable(&mut self, pointer: Option<Type>, span: Span) {
+        if let Some(
+            pointer @ Type::Pointer {
+                is_mutable: false, ..
+            },
+        ) = pointer
+        {
+            self.error(
+                span,
+                format!("can't write through `{pointer}`, which isn't `*mut`"),
+            );
+        }
+    }
+
+    /// The type that dereferencing a value of type `pointer` gives.
+    fn dereference(
+        &mut self,
+        pointer: Option<Type>,
+        span: Span,
+    ) -> Option<Type> {
+        match pointer? {
+            Type::Pointer { pointee, .. } => Some(*pointee),
+            typ => {
+                self.error(span, format!("can't dereference `{typ}`"));
+                None
+            }
+        }
+    }
+}
+
+/// A set of cases, as disjoint ranges sorted by their start.
+#[derive(Default)]
+struct Cases(Vec<(i128, i128)>);
+
+impl Cases {
+    /// Whether all cases from `start` to `end` are in the set, which is true
+    /// if there are none.
+    fn contains(&self, start: i128, end: i128) -> bool {
+        start > end
+            || self
+                .0
+                .iter()
+                .any(|&(first, last)| first <= start && end <= last)
+    }
+
+    fn insert(&mut self, mut start: i128, mut end: i128) {
+        if start > end {
+            return;
+        }
+        // Merges the ranges that overlap or touch the new one into it.
+        self.0.retain(|&(first, last)| {
+            let is_merged = first <= end.saturating_add(1)
+                && start <= last.saturating_add(1);
+            if is_merged {
+                start = start.min(first);
+                end = end.max(last);
+            }
+            !is_merged
+        });
+        let index = self.0.partition_point(|&(first, _)| first < start);
+        self.0.insert(index, (start, end));
+    }
+
+    /// The ranges of cases from `start` to `end` that aren't in the set.
+    fn missing(&self, start: i128, end: i128) -> Vec<(i128, i128)> {
+        let mut missing = Vec::new();
+        let mut next = start;
+        for &(first, last) in &self.0 {
+            if first > next {
+                missing.push((next, first - 1));
+            }
+            next = next.max(last + 1);
+        }
+        if next <= end {
+            missing.push((next, end));
+        }
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Database;
+
+    fn check_text(text: &str) -> Vec<String> {
+        let mut db = Database::new();
+        let file = "test.gneiss".into();
+        db.set_text(file, text.into());
+        db.check_file(file)
+            .iter()
+            .flat_map(|(_, errors)| errors.iter())
+            .map(|error| error.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn extern_functions_are_checked_against_c() {
+        let errors = check_text(
+            "
+extern fn write(fd: i32, buffer: u64, count: u64) -> i64;
+extern fn is-tty(fd: i32) -> i32;
+extern fn flush(nothing: unit) -> unit {}
+fn print(fd: i32) -> i64 { write(fd, 0_u64, 0_u64) }
+fn helper() -> unit;
+",
+        );
+        assert_eq!(
+            errors,
+            [
+                "`is-tty` isn't a valid name in C",
+                "`extern` functions can't have a body",
+                "`extern` functions can't take `unit` parameters",
+                "`helper` needs a body unless it is `extern`",
+            ]
+        );
+    }
+
+    #[test]
+    fn export_functions_are_checked_against_c() {
+        let errors = check_text(
+            "
+export fn add(x: i32, y: i32) -> i32 { x + y }
+export fn add-all(x: i32) -> i32 { x }
+export fn ignore(x: unit) -> unit { x }
+export fn main() -> i32 { add(1_i32, 2_i32) }
+",
+        );
+        assert_eq!(
+            errors,
+            [
+                "`add-all` isn't a valid name in C",
+                "`export` functions can't take `unit` parameters",
+                "`main` can't be `export`",
+            ]
+        );
+    }
+
+    #[test]
+    fn pointers_are_checked() {
+        let errors = check_text(
+            "
+fn f(x: i32, p: *i32, q: *mut u8) -> unit {
+    let a = &(x + 1_i32);
+    *p = 2_i32;
+    *q = 3_i8;
+    x = 4_i32;
+    let b = *x;
+    let c = p + q;
+    let d = {} as u8;
+    let e = &mut *p;
+}
+",
+        );
+        assert_eq!(
+            errors,
+            [
+                "can't take the address of a temporary",
+                "can't write through `*i32`, which isn't `*mut`",
+                "mismatched types `u8` and `i8` for `=`",
+                "can only assign through pointers",
+                "can't dereference `i32`",
+                "`+` can't be applied to `*i32` and `*mut u8`",
+                "can't cast `unit` to `u8`",
+                "can't write through `*i32`, which isn't `*mut`",
+            ]
+        );
+    }
+
+    #[test]
+    fn structs_are_checked() {
+        let errors = check_text(
+            "
+struct Point { x: i32, y: i32 }
+struct Twice { a: u8, a: Thing }
+packed struct Outer { inner: Inner }
+struct Inner { outer: Outer, next: *Inner }
+fn f(p: Point) -> i32 {
+    let q = Point { x: 1_i32, z: 2_i32, y: 3_u8 };
+    let r = Point { x: 1_i32, x: 2_i32 };
+    let s = Nowhere { a: 1_i32 };
+    p.z + p.x.y
+}
+extern fn g(p: Point) -> Point;
+",
+        );
+        assert_eq!(
+            errors,
+            [
+                "`Point` has no field `z`",
+                "expected field `y` of type `i32`, found `u8`",
+                "field `x` is initialized twice",
+                "missing field `y` in `Point`",
+                "unknown struct `Nowhere`",
+                "`Point` has no field `z`",
+                "`i32` has no field `y`",
+                "`extern` functions can't return structs or enums",
+                "`extern` functions can't take struct or enum parameters",
+                "field `a` is declared twice",
+                "unknown type `Thing`",
+                "`Outer` contains itself, so it would be infinitely large",
+                "`Inner` contains itself, so it would be infinitely large",
+            ]
+        );
+    }
+
+    #[test]
+    fn structs_that_contain_themselves_are_rejected() {
+        let errors = check_text(
+            "
+struct Node { value: i32, next: Node }
+struct Pair { left: Half, right: i32 }
+struct Half { pair: Pair }
+struct Wrapper { inner: Node }
+struct List { value: i32, next: *List, previous: *mut List }
+",
+        );
+        // `Wrapper` only contains a type that contains itself, and pointers
+        // have a size no matter what they point to.
+        assert_eq!(
+            errors,
+            [
+                "`Node` contains itself, so it would be infinitely large",
+                "`Pair` contains itself, so it would be infinitely large",
+                "`Half` contains itself, so it would be infinitely large",
+            ]
+        );
+    }
+
+    #[test]
+    fn enums_and_matches_are_checked() {
+        let errors = check_text(
+            "
+enum Shape { Circle(u32), Square(u32, u32), Empty }
+fn f(s: Shape, n: u8) -> u32 {
+    let a = Shape::Square(1_u32);
+    let b = Shape::Square(1_u32, 2_u8);
+    let c = Shape::Triangle;
+    let d = Color::Red;
+    let e = match s {
+        Shape::Circle(r) => r,
+        Shape::Circle(_) => 0_u32,
+    };
+    let g = match n {
+        0_u8 => 1_u32,
+        1_u8..=9_u8 => 2_u32,
+        5_u8 => 3_u32,
+        200_u8..=255_u8 => 4_u32,
+    };
+    let h = match n {
+        20_u16 => 1_u32,
+        30_u8..=40_u16 => 2_u32,
+        x => 3_u8,
+    };
+    let i = match s {
+        Shape::Square(w) => w,
+        Other::A => 0_u32,
+        _ => 1_u32,
+    };
+    match s { Shape::Empty => 1_u32, other => 2_u32 }
+}
+enum Twice { A, A(u8) }
+enum Tree { Leaf, Node(Tree, Missing) }
+enum List { Nil, Cons(u32, *List) }
+",
+        );
+        assert_eq!(
+            errors,
+            [
+                "`Shape::Square` takes 2 values but 1 were given",
+                "expected value of type `u32`, found `u8`",
+                "`Shape` has no variant `Triangle`",
+                "unknown enum `Color`",
+                "unreachable pattern",
+                "missing `Shape::Square`, `Shape::Empty` in `match`",
+                "unreachable pattern",
+                "missing `10_u8..=199_u8` in `match`",
+                "expected pattern of type `u8`, found `u16`",
+                "mismatched types `u8` and `u16` for `..=`",
+                "mismatched types `u32` and `u8` for `match` arms",
+                "`Shape::Square` has 2 values but the pattern has 1",
+                "unknown enum `Other`",
+                "variant `A` is declared twice",
+                "unknown type `Missing`",
+                "`Tree` contains itself, so it would be infinitely large",
+            ]
+        );
+    }
+
+    #[test]
+    fn payload_patterns_cover_whole_variants() {
+        let errors = check_text(
+            "
+enum Shape { Circle(u32), Square(u32, u32), Empty }
+fn side(s: Shape) -> u32 {
+    match s {
+        Shape::Circle(r) => r,
+        Shape::Square(w, _) => w,
+        Shape::Square(_, h) => h,
+    }
+}
+",
+        );
+        assert_eq!(
+            errors,
+            ["unreachable pattern", "missing `Shape::Empty` in `match`"]
+        );
+    }
+
+    #[test]
+    fn arms_after_a_catch_all_are_unreachable() {
+        let errors = check_text(
+            "
+enum Shape { Circle(u32), Empty }
+fn f(s: Shape, n: u8) -> u32 {
+    let a = match s { _ => 1_u32, Shape::Empty => 2_u32 };
+    let b = match n { x => 1_u32, 0_u8 => 2_u32, _ => 3_u32 };
+    a + b
+}
+",
+        );
+        assert_eq!(errors, ["unreachable pattern"; 3]);
+    }
+
+    #[test]
+    fn enums_that_contain_themselves_are_rejected() {
+        let errors = check_text(
+            "
+struct Holder { value: Wrapped }
+enum Wrapped { Empty, Full(Holder) }
+enum Term { Number(i64), Negate(Term), Add(*Term, *Term) }
+enum List { Nil, Cons(u32, *List) }
+",
+        );
+        assert_eq!(
+            errors,
+            [
+                "`Holder` contains itself, so it would be infinitely large",
+                "`Wrapped` contains itself, so it would be infinitely large",
+                "`Term` contains itself, so it would be infinitely large",
+            ]
+        );
+    }
+}
@@ -1,16 +1,536 @@
-use std::path::Path;
+use crate::{
+    ast::{self, Item},
+    backend::{self, c::LibraryKind, dwarf::Source, x86_64::Entry},
+    cache::{Cache, Key},
+    db::{Database, FileId},
+    ir, opt,
+    text::node_text,
+    CompileCommand,
+};
+use internment::Intern;
+use ropey::Rope;
+use std::{
+    ffi::OsString, fmt, io::Write, path::Path, process::ExitCode, str::FromStr,
+    sync::Arc,
+};
+use tree_sitter::{Node, Tree};
 
-pub fn compile(source_file: &Path) {
-    let source_code = std::fs::read_to_string(source_file).unwrap();
+/// The kind of output to produce.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emit {
+    /// An executable built by the system C compiler.
+    Exe,
+    /// A static library built by the system C compiler and `ar`.
+    Lib,
+    /// A shared library built by the system C compiler.
+    Dylib,
+    /// C source code.
+    C,
+    /// A C header that declares the `export` functions.
+    Header,
+    /// Textual LLVM IR.
+    LlvmIr,
+    /// A relocatable ELF object file for x86-64.
+    Obj,
+    /// Assembly for x86-64, in the syntax of the GNU assembler.
+    Asm,
+    /// A WebAssembly module in the binary format.
+    Wasm,
+    /// A WebAssembly module in the text format.
+    Wat,
+    /// The textual form of the IR.
+    Ir,
+}
+
+impl Emit {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Exe => "",
+            Self::Lib => "a",
+            Self::Dylib => "so",
+            Self::C => "c",
+            Self::Header => "h",
+            Self::LlvmIr => "ll",
+            Self::Obj => "o",
+            Self::Asm => "s",
+            Self::Wasm => "wasm",
+            Self::Wat => "wat",
+            Self::Ir => "ir",
+        }
+    }
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exe" => Ok(Self::Exe),
+            "lib" => Ok(Self::Lib),
+            "dylib" => Ok(Self::Dylib),
+            "c" => Ok(Self::C),
+            "header" => Ok(Self::Header),
+            "llvm-ir" => Ok(Self::LlvmIr),
+            "obj" => Ok(Self::Obj),
+            "asm" => Ok(Self::Asm),
+            "wasm" => Ok(Self::Wasm),
+            "wat" => Ok(Self::Wat),
+            "ir" => Ok(Self::Ir),
+            _ => Err(format!("unknown output kind `{s}`")),
+        }
+    }
+}
+
+pub fn compile(command: &CompileCommand) -> ExitCode {
+    let source_file = &command.file;
+    let source_code = match std::fs::read_to_string(source_file) {
+        Ok(source_code) => source_code,
+        Err(err) => {
+            eprintln!("Failed to read {}: {err}", source_file.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut db = Database::new();
+    let file = source_file.to_string_lossy().as_ref().into();
+    db.set_text(file, source_code.into());
+    let cache = Cache::new(command.cache_dir.clone(), command.verbose);
+
+    let tree = db.parse(file);
+    let text = db.text(file);
+    let ast = db.ast(file);
+    let reporter = Reporter {
+        source_file,
+        text: &text,
+    };
+    let mut has_errors = report_syntax_errors(&tree, &reporter);
 
-    let language = tree_sitter_gneiss::language();
-    let mut parser = tree_sitter::Parser::new();
-    parser.set_language(language).unwrap();
-    let tree = parser.parse(&source_code, None).unwrap();
+    // Any function may use any struct or enum, so their definitions are part
+    // of the cache key of every function.
+    let mut type_items = Vec::new();
+    let mut type_texts = String::new();
+    let structs = ast
+        .structs()
+        .iter()
+        .filter_map(|definition| definition.name.ok())
+        .map(Item::Struct);
+    let enums = ast
+        .enums()
+        .iter()
+        .filter_map(|definition| definition.name.ok())
+        .map(Item::Enum);
+    for item in structs.chain(enums) {
+        if type_items.contains(&item) {
+            continue;
+        }
+        type_items.push(item);
+        let node = ast::item_node(&tree, &text, item)
+            .expect("type is in the tree it was parsed from");
+        type_texts.push_str(&node_text(node, &text));
+        for error in db.check_item(file, item).iter() {
+            has_errors = true;
+            reporter
+                .error(node.start_byte() + error.span.start, &error.message);
+        }
+    }
+
+    let mut function_names = Vec::new();
+    let mut keys = Vec::new();
+    for function in ast.functions() {
+        let Ok(name) = function.signature.name else {
+            continue;
+        };
+        // Only the first function with a given name is checked.
+        if function_names.contains(&name) {
+            continue;
+        }
+        function_names.push(name);
+        let node = ast::function_node(&tree, &text, name)
+            .expect("function is in the tree it was parsed from");
+
+        let mut key = Key::new().str(&node_text(node, &text)).str(&type_texts);
+        for callee in function.callees() {
+            key = key.str(&callee).str(
+                &ast::function_node(&tree, &text, callee)
+                    .map_or("".into(), |callee| signature_text(callee, &text)),
+            );
+        }
+        keys.push(key);
+        let errors = cache.get_or_insert_with(
+            "check",
+            key,
+            format_args!("checking `{name}`"),
+            || db.check_body(file, name).to_vec(),
+        );
+
+        for error in errors {
+            has_errors = true;
+            reporter.error(node.start_byte() + error.span.start, error.message);
+        }
+    }
+
+    if has_errors {
+        cache.finish();
+        return ExitCode::FAILURE;
+    }
+
+    let mut functions = Vec::new();
+    let mut externs = Vec::new();
+    for (&name, &key) in function_names.iter().zip(&keys) {
+        let ast_function = ast.function(name);
+        if let Some(function) = ast_function.filter(|f| f.is_extern) {
+            if let Ok(function) = ir::lower::lower_extern(function) {
+                externs.push(function);
+                continue;
+            }
+        } else if let Some(function) =
+            lower(&mut db, &cache, command, key, file, name)
+        {
+            functions.push(function);
+            continue;
+        }
+        let node = ast::function_node(&tree, &text, name)
+            .expect("function is in the tree it was parsed from");
+        reporter.error(
+            node.start_byte(),
+            format!("`{name}` contains unsupported syntax"),
+        );
+        has_errors = true;
+    }
+    cache.finish();
+    if has_errors {
+        return ExitCode::FAILURE;
+    }
+    let mut program = ir::Program {
+        functions,
+        externs,
+        types: db.types(file).as_ref().clone(),
+    };
+    if let Err(err) = ir::verify::verify(&program) {
+        panic!("{err}\n{program}");
+    }
+    if command.dump_passes {
+        opt::optimize(&mut program, command.opt_level, true);
+    } else {
+        opt::optimize_program(&mut program, command.opt_level);
+    }
+
+    if command.no_libc
+        && !matches!(command.emit, Emit::Exe | Emit::Obj | Emit::Asm)
+    {
+        eprintln!(
+            "`--no-libc` only works with `--emit=exe`, `--emit=obj` and \
+             `--emit=asm`"
+        );
+        return ExitCode::FAILURE;
+    }
+    let entry = if command.no_libc {
+        Entry::Start
+    } else {
+        Entry::Main
+    };
+    if command.debug && !matches!(command.emit, Emit::Exe | Emit::Obj) {
+        eprintln!("`-g` only works with `--emit=exe` and `--emit=obj`");
+        return ExitCode::FAILURE;
+    }
+    let directory = std::env::current_dir().unwrap_or_default();
+    let source = command.debug.then(|| Source {
+        path: source_file,
+        directory: &directory,
+        text: &text,
+        function_starts: function_names
+            .iter()
+            .map(|&name| {
+                let node = ast::function_node(&tree, &text, name)
+                    .expect("function is in the tree it was parsed from");
+                (name, node.start_byte())
+            })
+            .collect(),
+    });
+    if matches!(command.emit, Emit::Wasm | Emit::Wat) && program.uses_syscalls()
+    {
+        eprintln!("WebAssembly modules can't make system calls");
+        return ExitCode::FAILURE;
+    }
+    if matches!(command.emit, Emit::Wasm | Emit::Wat)
+        && !program.externs.is_empty()
+    {
+        eprintln!("WebAssembly modules can't call `extern` functions");
+        return ExitCode::FAILURE;
+    }
+    let is_linked = matches!(command.emit, Emit::Exe | Emit::Dylib);
+    if !(command.libraries.is_empty() && command.library_paths.is_empty())
+        && (command.no_libc || !is_linked)
+    {
+        eprintln!(
+            "`-l` and `-L` only work with `--emit=exe` and `--emit=dylib`, \
+             and without `--no-libc`"
+        );
+        return ExitCode::FAILURE;
+    }
+
+    let output_path = command.output.clone().unwrap_or_else(|| {
+        let mut path = source_file.with_extension(command.emit.extension());
+        // Linkers look for the library of `-lNAME` in `libNAME.a` or
+        // `libNAME.so`.
+        if let (Emit::Lib | Emit::Dylib, Some(name)) =
+            (command.emit, path.file_name())
+        {
+            let mut library_name = OsString::from("lib");
+            library_name.push(name);
+            path.set_file_name(library_name);
+        }
+        // Don't let an executable replace a source file without an extension.
+        if path == *source_file {
+            path.with_extension("out")
+        } else {
+            path
+        }
+    });
+    let output = match command.emit {
+        Emit::Exe => {
+            return build_executable(
+                &program,
+                &output_path,
+                command,
+                source.as_ref(),
+                &tree,
+                &reporter,
+            )
+        }
+        Emit::Lib => {
+            return build_library(
+                &program,
+                &output_path,
+                command,
+                LibraryKind::Static,
+            )
+        }
+        Emit::Dylib => {
+            return build_library(
+                &program,
+                &output_path,
+                command,
+                LibraryKind::Shared,
+            )
+        }
+        Emit::C => backend::c::emit(&program).into_bytes(),
+        Emit::Header => {
+            let name = source_file.file_stem().unwrap_or_default();
+            backend::c::emit_header(&program, &name.to_string_lossy())
+                .into_bytes()
+        }
+        Emit::LlvmIr => backend::llvm::emit(&program).into_bytes(),
+        Emit::Obj => backend::x86_64::compile(&program, entry, source.as_ref())
+            .write_relocatable(),
+        Emit::Asm => backend::x86_64::emit_asm(&program, entry).into_bytes(),
+        Emit::Wasm => backend::wasm::emit_wasm(&program),
+        Emit::Wat => backend::wasm::emit_wat(&program).into_bytes(),
+        Emit::Ir => program.to_string().into_bytes(),
+    };
+    if let Err(err) = write_output(&output_path, &output) {
+        eprintln!("Failed to write {}: {err}", output_path.display());
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
 
-    let source_code = source_code.into();
-    let ast = crate::ast::File::parse(&tree, &source_code);
-    eprintln!("{ast:#?}");
+/// Lowers a function to IR and runs the optimizations that only look at the
+/// function itself, reusing the result from the cache if possible. Which
+/// passes run is part of the key. `key` covers everything else that the IR
+/// depends on.
+fn lower(
+    db: &mut Database,
+    cache: &Cache,
+    command: &CompileCommand,
+    key: Key,
+    file: FileId,
+    name: Intern<str>,
+) -> Option<Arc<ir::Function>> {
+    // Passes are only printed as they run, so they all have to run.
+    if command.dump_passes {
+        return db.lower(file, name);
+    }
+    let key = key.str(&format!("{:?}", command.opt_level));
+    cache
+        .get_or_insert_with(
+            "lower",
+            key,
+            format_args!("lowering `{name}`"),
+            || {
+                let mut function = Arc::unwrap_or_clone(db.lower(file, name)?);
+                opt::optimize_function(&mut function, command.opt_level);
+                Some(function)
+            },
+        )
+        .map(Arc::new)
+}
+
+fn build_executable(
+    program: &ir::Program,
+    output_path: &Path,
+    command: &CompileCommand,
+    source: Option<&Source>,
+    tree: &Tree,
+    reporter: &Reporter,
+) -> ExitCode {
+    let Some(main) = program.function("main".into()) else {
+        eprintln!(
+            "{}: error: there is no `main` function",
+            command.file.display()
+        );
+        return ExitCode::FAILURE;
+    };
+    if !main.parameters.is_empty() {
+        let node = ast::function_node(tree, reporter.text, main.name)
+            .expect("function is in the tree it was parsed from");
+        reporter.error(node.start_byte(), "`main` can't take parameters");
+        return ExitCode::FAILURE;
+    }
+    if output_path == Path::new("-") {
+        eprintln!("Can't write an executable to standard output");
+        return ExitCode::FAILURE;
+    }
+    let linker_args = linker_args(command);
+    // Debug information comes from the native backend, so debuggable
+    // executables are linked from its object.
+    let result = if command.no_libc {
+        backend::x86_64::compile(program, Entry::Start, source)
+            .write_executable("_start")
+            .and_then(|executable| {
+                write_executable(output_path, &executable)
+                    .map_err(|err| err.to_string())
+            })
+    } else if source.is_some() {
+        let object = backend::x86_64::compile(program, Entry::Main, source)
+            .write_relocatable();
+        backend::c::link(&object, output_path, &linker_args)
+    } else {
+        backend::c::build_executable(
+            program,
+            output_path,
+            command.opt_level,
+            &linker_args,
+        )
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Failed to build {}: {err}", output_path.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn build_library(
+    program: &ir::Program,
+    output_path: &Path,
+    command: &CompileCommand,
+    kind: LibraryKind,
+) -> ExitCode {
+    if output_path == Path::new("-") {
+        eprintln!("Can't write a library to standard output");
+        return ExitCode::FAILURE;
+    }
+    let result = backend::c::build_library(
+        program,
+        output_path,
+        command.opt_level,
+        kind,
+        &linker_args(command),
+    );
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Failed to build {}: {err}", output_path.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The `-L` and `-l` options for the C compiler.
+fn linker_args(command: &CompileCommand) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for path in &command.library_paths {
+        let mut arg = OsString::from("-L");
+        arg.push(path);
+        args.push(arg);
+    }
+    for library in &command.libraries {
+        args.push(format!("-l{library}").into());
+    }
+    args
+}
+
+struct Reporter<'a> {
+    source_file: &'a Path,
+    text: &'a Rope,
+}
+
+impl Reporter<'_> {
+    fn error(&self, byte: usize, message: impl fmt::Display) {
+        let line = self.text.byte_to_line(byte);
+        let column = byte - self.text.line_to_byte(line);
+        eprintln!(
+            "{}:{}:{}: error: {message}",
+            self.source_file.display(),
+            line + 1,
+            column + 1,
+        );
+    }
+}
+
+/// Reports errors and missing nodes in the tree, returning whether there were
+/// any.
+fn report_syntax_errors(tree: &Tree, reporter: &Reporter) -> bool {
+    if !tree.root_node().has_error() {
+        return false;
+    }
+    let mut cursor = tree.walk();
+    loop {
+        let node = cursor.node();
+        if node.is_error() {
+            reporter.error(node.start_byte(), "syntax error");
+        } else if node.is_missing() {
+            reporter
+                .error(node.start_byte(), format!("missing `{}`", node.kind()));
+        } else if node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return true;
+            }
+        }
+    }
+}
+
+/// Writes to standard output if the path is `-`.
+fn write_output(path: &Path, output: &[u8]) -> std::io::Result<()> {
+    if path == Path::new("-") {
+        std::io::stdout().write_all(output)
+    } else {
+        std::fs::write(path, output)
+    }
+}
+
+/// Writes a file that everyone may run.
+fn write_executable(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
 
-    todo!();
+/// The source code of a function up to its body, which is all that its callers
+/// depend on.
+fn signature_text(function: Node, text: &Rope) -> String {
+    let end = function
+        .child_by_field_name("body")
+        .map_or(function.end_byte(), |body| body.start_byte());
+    text.byte_slice(function.start_byte()..end).to_string()
 }
@@ -0,0 +1,631 @@
+//! A demand-driven query engine. Every derived value is computed from the
+//! source texts by a query that records which other queries it used. When a
+//! text changes, queries are only re-executed if one of their dependencies
+//! actually produced a different value ("early cutoff"), so that editing the
+//! body of a function doesn't re-check callers whose view of it, its
+//! signature, stayed the same.
+
+use crate::{
+    ast::{self, FunctionSignature, Item, Types},
+    check,
+    intrinsics::Intrinsic,
+    ir,
+};
+use internment::Intern;
+use ropey::Rope;
+use std::{collections::HashMap, sync::Arc};
+use tree_sitter::{Parser, Tree};
+
+/// Identifies a source file, using a path or URI.
+pub type FileId = Intern<str>;
+
+type Revision = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Query {
+    /// The input text of a file.
+    Text(FileId),
+    Parse(FileId),
+    Ast(FileId),
+    Function(FileId, Intern<str>),
+    Signature(FileId, Intern<str>),
+    /// The first struct or enum with each name in a file.
+    Types(FileId),
+    CheckBody(FileId, Intern<str>),
+    CheckStruct(FileId, Intern<str>),
+    CheckEnum(FileId, Intern<str>),
+    Lower(FileId, Intern<str>),
+}
+
+#[derive(Clone)]
+enum Value {
+    Tree(Tree),
+    Ast(Arc<ast::File>),
+    Function(Option<Arc<ast::Function>>),
+    Signature(Option<Arc<FunctionSignature>>),
+    Types(Arc<Types>),
+    Errors(Arc<Vec<check::Error>>),
+    Ir(Option<Arc<ir::Function>>),
+}
+
+impl Value {
+    /// Whether dependents can keep using the results they computed from
+    /// `self` if `other` is the new value.
+    fn is_same_as(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Ast(a), Self::Ast(b)) => a == b,
+            (Self::Function(a), Self::Function(b)) => a == b,
+            (Self::Signature(a), Self::Signature(b)) => a == b,
+            (Self::Types(a), Self::Types(b)) => a == b,
+            (Self::Errors(a), Self::Errors(b)) => a == b,
+            (Self::Ir(a), Self::Ir(b)) => a == b,
+            // Comparing trees is no cheaper than rebuilding the AST.
+            _ => false,
+        }
+    }
+}
+
+struct Memo {
+    value: Value,
+    dependencies: Vec<Query>,
+    /// The last revision at which the value was known to be up to date.
+    verified_at: Revision,
+    /// The last revision at which the value actually changed.
+    changed_at: Revision,
+}
+
+struct Input {
+    text: Rope,
+    /// The syntax tree and AST of `text`, if whoever set it already had them.
+    parsed: Option<(Tree, Arc<ast::File>)>,
+    changed_at: Revision,
+}
+
+pub struct Database {
+    revision: Revision,
+    parser: Parser,
+    inputs: HashMap<FileId, Input>,
+    memos: HashMap<Query, Memo>,
+    /// The dependencies recorded so far by each query that is currently
+    /// executing, innermost last.
+    active: Vec<Vec<Query>>,
+    #[cfg(test)]
+    executed: Vec<Query>,
+}
+
+impl Database {
+    pub fn new() -> Self {
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_gneiss::language()).unwrap();
+        Self {
+            revision: 0,
+            parser,
+            inputs: HashMap::new(),
+            memos: HashMap::new(),
+            active: Vec::new(),
+            #[cfg(test)]
+            executed: Vec::new(),
+        }
+    }
+
+    /// Sets the text of a file, doing nothing if it hasn't changed.
+    pub fn set_text(&mut self, file: FileId, text: Rope) {
+        self.set_input(file, text, None);
+    }
+
+    /// Sets the text of a file along with its syntax tree and AST, which the
+    /// file then doesn't have to be parsed for.
+    pub fn set_parsed(
+        &mut self,
+        file: FileId,
+        text: Rope,
+        tree: Tree,
+        ast: Arc<ast::File>,
+    ) {
+        self.set_input(file, text, Some((tree, ast)));
+    }
+
+    fn set_input(
+        &mut self,
+        file: FileId,
+        text: Rope,
+        parsed: Option<(Tree, Arc<ast::File>)>,
+    ) {
+        if self
+            .inputs
+            .get(&file)
+            .is_some_and(|input| input.text == text)
+        {
+            return;
+        }
+        self.revision += 1;
+        self.inputs.insert(
+            file,
+            Input {
+                text,
+                parsed,
+                changed_at: self.revision,
+            },
+        );
+    }
+
+    pub fn remove_file(&mut self, file: FileId) {
+        self.revision += 1;
+        self.inputs.remove(&file);
+        self.memos.retain(|query, _| query.file() != file);
+    }
+
+    /// Forgets the memos of a file that checking it no longer needs, such as
+    /// those of functions that have since been deleted.
+    pub fn collect_garbage(&mut self, file: FileId) {
+        let mut reachable: Vec<Query> = self
+            .items(file)
+            .into_iter()
+            .map(|item| Query::check(file, item))
+            .collect();
+        let mut i = 0;
+        while let Some(&query) = reachable.get(i) {
+            i += 1;
+            if let Some(memo) = self.memos.get(&query) {
+                for &dependency in &memo.dependencies {
+                    if !reachable.contains(&dependency) {
+                        reachable.push(dependency);
+                    }
+                }
+            }
+        }
+        self.memos.retain(|query, _| {
+            query.file() != file || reachable.contains(query)
+        });
+    }
+
+    pub fn text(&mut self, file: FileId) -> Rope {
+        self.record(Query::Text(file));
+        self.inputs
+            .get(&file)
+            .map(|input| input.text.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn parse(&mut self, file: FileId) -> Tree {
+        match self.fetch(Query::Parse(file)) {
+            Value::Tree(tree) => tree,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn ast(&mut self, file: FileId) -> Arc<ast::File> {
+        match self.fetch(Query::Ast(file)) {
+            Value::Ast(ast) => ast,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn function(
+        &mut self,
+        file: FileId,
+        name: Intern<str>,
+    ) -> Option<Arc<ast::Function>> {
+        match self.fetch(Query::Function(file, name)) {
+            Value::Function(function) => function,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn signature(
+        &mut self,
+        file: FileId,
+        name: Intern<str>,
+    ) -> Option<Arc<FunctionSignature>> {
+        match self.fetch(Query::Signature(file, name)) {
+            Value::Signature(signature) => signature,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn types(&mut self, file: FileId) -> Arc<Types> {
+        match self.fetch(Query::Types(file)) {
+            Value::Types(types) => types,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn check_body(
+        &mut self,
+        file: FileId,
+        name: Intern<str>,
+    ) -> Arc<Vec<check::Error>> {
+        match self.fetch(Query::CheckBody(file, name)) {
+            Value::Errors(errors) => errors,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn check_struct(
+        &mut self,
+        file: FileId,
+        name: Intern<str>,
+    ) -> Arc<Vec<check::Error>> {
+        match self.fetch(Query::CheckStruct(file, name)) {
+            Value::Errors(errors) => errors,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn check_enum(
+        &mut self,
+        file: FileId,
+        name: Intern<str>,
+    ) -> Arc<Vec<check::Error>> {
+        match self.fetch(Query::CheckEnum(file, name)) {
+            Value::Errors(errors) => errors,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Lowers a function to IR, which is only possible if it is free of
+    /// errors.
+    pub fn lower(
+        &mut self,
+        file: FileId,
+        name: Intern<str>,
+    ) -> Option<Arc<ir::Function>> {
+        match self.fetch(Query::Lower(file, name)) {
+            Value::Ir(function) => function,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Checks every named function, struct and enum in a file.
+    pub fn check_file(
+        &mut self,
+        file: FileId,
+    ) -> Vec<(Item, Arc<Vec<check::Error>>)> {
+        self.items(file)
+            .into_iter()
+            .map(|item| (item, self.check_item(file, item)))
+            .collect()
+    }
+
+    pub fn check_item(
+        &mut self,
+        file: FileId,
+        item: Item,
+    ) -> Arc<Vec<check::Error>> {
+        match item {
+            Item::Function(name) => self.check_body(file, name),
+            Item::Struct(name) => self.check_struct(file, name),
+            Item::Enum(name) => self.check_enum(file, name),
+        }
+    }
+
+    /// The first function, struct and enum with each name in a file.
+    fn items(&mut self, file: FileId) -> Vec<Item> {
+        let ast = self.ast(file);
+        let mut items = Vec::new();
+        let functions = ast
+            .functions()
+            .iter()
+            .filter_map(|function| function.signature.name.ok())
+            .map(Item::Function);
+        let structs = ast
+            .structs()
+            .iter()
+            .filter_map(|definition| definition.name.ok())
+            .map(Item::Struct);
+        let enums = ast
+            .enums()
+            .iter()
+            .filter_map(|definition| definition.name.ok())
+            .map(Item::Enum);
+        for item in functions.chain(structs).chain(enums) {
+            if !items.contains(&item) {
+                items.push(item);
+            }
+        }
+        items
+    }
+
+    fn record(&mut self, query: Query) {
+        if let Some(dependencies) = self.active.last_mut() {
+            dependencies.push(query);
+        }
+    }
+
+    fn fetch(&mut self, query: Query) -> Value {
+        self.record(query);
+        self.update(query);
+        self.memos[&query].value.clone()
+    }
+
+    /// Makes sure that the memo for `query` is up to date and returns the
+    /// revision at which its value last changed.
+    fn update(&mut self, query: Query) -> Revision {
+        if let Query::Text(file) = query {
+            // Removed files count as having changed just now.
+            return self
+                .inputs
+                .get(&file)
+                .map_or(self.revision, |input| input.changed_at);
+        }
+
+        if let Some(memo) = self.memos.get(&query) {
+            if memo.verified_at == self.revision {
+                return memo.changed_at;
+            }
+            let verified_at = memo.verified_at;
+            let dependencies = memo.dependencies.clone();
+            if dependencies
+                .into_iter()
+                .all(|dependency| self.update(dependency) <= verified_at)
+            {
+                let memo = self.memos.get_mut(&query).unwrap();
+                memo.verified_at = self.revision;
+                return memo.changed_at;
+            }
+        }
+
+        self.active.push(Vec::new());
+        let value = self.execute(query);
+        let dependencies = self.active.pop().unwrap();
+
+        let changed_at = match self.memos.get(&query) {
+            Some(old) if old.value.is_same_as(&value) => old.changed_at,
+            _ => self.revision,
+        };
+        self.memos.insert(
+            query,
+            Memo {
+                value,
+                dependencies,
+                verified_at: self.revision,
+                changed_at,
+            },
+        );
+        changed_at
+    }
+
+    fn execute(&mut self, query: Query) -> Value {
+        log::debug!("Executing {query:?}");
+        #[cfg(test)]
+        self.executed.push(query);
+
+        match query {
+            Query::Text(_) => unreachable!("inputs are not executed"),
+            Query::Parse(file) => {
+                let text = self.text(file);
+                if let Some((tree, _)) = self.parsed(file) {
+                    return Value::Tree(tree.clone());
+                }
+                Value::Tree(
+                    self.parser
+                        .parse_with(
+                            &mut |byte_offset, _position| {
+                                let (chunk, chunk_start, ..) =
+                                    text.chunk_at_byte(byte_offset);
+                                &chunk.as_bytes()[(byte_offset - chunk_start)..]
+                            },
+                            None,
+                        )
+                        .unwrap(),
+                )
+            }
+            Query::Ast(file) => match self.parsed(file) {
+                Some((_, ast)) => {
+                    let ast = ast.clone();
+                    self.record(Query::Text(file));
+                    Value::Ast(ast)
+                }
+                None => {
+                    let tree = self.parse(file);
+                    let text = self.text(file);
+                    Value::Ast(Arc::new(ast::File::parse(&tree, &text)))
+                }
+            },
+            Query::Function(file, name) => {
+                Value::Function(self.ast(file).function(name).cloned())
+            }
+            Query::Signature(file, name) => {
+                Value::Signature(match Intrinsic::from_name(&name) {
+                    Some(intrinsic) => {
+                        Some(Arc::new(intrinsic.signature(name)))
+                    }
+                    None => self
+                        .function(file, name)
+                        .map(|function| Arc::new(function.signature.clone())),
+                })
+            }
+            Query::Types(file) => {
+                Value::Types(Arc::new(self.ast(file).type_definitions()))
+            }
+            Query::CheckBody(file, name) => {
+                let errors = match self.function(file, name) {
+                    Some(function) => {
+                        let types = self.types(file);
+                        check::check_function(&function, &types, |callee| {
+                            self.signature(file, callee)
+                        })
+                    }
+                    None => Vec::new(),
+                };
+                Value::Errors(Arc::new(errors))
+            }
+            Query::CheckStruct(file, name) => {
+                let types = self.types(file);
+                let errors = types
+                    .structs
+                    .get(&name)
+                    .map(|definition| check::check_struct(definition, &types))
+                    .unwrap_or_default();
+                Value::Errors(Arc::new(errors))
+            }
+            Query::CheckEnum(file, name) => {
+                let types = self.types(file);
+                let errors = types
+                    .enums
+                    .get(&name)
+                    .map(|definition| check::check_enum(definition, &types))
+                    .unwrap_or_default();
+                Value::Errors(Arc::new(errors))
+            }
+            Query::Lower(file, name) => {
+                let types = self.types(file);
+                Value::Ir(
+                    self.function(file, name)
+                        .and_then(|function| {
+                            ir::lower::lower_function(
+                                &function,
+                                &types,
+                                |callee| self.signature(file, callee),
+                            )
+                            .ok()
+                        })
+                        .map(Arc::new),
+                )
+            }
+        }
+    }
+
+    fn parsed(&self, file: FileId) -> Option<&(Tree, Arc<ast::File>)> {
+        self.inputs.get(&file)?.parsed.as_ref()
+    }
+}
+
+impl Query {
+    fn check(file: FileId, item: Item) -> Self {
+        match item {
+            Item::Function(name) => Self::CheckBody(file, name),
+            Item::Struct(name) => Self::CheckStruct(file, name),
+            Item::Enum(name) => Self::CheckEnum(file, name),
+        }
+    }
+
+    fn file(self) -> FileId {
+        match self {
+            Self::Text(file)
+            | Self::Parse(file)
+            | Self::Ast(file)
+            | Self::Function(file, _)
+            | Self::Signature(file, _)
+            | Self::Types(file)
+            | Self::CheckBody(file, _)
+            | Self::CheckStruct(file, _)
+            | Self::CheckEnum(file, _)
+            | Self::Lower(file, _) => file,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> FileId {
+        "test.gneiss".into()
+    }
+
+    fn check_text(db: &mut Database, text: &str) -> Vec<String> {
+        db.set_text(file(), text.into());
+        db.executed.clear();
+        db.check_file(file())
+            .iter()
+            .flat_map(|(_, errors)| errors.iter())
+            .map(|error| error.message.clone())
+            .collect()
+    }
+
+    fn rechecked(db: &Database) -> Vec<&str> {
+        db.executed
+            .iter()
+            .filter_map(|query| match query {
+                Query::CheckBody(_, name) => Some(&**name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn editing_a_body_does_not_recheck_callers() {
+        let mut db = Database::new();
+        let errors = check_text(
+            &mut db,
+            "fn f() -> i32 { g() }\nfn g() -> i32 { 1_i32 }\n",
+        );
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(rechecked(&db), ["f", "g"]);
+
+        let errors = check_text(
+            &mut db,
+            "fn f() -> i32 { g() }\nfn g() -> i32 { 2_i32 }\n",
+        );
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(rechecked(&db), ["g"]);
+    }
+
+    #[test]
+    fn changing_a_signature_rechecks_callers() {
+        let mut db = Database::new();
+        check_text(&mut db, "fn f() -> i32 { g() }\nfn g() -> i32 { 1_i32 }\n");
+
+        let errors = check_text(
+            &mut db,
+            "fn f() -> i32 { g() }\nfn g() -> u8 { 1_u8 }\n",
+        );
+        assert_eq!(
+            errors,
+            ["function returns `i32` but its body evaluates to `u8`"]
+        );
+        assert_eq!(rechecked(&db), ["f", "g"]);
+    }
+
+    #[test]
+    fn moving_a_function_reuses_its_check() {
+        let mut db = Database::new();
+        let errors = check_text(&mut db, "fn f() -> unit { let x = h(); x }\n");
+        assert_eq!(errors, ["unknown function `h`"]);
+
+        let errors =
+            check_text(&mut db, "\n\nfn f() -> unit { let x = h(); x }\n");
+        assert_eq!(errors, ["unknown function `h`"]);
+        assert_eq!(db.executed, [Query::Parse(file()), Query::Ast(file())]);
+
+        check_text(&mut db, "\n\nfn f() -> unit { let x = h(); x }\n");
+        assert_eq!(db.executed, []);
+    }
+
+    #[test]
+    fn parsed_files_are_not_parsed_again() {
+        let text = "fn f() -> i32 { 1_u8 }\n";
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_gneiss::language()).unwrap();
+        let tree = parser.parse(text, None).unwrap();
+        let text = Rope::from(text);
+        let ast = Arc::new(ast::File::parse(&tree, &text));
+
+        let mut db = Database::new();
+        db.set_parsed(file(), text, tree, ast);
+        let errors = db.check_file(file());
+        assert_eq!(
+            errors[0].1[0].message,
+            "function returns `i32` but its body evaluates to `u8`"
+        );
+        assert!(!db.executed.contains(&Query::Parse(file())));
+    }
+
+    #[test]
+    fn deleted_functions_are_forgotten() {
+        let mut db = Database::new();
+        check_text(
+            &mut db,
+            "fn f() -> i32 { 1_i32 }\nfn g() -> i32 { 2_i32 }\n",
+        );
+        check_text(&mut db, "fn f() -> i32 { 1_i32 }\n");
+        let g = Query::CheckBody(file(), "g".into());
+        assert!(db.memos.contains_key(&g));
+
+        db.collect_garbage(file());
+        assert!(!db.memos.contains_key(&g));
+        assert!(!db.memos.contains_key(&Query::Function(file(), "g".into())));
+        assert!(db.memos.contains_key(&Query::CheckBody(file(), "f".into())));
+    }
+}
@@ -0,0 +1,67 @@
+//! Functions that are built into the compiler instead of being defined in
+//! source files. Their names are reserved, and calls to them look like calls
+//! to any other function.
+
+use crate::{
+    ast::{Expr, FunctionParameters, FunctionSignature, Span},
+    typ::Type,
+};
+use internment::Intern;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Intrinsic {
+    /// `syscallN(number, arg1, ..., argN)` makes the Linux system call
+    /// `number` and returns its result. Everything is an `i64`.
+    Syscall { arguments: usize },
+}
+
+impl Intrinsic {
+    pub fn from_name(name: &str) -> Option<Self> {
+        // Linux system calls take up to six arguments.
+        match name.strip_prefix("syscall")?.as_bytes() {
+            &[digit @ b'0'..=b'6'] => Some(Self::Syscall {
+                arguments: usize::from(digit - b'0'),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn signature(self, name: Intern<str>) -> FunctionSignature {
+        let Self::Syscall { arguments } = self;
+        let parameter = |name: String| {
+            let name = Expr::Identifier {
+                name: name.as_str().into(),
+                span: Span { start: 0, end: 0 },
+            };
+            (name, Type::I64)
+        };
+        let parameters = std::iter::once(parameter("number".to_owned()))
+            .chain((1..=arguments).map(|i| parameter(format!("arg{i}"))))
+            .collect();
+        FunctionSignature {
+            name: Ok(name),
+            parameters: Ok(FunctionParameters(parameters)),
+            return_type: Ok(Type::I64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_syscall_names() {
+        assert_eq!(
+            Intrinsic::from_name("syscall0"),
+            Some(Intrinsic::Syscall { arguments: 0 })
+        );
+        assert_eq!(
+            Intrinsic::from_name("syscall6"),
+            Some(Intrinsic::Syscall { arguments: 6 })
+        );
+        for name in ["syscall", "syscall7", "syscall01", "syscall+1", "sys"] {
+            assert_eq!(Intrinsic::from_name(name), None, "{name}");
+        }
+    }
+}
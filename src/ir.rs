@@ -0,0 +1,372 @@
+//! A typed intermediate representation in SSA form that sits between the AST
+//! and the backends. Each function is a control-flow graph of basic blocks
+//! whose values are defined exactly once. Instead of phi nodes, blocks take
+//! parameters that jumps pass arguments to.
+//!
+//! Values of type `unit` don't exist in the IR: expressions of that type
+//! don't produce a value, and `unit` parameters and arguments are dropped.
+//! Pointers keep their types, but backends treat them as `u64` addresses.
+//! So are struct and enum values: they are the addresses of the memory that
+//! holds them, which backends lay out according to `Program::types`.
+
+pub mod cfg;
+pub mod lower;
+mod print;
+pub mod verify;
+
+use crate::{
+    ast::{BinaryOperator, Span, Types},
+    typ::Type,
+};
+use internment::Intern;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Arc<Function>>,
+    pub externs: Vec<ExternFunction>,
+    /// The definitions of the structs and enums that the functions use.
+    pub types: Types,
+}
+
+impl Program {
+    pub fn function(&self, name: Intern<str>) -> Option<&Arc<Function>> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    pub fn extern_function(
+        &self,
+        name: Intern<str>,
+    ) -> Option<&ExternFunction> {
+        self.externs.iter().find(|function| function.name == name)
+    }
+
+    /// Whether any function reserves stack memory or accesses memory through
+    /// a pointer.
+    pub fn uses_memory(&self) -> bool {
+        self.functions.iter().any(|function| {
+            function.blocks.iter().any(|block| {
+                block.instructions.iter().any(|instruction| {
+                    matches!(
+                        instruction,
+                        Instruction::StackSlot { .. }
+                            | Instruction::Load { .. }
+                            | Instruction::Store { .. }
+                    )
+                })
+            })
+        })
+    }
+
+    pub fn uses_syscalls(&self) -> bool {
+        self.functions.iter().any(|function| {
+            function.blocks.iter().any(|block| {
+                block.instructions.iter().any(|instruction| {
+                    matches!(instruction, Instruction::Syscall { .. })
+                })
+            })
+        })
+    }
+}
+
+/// A function that is defined outside of the program and called with the C
+/// ABI.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternFunction {
+    pub name: Intern<str>,
+    pub parameter_types: Vec<Type>,
+    pub return_type: Type,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Function {
+    #[serde(with = "crate::cache::interned")]
+    pub name: Intern<str>,
+    /// Whether C code calls the function by its name, which makes its symbol
+    /// unmangled.
+    pub is_export: bool,
+    pub parameters: Vec<Value>,
+    pub return_type: Type,
+    /// Information about each value, indexed by `Value`.
+    pub values: Vec<ValueInfo>,
+    /// Indexed by `BlockId`. The first block is the entry point.
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    pub const ENTRY: BlockId = BlockId(0);
+
+    /// A function without blocks, for building IR by hand in tests.
+    #[cfg(test)]
+    pub fn new(
+        name: &str,
+        return_type: Type,
+        parameter_types: &[Type],
+    ) -> Self {
+        let mut function = Self {
+            name: name.into(),
+            is_export: false,
+            parameters: Vec::new(),
+            return_type,
+            values: Vec::new(),
+            blocks: Vec::new(),
+        };
+        function.parameters = parameter_types
+            .iter()
+            .map(|&typ| function.new_value(typ, None))
+            .collect();
+        function
+    }
+
+    pub fn new_value(&mut self, typ: Type, name: Option<Intern<str>>) -> Value {
+        self.values.push(ValueInfo {
+            typ,
+            name,
+            span: None,
+        });
+        Value(self.values.len() - 1)
+    }
+
+    pub fn typ(&self, value: Value) -> Type {
+        self.values[value.0].typ
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id.0]
+    }
+
+    pub fn block_mut(&mut self, id: BlockId) -> &mut Block {
+        &mut self.blocks[id.0]
+    }
+
+    pub fn block_ids(&self) -> impl Iterator<Item = BlockId> {
+        (0..self.blocks.len()).map(BlockId)
+    }
+
+    /// The types of the parameters, which is what callers need to know
+    /// together with `return_type`.
+    pub fn parameter_types(&self) -> Vec<Type> {
+        self.parameters
+            .iter()
+            .map(|&value| self.typ(value))
+            .collect()
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Deserialize,
+    Serialize,
+)]
+pub struct Value(pub usize);
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Deserialize,
+    Serialize,
+)]
+pub struct BlockId(pub usize);
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ValueInfo {
+    pub typ: Type,
+    /// The `let` binding or parameter that holds this value, if any.
+    #[serde(with = "crate::cache::optional_interned")]
+    pub name: Option<Intern<str>>,
+    /// The code that computes the value, relative to the start of the
+    /// function, for debug information. Values that optimizations create don't
+    /// have one.
+    pub span: Option<Span>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Block {
+    pub parameters: Vec<Value>,
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Instruction {
+    /// `value` is truncated to the type of `dest`.
+    Const {
+        dest: Value,
+        value: u64,
+    },
+    Copy {
+        dest: Value,
+        source: Value,
+    },
+    /// Both operands have the same type as `dest` and arithmetic wraps
+    /// around. The shift amount is taken modulo the width of the type, and
+    /// division by zero is undefined behavior.
+    Binary {
+        dest: Value,
+        operator: BinaryOperator,
+        left: Value,
+        right: Value,
+    },
+    /// `dest` is absent if the callee returns `unit`.
+    Call {
+        dest: Option<Value>,
+        #[serde(with = "crate::cache::interned")]
+        callee: Intern<str>,
+        arguments: Vec<Value>,
+    },
+    /// A Linux system call. The first argument is the number of the call and
+    /// up to six more are passed to it. All of them and `dest` are `i64`.
+    Syscall {
+        dest: Value,
+        arguments: Vec<Value>,
+    },
+    /// Reserves memory in the stack frame for a value of the type that `dest`
+    /// points to, aligned as its layout requires. The memory lives until the
+    /// function returns.
+    StackSlot {
+        dest: Value,
+    },
+    /// Reads a value of `dest`'s type, which isn't a struct or an enum, from
+    /// the memory that `address` points to.
+    Load {
+        dest: Value,
+        address: Value,
+    },
+    /// Writes `value`, which isn't a struct or an enum, to the memory that
+    /// `address` points to.
+    Store {
+        address: Value,
+        value: Value,
+    },
+    /// Converts between integer and pointer types, truncating `source` or
+    /// extending it according to its signedness. Pointers and struct or enum
+    /// values can also be converted to each other, which keeps the address.
+    Cast {
+        dest: Value,
+        source: Value,
+    },
+}
+
+impl Instruction {
+    pub fn dest(&self) -> Option<Value> {
+        match *self {
+            Self::Const { dest, .. }
+            | Self::Copy { dest, .. }
+            | Self::Binary { dest, .. }
+            | Self::Syscall { dest, .. }
+            | Self::StackSlot { dest }
+            | Self::Load { dest, .. }
+            | Self::Cast { dest, .. } => Some(dest),
+            Self::Call { dest, .. } => dest,
+            Self::Store { .. } => None,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Self::Const { .. } | Self::StackSlot { .. } => Vec::new(),
+            Self::Copy { source, .. } | Self::Cast { source, .. } => {
+                vec![*source]
+            }
+            Self::Load { address, .. } => vec![*address],
+            Self::Store { address, value } => vec![*address, *value],
+            Self::Binary { left, right, .. } => vec![*left, *right],
+            Self::Call { arguments, .. } | Self::Syscall { arguments, .. } => {
+                arguments.clone()
+            }
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Self::Const { .. } | Self::StackSlot { .. } => Vec::new(),
+            Self::Copy { source, .. } | Self::Cast { source, .. } => {
+                vec![source]
+            }
+            Self::Load { address, .. } => vec![address],
+            Self::Store { address, value } => vec![address, value],
+            Self::Binary { left, right, .. } => vec![left, right],
+            Self::Call { arguments, .. } | Self::Syscall { arguments, .. } => {
+                arguments.iter_mut().collect()
+            }
+        }
+    }
+
+    /// Whether the instruction does anything besides computing its result.
+    pub fn has_side_effects(&self) -> bool {
+        matches!(
+            self,
+            Self::Call { .. } | Self::Syscall { .. } | Self::Store { .. }
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Terminator {
+    /// The value is absent if the function returns `unit`.
+    Return(Option<Value>),
+    Jump(BlockId, Vec<Value>),
+    /// Jumps to `then` if the integer `value` lies within `low..=high` and to
+    /// `otherwise` if it doesn't. The bounds are truncated to the type of
+    /// `value` and compared according to its signedness, and `low` isn't
+    /// greater than `high`. Neither target takes parameters.
+    Branch {
+        value: Value,
+        low: u64,
+        high: u64,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+}
+
+impl Terminator {
+    pub fn operands(&self) -> Vec<Value> {
+        match self {
+            Self::Return(value) => value.iter().copied().collect(),
+            Self::Jump(_, arguments) => arguments.clone(),
+            Self::Branch { value, .. } => vec![*value],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Self::Return(value) => value.iter_mut().collect(),
+            Self::Jump(_, arguments) => arguments.iter_mut().collect(),
+            Self::Branch { value, .. } => vec![value],
+        }
+    }
+
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Self::Return(_) => Vec::new(),
+            Self::Jump(target, _) => vec![*target],
+            Self::Branch {
+                then, otherwise, ..
+            } => vec![*then, *otherwise],
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Self::Return(_) => Vec::new(),
+            Self::Jump(target, _) => vec![target],
+            Self::Branch {
+                then, otherwise, ..
+            } => vec![then, otherwise],
+        }
+    }
+}
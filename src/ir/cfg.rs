@@ -0,0 +1,180 @@
+//! Analyses of the control-flow graph of a function.
+
+use super::{BlockId, Function, Value};
+use std::collections::HashSet;
+
+/// The blocks that are reachable from the entry block, ordered so that each
+/// block comes before its successors except along back edges.
+pub fn reverse_postorder(function: &Function) -> Vec<BlockId> {
+    let mut visited = vec![false; function.blocks.len()];
+    let mut postorder = Vec::new();
+    // Each entry is a block and how many of its successors have been visited.
+    let mut stack = vec![(Function::ENTRY, 0)];
+    visited[Function::ENTRY.0] = true;
+    while let Some((block, next_successor)) = stack.last_mut() {
+        let successors = function.block(*block).terminator.successors();
+        if let Some(&successor) = successors.get(*next_successor) {
+            *next_successor += 1;
+            if !visited[successor.0] {
+                visited[successor.0] = true;
+                stack.push((successor, 0));
+            }
+        } else {
+            postorder.push(*block);
+            stack.pop();
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+/// The predecessors of each block, indexed by `BlockId`.
+pub fn predecessors(function: &Function) -> Vec<Vec<BlockId>> {
+    let mut predecessors = vec![Vec::new(); function.blocks.len()];
+    for block in function.block_ids() {
+        for successor in function.block(block).terminator.successors() {
+            if !predecessors[successor.0].contains(&block) {
+                predecessors[successor.0].push(block);
+            }
+        }
+    }
+    predecessors
+}
+
+/// The dominator tree of the reachable blocks of a function.
+pub struct Dominators {
+    /// The immediate dominator of each block, or `None` for the entry block
+    /// and unreachable blocks.
+    immediate: Vec<Option<BlockId>>,
+}
+
+impl Dominators {
+    /// Uses the algorithm from "A Simple, Fast Dominance Algorithm" by Cooper,
+    /// Harvey and Kennedy.
+    pub fn new(function: &Function) -> Self {
+        let order = reverse_postorder(function);
+        let mut rpo_index = vec![usize::MAX; function.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            rpo_index[block.0] = i;
+        }
+        let predecessors = predecessors(function);
+
+        let mut immediate = vec![None; function.blocks.len()];
+        immediate[Function::ENTRY.0] = Some(Function::ENTRY);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order[1..] {
+                let mut new_idom = None;
+                for &predecessor in &predecessors[block.0] {
+                    if immediate[predecessor.0].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => predecessor,
+                        Some(idom) => {
+                            intersect(&immediate, &rpo_index, predecessor, idom)
+                        }
+                    });
+                }
+                if new_idom != immediate[block.0] {
+                    immediate[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        immediate[Function::ENTRY.0] = None;
+        Self { immediate }
+    }
+
+    /// Whether every path from the entry block to `b` goes through `a`.
+    pub fn dominates(&self, a: BlockId, mut b: BlockId) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.immediate[b.0] {
+                Some(idom) => b = idom,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// The values that are live at the start and at the end of each block,
+/// indexed by `BlockId`. Block parameters are defined at the start of their
+/// block and arguments of jumps are used at the end of theirs.
+pub struct Liveness {
+    pub live_in: Vec<HashSet<Value>>,
+    pub live_out: Vec<HashSet<Value>>,
+}
+
+impl Liveness {
+    pub fn new(function: &Function) -> Self {
+        // The values that each block uses before defining them, and the ones
+        // that it defines.
+        let mut uses = Vec::new();
+        let mut defs = Vec::new();
+        for id in function.block_ids() {
+            let block = function.block(id);
+            let mut block_defs =
+                block.parameters.iter().copied().collect::<HashSet<_>>();
+            let mut block_uses = HashSet::new();
+            for instruction in &block.instructions {
+                for operand in instruction.operands() {
+                    if !block_defs.contains(&operand) {
+                        block_uses.insert(operand);
+                    }
+                }
+                block_defs.extend(instruction.dest());
+            }
+            for operand in block.terminator.operands() {
+                if !block_defs.contains(&operand) {
+                    block_uses.insert(operand);
+                }
+            }
+            uses.push(block_uses);
+            defs.push(block_defs);
+        }
+
+        let mut live_in = uses.clone();
+        let mut live_out = vec![HashSet::new(); function.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for id in (0..function.blocks.len()).rev().map(BlockId) {
+                let out = function
+                    .block(id)
+                    .terminator
+                    .successors()
+                    .into_iter()
+                    .flat_map(|successor| live_in[successor.0].clone())
+                    .collect::<HashSet<_>>();
+                for &value in &out {
+                    if !defs[id.0].contains(&value) {
+                        changed |= live_in[id.0].insert(value);
+                    }
+                }
+                live_out[id.0] = out;
+            }
+        }
+        Self { live_in, live_out }
+    }
+}
+
+fn intersect(
+    immediate: &[Option<BlockId>],
+    rpo_index: &[usize],
+    mut a: BlockId,
+    mut b: BlockId,
+) -> BlockId {
+    while a != b {
+        while rpo_index[a.0] > rpo_index[b.0] {
+            a = immediate[a.0].expect("processed blocks have a dominator");
+        }
+        while rpo_index[b.0] > rpo_index[a.0] {
+            b = immediate[b.0].expect("processed blocks have a dominator");
+        }
+    }
+    a
+}
@@ -0,0 +1,983 @@
+//! Lowering from the AST to the IR. Lowering only succeeds for functions that
+//! are free of syntax and type errors.
+//!
+//! A struct or enum value is the address of a stack slot that holds its
+//! fields or its tag and payload and that is never written to after they are
+//! stored. Reading one from memory that can change copies it into a new slot.
+//! Functions that return a struct or enum instead take a pointer to a slot of
+//! the caller as an extra last parameter and copy their result into it.
+//!
+//! A `match` tests the patterns of its arms one after another, branching on
+//! the value or on the tag of an enum. The last arm isn't tested, since the
+//! checker makes sure that every value reaches an arm. The arms then jump to
+//! a block after all of them that takes the result as a parameter.
+
+use super::{
+    Block, BlockId, ExternFunction, Function, Instruction, Terminator, Value,
+};
+use crate::{
+    ast::{
+        self, BinaryOperator, Expr, FunctionSignature, Pattern, Span,
+        Statement, SyntaxError, Types,
+    },
+    intrinsics::Intrinsic,
+    layout::{Layout, TAG},
+    typ::Type,
+};
+use internment::Intern;
+use std::{collections::HashMap, sync::Arc};
+
+/// The function can't be lowered because it contains errors.
+#[derive(Debug)]
+pub struct Invalid;
+
+impl From<SyntaxError> for Invalid {
+    fn from(SyntaxError: SyntaxError) -> Self {
+        Self
+    }
+}
+
+impl From<&SyntaxError> for Invalid {
+    fn from(SyntaxError: &SyntaxError) -> Self {
+        Self
+    }
+}
+
+type Result<T> = std::result::Result<T, Invalid>;
+
+/// Lowers `function`, looking up the functions it calls using `signature_of`.
+pub fn lower_function(
+    function: &ast::Function,
+    types: &Types,
+    signature_of: impl FnMut(Intern<str>) -> Option<Arc<FunctionSignature>>,
+) -> Result<Function> {
+    let signature = &function.signature;
+    let return_type = signature.return_type?;
+    let mut lowerer = Lowerer {
+        function: Function {
+            name: signature.name?,
+            is_export: function.is_export,
+            parameters: Vec::new(),
+            return_type: match return_type {
+                Type::Named(_) => Type::Unit,
+                typ => typ,
+            },
+            values: Vec::new(),
+            blocks: Vec::new(),
+        },
+        current_block: Function::ENTRY,
+        instructions: Vec::new(),
+        scopes: vec![HashMap::new()],
+        addressed: function.addressed_variables(),
+        span: None,
+        types,
+        signature_of,
+    };
+    lowerer.function.blocks.push(Block {
+        parameters: Vec::new(),
+        instructions: Vec::new(),
+        terminator: Terminator::Return(None),
+    });
+
+    for (pattern, typ) in &signature.parameters.as_ref()?.0 {
+        let Expr::Identifier { name, span } = pattern else {
+            return Err(Invalid);
+        };
+        lowerer.span = Some(*span);
+        let value = (*typ != Type::Unit).then(|| {
+            let value = lowerer.new_value(*typ, Some(*name));
+            lowerer.function.parameters.push(value);
+            value
+        });
+        lowerer.bind(*name, *typ, value)?;
+    }
+    lowerer.span = None;
+    let result_address = matches!(return_type, Type::Named(_)).then(|| {
+        let address = lowerer.new_value(Type::pointer(return_type, true), None);
+        lowerer.function.parameters.push(address);
+        address
+    });
+
+    let body = function.body.as_ref().ok_or(Invalid)?;
+    let mut result = lowerer.lower_block(body.as_ref()?)?;
+    if let Some(address) = result_address {
+        lowerer.copy(address, result.ok_or(Invalid)?)?;
+        result = None;
+    }
+    lowerer.finish_block(Terminator::Return(result));
+    Ok(lowerer.function)
+}
+
+/// Lowers the signature of an `extern` function. `unit` parameters and return
+/// types don't exist in C, so the checker rejects the former and the latter
+/// becomes `void`.
+pub fn lower_extern(function: &ast::Function) -> Result<ExternFunction> {
+    let signature = &function.signature;
+    Ok(ExternFunction {
+        name: signature.name?,
+        parameter_types: signature
+            .parameters
+            .as_ref()?
+            .0
+            .iter()
+            .map(|(_, typ)| *typ)
+            .collect(),
+        return_type: signature.return_type?,
+    })
+}
+
+struct Lowerer<'a, F> {
+    function: Function,
+    current_block: BlockId,
+    /// Instructions of the current block.
+    instructions: Vec<Instruction>,
+    /// Variables in scope.
+    scopes: Vec<HashMap<Intern<str>, Variable>>,
+    /// Names of variables whose address is taken somewhere in the function,
+    /// which makes every variable with one of these names live in memory.
+    addressed: Vec<Intern<str>>,
+    /// The innermost `let` or expression with a span that is being lowered.
+    /// Values without a span of their own, like those of literals, get this
+    /// one.
+    span: Option<Span>,
+    types: &'a Types,
+    signature_of: F,
+}
+
+#[derive(Clone, Copy)]
+enum Variable {
+    /// The value of the variable, which is absent if it has type `unit`.
+    Value(Option<Value>),
+    /// A pointer to the stack slot that holds the variable.
+    Memory(Value),
+}
+
+impl<F: FnMut(Intern<str>) -> Option<Arc<FunctionSignature>>> Lowerer<'_, F> {
+    fn finish_block(&mut self, terminator: Terminator) {
+        let block = self.function.block_mut(self.current_block);
+        block.instructions = std::mem::take(&mut self.instructions);
+        block.terminator = terminator;
+    }
+
+    /// Adds a block without parameters, which code can be lowered into once
+    /// it becomes the current block.
+    fn new_block(&mut self) -> BlockId {
+        self.function.blocks.push(Block {
+            parameters: Vec::new(),
+            instructions: Vec::new(),
+            terminator: Terminator::Return(None),
+        });
+        BlockId(self.function.blocks.len() - 1)
+    }
+
+    /// Creates a value that the code being lowered computes.
+    fn new_value(&mut self, typ: Type, name: Option<Intern<str>>) -> Value {
+        let value = self.function.new_value(typ, name);
+        self.function.values[value.0].span = self.span;
+        value
+    }
+
+    fn bind(
+        &mut self,
+        name: Intern<str>,
+        typ: Type,
+        value: Option<Value>,
+    ) -> Result<()> {
+        let variable = if self.addressed.contains(&name) {
+            let address = self.stack_slot(typ);
+            if let Some(value) = value {
+                self.store(address, value)?;
+            }
+            Variable::Memory(address)
+        } else {
+            Variable::Value(value)
+        };
+        self.scopes
+            .last_mut()
+            .expect("there is always a scope")
+            .insert(name, variable);
+        Ok(())
+    }
+
+    /// Binds `name` to a copy of `value` that carries the name.
+    fn bind_copy(
+        &mut self,
+        name: Intern<str>,
+        typ: Type,
+        value: Option<Value>,
+    ) -> Result<()> {
+        let value = value.map(|source| {
+            let dest = self.new_value(typ, Some(name));
+            self.instructions.push(Instruction::Copy { dest, source });
+            dest
+        });
+        self.bind(name, typ, value)
+    }
+
+    /// Reserves memory for a value of type `typ`, returning a pointer to it.
+    fn stack_slot(&mut self, typ: Type) -> Value {
+        let dest = self.new_value(Type::pointer(typ, true), None);
+        self.instructions.push(Instruction::StackSlot { dest });
+        dest
+    }
+
+    fn variable(&self, name: Intern<str>) -> Result<Variable> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name).copied())
+            .ok_or(Invalid)
+    }
+
+    /// Reads the value that `address` points to, which is absent if it has
+    /// type `unit`.
+    fn load(&mut self, address: Value) -> Result<Option<Value>> {
+        let typ = self.function.typ(address).pointee().ok_or(Invalid)?;
+        match typ {
+            Type::Unit => Ok(None),
+            Type::Named(_) => {
+                let copy = self.stack_slot(typ);
+                self.copy(copy, address)?;
+                Ok(Some(self.cast(copy, typ)))
+            }
+            _ => {
+                let dest = self.new_value(typ, None);
+                self.instructions.push(Instruction::Load { dest, address });
+                Ok(Some(dest))
+            }
+        }
+    }
+
+    /// Writes `value` to the memory that `address` points to.
+    fn store(&mut self, address: Value, value: Value) -> Result<()> {
+        if let Type::Named(_) = self.function.typ(value) {
+            self.copy(address, value)
+        } else {
+            self.instructions
+                .push(Instruction::Store { address, value });
+            Ok(())
+        }
+    }
+
+    /// Copies the struct or enum at `source`, which is a value or a pointer
+    /// to one, to the memory that `dest` points to. Structs are copied field
+    /// by field and enums word by word, since only the tag tells which
+    /// payload they hold.
+    fn copy(&mut self, dest: Value, source: Value) -> Result<()> {
+        let name = self.type_name_at(source)?;
+        let Some(definition) = self.types.structs.get(&name).cloned() else {
+            let layout =
+                Layout::of(Type::Named(name), self.types).ok_or(Invalid)?;
+            let word = if layout.align == 8 {
+                Type::U64
+            } else {
+                Type::U32
+            };
+            for offset in (0..layout.size).step_by(layout.align as usize) {
+                let dest = self.offset_address(dest, offset, word);
+                let source = self.offset_address(source, offset, word);
+                let value = self.load(source)?.ok_or(Invalid)?;
+                self.instructions.push(Instruction::Store {
+                    address: dest,
+                    value,
+                });
+            }
+            return Ok(());
+        };
+        for (index, field) in definition.fields.as_ref()?.iter().enumerate() {
+            if field.typ == Type::Unit {
+                continue;
+            }
+            let dest = self.field_address(dest, index)?;
+            let source = self.field_address(source, index)?;
+            if let Type::Named(_) = field.typ {
+                self.copy(dest, source)?;
+            } else {
+                let value = self.load(source)?.ok_or(Invalid)?;
+                self.instructions.push(Instruction::Store {
+                    address: dest,
+                    value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The name of the struct or enum at `address`, which is a value or a
+    /// pointer to one.
+    fn type_name_at(&self, address: Value) -> Result<Intern<str>> {
+        let typ = self.function.typ(address);
+        match typ.pointee().unwrap_or(typ) {
+            Type::Named(name) => Ok(name),
+            _ => Err(Invalid),
+        }
+    }
+
+    /// The definition of the struct at `address`, which is a struct value or a
+    /// pointer to one.
+    fn struct_at(&self, address: Value) -> Result<Arc<ast::Struct>> {
+        let name = self.type_name_at(address)?;
+        self.types.structs.get(&name).cloned().ok_or(Invalid)
+    }
+
+    /// The definition of the enum at `address`, which is an enum value or a
+    /// pointer to one.
+    fn enum_at(&self, address: Value) -> Result<Arc<ast::Enum>> {
+        let name = self.type_name_at(address)?;
+        self.types.enums.get(&name).cloned().ok_or(Invalid)
+    }
+
+    /// A pointer to a field of the struct at `address`, which is a struct
+    /// value or a pointer to one. The pointer is `*mut` if `address` is.
+    fn field_address(&mut self, address: Value, index: usize) -> Result<Value> {
+        let definition = self.struct_at(address)?;
+        let typ = self.function.typ(address);
+        let layout = Layout::of(typ.pointee().unwrap_or(typ), self.types)
+            .ok_or(Invalid)?;
+        let field = definition.fields.as_ref()?.get(index).ok_or(Invalid)?;
+        Ok(self.offset_address(address, layout.offsets[index], field.typ))
+    }
+
+    /// A pointer to the value of type `typ` that is `offset` bytes into the
+    /// memory at `address`, which is a struct or enum value or a pointer. The
+    /// pointer is `*mut` if `address` is.
+    fn offset_address(
+        &mut self,
+        address: Value,
+        offset: u64,
+        typ: Type,
+    ) -> Value {
+        let is_mutable = matches!(
+            self.function.typ(address),
+            Type::Pointer {
+                is_mutable: true,
+                ..
+            }
+        );
+        let pointer = Type::pointer(typ, is_mutable);
+        let base = self.cast(address, pointer);
+        if offset == 0 {
+            return base;
+        }
+        let offset_value = self.new_value(pointer, None);
+        self.instructions.push(Instruction::Const {
+            dest: offset_value,
+            value: offset,
+        });
+        let dest = self.new_value(pointer, None);
+        self.instructions.push(Instruction::Binary {
+            dest,
+            operator: BinaryOperator::Add,
+            left: base,
+            right: offset_value,
+        });
+        dest
+    }
+
+    /// The address of a variable that lives in memory, of what a dereference
+    /// points to or of a field of one of those or of a struct value. The
+    /// result is a pointer or, for a struct value, the value itself.
+    fn lower_address(&mut self, expr: &Expr) -> Result<Value> {
+        match expr {
+            Expr::Identifier { name, .. } => match self.variable(*name)? {
+                Variable::Memory(address) => Ok(address),
+                Variable::Value(value) => value.ok_or(Invalid),
+            },
+            Expr::Dereference { pointer, .. } => {
+                self.lower_expr(pointer.as_ref()?)?.ok_or(Invalid)
+            }
+            Expr::Field { value, field, span } => {
+                let outer = self.span.replace(*span);
+                let address = self.lower_address(value.as_ref()?)?;
+                let (index, _) = self
+                    .struct_at(address)?
+                    .field(*field.as_ref()?)
+                    .ok_or(Invalid)?;
+                let address = self.field_address(address, index)?;
+                self.span = outer;
+                Ok(address)
+            }
+            expr => self.lower_expr(expr)?.ok_or(Invalid),
+        }
+    }
+
+    /// Converts `source` to `typ` unless it already has that type.
+    fn cast(&mut self, source: Value, typ: Type) -> Value {
+        if self.function.typ(source) == typ {
+            return source;
+        }
+        let dest = self.new_value(typ, None);
+        self.instructions.push(Instruction::Cast { dest, source });
+        dest
+    }
+
+    fn lower_block(&mut self, block: &ast::Block) -> Result<Option<Value>> {
+        self.scopes.push(HashMap::new());
+        for statement in &block.statements {
+            match statement.as_ref()? {
+                Statement::Expr(expr) => {
+                    self.lower_expr(expr.as_ref()?)?;
+                }
+                Statement::Let { pattern, value } => {
+                    let Expr::Identifier { name, span } = pattern.as_ref()?
+                    else {
+                        return Err(Invalid);
+                    };
+                    let outer = self.span.replace(*span);
+                    let value = self.lower_expr(value.as_ref()?)?;
+                    let typ = value
+                        .map_or(Type::Unit, |value| self.function.typ(value));
+                    self.bind_copy(*name, typ, value)?;
+                    self.span = outer;
+                }
+                Statement::Assign {
+                    target,
+                    value,
+                    span,
+                } => {
+                    let outer = self.span.replace(*span);
+                    let address = self.lower_address(target.as_ref()?)?;
+                    if let Some(value) = self.lower_expr(value.as_ref()?)? {
+                        self.store(address, value)?;
+                    }
+                    self.span = outer;
+                }
+            }
+        }
+        let result = match &block.result {
+            Some(result) => self.lower_expr(result.as_ref()?)?,
+            None => None,
+        };
+        self.scopes.pop();
+        Ok(result)
+    }
+
+    /// Returns the value of `expr`, which is absent if it has type `unit`.
+    fn lower_expr(&mut self, expr: &Expr) -> Result<Option<Value>> {
+        match expr {
+            Expr::Block(block) => self.lower_block(block),
+            Expr::Identifier { name, .. } => match self.variable(*name)? {
+                Variable::Value(value) => Ok(value),
+                Variable::Memory(address) => self.load(address),
+            },
+            Expr::FunctionCall {
+                name,
+                arguments,
+                span,
+            } => {
+                let outer = self.span.replace(*span);
+                let callee = *name.as_ref()?;
+                let signature = (self.signature_of)(callee).ok_or(Invalid)?;
+                let return_type = signature.return_type?;
+                let mut argument_values = Vec::new();
+                for argument in &arguments.as_ref()?.0 {
+                    argument_values
+                        .extend(self.lower_expr(argument.as_ref()?)?);
+                }
+                if let Type::Named(_) = return_type {
+                    let result = self.stack_slot(return_type);
+                    argument_values.push(result);
+                    self.instructions.push(Instruction::Call {
+                        dest: None,
+                        callee,
+                        arguments: argument_values,
+                    });
+                    let result = self.cast(result, return_type);
+                    self.span = outer;
+                    return Ok(Some(result));
+                }
+                let dest = (return_type != Type::Unit)
+                    .then(|| self.new_value(return_type, None));
+                self.instructions.push(match Intrinsic::from_name(&callee) {
+                    Some(Intrinsic::Syscall { .. }) => Instruction::Syscall {
+                        dest: dest.expect("system calls return `i64`"),
+                        arguments: argument_values,
+                    },
+                    None => Instruction::Call {
+                        dest,
+                        callee,
+                        arguments: argument_values,
+                    },
+                });
+                self.span = outer;
+                Ok(dest)
+            }
+            Expr::IntLiteral(literal) => {
+                let typ = literal.typ();
+                let dest = self.new_value(typ, None);
+                self.instructions.push(Instruction::Const {
+                    dest,
+                    value: typ.truncate(literal.value()? as u64),
+                });
+                Ok(Some(dest))
+            }
+            Expr::Binary {
+                operator,
+                left,
+                right,
+                span,
+            } => {
+                let outer = self.span.replace(*span);
+                let left = self.lower_expr(left.as_ref()?)?.ok_or(Invalid)?;
+                let mut right =
+                    self.lower_expr(right.as_ref()?)?.ok_or(Invalid)?;
+                let typ = self.function.typ(left);
+                if let Some(pointee) = typ.pointee() {
+                    // Scales the offset from elements to bytes.
+                    let layout =
+                        Layout::of(pointee, self.types).ok_or(Invalid)?;
+                    right = self.cast(right, Type::U64);
+                    if layout.size != 1 {
+                        let size = self.new_value(Type::U64, None);
+                        self.instructions.push(Instruction::Const {
+                            dest: size,
+                            value: layout.size,
+                        });
+                        let bytes = self.new_value(Type::U64, None);
+                        self.instructions.push(Instruction::Binary {
+                            dest: bytes,
+                            operator: BinaryOperator::Mul,
+                            left: right,
+                            right: size,
+                        });
+                        right = bytes;
+                    }
+                    right = self.cast(right, typ);
+                }
+                let dest = self.new_value(typ, None);
+                self.instructions.push(Instruction::Binary {
+                    dest,
+                    operator: *operator,
+                    left,
+                    right,
+                });
+                self.span = outer;
+                Ok(Some(dest))
+            }
+            Expr::AddressOf {
+                is_mutable,
+                value,
+                span,
+            } => {
+                let outer = self.span.replace(*span);
+                let address = self.lower_address(value.as_ref()?)?;
+                let pointee =
+                    self.function.typ(address).pointee().ok_or(Invalid)?;
+                let address =
+                    self.cast(address, Type::pointer(pointee, *is_mutable));
+                self.span = outer;
+                Ok(Some(address))
+            }
+            Expr::Dereference { pointer, span } => {
+                let outer = self.span.replace(*span);
+                let address =
+                    self.lower_expr(pointer.as_ref()?)?.ok_or(Invalid)?;
+                let value = self.load(address)?;
+                self.span = outer;
+                Ok(value)
+            }
+            Expr::Cast { value, typ, span } => {
+                let outer = self.span.replace(*span);
+                let typ = *typ.as_ref()?;
+                let value = match self.lower_expr(value.as_ref()?)? {
+                    Some(value) => Some(self.cast(value, typ)),
+                    None if typ == Type::Unit => None,
+                    None => return Err(Invalid),
+                };
+                self.span = outer;
+                Ok(value)
+            }
+            Expr::StructLiteral { name, fields, span } => {
+                let outer = self.span.replace(*span);
+                let typ = Type::Named(*name.as_ref()?);
+                let address = self.stack_slot(typ);
+                for field in fields.as_ref()? {
+                    let value = self.lower_expr(field.value.as_ref()?)?;
+                    let (index, _) = self
+                        .struct_at(address)?
+                        .field(field.name)
+                        .ok_or(Invalid)?;
+                    let field_address = self.field_address(address, index)?;
+                    if let Some(value) = value {
+                        self.store(field_address, value)?;
+                    }
+                }
+                let value = self.cast(address, typ);
+                self.span = outer;
+                Ok(Some(value))
+            }
+            Expr::Field { span, .. } => {
+                let outer = self.span.replace(*span);
+                let address = self.lower_address(expr)?;
+                let value = self.load(address)?;
+                self.span = outer;
+                Ok(value)
+            }
+            Expr::Variant {
+                name,
+                variant,
+                payload,
+                span,
+            } => {
+                let outer = self.span.replace(*span);
+                let typ = Type::Named(*name.as_ref()?);
+                let address = self.stack_slot(typ);
+                let (index, declared) = self
+                    .enum_at(address)?
+                    .variant(*variant.as_ref()?)
+                    .map(|(index, declared)| (index, declared.clone()))
+                    .ok_or(Invalid)?;
+                let tag = self.new_value(TAG, None);
+                self.instructions.push(Instruction::Const {
+                    dest: tag,
+                    value: index as u64,
+                });
+                let tag_address = self.offset_address(address, 0, TAG);
+                self.store(tag_address, tag)?;
+                let layout = Layout::of(typ, self.types).ok_or(Invalid)?;
+                for (i, value) in payload.0.iter().enumerate() {
+                    let value = self.lower_expr(value.as_ref()?)?;
+                    let payload_type =
+                        *declared.payload.get(i).ok_or(Invalid)?;
+                    let offset = layout.variants[index][i];
+                    let value_address =
+                        self.offset_address(address, offset, payload_type);
+                    if let Some(value) = value {
+                        self.store(value_address, value)?;
+                    }
+                }
+                let value = self.cast(address, typ);
+                self.span = outer;
+                Ok(Some(value))
+            }
+            Expr::Match { value, arms, span } => {
+                let outer = self.span.replace(*span);
+                let scrutinee = self.lower_expr(value.as_ref()?)?;
+                let typ = scrutinee
+                    .map_or(Type::Unit, |value| self.function.typ(value));
+                let arms = arms.as_ref()?;
+                let mut tag = None;
+                // The block at the end of each arm and its result.
+                let mut ends = Vec::new();
+                for (i, arm) in arms.iter().enumerate() {
+                    let pattern = arm.pattern.as_ref()?;
+                    let range = match pattern {
+                        _ if i == arms.len() - 1 => None,
+                        Pattern::Wildcard | Pattern::Binding(_) => None,
+                        Pattern::IntLiteral(literal) => {
+                            let value = typ.truncate(literal.value()? as u64);
+                            Some((scrutinee.ok_or(Invalid)?, value, value))
+                        }
+                        Pattern::Range(start, end) => Some((
+                            scrutinee.ok_or(Invalid)?,
+                            typ.truncate(start.value()? as u64),
+                            typ.truncate(end.value()? as u64),
+                        )),
+                        Pattern::Variant { variant, .. } => {
+                            let scrutinee = scrutinee.ok_or(Invalid)?;
+                            let (index, _) = self
+                                .enum_at(scrutinee)?
+                                .variant(*variant)
+                                .ok_or(Invalid)?;
+                            let tag = match tag {
+                                Some(tag) => tag,
+                                None => {
+                                    let address =
+                                        self.offset_address(scrutinee, 0, TAG);
+                                    let value =
+                                        self.load(address)?.ok_or(Invalid)?;
+                                    *tag.insert(value)
+                                }
+                            };
+                            Some((tag, index as u64, index as u64))
+                        }
+                    };
+                    let otherwise = range.map(|(value, low, high)| {
+                        let then = self.new_block();
+                        let otherwise = self.new_block();
+                        self.finish_block(Terminator::Branch {
+                            value,
+                            low,
+                            high,
+                            then,
+                            otherwise,
+                        });
+                        self.current_block = then;
+                        otherwise
+                    });
+
+                    self.scopes.push(HashMap::new());
+                    self.span = Some(arm.pattern_span);
+                    match pattern {
+                        Pattern::Binding(name) => {
+                            self.bind_copy(*name, typ, scrutinee)?;
+                        }
+                        Pattern::Variant {
+                            variant, bindings, ..
+                        } => {
+                            let scrutinee = scrutinee.ok_or(Invalid)?;
+                            let layout =
+                                Layout::of(typ, self.types).ok_or(Invalid)?;
+                            let (index, declared) = self
+                                .enum_at(scrutinee)?
+                                .variant(*variant)
+                                .map(|(index, declared)| {
+                                    (index, declared.clone())
+                                })
+                                .ok_or(Invalid)?;
+                            for (i, binding) in bindings.iter().enumerate() {
+                                let Some(name) = binding else {
+                                    continue;
+                                };
+                                let payload_type =
+                                    *declared.payload.get(i).ok_or(Invalid)?;
+                                let address = self.offset_address(
+                                    scrutinee,
+                                    layout.variants[index][i],
+                                    payload_type,
+                                );
+                                let value = self.load(address)?;
+                                self.bind_copy(*name, payload_type, value)?;
+                            }
+                        }
+                        _ => {}
+                    }
+                    self.span = Some(*span);
+                    let result = self.lower_expr(arm.value.as_ref()?)?;
+                    self.scopes.pop();
+                    ends.push((self.current_block, result));
+                    self.finish_block(Terminator::Return(None));
+                    if let Some(otherwise) = otherwise {
+                        self.current_block = otherwise;
+                    }
+                }
+
+                let join = self.new_block();
+                let result = ends[0].1.map(|value| {
+                    let typ = self.function.typ(value);
+                    let parameter = self.new_value(typ, None);
+                    self.function.block_mut(join).parameters.push(parameter);
+                    parameter
+                });
+                for (end, value) in ends {
+                    self.function.block_mut(end).terminator =
+                        Terminator::Jump(join, value.into_iter().collect());
+                }
+                self.current_block = join;
+                self.span = outer;
+                Ok(result)
+            }
+        }
+    }
+}
+
+/// Lowers every function in `text` and optimizes the resulting program, for
+/// tests of what comes after lowering.
+#[cfg(test)]
+pub fn lower_text(text: &str, level: crate::opt::OptLevel) -> super::Program {
+    let mut db = crate::db::Database::new();
+    let file = "test.gneiss".into();
+    db.set_text(file, text.into());
+    let mut program = super::Program {
+        functions: Vec::new(),
+        externs: Vec::new(),
+        types: db.types(file).as_ref().clone(),
+    };
+    for function in db.ast(file).functions() {
+        if function.is_extern {
+            program.externs.push(lower_extern(function).unwrap());
+        } else {
+            let name = function.signature.name.unwrap();
+            program.functions.push(db.lower(file, name).unwrap());
+        }
+    }
+    crate::opt::optimize(&mut program, level, false);
+    program
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Database;
+
+    fn lower(text: &str, name: &str) -> String {
+        let mut db = Database::new();
+        let file = "test.gneiss".into();
+        db.set_text(file, text.into());
+        db.lower(file, name.into()).unwrap().to_string()
+    }
+
+    #[test]
+    fn lowers_blocks_lets_and_calls() {
+        let text = "
+fn f() -> i8 {
+    let x = { g(); -1_i8 };
+    let unused = g();
+    x
+}
+fn g() -> unit {}
+";
+        assert_eq!(
+            lower(text, "f"),
+            "\
+fn f() -> i8 {
+bb0:
+    call g()
+    v0: i8 = const -1
+    v1: i8 = copy v0 // x
+    call g()
+    return v1
+}
+"
+        );
+    }
+
+    #[test]
+    fn addressed_variables_live_in_stack_slots() {
+        let text = "
+fn f(x: i32) -> i32 {
+    let p = &mut x;
+    *p = 1_i32;
+    x
+}
+";
+        assert_eq!(
+            lower(text, "f"),
+            "\
+fn f(v0: i32) -> i32 { // v0 is x
+bb0:
+    v1: *mut i32 = stack_slot
+    store v1, v0
+    v2: *mut i32 = copy v1 // p
+    v3: i32 = const 1
+    store v2, v3
+    v4: i32 = load v1
+    return v4
+}
+"
+        );
+    }
+
+    #[test]
+    fn pointer_arithmetic_counts_elements() {
+        let text = "
+fn f(p: *u32) -> *u32 { p - 1_i8 }
+";
+        assert_eq!(
+            lower(text, "f"),
+            "\
+fn f(v0: *u32) -> *u32 { // v0 is p
+bb0:
+    v1: i8 = const 1
+    v2: u64 = cast v1
+    v3: u64 = const 4
+    v4: u64 = v2 * v3
+    v5: *u32 = cast v4
+    v6: *u32 = v0 - v5
+    return v6
+}
+"
+        );
+    }
+
+    #[test]
+    fn structs_are_copied_field_by_field() {
+        let text = "
+struct Pair { a: u8, b: u32 }
+fn f(b: u32) -> Pair { Pair { b: b, a: 1_u8 } }
+fn g(p: Pair) -> u32 { f(p.b).a as u32 }
+";
+        assert_eq!(
+            lower(text, "f"),
+            "\
+fn f(v0: u32, v1: *mut Pair) -> unit { // v0 is b
+bb0:
+    v2: *mut Pair = stack_slot
+    v3: *mut u32 = cast v2
+    v4: *mut u32 = const 4
+    v5: *mut u32 = v3 + v4
+    store v5, v0
+    v6: u8 = const 1
+    v7: *mut u8 = cast v2
+    store v7, v6
+    v8: Pair = cast v2
+    v9: *mut u8 = cast v1
+    v10: *u8 = cast v8
+    v11: u8 = load v10
+    store v9, v11
+    v12: *mut u32 = cast v1
+    v13: *mut u32 = const 4
+    v14: *mut u32 = v12 + v13
+    v15: *u32 = cast v8
+    v16: *u32 = const 4
+    v17: *u32 = v15 + v16
+    v18: u32 = load v17
+    store v14, v18
+    return
+}
+"
+        );
+        assert_eq!(
+            lower(text, "g"),
+            "\
+fn g(v0: Pair) -> u32 { // v0 is p
+bb0:
+    v1: *u32 = cast v0
+    v2: *u32 = const 4
+    v3: *u32 = v1 + v2
+    v4: u32 = load v3
+    v5: *mut Pair = stack_slot
+    call f(v4, v5)
+    v6: Pair = cast v5
+    v7: *u8 = cast v6
+    v8: u8 = load v7
+    v9: u32 = cast v8
+    return v9
+}
+"
+        );
+    }
+
+    #[test]
+    fn matches_test_arms_in_order() {
+        let text = "
+enum Reading { Missing, Value(i8) }
+fn f(r: Reading) -> u8 {
+    match r {
+        Reading::Value(x) => match x { -5_i8..=5_i8 => 1_u8, _ => 2_u8 },
+        Reading::Missing => 0_u8,
+    }
+}
+";
+        assert_eq!(
+            lower(text, "f"),
+            "\
+fn f(v0: Reading) -> u8 { // v0 is r
+bb0:
+    v1: *u32 = cast v0
+    v2: u32 = load v1
+    branch v2 in 1..=1 then bb1 else bb2
+bb1:
+    v3: *i8 = cast v0
+    v4: *i8 = const 4
+    v5: *i8 = v3 + v4
+    v6: i8 = load v5
+    v7: i8 = copy v6 // x
+    branch v7 in -5..=5 then bb3 else bb4
+bb2:
+    v11: u8 = const 0
+    jump bb6(v11)
+bb3:
+    v8: u8 = const 1
+    jump bb5(v8)
+bb4:
+    v9: u8 = const 2
+    jump bb5(v9)
+bb5(v10: u8):
+    jump bb6(v10)
+bb6(v12: u8):
+    return v12
+}
+"
+        );
+    }
+}
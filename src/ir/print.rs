@@ -0,0 +1,200 @@
+//! The textual form of the IR, used for `--emit=ir` and for dumps between
+//! passes. Names of `let` bindings and parameters are shown in comments.
+
+use super::{
+    Block, BlockId, ExternFunction, Function, Instruction, Program, Terminator,
+    Value,
+};
+use std::fmt;
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for function in &self.externs {
+            writeln!(f, "{function}")?;
+        }
+        for (i, function) in self.functions.iter().enumerate() {
+            if i != 0 || !self.externs.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "{function}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExternFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "extern fn {}(", self.name)?;
+        for (i, typ) in self.parameter_types.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{typ}")?;
+        }
+        write!(f, ") -> {}", self.return_type)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_export {
+            f.write_str("export ")?;
+        }
+        write!(f, "fn {}(", self.name)?;
+        self.write_parameters(f, &self.parameters)?;
+        write!(f, ") -> {} {{", self.return_type)?;
+        self.write_names(f, &self.parameters)?;
+        writeln!(f)?;
+        for id in self.block_ids() {
+            self.write_block(f, id, self.block(id))?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl Function {
+    fn write_parameters(
+        &self,
+        f: &mut fmt::Formatter,
+        parameters: &[Value],
+    ) -> fmt::Result {
+        for (i, &parameter) in parameters.iter().enumerate() {
+            if i != 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{parameter}: {}", self.typ(parameter))?;
+        }
+        Ok(())
+    }
+
+    /// Writes a comment with the source names of the values, if any.
+    fn write_names(
+        &self,
+        f: &mut fmt::Formatter,
+        values: &[Value],
+    ) -> fmt::Result {
+        let mut named = values
+            .iter()
+            .filter_map(|&value| Some((value, self.values[value.0].name?)));
+        if let Some((value, name)) = named.next() {
+            write!(f, " // {value} is {name}")?;
+            for (value, name) in named {
+                write!(f, ", {value} is {name}")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(
+        &self,
+        f: &mut fmt::Formatter,
+        id: BlockId,
+        block: &Block,
+    ) -> fmt::Result {
+        write!(f, "{id}")?;
+        if !block.parameters.is_empty() {
+            f.write_str("(")?;
+            self.write_parameters(f, &block.parameters)?;
+            f.write_str(")")?;
+        }
+        f.write_str(":")?;
+        self.write_names(f, &block.parameters)?;
+        writeln!(f)?;
+
+        for instruction in &block.instructions {
+            f.write_str("    ")?;
+            if let Some(dest) = instruction.dest() {
+                write!(f, "{dest}: {} = ", self.typ(dest))?;
+            }
+            match instruction {
+                Instruction::Const { dest, value } => {
+                    write!(f, "const {}", self.typ(*dest).to_i128(*value))?;
+                }
+                Instruction::Copy { source, .. } => write!(f, "copy {source}")?,
+                Instruction::Binary {
+                    operator,
+                    left,
+                    right,
+                    ..
+                } => write!(f, "{left} {} {right}", operator.symbol())?,
+                Instruction::Call {
+                    callee, arguments, ..
+                } => {
+                    write!(f, "call {callee}(")?;
+                    write_list(f, arguments)?;
+                    f.write_str(")")?;
+                }
+                Instruction::Syscall { arguments, .. } => {
+                    f.write_str("syscall ")?;
+                    write_list(f, arguments)?;
+                }
+                Instruction::StackSlot { .. } => f.write_str("stack_slot")?,
+                Instruction::Load { address, .. } => {
+                    write!(f, "load {address}")?;
+                }
+                Instruction::Store { address, value } => {
+                    write!(f, "store {address}, {value}")?;
+                }
+                Instruction::Cast { source, .. } => write!(f, "cast {source}")?,
+            }
+            if let Some(name) =
+                instruction.dest().and_then(|dest| self.values[dest.0].name)
+            {
+                write!(f, " // {name}")?;
+            }
+            writeln!(f)?;
+        }
+
+        f.write_str("    ")?;
+        match &block.terminator {
+            Terminator::Return(None) => f.write_str("return")?,
+            Terminator::Return(Some(value)) => write!(f, "return {value}")?,
+            Terminator::Jump(target, arguments) => {
+                write!(f, "jump {target}")?;
+                if !arguments.is_empty() {
+                    f.write_str("(")?;
+                    write_list(f, arguments)?;
+                    f.write_str(")")?;
+                }
+            }
+            Terminator::Branch {
+                value,
+                low,
+                high,
+                then,
+                otherwise,
+            } => {
+                let typ = self.typ(*value);
+                write!(
+                    f,
+                    "branch {value} in {}..={} then {then} else {otherwise}",
+                    typ.to_i128(*low),
+                    typ.to_i128(*high),
+                )?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter, values: &[Value]) -> fmt::Result {
+    for (i, value) in values.iter().enumerate() {
+        if i != 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{value}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
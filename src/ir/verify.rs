@@ -0,0 +1,499 @@
+//! Checks that the IR is well-formed. Lowering and every pass are expected to
+//! produce valid IR, so failures indicate bugs in the compiler rather than in
+//! the program being compiled.
+
+use super::{
+    cfg::Dominators, Block, BlockId, Function, Instruction, Program,
+    Terminator, Value,
+};
+use crate::{layout::Layout, typ::Type};
+use internment::Intern;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct Error {
+    pub function: Intern<str>,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid IR in `{}`: {}", self.function, self.message)
+    }
+}
+
+pub fn verify(program: &Program) -> Result<(), Error> {
+    for function in &program.functions {
+        let mut verifier = Verifier {
+            program,
+            function,
+            dominators: Dominators::new(function),
+            definitions: vec![None; function.values.len()],
+        };
+        verifier.verify().map_err(|message| Error {
+            function: function.name,
+            message,
+        })?;
+    }
+    Ok(())
+}
+
+/// Where a value is defined.
+#[derive(Clone, Copy)]
+enum Definition {
+    /// A function or block parameter, available throughout the block.
+    Parameter(BlockId),
+    /// The result of the instruction at the given index in the block.
+    Instruction(BlockId, usize),
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    function: &'a Function,
+    dominators: Dominators,
+    definitions: Vec<Option<Definition>>,
+}
+
+impl Verifier<'_> {
+    fn verify(&mut self) -> Result<(), String> {
+        let function = self.function;
+        if function.blocks.is_empty() {
+            return Err("function has no blocks".to_owned());
+        }
+        if !function.block(Function::ENTRY).parameters.is_empty() {
+            return Err("entry block has parameters".to_owned());
+        }
+        if function.values.iter().any(|value| value.typ == Type::Unit) {
+            return Err("value of type `unit`".to_owned());
+        }
+
+        for &parameter in &function.parameters {
+            self.define(parameter, Definition::Parameter(Function::ENTRY))?;
+        }
+        for id in function.block_ids() {
+            let block = function.block(id);
+            for &parameter in &block.parameters {
+                self.define(parameter, Definition::Parameter(id))?;
+            }
+            for (i, instruction) in block.instructions.iter().enumerate() {
+                if let Some(dest) = instruction.dest() {
+                    self.define(dest, Definition::Instruction(id, i))?;
+                }
+            }
+        }
+
+        for id in function.block_ids() {
+            let block = function.block(id);
+            for (i, instruction) in block.instructions.iter().enumerate() {
+                for operand in instruction.operands() {
+                    self.check_use(operand, id, i)?;
+                }
+                self.check_instruction(instruction)?;
+            }
+            for operand in block.terminator.operands() {
+                self.check_use(operand, id, block.instructions.len())?;
+            }
+            self.check_terminator(&block.terminator)?;
+        }
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        value: Value,
+        definition: Definition,
+    ) -> Result<(), String> {
+        match self.definitions.get_mut(value.0) {
+            None => Err(format!("{value} is out of range")),
+            Some(Some(_)) => Err(format!("{value} is defined more than once")),
+            Some(slot) => {
+                *slot = Some(definition);
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that `value` is defined before it is used at the given index in
+    /// `block`, on every path through the function.
+    fn check_use(
+        &self,
+        value: Value,
+        block: BlockId,
+        index: usize,
+    ) -> Result<(), String> {
+        let Some(&Some(definition)) = self.definitions.get(value.0) else {
+            return Err(format!(
+                "{value} is used in {block} but never defined"
+            ));
+        };
+        let is_available = match definition {
+            Definition::Parameter(defining_block) => {
+                self.dominators.dominates(defining_block, block)
+            }
+            Definition::Instruction(defining_block, i) => {
+                if defining_block == block {
+                    i < index
+                } else {
+                    self.dominators.dominates(defining_block, block)
+                }
+            }
+        };
+        if is_available {
+            Ok(())
+        } else {
+            Err(format!("{value} is used in {block} before it is defined"))
+        }
+    }
+
+    fn check_instruction(
+        &self,
+        instruction: &Instruction,
+    ) -> Result<(), String> {
+        let function = self.function;
+        match instruction {
+            Instruction::Const { dest, value } => {
+                let typ = function.typ(*dest);
+                if typ.truncate(*value) != *value {
+                    return Err(format!(
+                        "constant {value} doesn't fit in `{typ}`"
+                    ));
+                }
+            }
+            Instruction::Copy { dest, source } => {
+                self.expect_type(*source, function.typ(*dest))?;
+            }
+            Instruction::Binary {
+                dest, left, right, ..
+            } => {
+                self.expect_type(*left, function.typ(*dest))?;
+                self.expect_type(*right, function.typ(*dest))?;
+            }
+            Instruction::Call {
+                dest,
+                callee,
+                arguments,
+            } => {
+                let (parameter_types, return_type) = if let Some(function) =
+                    self.program.function(*callee)
+                {
+                    (function.parameter_types(), function.return_type)
+                } else if let Some(function) =
+                    self.program.extern_function(*callee)
+                {
+                    (function.parameter_types.clone(), function.return_type)
+                } else {
+                    return Err(format!("call to unknown function `{callee}`"));
+                };
+                if parameter_types.len() != arguments.len() {
+                    return Err(format!(
+                        "`{callee}` takes {} arguments but {} were given",
+                        parameter_types.len(),
+                        arguments.len()
+                    ));
+                }
+                for (&argument, typ) in arguments.iter().zip(parameter_types) {
+                    self.expect_type(argument, typ)?;
+                }
+                self.check_result(*dest, return_type)?;
+            }
+            Instruction::Syscall { dest, arguments } => {
+                if !(1..=7).contains(&arguments.len()) {
+                    return Err(format!(
+                        "system call with {} arguments",
+                        arguments.len()
+                    ));
+                }
+                for &value in arguments.iter().chain([dest]) {
+                    self.expect_type(value, Type::I64)?;
+                }
+            }
+            Instruction::StackSlot { dest } => {
+                let pointee = self.pointee(*dest)?;
+                if Layout::of(pointee, &self.program.types).is_none() {
+                    return Err(format!("`{pointee}` has no layout"));
+                }
+            }
+            Instruction::Load { dest, address } => {
+                let pointee = self.scalar_pointee(*address)?;
+                self.expect_type(*dest, pointee)?;
+            }
+            Instruction::Store { address, value } => {
+                let pointee = self.scalar_pointee(*address)?;
+                self.expect_type(*value, pointee)?;
+            }
+            Instruction::Cast { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn check_terminator(&self, terminator: &Terminator) -> Result<(), String> {
+        let function = self.function;
+        match terminator {
+            Terminator::Return(value) => {
+                self.check_result(*value, function.return_type)
+            }
+            Terminator::Jump(target, arguments) => {
+                let target_block = self.target(*target)?;
+                if target_block.parameters.len() != arguments.len() {
+                    return Err(format!(
+                        "{target} takes {} arguments but {} were given",
+                        target_block.parameters.len(),
+                        arguments.len()
+                    ));
+                }
+                for (&argument, &parameter) in
+                    arguments.iter().zip(&target_block.parameters)
+                {
+                    self.expect_type(argument, function.typ(parameter))?;
+                }
+                Ok(())
+            }
+            Terminator::Branch {
+                value,
+                low,
+                high,
+                then,
+                otherwise,
+            } => {
+                let typ = function.typ(*value);
+                if !typ.is_integer() {
+                    return Err(format!(
+                        "branch on {value} of type `{typ}`, not an integer"
+                    ));
+                }
+                for bound in [low, high] {
+                    if typ.truncate(*bound) != *bound {
+                        return Err(format!(
+                            "bound {bound} doesn't fit in `{typ}`"
+                        ));
+                    }
+                }
+                let (low, high) = (typ.to_i128(*low), typ.to_i128(*high));
+                if low > high {
+                    return Err(format!("empty range {low}..={high}"));
+                }
+                for target in [then, otherwise] {
+                    if !self.target(*target)?.parameters.is_empty() {
+                        return Err(format!(
+                            "branch to {target}, which has parameters"
+                        ));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The block that a terminator jumps to, which can't be the entry block.
+    fn target(&self, target: BlockId) -> Result<&Block, String> {
+        if target == Function::ENTRY {
+            return Err("jump to the entry block".to_owned());
+        }
+        self.function
+            .blocks
+            .get(target.0)
+            .ok_or_else(|| format!("jump to nonexistent block {target}"))
+    }
+
+    /// Checks that a result is present exactly if its type isn't `unit`.
+    fn check_result(
+        &self,
+        value: Option<Value>,
+        typ: Type,
+    ) -> Result<(), String> {
+        match (value, typ) {
+            (None, Type::Unit) => Ok(()),
+            (Some(value), Type::Unit) => {
+                Err(format!("{value} is used as a `unit` result"))
+            }
+            (None, _) => Err(format!("missing result of type `{typ}`")),
+            (Some(value), _) => self.expect_type(value, typ),
+        }
+    }
+
+    /// The type that `pointer` points to, which must be a pointer.
+    fn pointee(&self, pointer: Value) -> Result<Type, String> {
+        let typ = self.function.typ(pointer);
+        typ.pointee()
+            .ok_or_else(|| format!("{pointer} has type `{typ}`, not a pointer"))
+    }
+
+    /// Like `pointee`, but structs and enums, which are copied piece by
+    /// piece, aren't allowed.
+    fn scalar_pointee(&self, pointer: Value) -> Result<Type, String> {
+        match self.pointee(pointer)? {
+            Type::Named(name) => Err(format!("{pointer} points to `{name}`")),
+            typ => Ok(typ),
+        }
+    }
+
+    fn expect_type(&self, value: Value, expected: Type) -> Result<(), String> {
+        let found = self.function.typ(value);
+        if found == expected {
+            Ok(())
+        } else {
+            Err(format!(
+                "{value} has type `{found}` but `{expected}` was expected"
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Types;
+    use std::sync::Arc;
+
+    fn function(
+        values: &[Type],
+        blocks: Vec<Block>,
+        return_type: Type,
+    ) -> Program {
+        let mut function = Function::new("f", return_type, &[]);
+        for &typ in values {
+            function.new_value(typ, None);
+        }
+        function.blocks = blocks;
+        Program {
+            functions: vec![Arc::new(function)],
+            externs: Vec::new(),
+            types: Types::default(),
+        }
+    }
+
+    fn block(
+        parameters: Vec<Value>,
+        instructions: Vec<Instruction>,
+        terminator: Terminator,
+    ) -> Block {
+        Block {
+            parameters,
+            instructions,
+            terminator,
+        }
+    }
+
+    #[test]
+    fn accepts_values_passed_between_blocks() {
+        let program = function(
+            &[Type::U8, Type::U8],
+            vec![
+                block(
+                    Vec::new(),
+                    vec![Instruction::Const {
+                        dest: Value(0),
+                        value: 255,
+                    }],
+                    Terminator::Jump(BlockId(1), vec![Value(0)]),
+                ),
+                block(
+                    vec![Value(1)],
+                    Vec::new(),
+                    Terminator::Return(Some(Value(1))),
+                ),
+            ],
+            Type::U8,
+        );
+        verify(&program).unwrap();
+    }
+
+    #[test]
+    fn rejects_uses_not_dominated_by_definitions() {
+        let program = function(
+            &[Type::I32],
+            vec![
+                block(
+                    Vec::new(),
+                    Vec::new(),
+                    Terminator::Jump(BlockId(2), Vec::new()),
+                ),
+                block(
+                    Vec::new(),
+                    vec![Instruction::Const {
+                        dest: Value(0),
+                        value: 1,
+                    }],
+                    Terminator::Jump(BlockId(2), Vec::new()),
+                ),
+                block(
+                    Vec::new(),
+                    Vec::new(),
+                    Terminator::Return(Some(Value(0))),
+                ),
+            ],
+            Type::I32,
+        );
+        let err = verify(&program).unwrap_err();
+        assert_eq!(err.message, "v0 is used in bb2 before it is defined");
+    }
+
+    #[test]
+    fn rejects_mismatched_types() {
+        let program = function(
+            &[Type::I8, Type::I16],
+            vec![block(
+                Vec::new(),
+                vec![
+                    Instruction::Const {
+                        dest: Value(0),
+                        value: 1,
+                    },
+                    Instruction::Copy {
+                        dest: Value(1),
+                        source: Value(0),
+                    },
+                ],
+                Terminator::Return(None),
+            )],
+            Type::Unit,
+        );
+        let err = verify(&program).unwrap_err();
+        assert_eq!(err.message, "v0 has type `i8` but `i16` was expected");
+    }
+
+    #[test]
+    fn rejects_oversized_constants() {
+        let program = function(
+            &[Type::I8],
+            vec![block(
+                Vec::new(),
+                vec![Instruction::Const {
+                    dest: Value(0),
+                    value: 256,
+                }],
+                Terminator::Return(Some(Value(0))),
+            )],
+            Type::I8,
+        );
+        let err = verify(&program).unwrap_err();
+        assert_eq!(err.message, "constant 256 doesn't fit in `i8`");
+    }
+
+    #[test]
+    fn rejects_branches_on_empty_ranges() {
+        let program = function(
+            &[Type::I8],
+            vec![
+                block(
+                    Vec::new(),
+                    vec![Instruction::Const {
+                        dest: Value(0),
+                        value: 0,
+                    }],
+                    Terminator::Branch {
+                        value: Value(0),
+                        low: 5,
+                        high: Type::I8.truncate(-5_i64 as u64),
+                        then: BlockId(1),
+                        otherwise: BlockId(1),
+                    },
+                ),
+                block(Vec::new(), Vec::new(), Terminator::Return(None)),
+            ],
+            Type::Unit,
+        );
+        let err = verify(&program).unwrap_err();
+        assert_eq!(err.message, "empty range 5..=-5");
+    }
+}
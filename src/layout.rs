@@ -0,0 +1,260 @@
+//! Where values are placed in memory. Integers and pointers are aligned to
+//! their size. Structs are laid out like in C: each field goes at the next
+//! offset after the previous one that is a multiple of its alignment, and the
+//! struct is as aligned as its most aligned field, with padding at the end to
+//! make its size a multiple of that. Packed structs have no padding and an
+//! alignment of 1.
+//!
+//! Enums are laid out like a C struct of a `TAG` that holds the index of the
+//! variant, followed by a union of structs with the payloads of the variants.
+
+use crate::{ast::Types, typ::Type};
+use internment::Intern;
+
+/// The type of the tag at the start of every enum.
+pub const TAG: Type = Type::U32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u64,
+    pub align: u64,
+    /// The offset in bytes of each field of a struct, in declaration order.
+    pub offsets: Vec<u64>,
+    /// The offsets in bytes of the payload of each variant of an enum.
+    pub variants: Vec<Vec<u64>>,
+}
+
+impl Layout {
+    /// The layout of `typ`, or `None` if it contains a struct or enum that is
+    /// unknown, contains errors or contains itself.
+    pub fn of(typ: Type, types: &Types) -> Option<Self> {
+        Self::of_nested(typ, types, &mut Vec::new())
+    }
+
+    /// `outer` holds the structs and enums whose members are being laid out.
+    fn of_nested(
+        typ: Type,
+        types: &Types,
+        outer: &mut Vec<Intern<str>>,
+    ) -> Option<Self> {
+        let Type::Named(name) = typ else {
+            let size = typ.bits().map_or(0, |bits| u64::from(bits / 8));
+            return Some(Self {
+                size,
+                align: size.max(1),
+                offsets: Vec::new(),
+                variants: Vec::new(),
+            });
+        };
+        if outer.contains(&name) {
+            return None;
+        }
+        outer.push(name);
+        let layout = if let Some(definition) = types.structs.get(&name) {
+            let fields = definition.fields.as_ref().ok()?;
+            Self::of_fields(
+                fields.iter().map(|field| field.typ),
+                definition.is_packed,
+                types,
+                outer,
+            )
+        } else {
+            let definition = types.enums.get(&name)?;
+            let payloads = definition
+                .variants
+                .as_ref()
+                .ok()?
+                .iter()
+                .map(|variant| {
+                    Self::of_fields(
+                        variant.payload.iter().copied(),
+                        false,
+                        types,
+                        outer,
+                    )
+                })
+                .collect::<Option<Vec<_>>>()?;
+            let union_align = payloads
+                .iter()
+                .map(|payload| payload.align)
+                .max()
+                .unwrap_or(1);
+            let union_size = payloads
+                .iter()
+                .map(|payload| payload.size)
+                .max()
+                .unwrap_or(0);
+            let tag_size = u64::from(TAG.bits()? / 8);
+            let align = union_align.max(tag_size);
+            let start = tag_size.next_multiple_of(union_align);
+            Some(Self {
+                size: (start + union_size).next_multiple_of(align),
+                align,
+                offsets: Vec::new(),
+                variants: payloads
+                    .into_iter()
+                    .map(|payload| {
+                        payload
+                            .offsets
+                            .iter()
+                            .map(|&offset| start + offset)
+                            .collect()
+                    })
+                    .collect(),
+            })
+        };
+        outer.pop();
+        layout
+    }
+
+    /// Lays out values of the given types one after another like the fields
+    /// of a struct.
+    fn of_fields(
+        types_of_fields: impl Iterator<Item = Type>,
+        is_packed: bool,
+        types: &Types,
+        outer: &mut Vec<Intern<str>>,
+    ) -> Option<Self> {
+        let mut layout = Self {
+            size: 0,
+            align: 1,
+            offsets: Vec::new(),
+            variants: Vec::new(),
+        };
+        for typ in types_of_fields {
+            let field_layout = Self::of_nested(typ, types, outer)?;
+            let align = if is_packed { 1 } else { field_layout.align };
+            let offset = layout.size.next_multiple_of(align);
+            layout.offsets.push(offset);
+            layout.size = offset + field_layout.size;
+            layout.align = layout.align.max(align);
+        }
+        layout.size = layout.size.next_multiple_of(layout.align);
+        Some(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Enum, Field, Span, Struct, Variant};
+    use std::sync::Arc;
+
+    const SPAN: Span = Span { start: 0, end: 0 };
+
+    /// Defines structs whose fields have the given types, and enums whose
+    /// variants have the given payloads.
+    fn types(
+        structs: &[(&str, bool, &[Type])],
+        enums: &[(&str, &[&[Type]])],
+    ) -> Types {
+        let structs = structs.iter().map(|&(name, is_packed, types)| {
+            let definition = Struct {
+                name: Ok(name.into()),
+                name_span: Ok(SPAN),
+                is_packed,
+                fields: Ok(types
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &typ)| Field {
+                        name: format!("f{i}").as_str().into(),
+                        span: SPAN,
+                        typ,
+                    })
+                    .collect()),
+            };
+            (name.into(), Arc::new(definition))
+        });
+        let enums = enums.iter().map(|&(name, payloads)| {
+            let definition = Enum {
+                name: Ok(name.into()),
+                name_span: Ok(SPAN),
+                variants: Ok(payloads
+                    .iter()
+                    .enumerate()
+                    .map(|(i, payload)| Variant {
+                        name: format!("V{i}").as_str().into(),
+                        span: SPAN,
+                        payload: payload.to_vec(),
+                    })
+                    .collect()),
+            };
+            (name.into(), Arc::new(definition))
+        });
+        Types {
+            structs: structs.collect(),
+            enums: enums.collect(),
+        }
+    }
+
+    fn layout(name: &str, types: &Types) -> Option<(u64, u64, Vec<u64>)> {
+        Layout::of(Type::Named(name.into()), types)
+            .map(|layout| (layout.size, layout.align, layout.offsets))
+    }
+
+    #[test]
+    fn fields_are_aligned_in_declaration_order() {
+        let types = types(
+            &[
+                ("Padded", false, &[Type::U8, Type::U32, Type::U16]),
+                ("Packed", true, &[Type::U8, Type::U32, Type::U16]),
+                (
+                    "Nested",
+                    false,
+                    &[Type::I8, Type::Named("Padded".into()), Type::Unit],
+                ),
+                ("Empty", false, &[]),
+            ],
+            &[],
+        );
+        assert_eq!(layout("Padded", &types), Some((12, 4, vec![0, 4, 8])));
+        assert_eq!(layout("Packed", &types), Some((7, 1, vec![0, 1, 5])));
+        assert_eq!(layout("Nested", &types), Some((16, 4, vec![0, 4, 16])));
+        assert_eq!(layout("Empty", &types), Some((0, 1, vec![])));
+    }
+
+    #[test]
+    fn payloads_follow_the_tag() {
+        let types = types(
+            &[("Packed", true, &[Type::U8, Type::U16])],
+            &[
+                (
+                    "Wide",
+                    &[&[], &[Type::U8, Type::U64], &[Type::U16, Type::U16]],
+                ),
+                ("Narrow", &[&[Type::U8], &[Type::Named("Packed".into())]]),
+                ("Empty", &[]),
+            ],
+        );
+        let variants = |name: &str| {
+            Layout::of(Type::Named(name.into()), &types)
+                .map(|layout| (layout.size, layout.align, layout.variants))
+        };
+        assert_eq!(
+            variants("Wide"),
+            Some((24, 8, vec![vec![], vec![8, 16], vec![8, 10]]))
+        );
+        assert_eq!(variants("Narrow"), Some((8, 4, vec![vec![4], vec![4]])));
+        assert_eq!(variants("Empty"), Some((4, 4, vec![])));
+    }
+
+    #[test]
+    fn types_containing_themselves_have_no_layout() {
+        let types = types(
+            &[
+                (
+                    "List",
+                    false,
+                    &[Type::pointer(Type::Named("List".into()), false)],
+                ),
+                ("A", false, &[Type::Named("B".into())]),
+                ("B", false, &[Type::U64, Type::Named("A".into())]),
+            ],
+            &[("Tree", &[&[], &[Type::U8, Type::Named("Tree".into())]])],
+        );
+        assert_eq!(layout("List", &types), Some((8, 8, vec![0])));
+        assert_eq!(layout("A", &types), None);
+        assert_eq!(layout("Tree", &types), None);
+        assert_eq!(layout("Unknown", &types), None);
+    }
+}
@@ -1,40 +1,151 @@
 mod completion;
+pub mod config;
+mod consistency;
 mod document;
 mod hover;
+mod progress;
+mod snapshot;
+#[cfg(test)]
+mod tests;
+mod worker;
+mod workspace;
 
-use crate::text::{byte_to_point, PositionEncoding};
+use crate::{db::Database, text::PositionEncoding};
+use config::Config;
+use crossbeam_channel::{Receiver, Sender};
 use document::Document;
-use lsp_server::{Connection, IoThreads, Message, Notification, Request};
+use lsp_server::{
+    Connection, ErrorCode, IoThreads, Message, Notification, Request,
+    RequestId, Response,
+};
 use lsp_types::{
     notification::{
-        DidChangeTextDocument, DidOpenTextDocument, Notification as _,
-        PublishDiagnostics,
+        Cancel, DidChangeConfiguration, DidChangeTextDocument,
+        DidChangeWatchedFiles, DidCloseTextDocument, DidOpenTextDocument,
+        DidSaveTextDocument, Notification as _, PublishDiagnostics,
     },
-    request::{Completion, HoverRequest, Request as _},
+    request::{Completion, HoverRequest, Request as _, WorkspaceConfiguration},
     *,
 };
-use ropey::Rope;
-use std::collections::HashMap;
-use tree_sitter::{InputEdit, Parser};
+use progress::ProgressState;
+use snapshot::Snapshot;
+use std::{
+    collections::HashMap,
+    io,
+    net::ToSocketAddrs,
+    ops::ControlFlow,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    sync::Arc,
+};
+use tree_sitter::Parser;
+use worker::{PendingRequests, WorkerPool};
+
+/// An error that is reported back to the client in response to a request.
+pub struct RequestError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl RequestError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn unknown_document(uri: &Url) -> Self {
+        Self::new(ErrorCode::RequestFailed, format!("unknown document: {uri}"))
+    }
+}
+
+impl From<serde_json::Error> for RequestError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::new(ErrorCode::InvalidParams, err.to_string())
+    }
+}
+
+type ResponseHandler = Box<dyn FnOnce(&mut LanguageServer, serde_json::Value)>;
+
+/// Work that another thread hands back to the main thread, such as adding a
+/// file that was indexed in the background.
+type Task = Box<dyn FnOnce(&mut LanguageServer) + Send>;
 
 pub struct LanguageServer {
     connection: Connection,
-    io_threads: IoThreads,
+    /// Absent when running in-process in tests.
+    io_threads: Option<IoThreads>,
     parser: Parser,
     pos_enc: PositionEncoding,
-    docs: HashMap<Url, Document>,
+    config: Config,
+    docs: HashMap<Url, Arc<Document>>,
+    /// Source files in the workspace as they are on disk, including those
+    /// that are also open in the editor.
+    workspace_files: HashMap<Url, Arc<Document>>,
+    workspace_roots: Vec<PathBuf>,
+    /// Checks whichever version of each file is shown to the user.
+    db: Database,
+    workers: WorkerPool,
+    pending: PendingRequests,
+    task_sender: Sender<Task>,
+    task_receiver: Receiver<Task>,
+    next_request_id: i32,
+    /// Callbacks for requests sent to the client that haven't been answered.
+    response_handlers: HashMap<RequestId, ResponseHandler>,
+    next_progress_token: u32,
+    progress: HashMap<ProgressToken, ProgressState>,
+    work_done_progress: bool,
+    watch_files: bool,
+    pull_config: bool,
 }
 
 impl LanguageServer {
-    pub fn new() -> Self {
+    /// Communicates with the client over stdin and stdout.
+    pub fn stdio() -> Self {
         let (connection, io_threads) = Connection::stdio();
+        Self::initialize(connection, Some(io_threads))
+    }
+
+    /// Waits for a client to connect to `addr` over TCP.
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let (connection, io_threads) = Connection::listen(addr)?;
+        Ok(Self::initialize(connection, Some(io_threads)))
+    }
+
+    /// Connects to a client that is listening on `addr` over TCP.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let (connection, io_threads) = Connection::connect(addr)?;
+        Ok(Self::initialize(connection, Some(io_threads)))
+    }
+
+    /// Uses one half of `Connection::memory()` for running the server
+    /// in-process in tests. Blocks until the client has sent its `initialize`
+    /// request.
+    #[cfg(test)]
+    pub fn from_connection(connection: Connection) -> Self {
+        Self::initialize(connection, None)
+    }
+
+    fn initialize(
+        connection: Connection,
+        io_threads: Option<IoThreads>,
+    ) -> Self {
         let (id, initialize_params) = connection.initialize_start().unwrap();
         let initialize_params: InitializeParams =
             serde_json::from_value(initialize_params).unwrap();
         let position_encoding = PositionEncoding::from(&initialize_params);
         let server_capabilities = serde_json::to_value(ServerCapabilities {
             position_encoding: Some(position_encoding.into()),
-            text_document_sync: Some(TextDocumentSyncKind::INCREMENTAL.into()),
+            text_document_sync: Some(
+                TextDocumentSyncOptions {
+                    open_close: Some(true),
+                    change: Some(TextDocumentSyncKind::INCREMENTAL),
+                    save: Some(true.into()),
+                    ..Default::default()
+                }
+                .into(),
+            ),
             completion_provider: Some(CompletionOptions::default()),
             hover_provider: Some(HoverProviderCapability::Simple(true)),
             ..Default::default()
@@ -49,121 +160,296 @@ impl LanguageServer {
             )
             .unwrap();
         log::info!("Initialized LSP: {initialize_params:#?}");
+        let config = initialize_params
+            .initialization_options
+            .clone()
+            .and_then(Config::from_json)
+            .unwrap_or_default();
+        let root_uris = match &initialize_params.workspace_folders {
+            Some(folders) => folders.iter().map(|folder| &folder.uri).collect(),
+            None => initialize_params.root_uri.iter().collect::<Vec<_>>(),
+        };
+        let workspace_roots = root_uris
+            .into_iter()
+            .filter_map(|uri| uri.to_file_path().ok())
+            .collect();
+        let capabilities = &initialize_params.capabilities;
+        let work_done_progress = capabilities
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        let watch_files = capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files)
+            .and_then(|watched| watched.dynamic_registration)
+            .unwrap_or(false);
+        let pull_config = capabilities
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.configuration)
+            .unwrap_or(false);
 
         let language = tree_sitter_gneiss::language();
         let mut parser = Parser::new();
         parser.set_language(language).unwrap();
+        let (task_sender, task_receiver) = crossbeam_channel::unbounded();
 
         Self {
             connection,
             io_threads,
             parser,
             pos_enc: position_encoding,
+            config,
             docs: HashMap::new(),
+            workspace_files: HashMap::new(),
+            workspace_roots,
+            db: Database::new(),
+            workers: WorkerPool::new(),
+            pending: PendingRequests::default(),
+            task_sender,
+            task_receiver,
+            next_request_id: 0,
+            response_handlers: HashMap::new(),
+            next_progress_token: 0,
+            progress: HashMap::new(),
+            work_done_progress,
+            watch_files,
+            pull_config,
         }
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
     pub fn run(mut self) {
-        while let Ok(message) = self.connection.receiver.recv() {
-            match message {
-                Message::Request(request) => {
-                    if self.connection.handle_shutdown(&request).unwrap() {
+        log::info!("Initialized LSP with {:#?}", self.config);
+        if self.pull_config {
+            self.request_config();
+        }
+        self.index_workspace();
+
+        let messages = self.connection.receiver.clone();
+        let tasks = self.task_receiver.clone();
+        loop {
+            crossbeam_channel::select! {
+                recv(messages) -> message => {
+                    let Ok(message) = message else {
+                        break;
+                    };
+                    if self.handle_message(message).is_break() {
                         break;
                     }
-                    self.handle_request(request);
                 }
-                Message::Response(_) => todo!(),
-                Message::Notification(notification) => {
-                    self.handle_notification(notification);
+                recv(tasks) -> task => {
+                    task.expect("the server keeps a sender")(&mut self);
                 }
             }
         }
 
-        self.io_threads.join().unwrap();
+        let Self {
+            connection,
+            io_threads,
+            workers,
+            task_receiver,
+            ..
+        } = self;
+        // Background jobs stop once they can't hand back their results.
+        drop((task_receiver, tasks));
+        // Let in-flight requests finish and release their senders so that the
+        // writer thread can exit.
+        drop(workers);
+        drop(connection);
+        if let Some(io_threads) = io_threads {
+            io_threads.join().unwrap();
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) -> ControlFlow<()> {
+        match message {
+            Message::Request(request) => {
+                match self.connection.handle_shutdown(&request) {
+                    Ok(true) => return ControlFlow::Break(()),
+                    Ok(false) => self.handle_request(request),
+                    Err(err) => {
+                        log::error!("Failed to shut down cleanly: {err}");
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            Message::Response(response) => self.handle_response(response),
+            Message::Notification(notification) => {
+                self.handle_notification(notification);
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn handle_response(&mut self, response: Response) {
+        let Some(handler) = self.response_handlers.remove(&response.id) else {
+            log::warn!("Unexpected response: {response:?}");
+            return;
+        };
+        if let Some(err) = response.error {
+            log::error!(
+                "Request {} failed with code {}: {}",
+                response.id,
+                err.code,
+                err.message
+            );
+            return;
+        }
+        handler(self, response.result.unwrap_or_default());
+    }
+
+    fn handle_notification(&mut self, notification: Notification) {
+        let method = notification.method.clone();
+        match catch_unwind(AssertUnwindSafe(|| {
+            self.dispatch_notification(notification)
+        })) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                log::error!(
+                    "Invalid params for notification {method:?}: {err}"
+                );
+            }
+            Err(_) => {
+                log::error!("Panicked while handling notification {method:?}")
+            }
+        }
     }
 
-    fn handle_notification(
+    fn dispatch_notification(
         &mut self,
         Notification { method, params }: Notification,
-    ) {
+    ) -> serde_json::Result<()> {
         match &*method {
             DidOpenTextDocument::METHOD => {
                 let params: DidOpenTextDocumentParams =
-                    serde_json::from_value(params).unwrap();
-                self.open(params.text_document.uri, params.text_document.text);
+                    serde_json::from_value(params)?;
+                self.open(
+                    params.text_document.uri,
+                    params.text_document.text,
+                    params.text_document.version,
+                );
             }
             DidChangeTextDocument::METHOD => {
                 let params: DidChangeTextDocumentParams =
-                    serde_json::from_value(params).unwrap();
-                self.edit(params.text_document.uri, &params.content_changes);
+                    serde_json::from_value(params)?;
+                self.edit(
+                    params.text_document.uri,
+                    params.text_document.version,
+                    &params.content_changes,
+                );
+            }
+            DidSaveTextDocument::METHOD => {
+                let params: DidSaveTextDocumentParams =
+                    serde_json::from_value(params)?;
+                self.update_and_publish_diagnostics(params.text_document.uri);
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    serde_json::from_value(params)?;
+                self.close(params.text_document.uri);
+            }
+            DidChangeConfiguration::METHOD => {
+                let params: DidChangeConfigurationParams =
+                    serde_json::from_value(params)?;
+                if self.pull_config {
+                    // The notification may not contain the settings at all,
+                    // so ask for them explicitly.
+                    self.request_config();
+                } else {
+                    let mut settings = params.settings;
+                    let settings = match settings.get_mut(config::SECTION) {
+                        Some(section) => section.take(),
+                        None => settings,
+                    };
+                    if let Some(config) = Config::from_json(settings) {
+                        self.apply_config(config);
+                    }
+                }
+            }
+            DidChangeWatchedFiles::METHOD => {
+                let params: DidChangeWatchedFilesParams =
+                    serde_json::from_value(params)?;
+                self.did_change_watched_files(params);
+            }
+            Cancel::METHOD => {
+                let params: CancelParams = serde_json::from_value(params)?;
+                self.pending.cancel(&match params.id {
+                    NumberOrString::Number(id) => RequestId::from(id),
+                    NumberOrString::String(id) => RequestId::from(id),
+                });
             }
             _ => log::warn!("Unhandled notification method: {method:?}"),
         }
+        Ok(())
     }
 
-    fn open(&mut self, uri: Url, text: String) {
-        let tree = self.parser.parse(&text, None).unwrap();
-        let text = Rope::from(text);
-        let ast = crate::ast::File::parse(&tree, &text);
-        log::info!("\n{:#?}", ast);
-
-        self.docs.insert(
-            uri.clone(),
-            Document {
-                text,
-                tree,
-                ast,
-                diagnostics: Vec::new(),
-            },
-        );
+    fn open(&mut self, uri: Url, text: String, version: i32) {
+        let doc = Document::new(&mut self.parser, text, version);
+        self.docs.insert(uri.clone(), Arc::new(doc));
 
         self.update_and_publish_diagnostics(uri);
     }
 
-    fn edit(&mut self, uri: Url, edits: &[TextDocumentContentChangeEvent]) {
-        let doc = self.docs.get_mut(&uri).unwrap();
+    fn close(&mut self, uri: Url) {
+        self.docs.remove(&uri);
+        self.pending.invalidate_all();
+
+        if self.workspace_files.contains_key(&uri) {
+            // Go back to showing diagnostics for the file as it is on disk.
+            self.update_and_publish_diagnostics(uri);
+        } else {
+            self.db.remove_file(uri.as_str().into());
+            // Clear any diagnostics the client is still showing for the file.
+            self.publish_diagnostics(uri, Vec::new(), None);
+        }
+    }
+
+    fn edit(
+        &mut self,
+        uri: Url,
+        version: i32,
+        edits: &[TextDocumentContentChangeEvent],
+    ) {
+        let Some(doc) = self.docs.get_mut(&uri) else {
+            log::warn!("Ignoring edit of unknown document {uri}");
+            return;
+        };
 
-        for edit in edits.iter().rev() {
-            let range = edit.range.expect(
-                "edits that replace the entire document are not supported",
+        if version <= doc.version {
+            log::warn!(
+                "Ignoring out-of-order edit of {uri} (version {version} after \
+                 {})",
+                doc.version
             );
-            let start_byte =
-                self.pos_enc.position_to_byte(&doc.text, range.start);
-            let old_end_byte =
-                self.pos_enc.position_to_byte(&doc.text, range.end);
-            let new_end_byte = start_byte + edit.text.len();
-            let old_end_position =
-                self.pos_enc.position_to_point(&doc.text, range.end);
-            let start_char = doc.text.byte_to_char(start_byte);
-            doc.text
-                .remove(start_char..doc.text.byte_to_char(old_end_byte));
-            doc.text.insert(start_char, &edit.text);
-            let edit = InputEdit {
-                start_byte,
-                old_end_byte,
-                new_end_byte,
-                start_position: self
-                    .pos_enc
-                    .position_to_point(&doc.text, range.start),
-                old_end_position,
-                new_end_position: byte_to_point(&doc.text, new_end_byte),
-            };
-            doc.tree.edit(&edit);
-        }
-
-        doc.tree = self
-            .parser
-            .parse_with(
-                &mut |byte_offest, _position| {
-                    let (chunk, chunk_start, ..) =
-                        doc.text.chunk_at_byte(byte_offest);
-                    &chunk.as_bytes()[(byte_offest - chunk_start)..]
-                },
-                Some(&doc.tree),
-            )
-            .unwrap();
+            return;
+        }
+        let doc = Arc::make_mut(doc);
+        doc.version = version;
+        self.pending.invalidate_all();
 
-        doc.ast = crate::ast::File::parse(&doc.tree, &doc.text);
+        let old_text = self
+            .config
+            .debug
+            .check_incremental_edits
+            .then(|| doc.text.clone());
+
+        doc.apply_changes(&mut self.parser, self.pos_enc, edits);
+
+        if let Some(old_text) = old_text {
+            if let Err(err) = consistency::check(doc, &mut self.parser) {
+                log::error!(
+                    "Incremental edit of {uri} diverged from a full reparse: \
+                     {err}\nText before the edit: {:?}\nEdits: {edits:#?}",
+                    old_text.to_string(),
+                );
+            }
+        }
 
         log::info!("\n{:#?}", doc.ast);
 
@@ -173,23 +459,195 @@ impl LanguageServer {
     fn handle_request(&self, Request { id, method, params }: Request) {
         match &*method {
             Completion::METHOD => {
-                let params = serde_json::from_value(params).unwrap();
-                self.complete(id, &params);
+                self.spawn_read_only::<Completion>(
+                    id,
+                    params,
+                    Snapshot::complete,
+                );
             }
             HoverRequest::METHOD => {
-                let params = serde_json::from_value(params).unwrap();
-                self.hover(id, &params);
+                self.spawn_read_only::<HoverRequest>(
+                    id,
+                    params,
+                    Snapshot::hover,
+                );
+            }
+            _ => {
+                let err = RequestError::new(
+                    ErrorCode::MethodNotFound,
+                    format!("unhandled request method: {method:?}"),
+                );
+                self.connection
+                    .sender
+                    .send(response(id, Err(err)).into())
+                    .unwrap();
             }
-            _ => log::warn!("Unhandled request method: {method:?}"),
+        }
+    }
+
+    /// Handles a request on the worker pool using a snapshot of the current
+    /// documents.
+    fn spawn_read_only<R: lsp_types::request::Request>(
+        &self,
+        id: RequestId,
+        params: serde_json::Value,
+        handler: fn(&Snapshot, &R::Params) -> Result<R::Result, RequestError>,
+    ) where
+        R::Params: 'static,
+        R::Result: 'static,
+    {
+        let snapshot = self.snapshot();
+        let token = self.pending.register(id.clone());
+        let pending = self.pending.clone();
+        let sender = self.connection.sender.clone();
+
+        self.workers.spawn(move || {
+            let result = token.check().and_then(|()| {
+                catch_unwind(AssertUnwindSafe(|| {
+                    let params = serde_json::from_value(params)?;
+                    Ok(serde_json::to_value(handler(&snapshot, &params)?)?)
+                }))
+                .unwrap_or_else(|_| {
+                    Err(RequestError::new(
+                        ErrorCode::RequestFailed,
+                        format!(
+                            "internal error while handling {:?}",
+                            R::METHOD
+                        ),
+                    ))
+                })
+            });
+            pending.finish(&id);
+            // The request may have been cancelled or outdated by an edit while
+            // it was being handled.
+            let result = token.check().and(result);
+
+            if sender.send(response(id, result).into()).is_err() {
+                log::warn!("Connection closed before {:?} finished", R::METHOD);
+            }
+        });
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pos_enc: self.pos_enc,
+            docs: self.docs.clone(),
+            workspace_files: self.workspace_files.clone(),
         }
     }
 
     fn update_and_publish_diagnostics(&mut self, uri: Url) {
-        let doc = self.docs.get_mut(&uri).unwrap();
+        // Files that aren't open in the editor have no version.
+        let (doc, is_open) = match self.docs.get_mut(&uri) {
+            Some(doc) => (doc, true),
+            None => match self.workspace_files.get_mut(&uri) {
+                Some(doc) => (doc, false),
+                None => {
+                    log::warn!(
+                        "Cannot publish diagnostics for unknown document {uri}"
+                    );
+                    return;
+                }
+            },
+        };
+        let doc = Arc::make_mut(doc);
         doc.diagnostics.clear();
 
-        doc.check_syntax_errors(self.pos_enc);
+        if self.config.diagnostics.syntax_errors {
+            doc.check_syntax_errors(self.pos_enc);
+        }
+        if self.config.diagnostics.semantic_errors {
+            let file = uri.as_str().into();
+            self.db.set_parsed(
+                file,
+                doc.text.clone(),
+                doc.tree.clone(),
+                doc.ast.clone(),
+            );
+            let errors = self.db.check_file(file);
+            self.db.collect_garbage(file);
+            doc.check_semantic_errors(&errors, self.pos_enc);
+        }
+
+        let diagnostics = doc.diagnostics.clone();
+        let version = is_open.then_some(doc.version);
+        self.publish_diagnostics(uri, diagnostics, version);
+    }
 
+    fn request_config(&mut self) {
+        self.send_request_then::<WorkspaceConfiguration>(
+            ConfigurationParams {
+                items: vec![ConfigurationItem {
+                    scope_uri: None,
+                    section: Some(config::SECTION.to_owned()),
+                }],
+            },
+            |server, mut settings| {
+                if let Some(config) = settings.pop().and_then(Config::from_json)
+                {
+                    server.apply_config(config);
+                }
+            },
+        );
+    }
+
+    fn apply_config(&mut self, config: Config) {
+        log::info!("Updated configuration: {config:#?}");
+        if config.log.path != self.config.log.path {
+            log::warn!("Changing the log file requires restarting the server");
+        }
+        log::set_max_level(config.log.level);
+        self.config = config;
+
+        // The set of enabled diagnostics may have changed.
+        let uris = self
+            .docs
+            .keys()
+            .chain(self.workspace_files.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+        for uri in uris {
+            self.update_and_publish_diagnostics(uri);
+        }
+    }
+
+    fn send_request<R: lsp_types::request::Request>(
+        &mut self,
+        params: R::Params,
+    ) {
+        self.send_request_then::<R>(params, |_, _| {});
+    }
+
+    /// Sends a request to the client and calls `handler` with the result once
+    /// the client responds.
+    fn send_request_then<R: lsp_types::request::Request>(
+        &mut self,
+        params: R::Params,
+        handler: impl FnOnce(&mut Self, R::Result) + 'static,
+    ) {
+        self.next_request_id += 1;
+        let id = RequestId::from(self.next_request_id);
+        self.response_handlers.insert(
+            id.clone(),
+            Box::new(|server, result| match serde_json::from_value(result) {
+                Ok(result) => handler(server, result),
+                Err(err) => {
+                    log::error!("Invalid response to {}: {err}", R::METHOD)
+                }
+            }),
+        );
+        self.connection
+            .sender
+            .send(Request::new(id, R::METHOD.to_owned(), params).into())
+            .unwrap();
+    }
+
+    fn publish_diagnostics(
+        &self,
+        uri: Url,
+        diagnostics: Vec<Diagnostic>,
+        version: Option<i32>,
+    ) {
         self.connection
             .sender
             .send(
@@ -197,8 +655,8 @@ impl LanguageServer {
                     PublishDiagnostics::METHOD.to_owned(),
                     PublishDiagnosticsParams {
                         uri,
-                        diagnostics: doc.diagnostics.clone(),
-                        version: None,
+                        diagnostics,
+                        version,
                     },
                 )
                 .into(),
@@ -206,3 +664,15 @@ impl LanguageServer {
             .unwrap();
     }
 }
+
+fn response(
+    id: RequestId,
+    result: Result<serde_json::Value, RequestError>,
+) -> Response {
+    match result {
+        Ok(result) => Response::new_ok(id, result),
+        Err(RequestError { code, message }) => {
+            Response::new_err(id, code as i32, message)
+        }
+    }
+}
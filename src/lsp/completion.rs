@@ -1,18 +1,16 @@
-use super::LanguageServer;
-use lsp_server::{Message, RequestId};
+use super::{snapshot::Snapshot, RequestError};
 use lsp_types::{CompletionItem, CompletionParams, CompletionResponse};
 
-impl LanguageServer {
-    pub fn complete(&self, id: RequestId, params: &CompletionParams) {
-        self.connection
-            .sender
-            .send(Message::Response(lsp_server::Response::new_ok(
-                id,
-                CompletionResponse::Array(vec![CompletionItem {
-                    label: "foo".to_owned(),
-                    ..Default::default()
-                }]),
-            )))
-            .unwrap();
+impl Snapshot {
+    pub fn complete(
+        &self,
+        params: &CompletionParams,
+    ) -> Result<Option<CompletionResponse>, RequestError> {
+        self.doc(&params.text_document_position.text_document.uri)?;
+
+        Ok(Some(CompletionResponse::Array(vec![CompletionItem {
+            label: "foo".to_owned(),
+            ..Default::default()
+        }])))
     }
 }
@@ -0,0 +1,163 @@
+use log::LevelFilter;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// The section name used for `workspace/configuration` requests.
+pub const SECTION: &str = "gneiss";
+
+/// User settings, read from `initializationOptions` and refreshed whenever the
+/// client reports a configuration change.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Config {
+    pub log: LogConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub inlay_hints: InlayHintsConfig,
+    pub formatter: FormatterConfig,
+    pub debug: DebugConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LogConfig {
+    /// Only read at startup, since the log file is opened before the server
+    /// starts handling messages.
+    pub path: PathBuf,
+    pub level: LevelFilter,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            path: "lsp.log".into(),
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DiagnosticsConfig {
+    pub syntax_errors: bool,
+    /// Type errors and references to unknown variables or functions.
+    pub semantic_errors: bool,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        Self {
+            syntax_errors: true,
+            semantic_errors: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct InlayHintsConfig {
+    /// The types of variables whose type isn't written out.
+    pub types: bool,
+    /// The names of parameters at call sites.
+    pub parameter_names: bool,
+}
+
+impl Default for InlayHintsConfig {
+    fn default() -> Self {
+        Self {
+            types: true,
+            parameter_names: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct FormatterConfig {
+    pub indent_width: usize,
+    pub max_width: usize,
+}
+
+impl Default for FormatterConfig {
+    fn default() -> Self {
+        Self {
+            indent_width: 4,
+            max_width: 80,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DebugConfig {
+    /// Reparse documents from scratch after every edit and log any
+    /// differences from the incrementally updated tree and AST.
+    pub check_incremental_edits: bool,
+}
+
+impl Config {
+    /// Parses settings sent by the client. Returns `None` if there are no
+    /// settings or they are malformed, in which case the current configuration
+    /// should be kept.
+    pub fn from_json(value: serde_json::Value) -> Option<Self> {
+        if value.is_null() {
+            return None;
+        }
+        serde_json::from_value(value)
+            .map_err(|err| log::error!("Invalid configuration: {err}"))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn missing_settings_keep_their_defaults() {
+        let config = Config::from_json(json!({
+            "diagnostics": { "semanticErrors": false },
+            "formatter": { "maxWidth": 100 },
+        }))
+        .unwrap();
+        assert_eq!(config.log.path, PathBuf::from("lsp.log"));
+        assert_eq!(config.log.level, LevelFilter::Info);
+        assert!(config.diagnostics.syntax_errors);
+        assert!(!config.diagnostics.semantic_errors);
+        assert!(config.inlay_hints.types);
+        assert!(config.inlay_hints.parameter_names);
+        assert_eq!(config.formatter.indent_width, 4);
+        assert_eq!(config.formatter.max_width, 100);
+        assert!(!config.debug.check_incremental_edits);
+    }
+
+    #[test]
+    fn every_section_is_read() {
+        let config = Config::from_json(json!({
+            "log": { "path": "/tmp/gneiss.log", "level": "debug" },
+            "diagnostics": { "syntaxErrors": false, "semanticErrors": false },
+            "inlayHints": { "types": false, "parameterNames": false },
+            "formatter": { "indentWidth": 2, "maxWidth": 100 },
+            "debug": { "checkIncrementalEdits": true },
+        }))
+        .unwrap();
+        assert_eq!(config.log.path, PathBuf::from("/tmp/gneiss.log"));
+        assert_eq!(config.log.level, LevelFilter::Debug);
+        assert!(!config.diagnostics.syntax_errors);
+        assert!(!config.diagnostics.semantic_errors);
+        assert!(!config.inlay_hints.types);
+        assert!(!config.inlay_hints.parameter_names);
+        assert_eq!(config.formatter.indent_width, 2);
+        assert_eq!(config.formatter.max_width, 100);
+        assert!(config.debug.check_incremental_edits);
+    }
+
+    #[test]
+    fn malformed_settings_are_ignored() {
+        assert!(Config::from_json(serde_json::Value::Null).is_none());
+        assert!(Config::from_json(
+            json!({ "formatter": { "maxWidth": "wide" } })
+        )
+        .is_none());
+    }
+}
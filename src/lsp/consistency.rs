@@ -0,0 +1,152 @@
+//! A debugging aid that compares incrementally updated documents against ones
+//! parsed from scratch, to catch mistakes in how edits are applied.
+
+use super::document::Document;
+use tree_sitter::{Node, Parser};
+
+pub fn check(doc: &Document, parser: &mut Parser) -> Result<(), String> {
+    let fresh_tree = parser.parse(doc.text.to_string(), None).unwrap();
+    compare_nodes(doc.tree.root_node(), fresh_tree.root_node())?;
+
+    let fresh_ast = crate::ast::File::parse(&fresh_tree, &doc.text);
+    if *doc.ast != fresh_ast {
+        return Err(format!(
+            "incremental AST {:#?} differs from fresh AST {fresh_ast:#?}",
+            doc.ast
+        ));
+    }
+
+    Ok(())
+}
+
+fn compare_nodes(incremental: Node, fresh: Node) -> Result<(), String> {
+    if incremental.kind_id() != fresh.kind_id()
+        || incremental.byte_range() != fresh.byte_range()
+        || incremental.start_position() != fresh.start_position()
+        || incremental.end_position() != fresh.end_position()
+        || incremental.is_missing() != fresh.is_missing()
+        || incremental.child_count() != fresh.child_count()
+    {
+        return Err(format!(
+            "incremental tree has {} where a fresh parse has {}",
+            describe(incremental),
+            describe(fresh),
+        ));
+    }
+
+    incremental
+        .children(&mut incremental.walk())
+        .zip(fresh.children(&mut fresh.walk()))
+        .try_for_each(|(incremental, fresh)| compare_nodes(incremental, fresh))
+}
+
+fn describe(node: Node) -> String {
+    format!(
+        "{} with {} children at bytes {:?}, points {:?}..{:?}",
+        node.kind(),
+        node.child_count(),
+        node.byte_range(),
+        node.start_position(),
+        node.end_position(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::PositionEncoding;
+    use lsp_types::{Range, TextDocumentContentChangeEvent};
+    use ropey::Rope;
+
+    /// Pieces of source code that random edits are built from. Includes
+    /// characters that take up a different number of code units in each
+    /// position encoding.
+    const FRAGMENTS: &[&str] = &[
+        "fn ", "main", "äö", "€", "🦀", "𝔤", "(", ")", " -> ", "unit", "i32",
+        "{", "}", "let ", " = ", ";", "1_u8", "\n", "// ", " ", "x", ", ",
+        "foo(",
+    ];
+
+    const INITIAL_TEXT: &str =
+        "fn main() -> unit {\n    let x = foo(1_u8, äö);\n    x\n}\n";
+
+    /// A xorshift generator, so that failures are reproducible from the seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn char_boundary(&mut self, text: &str) -> usize {
+            let boundaries = text
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([text.len()])
+                .collect::<Vec<_>>();
+            boundaries[self.below(boundaries.len())]
+        }
+    }
+
+    fn random_change(
+        rng: &mut Rng,
+        text: &mut String,
+        pos_enc: PositionEncoding,
+    ) -> TextDocumentContentChangeEvent {
+        let a = rng.char_boundary(text);
+        let b = rng.char_boundary(text);
+        let (start, end) = (a.min(b), a.max(b));
+        let new_text = (0..rng.below(4))
+            .map(|_| FRAGMENTS[rng.below(FRAGMENTS.len())])
+            .collect::<String>();
+
+        let rope = Rope::from(text.as_str());
+        let range = Range {
+            start: pos_enc.byte_to_position(&rope, start),
+            end: pos_enc.byte_to_position(&rope, end),
+        };
+        text.replace_range(start..end, &new_text);
+        TextDocumentContentChangeEvent {
+            range: Some(range),
+            range_length: None,
+            text: new_text,
+        }
+    }
+
+    #[test]
+    fn random_edits_match_full_reparse() {
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_gneiss::language()).unwrap();
+
+        for seed in 1..=300 {
+            let mut rng = Rng(seed);
+            let pos_enc = [
+                PositionEncoding::Utf8,
+                PositionEncoding::Utf16,
+                PositionEncoding::Utf32,
+            ][rng.below(3)];
+            let mut text = INITIAL_TEXT.to_owned();
+            let mut doc = Document::new(&mut parser, text.clone(), 0);
+            let mut history = Vec::new();
+
+            for _ in 0..20 {
+                let changes = (0..=rng.below(3))
+                    .map(|_| random_change(&mut rng, &mut text, pos_enc))
+                    .collect::<Vec<_>>();
+                doc.apply_changes(&mut parser, pos_enc, &changes);
+                history.push(changes);
+
+                assert_eq!(doc.text.to_string(), text, "seed {seed}");
+                if let Err(err) = check(&doc, &mut parser) {
+                    panic!(
+                        "seed {seed} ({pos_enc:?}): {err}\n\
+                         edits: {history:#?}"
+                    );
+                }
+            }
+        }
+    }
+}
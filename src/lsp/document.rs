@@ -1,16 +1,116 @@
-use crate::text::PositionEncoding;
-use lsp_types::{Diagnostic, DiagnosticSeverity, Range};
+use crate::{
+    ast::Item,
+    check,
+    text::{byte_to_point, PositionEncoding},
+};
+use lsp_types::{
+    Diagnostic, DiagnosticSeverity, Range, TextDocumentContentChangeEvent,
+};
 use ropey::Rope;
-use tree_sitter::{Tree, TreeCursor};
+use std::{ops, sync::Arc};
+use tree_sitter::{InputEdit, Parser, Tree, TreeCursor};
 
+#[derive(Clone)]
 pub struct Document {
     pub text: Rope,
     pub tree: Tree,
-    pub ast: crate::ast::File,
+    pub ast: Arc<crate::ast::File>,
+    pub version: i32,
     pub diagnostics: Vec<Diagnostic>,
 }
 
 impl Document {
+    pub fn new(parser: &mut Parser, text: String, version: i32) -> Self {
+        let tree = parser.parse(&text, None).unwrap();
+        let text = Rope::from(text);
+        let ast = crate::ast::File::parse(&tree, &text);
+        log::info!("\n{:#?}", ast);
+
+        Self {
+            text,
+            tree,
+            ast: Arc::new(ast),
+            version,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Applies the changes from a `textDocument/didChange` notification in
+    /// order and reparses the document, reusing the old tree where possible.
+    pub fn apply_changes(
+        &mut self,
+        parser: &mut Parser,
+        pos_enc: PositionEncoding,
+        changes: &[TextDocumentContentChangeEvent],
+    ) {
+        // A full replacement invalidates the old tree, so there is nothing to
+        // reuse when reparsing.
+        let mut reuse_tree = true;
+        // Byte ranges of the new text that were inserted by the changes.
+        let mut edited_ranges = Vec::new();
+
+        for change in changes {
+            let Some(range) = change.range else {
+                self.text = Rope::from(&*change.text);
+                reuse_tree = false;
+                continue;
+            };
+            let start_byte = pos_enc.position_to_byte(&self.text, range.start);
+            let old_end_byte = pos_enc.position_to_byte(&self.text, range.end);
+            let new_end_byte = start_byte + change.text.len();
+            let start_position =
+                pos_enc.position_to_point(&self.text, range.start);
+            let old_end_position =
+                pos_enc.position_to_point(&self.text, range.end);
+            let start_char = self.text.byte_to_char(start_byte);
+            self.text
+                .remove(start_char..self.text.byte_to_char(old_end_byte));
+            self.text.insert(start_char, &change.text);
+            if reuse_tree {
+                let edit = InputEdit {
+                    start_byte,
+                    old_end_byte,
+                    new_end_byte,
+                    start_position,
+                    old_end_position,
+                    new_end_position: byte_to_point(&self.text, new_end_byte),
+                };
+                self.tree.edit(&edit);
+                for range in &mut edited_ranges {
+                    *range = shift_range(range, &edit);
+                }
+                edited_ranges.push(start_byte..new_end_byte);
+            }
+        }
+
+        let text = &self.text;
+        let tree = parser
+            .parse_with(
+                &mut |byte_offest, _position| {
+                    let (chunk, chunk_start, ..) =
+                        text.chunk_at_byte(byte_offest);
+                    &chunk.as_bytes()[(byte_offest - chunk_start)..]
+                },
+                reuse_tree.then_some(&self.tree),
+            )
+            .unwrap();
+
+        self.ast = Arc::new(if reuse_tree {
+            // Renaming an identifier doesn't change the structure of the tree,
+            // so the edited ranges have to be checked as well.
+            edited_ranges.extend(
+                self.tree
+                    .changed_ranges(&tree)
+                    .map(|range| range.start_byte..range.end_byte),
+            );
+            self.ast
+                .reparse(&self.tree, &tree, &self.text, &edited_ranges)
+        } else {
+            crate::ast::File::parse(&tree, &self.text)
+        });
+        self.tree = tree;
+    }
+
     pub fn check_syntax_errors(&mut self, pos_enc: PositionEncoding) {
         let mut cursor = self.tree.walk();
         check_node_for_syntax_errors(
@@ -20,6 +120,56 @@ impl Document {
             pos_enc,
         );
     }
+
+    pub fn check_semantic_errors(
+        &mut self,
+        errors: &[(Item, Arc<Vec<check::Error>>)],
+        pos_enc: PositionEncoding,
+    ) {
+        for (item, errors) in errors {
+            let Some(node) =
+                crate::ast::item_node(&self.tree, &self.text, *item)
+            else {
+                continue;
+            };
+            let item_start = node.start_byte();
+            for error in &**errors {
+                let range = Range {
+                    start: pos_enc.byte_to_position(
+                        &self.text,
+                        item_start + error.span.start,
+                    ),
+                    end: pos_enc.byte_to_position(
+                        &self.text,
+                        item_start + error.span.end,
+                    ),
+                };
+                self.diagnostics.push(Diagnostic {
+                    range,
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    message: error.message.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+}
+
+/// Maps a byte range from before `edit` to the corresponding range after it.
+fn shift_range(
+    range: &ops::Range<usize>,
+    edit: &InputEdit,
+) -> ops::Range<usize> {
+    let shift = |byte: usize| {
+        if byte <= edit.start_byte {
+            byte
+        } else if byte >= edit.old_end_byte {
+            byte - edit.old_end_byte + edit.new_end_byte
+        } else {
+            edit.new_end_byte
+        }
+    };
+    shift(range.start)..shift(range.end)
 }
 
 fn check_node_for_syntax_errors(
@@ -0,0 +1,124 @@
+use super::LanguageServer;
+use lsp_types::{
+    notification::{Notification as _, Progress as ProgressNotification},
+    request::WorkDoneProgressCreate,
+    NumberOrString, ProgressParams, ProgressParamsValue, ProgressToken,
+    WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCreateParams,
+    WorkDoneProgressEnd, WorkDoneProgressReport,
+};
+
+/// A work-done progress report shown by the client. Does nothing if the client
+/// doesn't support progress reporting.
+#[derive(Clone)]
+pub struct Progress {
+    token: Option<ProgressToken>,
+}
+
+/// A progress report that has begun but not ended. Nothing may be sent for it
+/// until the client has answered the request to create its token.
+pub enum ProgressState {
+    Requested { title: String },
+    Created,
+}
+
+impl LanguageServer {
+    pub fn begin_progress(&mut self, title: &str) -> Progress {
+        if !self.work_done_progress {
+            return Progress { token: None };
+        }
+
+        self.next_progress_token += 1;
+        let token = NumberOrString::String(format!(
+            "gneiss/{}",
+            self.next_progress_token
+        ));
+        self.progress.insert(
+            token.clone(),
+            ProgressState::Requested {
+                title: title.to_owned(),
+            },
+        );
+        let progress = Progress {
+            token: Some(token.clone()),
+        };
+        self.send_request_then::<WorkDoneProgressCreate>(
+            WorkDoneProgressCreateParams {
+                token: token.clone(),
+            },
+            move |server, ()| {
+                // The work may have finished while waiting for the client.
+                let Some(state) = server.progress.get_mut(&token) else {
+                    return;
+                };
+                if let ProgressState::Requested { title } = state {
+                    let title = std::mem::take(title);
+                    *state = ProgressState::Created;
+                    server.send_progress(
+                        &token,
+                        WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                            title,
+                            percentage: Some(0),
+                            ..Default::default()
+                        }),
+                    );
+                }
+            },
+        );
+        progress
+    }
+
+    pub fn report_progress(
+        &self,
+        progress: &Progress,
+        message: String,
+        percentage: u32,
+    ) {
+        if let Some(token) = self.created_token(progress) {
+            self.send_progress(
+                token,
+                WorkDoneProgress::Report(WorkDoneProgressReport {
+                    message: Some(message),
+                    percentage: Some(percentage),
+                    ..Default::default()
+                }),
+            );
+        }
+    }
+
+    pub fn end_progress(&mut self, progress: Progress) {
+        if let Some(token) = self.created_token(&progress) {
+            self.send_progress(
+                token,
+                WorkDoneProgress::End(WorkDoneProgressEnd { message: None }),
+            );
+        }
+        if let Some(token) = &progress.token {
+            self.progress.remove(token);
+        }
+    }
+
+    fn created_token<'a>(
+        &self,
+        progress: &'a Progress,
+    ) -> Option<&'a ProgressToken> {
+        progress.token.as_ref().filter(|token| {
+            matches!(self.progress.get(token), Some(ProgressState::Created))
+        })
+    }
+
+    fn send_progress(&self, token: &ProgressToken, value: WorkDoneProgress) {
+        self.connection
+            .sender
+            .send(
+                lsp_server::Notification::new(
+                    ProgressNotification::METHOD.to_owned(),
+                    ProgressParams {
+                        token: token.clone(),
+                        value: ProgressParamsValue::WorkDone(value),
+                    },
+                )
+                .into(),
+            )
+            .unwrap();
+    }
+}
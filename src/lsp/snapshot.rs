@@ -0,0 +1,24 @@
+use super::{document::Document, RequestError};
+use crate::text::PositionEncoding;
+use lsp_types::Url;
+use std::{collections::HashMap, sync::Arc};
+
+/// An immutable view of the server state that read-only requests can work on
+/// from another thread while the main thread keeps applying edits.
+pub struct Snapshot {
+    pub pos_enc: PositionEncoding,
+    pub docs: HashMap<Url, Arc<Document>>,
+    pub workspace_files: HashMap<Url, Arc<Document>>,
+}
+
+impl Snapshot {
+    /// Looks up a document, preferring the editor's version of open files over
+    /// what is on disk.
+    pub fn doc(&self, uri: &Url) -> Result<&Document, RequestError> {
+        self.docs
+            .get(uri)
+            .or_else(|| self.workspace_files.get(uri))
+            .map(Arc::as_ref)
+            .ok_or_else(|| RequestError::unknown_document(uri))
+    }
+}
@@ -0,0 +1,538 @@
+//! End-to-end tests that drive the server through an in-memory connection,
+//! the same way an editor would over stdio.
+
+use super::LanguageServer;
+use crate::text::PositionEncoding;
+use lsp_server::{
+    Connection, ErrorCode, Message, Notification, Request, RequestId, Response,
+    ResponseError,
+};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Exit,
+        Initialized, Notification as _, Progress as ProgressNotification,
+        PublishDiagnostics,
+    },
+    request::{
+        Completion, HoverRequest, Initialize, Request as _, Shutdown,
+        WorkDoneProgressCreate,
+    },
+    *,
+};
+use ropey::Rope;
+use std::{
+    collections::VecDeque,
+    ops::Range as ByteRange,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const ALL_ENCODINGS: [PositionEncodingKind; 3] = [
+    PositionEncodingKind::UTF8,
+    PositionEncodingKind::UTF16,
+    PositionEncodingKind::UTF32,
+];
+
+struct TestClient {
+    connection: Connection,
+    server: JoinHandle<()>,
+    position_encoding: PositionEncodingKind,
+    next_request_id: i32,
+    /// Notifications that arrived while waiting for something else.
+    notifications: VecDeque<Notification>,
+}
+
+impl TestClient {
+    fn start(position_encodings: Option<Vec<PositionEncodingKind>>) -> Self {
+        Self::initialize(InitializeParams {
+            capabilities: ClientCapabilities {
+                general: Some(GeneralClientCapabilities {
+                    position_encodings,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn initialize(params: InitializeParams) -> Self {
+        let (client, server) = Connection::memory();
+        let server = thread::spawn(move || {
+            LanguageServer::from_connection(server).run();
+        });
+        let mut client = Self {
+            connection: client,
+            server,
+            position_encoding: PositionEncodingKind::UTF16,
+            next_request_id: 0,
+            notifications: VecDeque::new(),
+        };
+
+        let result = client.request::<Initialize>(params);
+        client.position_encoding = result
+            .capabilities
+            .position_encoding
+            .expect("server did not pick a position encoding");
+        client.notify::<Initialized>(InitializedParams {});
+        client
+    }
+
+    fn with_encoding(encoding: PositionEncodingKind) -> Self {
+        let client = Self::start(Some(vec![encoding.clone()]));
+        assert_eq!(client.position_encoding, encoding);
+        client
+    }
+
+    fn shutdown(mut self) {
+        self.request::<Shutdown>(());
+        self.notify::<Exit>(());
+        drop(self.connection);
+        self.server.join().expect("server panicked");
+    }
+
+    fn notify<N: lsp_types::notification::Notification>(
+        &self,
+        params: N::Params,
+    ) {
+        self.connection
+            .sender
+            .send(Notification::new(N::METHOD.to_owned(), params).into())
+            .unwrap();
+    }
+
+    fn request<R: lsp_types::request::Request>(
+        &mut self,
+        params: R::Params,
+    ) -> R::Result {
+        let result = self
+            .raw_request(R::METHOD, serde_json::to_value(params).unwrap())
+            .unwrap_or_else(|err| {
+                panic!("{} failed: {}", R::METHOD, err.message)
+            });
+        serde_json::from_value(result).unwrap()
+    }
+
+    fn raw_request(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, ResponseError> {
+        self.next_request_id += 1;
+        let id = RequestId::from(self.next_request_id);
+        self.connection
+            .sender
+            .send(Request::new(id.clone(), method.to_owned(), params).into())
+            .unwrap();
+
+        loop {
+            match self.recv() {
+                Message::Response(response) if response.id == id => {
+                    return match response.error {
+                        Some(err) => Err(err),
+                        None => Ok(response.result.unwrap_or_default()),
+                    };
+                }
+                Message::Response(response) => {
+                    panic!("unexpected response: {response:?}");
+                }
+                Message::Notification(notification) => {
+                    self.notifications.push_back(notification);
+                }
+                Message::Request(request) => self.reply_to_server(request),
+            }
+        }
+    }
+
+    fn next_notification<N: lsp_types::notification::Notification>(
+        &mut self,
+    ) -> N::Params {
+        loop {
+            if let Some(index) = self
+                .notifications
+                .iter()
+                .position(|notification| notification.method == N::METHOD)
+            {
+                let notification = self.notifications.remove(index).unwrap();
+                return serde_json::from_value(notification.params).unwrap();
+            }
+            match self.recv() {
+                Message::Notification(notification) => {
+                    self.notifications.push_back(notification);
+                }
+                Message::Request(request) => self.reply_to_server(request),
+                Message::Response(response) => {
+                    panic!("unexpected response: {response:?}");
+                }
+            }
+        }
+    }
+
+    fn diagnostics(&mut self) -> PublishDiagnosticsParams {
+        self.next_notification::<PublishDiagnostics>()
+    }
+
+    fn reply_to_server(&self, request: Request) {
+        self.connection
+            .sender
+            .send(Response::new_ok(request.id, serde_json::Value::Null).into())
+            .unwrap();
+    }
+
+    fn recv(&self) -> Message {
+        self.connection
+            .receiver
+            .recv_timeout(TIMEOUT)
+            .expect("timed out waiting for the server")
+    }
+
+    /// Converts a byte offset in `text` into a position in the negotiated
+    /// encoding.
+    fn position(&self, text: &str, byte: usize) -> Position {
+        PositionEncoding::try_from(self.position_encoding.clone())
+            .unwrap()
+            .byte_to_position(&Rope::from(text), byte)
+    }
+
+    fn position_of(&self, text: &str, needle: &str) -> Position {
+        let byte = text.find(needle).expect("needle not found in text");
+        self.position(text, byte)
+    }
+}
+
+/// A document as the client sees it, kept in sync with what is sent to the
+/// server.
+struct TestDocument {
+    uri: Url,
+    text: String,
+    version: i32,
+}
+
+impl TestDocument {
+    fn open(client: &mut TestClient, text: &str) -> Self {
+        let doc = Self {
+            uri: Url::parse("file:///test.gneiss").unwrap(),
+            text: text.to_owned(),
+            version: 1,
+        };
+        client.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: doc.uri.clone(),
+                language_id: "gneiss".to_owned(),
+                version: doc.version,
+                text: doc.text.clone(),
+            },
+        });
+        doc
+    }
+
+    /// Sends a single notification containing all `edits`, which are applied
+    /// one after another as the protocol requires.
+    fn edit(
+        &mut self,
+        client: &mut TestClient,
+        edits: &[(ByteRange<usize>, &str)],
+    ) {
+        let content_changes = edits
+            .iter()
+            .map(|(range, new_text)| {
+                let change = TextDocumentContentChangeEvent {
+                    range: Some(Range {
+                        start: client.position(&self.text, range.start),
+                        end: client.position(&self.text, range.end),
+                    }),
+                    range_length: None,
+                    text: (*new_text).to_owned(),
+                };
+                self.text.replace_range(range.clone(), new_text);
+                change
+            })
+            .collect();
+        self.send_changes(client, content_changes);
+    }
+
+    fn replace_all(&mut self, client: &mut TestClient, text: &str) {
+        self.text = text.to_owned();
+        self.send_changes(
+            client,
+            vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_owned(),
+            }],
+        );
+    }
+
+    fn send_changes(
+        &mut self,
+        client: &mut TestClient,
+        content_changes: Vec<TextDocumentContentChangeEvent>,
+    ) {
+        self.version += 1;
+        client.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: self.uri.clone(),
+                version: self.version,
+            },
+            content_changes,
+        });
+    }
+
+    fn hover_kind(&self, client: &mut TestClient, needle: &str) -> String {
+        let position = client.position_of(&self.text, needle);
+        let hover = client
+            .request::<HoverRequest>(HoverParams {
+                text_document_position_params: TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: self.uri.clone(),
+                    },
+                    position,
+                },
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            })
+            .expect("no hover information");
+        let HoverContents::Markup(MarkupContent { value, .. }) = hover.contents
+        else {
+            panic!("unexpected hover contents: {:?}", hover.contents);
+        };
+        value
+    }
+}
+
+fn byte_range_of(text: &str, needle: &str) -> ByteRange<usize> {
+    let start = text.find(needle).expect("needle not found in text");
+    start..start + needle.len()
+}
+
+#[test]
+fn negotiates_position_encoding() {
+    for (offered, expected) in [
+        (None, PositionEncodingKind::UTF16),
+        (Some(vec![]), PositionEncodingKind::UTF16),
+        (
+            Some(vec![PositionEncodingKind::UTF32]),
+            PositionEncodingKind::UTF32,
+        ),
+        (
+            Some(vec![
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8,
+            ]),
+            PositionEncodingKind::UTF8,
+        ),
+    ] {
+        let client = TestClient::start(offered);
+        assert_eq!(client.position_encoding, expected);
+        client.shutdown();
+    }
+}
+
+#[test]
+fn publishes_versioned_diagnostics() {
+    for encoding in ALL_ENCODINGS {
+        let mut client = TestClient::with_encoding(encoding);
+        let mut doc =
+            TestDocument::open(&mut client, "fn äö() -> unit { $ }\n");
+
+        let diagnostics = client.diagnostics();
+        assert_eq!(diagnostics.uri, doc.uri);
+        assert_eq!(diagnostics.version, Some(1));
+        assert_eq!(diagnostics.diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics.diagnostics[0].range.start,
+            client.position_of(&doc.text, "$"),
+        );
+
+        let error = byte_range_of(&doc.text, "$ ");
+        doc.edit(&mut client, &[(error, "")]);
+        let diagnostics = client.diagnostics();
+        assert_eq!(diagnostics.version, Some(2));
+        assert!(diagnostics.diagnostics.is_empty());
+
+        client.notify::<DidCloseTextDocument>(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier {
+                uri: doc.uri.clone(),
+            },
+        });
+        let diagnostics = client.diagnostics();
+        assert_eq!(diagnostics.version, None);
+        assert!(diagnostics.diagnostics.is_empty());
+
+        client.shutdown();
+    }
+}
+
+#[test]
+fn incremental_edits_with_multi_byte_characters() {
+    for encoding in ALL_ENCODINGS {
+        let mut client = TestClient::with_encoding(encoding);
+        let mut doc = TestDocument::open(&mut client, "fn f() -> unit {}\n");
+        client.diagnostics();
+
+        // Two-byte, three-byte and astral-plane characters take up different
+        // numbers of code units in every encoding.
+        let name = byte_range_of(&doc.text, "f(");
+        doc.edit(&mut client, &[(name.start..name.start + 1, "äöü€")]);
+        client.diagnostics();
+        assert_eq!(
+            doc.hover_kind(&mut client, "unit"),
+            "Hovering kind `primitive_type`"
+        );
+
+        let end = doc.text.len();
+        doc.edit(
+            &mut client,
+            &[(end..end, "// 𝔤𝔫𝔢𝔦𝔰𝔰\n"), (0..0, "// 🦀🦀 comment\n")],
+        );
+        client.diagnostics();
+        assert_eq!(
+            doc.hover_kind(&mut client, "äöü€"),
+            "Hovering kind `identifier`"
+        );
+        assert_eq!(
+            doc.hover_kind(&mut client, "unit"),
+            "Hovering kind `primitive_type`"
+        );
+
+        // Later changes in the same notification refer to the text as it is
+        // after the earlier ones.
+        let comment = byte_range_of(&doc.text, "🦀🦀");
+        let unit = byte_range_of(&doc.text, "unit");
+        doc.edit(
+            &mut client,
+            &[(unit, "u8"), (comment.start..comment.start + 4, "")],
+        );
+        client.diagnostics();
+        assert_eq!(doc.text, "// 🦀 comment\nfn äöü€() -> u8 {}\n// 𝔤𝔫𝔢𝔦𝔰𝔰\n");
+        assert_eq!(
+            doc.hover_kind(&mut client, "u8"),
+            "Hovering kind `primitive_type`"
+        );
+        assert_eq!(doc.hover_kind(&mut client, "fn"), "Hovering kind `fn`");
+
+        client.shutdown();
+    }
+}
+
+#[test]
+fn full_document_replacement() {
+    let mut client = TestClient::with_encoding(PositionEncodingKind::UTF16);
+    let mut doc = TestDocument::open(&mut client, "fn f() -> unit {}\n");
+    client.diagnostics();
+
+    doc.replace_all(&mut client, "fn g() -> i32 { $ }\n");
+    let diagnostics = client.diagnostics();
+    assert_eq!(diagnostics.version, Some(2));
+    assert_eq!(diagnostics.diagnostics.len(), 1);
+    assert_eq!(
+        doc.hover_kind(&mut client, "i32"),
+        "Hovering kind `primitive_type`"
+    );
+
+    client.shutdown();
+}
+
+#[test]
+fn completion() {
+    let mut client = TestClient::with_encoding(PositionEncodingKind::UTF8);
+    let doc = TestDocument::open(&mut client, "fn f() -> unit {}\n");
+    client.diagnostics();
+
+    let completions = client.request::<Completion>(CompletionParams {
+        text_document_position: TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: doc.uri.clone(),
+            },
+            position: client.position_of(&doc.text, "}"),
+        },
+        work_done_progress_params: WorkDoneProgressParams::default(),
+        partial_result_params: PartialResultParams::default(),
+        context: None,
+    });
+    let Some(CompletionResponse::Array(items)) = completions else {
+        panic!("unexpected completions: {completions:?}");
+    };
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].label, "foo");
+
+    client.shutdown();
+}
+
+#[test]
+fn request_errors() {
+    let mut client = TestClient::with_encoding(PositionEncodingKind::UTF16);
+
+    let err = client
+        .raw_request("gneiss/doesNotExist", serde_json::Value::Null)
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::MethodNotFound as i32);
+
+    let err = client
+        .raw_request(HoverRequest::METHOD, serde_json::json!({ "foo": 1 }))
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::InvalidParams as i32);
+
+    let err = client
+        .raw_request(
+            HoverRequest::METHOD,
+            serde_json::json!({
+                "textDocument": { "uri": "file:///unknown.gneiss" },
+                "position": { "line": 0, "character": 0 },
+            }),
+        )
+        .unwrap_err();
+    assert_eq!(err.code, ErrorCode::RequestFailed as i32);
+
+    // The server keeps working after failed requests.
+    TestDocument::open(&mut client, "fn f() -> unit {}\n");
+    assert!(client.diagnostics().diagnostics.is_empty());
+
+    client.shutdown();
+}
+
+#[test]
+fn indexes_the_workspace_in_the_background() {
+    let root = std::env::temp_dir()
+        .join(format!("gneiss-workspace-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let path = root.join("unopened.gneiss");
+    std::fs::write(&path, "fn f() -> i32 { 1_u8 }\n").unwrap();
+
+    let mut client = TestClient::initialize(InitializeParams {
+        workspace_folders: Some(vec![WorkspaceFolder {
+            uri: Url::from_file_path(&root).unwrap(),
+            name: "test".to_owned(),
+        }]),
+        capabilities: ClientCapabilities {
+            window: Some(WindowClientCapabilities {
+                work_done_progress: Some(true),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    // Progress may only be reported once the client has created the token.
+    let request = loop {
+        match client.recv() {
+            Message::Request(request) => break request,
+            Message::Notification(notification) => {
+                assert_ne!(notification.method, ProgressNotification::METHOD);
+                client.notifications.push_back(notification);
+            }
+            Message::Response(response) => {
+                panic!("unexpected response: {response:?}");
+            }
+        }
+    };
+    assert_eq!(request.method, WorkDoneProgressCreate::METHOD);
+    client.reply_to_server(request);
+
+    let diagnostics = client.diagnostics();
+    assert_eq!(diagnostics.uri, Url::from_file_path(&path).unwrap());
+    assert_eq!(diagnostics.diagnostics.len(), 1);
+    client.shutdown();
+    std::fs::remove_dir_all(root).unwrap();
+}
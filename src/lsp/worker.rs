@@ -0,0 +1,146 @@
+use super::RequestError;
+use lsp_server::{ErrorCode, RequestId};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads that run read-only requests off the main thread so
+/// that slow requests don't hold up document edits.
+pub struct WorkerPool {
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new() -> Self {
+        let thread_count =
+            thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..thread_count)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("lsp-worker-{i}"))
+                    .spawn(move || loop {
+                        let job = receiver.lock().unwrap().recv();
+                        let Ok(job) = job else {
+                            break;
+                        };
+                        job();
+                    })
+                    .unwrap()
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            threads,
+        }
+    }
+
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        self.sender
+            .as_ref()
+            .expect("worker pool is shutting down")
+            .send(Box::new(job))
+            .expect("all worker threads have exited");
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the channel makes every worker exit once the queue is empty.
+        self.sender = None;
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                log::error!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+// Ordered so that later states replace earlier ones.
+const RUNNING: u8 = 0;
+const CONTENT_MODIFIED: u8 = 1;
+const CANCELLED: u8 = 2;
+
+/// Shared between the main thread and the worker handling a request, so that
+/// the main thread can tell the worker that its result is no longer wanted.
+#[derive(Clone)]
+pub struct CancellationToken(Arc<AtomicU8>);
+
+impl CancellationToken {
+    pub fn check(&self) -> Result<(), RequestError> {
+        match self.0.load(Ordering::Relaxed) {
+            RUNNING => Ok(()),
+            CONTENT_MODIFIED => Err(RequestError::new(
+                ErrorCode::ContentModified,
+                "document was modified while handling the request",
+            )),
+            _ => Err(RequestError::new(
+                ErrorCode::RequestCanceled,
+                "request was cancelled",
+            )),
+        }
+    }
+
+    fn set(&self, state: u8) {
+        // A cancellation by the client takes precedence over staleness.
+        self.0.fetch_max(state, Ordering::Relaxed);
+    }
+}
+
+/// Requests that have been handed to the worker pool but not yet answered.
+#[derive(Clone, Default)]
+pub struct PendingRequests(Arc<Mutex<HashMap<RequestId, CancellationToken>>>);
+
+impl PendingRequests {
+    pub fn register(&self, id: RequestId) -> CancellationToken {
+        let token = CancellationToken(Arc::new(AtomicU8::new(RUNNING)));
+        self.0.lock().unwrap().insert(id, token.clone());
+        token
+    }
+
+    pub fn finish(&self, id: &RequestId) {
+        self.0.lock().unwrap().remove(id);
+    }
+
+    /// Handles a `$/cancelRequest` from the client.
+    pub fn cancel(&self, id: &RequestId) {
+        if let Some(token) = self.0.lock().unwrap().get(id) {
+            token.set(CANCELLED);
+        }
+    }
+
+    /// Marks every pending request as stale. Called whenever a document
+    /// changes, since results computed from the old text are useless.
+    pub fn invalidate_all(&self) {
+        for token in self.0.lock().unwrap().values() {
+            token.set(CONTENT_MODIFIED);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancellation_takes_precedence_over_staleness() {
+        let pending = PendingRequests::default();
+        let id = RequestId::from(1);
+        let token = pending.register(id.clone());
+        pending.cancel(&id);
+        pending.invalidate_all();
+        let err = token.check().unwrap_err();
+        assert_eq!(err.code as i32, ErrorCode::RequestCanceled as i32);
+    }
+}
@@ -0,0 +1,154 @@
+use super::{document::Document, LanguageServer, Task};
+use lsp_types::{
+    request::RegisterCapability, DidChangeWatchedFilesParams,
+    DidChangeWatchedFilesRegistrationOptions, FileChangeType,
+    FileSystemWatcher, GlobPattern, Registration, RegistrationParams, Url,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tree_sitter::Parser;
+
+const SOURCE_FILE_EXTENSION: &str = "gneiss";
+
+impl LanguageServer {
+    /// Parses every source file in the workspace on the worker pool, so that
+    /// files the editor hasn't opened are still known to the server.
+    pub fn index_workspace(&mut self) {
+        if self.watch_files {
+            self.register_file_watcher();
+        }
+
+        let roots = self.workspace_roots.clone();
+        let progress = self.begin_progress("Indexing");
+        let task_sender = self.task_sender.clone();
+        self.workers.spawn(move || {
+            let mut paths = Vec::new();
+            for root in &roots {
+                find_source_files(root, &mut paths);
+            }
+
+            let mut parser = Parser::new();
+            parser.set_language(tree_sitter_gneiss::language()).unwrap();
+            let count = paths.len();
+            for (i, path) in paths.into_iter().enumerate() {
+                let file = Url::from_file_path(&path).ok().and_then(|uri| {
+                    Some((uri, read_document(&mut parser, &path)?))
+                });
+                let progress = progress.clone();
+                let task: Task = Box::new(move |server| {
+                    server.report_progress(
+                        &progress,
+                        format!("{}/{count}", i + 1),
+                        (i * 100 / count) as u32,
+                    );
+                    if let Some((uri, doc)) = file {
+                        server.add_workspace_file(uri, doc);
+                    }
+                });
+                if task_sender.send(task).is_err() {
+                    log::info!("Stopped indexing because of a shutdown");
+                    return;
+                }
+            }
+            let task: Task = Box::new(move |server| {
+                server.end_progress(progress);
+                log::info!("Indexed {count} workspace files");
+            });
+            if task_sender.send(task).is_err() {
+                log::info!("Stopped indexing because of a shutdown");
+            }
+        });
+    }
+
+    pub fn did_change_watched_files(
+        &mut self,
+        params: DidChangeWatchedFilesParams,
+    ) {
+        for event in params.changes {
+            if event.typ == FileChangeType::DELETED {
+                self.workspace_files.remove(&event.uri);
+                self.pending.invalidate_all();
+                if !self.docs.contains_key(&event.uri) {
+                    self.db.remove_file(event.uri.as_str().into());
+                    self.publish_diagnostics(event.uri, Vec::new(), None);
+                }
+            } else if let Ok(path) = event.uri.to_file_path() {
+                self.index_file(event.uri, &path);
+            }
+        }
+    }
+
+    fn index_file(&mut self, uri: Url, path: &Path) {
+        if let Some(doc) = read_document(&mut self.parser, path) {
+            self.add_workspace_file(uri, doc);
+        }
+    }
+
+    fn add_workspace_file(&mut self, uri: Url, doc: Document) {
+        self.workspace_files.insert(uri.clone(), Arc::new(doc));
+        self.pending.invalidate_all();
+
+        // Open documents are more up to date than what is on disk.
+        if !self.docs.contains_key(&uri) {
+            self.update_and_publish_diagnostics(uri);
+        }
+    }
+
+    fn register_file_watcher(&mut self) {
+        let options = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: GlobPattern::String(format!(
+                    "**/*.{SOURCE_FILE_EXTENSION}"
+                )),
+                kind: None,
+            }],
+        };
+        self.send_request::<RegisterCapability>(RegistrationParams {
+            registrations: vec![Registration {
+                id: "gneiss/watch-source-files".to_owned(),
+                method: "workspace/didChangeWatchedFiles".to_owned(),
+                register_options: Some(serde_json::to_value(options).unwrap()),
+            }],
+        });
+    }
+}
+
+fn read_document(parser: &mut Parser, path: &Path) -> Option<Document> {
+    match fs::read_to_string(path) {
+        Ok(text) => Some(Document::new(parser, text, 0)),
+        Err(err) => {
+            log::warn!("Failed to read {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+fn find_source_files(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::warn!("Failed to read directory {}: {err}", dir.display());
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_hidden = entry.file_name().to_string_lossy().starts_with('.');
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() && !is_hidden => {
+                find_source_files(&path, paths);
+            }
+            Ok(file_type)
+                if file_type.is_file()
+                    && path.extension()
+                        == Some(SOURCE_FILE_EXTENSION.as_ref()) =>
+            {
+                paths.push(path);
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,13 +1,26 @@
 #![forbid(unsafe_code)]
 
 mod ast;
+mod backend;
+mod cache;
+mod check;
 mod compile;
+mod db;
+mod intrinsics;
+mod ir;
+mod layout;
 mod lsp;
+mod opt;
 mod text;
 mod typ;
 
 use gumdrop::Options;
-use std::{path::PathBuf, process::ExitCode};
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+};
 
 #[derive(Options)]
 struct Opts {
@@ -31,10 +44,61 @@ struct CompileCommand {
     /// The source file to compile
     #[options(free, required)]
     file: PathBuf,
+
+    /// Where to keep results for reuse by later compilations
+    #[options(no_short, meta = "DIR", default = ".gneiss-cache")]
+    cache_dir: PathBuf,
+
+    /// Report which results were reused from the cache
+    verbose: bool,
+
+    /// Optimization level: 0, 1 or 2
+    #[options(short = "O", meta = "LEVEL", default = "0")]
+    opt_level: opt::OptLevel,
+
+    /// Print the IR before and after each optimization pass that changes it
+    #[options(no_short)]
+    dump_passes: bool,
+
+    /// What to output: exe, lib, dylib, c, header, llvm-ir, obj, asm, wasm,
+    /// wat or ir
+    #[options(no_short, meta = "KIND", default = "exe")]
+    emit: compile::Emit,
+
+    /// Start at `_start` instead of using the C library, which makes exe
+    /// output a static executable
+    #[options(no_short)]
+    no_libc: bool,
+
+    /// Include debug information for debuggers like GDB and LLDB, which
+    /// builds exe output with the native backend
+    #[options(short = "g")]
+    debug: bool,
+
+    /// A system library to link exe and dylib output with, for `extern`
+    /// functions
+    #[options(short = "l", meta = "NAME")]
+    libraries: Vec<String>,
+
+    /// A directory to search for libraries given with -l
+    #[options(short = "L", meta = "DIR")]
+    library_paths: Vec<PathBuf>,
+
+    /// Where to write the output, or - for standard output
+    #[options(meta = "PATH")]
+    output: Option<PathBuf>,
 }
 
 #[derive(Options)]
-struct LspCommand {}
+struct LspCommand {
+    /// Wait for a client to connect to this address instead of using stdio
+    #[options(no_short, meta = "ADDR")]
+    listen: Option<String>,
+
+    /// Connect to a client at this address instead of using stdio
+    #[options(no_short, meta = "ADDR")]
+    connect: Option<String>,
+}
 
 fn main() -> ExitCode {
     let opts = Opts::parse_args_default_or_exit();
@@ -47,22 +111,92 @@ fn main() -> ExitCode {
     };
 
     match command {
-        Command::Compile(CompileCommand { file }) => compile::compile(&file),
-        Command::Lsp(LspCommand {}) => {
+        Command::Compile(command) => return compile::compile(&command),
+        Command::Lsp(LspCommand { listen, connect }) => {
+            // The log level can be changed later on, so let the logger accept
+            // everything and filter using `log::set_max_level` instead.
+            let log_output = LogOutput::default();
             simplelog::WriteLogger::init(
-                log::LevelFilter::Info,
+                log::LevelFilter::Trace,
                 simplelog::Config::default(),
-                std::fs::File::create("lsp.log").unwrap(),
+                log_output.clone(),
             )
             .unwrap();
+            log::set_max_level(log::LevelFilter::Info);
             std::panic::set_hook(Box::new(|panic_info| {
                 log::error!("{panic_info}");
                 log::error!("{}", std::backtrace::Backtrace::force_capture());
             }));
 
-            lsp::LanguageServer::new().run();
+            let server = match (listen, connect) {
+                (None, None) => lsp::LanguageServer::stdio(),
+                (Some(addr), None) => {
+                    match lsp::LanguageServer::listen(&*addr) {
+                        Ok(server) => server,
+                        Err(err) => {
+                            eprintln!("Failed to listen on {addr}: {err}");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                }
+                (None, Some(addr)) => {
+                    match lsp::LanguageServer::connect(&*addr) {
+                        Ok(server) => server,
+                        Err(err) => {
+                            eprintln!("Failed to connect to {addr}: {err}");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                }
+                (Some(_), Some(_)) => {
+                    eprintln!(
+                        "`--listen` and `--connect` are mutually exclusive"
+                    );
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            let log_config = &server.config().log;
+            match std::fs::File::create(&log_config.path) {
+                Ok(file) => log_output.redirect(file),
+                Err(err) => log::error!(
+                    "Failed to create log file {}, logging to stderr \
+                     instead: {err}",
+                    log_config.path.display()
+                ),
+            }
+            log::set_max_level(log_config.level);
+
+            server.run();
         }
     }
 
     ExitCode::SUCCESS
 }
+
+/// Where the language server writes its log. Messages go to standard error
+/// until the log file from the client's settings has been opened.
+#[derive(Clone)]
+struct LogOutput(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl LogOutput {
+    fn redirect(&self, writer: impl Write + Send + 'static) {
+        *self.0.lock().unwrap() = Box::new(writer);
+    }
+}
+
+impl Default for LogOutput {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(Box::new(io::stderr()))))
+    }
+}
+
+impl Write for LogOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
@@ -0,0 +1,210 @@
+//! Optimization passes over the IR. Each pass reports whether it changed
+//! anything, so that passes that enable each other can be repeated until the
+//! program stops changing.
+
+mod constant_folding;
+mod copy_propagation;
+mod dead_code;
+mod inlining;
+mod simplify_cfg;
+
+use crate::ir::{verify::verify, Function, Program};
+use std::{str::FromStr, sync::Arc};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// No optimizations.
+    O0,
+    /// Optimizations within each function.
+    O1,
+    /// Inlining of small functions in addition to everything in `O1`.
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::O0),
+            "1" => Ok(Self::O1),
+            "2" => Ok(Self::O2),
+            _ => Err(format!("invalid optimization level `{s}`")),
+        }
+    }
+}
+
+type FunctionPass = fn(&mut Function) -> bool;
+
+/// Passes that work on one function at a time, in the order they are run.
+const FUNCTION_PASSES: &[(&str, FunctionPass)] = &[
+    ("copy-propagation", copy_propagation::run),
+    ("constant-folding", constant_folding::run),
+    ("dead-code-elimination", dead_code::run),
+    ("simplify-cfg", simplify_cfg::run),
+];
+
+/// Gives up on reaching a fixed point after this many rounds of
+/// `FUNCTION_PASSES`.
+const MAX_ROUNDS: usize = 10;
+
+/// Optimizes `program` in place. If `dump` is set, the IR is printed to
+/// standard error before and after every pass that changes it.
+pub fn optimize(program: &mut Program, level: OptLevel, dump: bool) {
+    let mut pipeline = Pipeline { program, dump };
+    if level >= OptLevel::O1 {
+        pipeline.run_function_passes();
+    }
+    pipeline.run_program_passes(level);
+}
+
+/// Runs the passes of `level` that only look at `function`, so that the
+/// result can be cached per function. `optimize_program` does the rest of
+/// what `optimize` does.
+pub fn optimize_function(function: &mut Function, level: OptLevel) {
+    if level < OptLevel::O1 {
+        return;
+    }
+    for _ in 0..MAX_ROUNDS {
+        let changed = FUNCTION_PASSES
+            .iter()
+            .fold(false, |changed, (_, pass)| pass(function) | changed);
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// Runs the passes of `level` that look at the whole program, once every
+/// function has been through `optimize_function`.
+pub fn optimize_program(program: &mut Program, level: OptLevel) {
+    Pipeline {
+        program,
+        dump: false,
+    }
+    .run_program_passes(level);
+}
+
+struct Pipeline<'a> {
+    program: &'a mut Program,
+    dump: bool,
+}
+
+impl Pipeline<'_> {
+    fn run_program_passes(&mut self, level: OptLevel) {
+        if level >= OptLevel::O2 && self.run("inlining", inlining::run) {
+            self.run_function_passes();
+        }
+    }
+
+    fn run_function_passes(&mut self) {
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for &(name, pass) in FUNCTION_PASSES {
+                changed |= self.run(name, |program| {
+                    program.functions.iter_mut().fold(
+                        false,
+                        |changed, function| {
+                            pass(Arc::make_mut(function)) | changed
+                        },
+                    )
+                });
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    fn run(
+        &mut self,
+        name: &str,
+        pass: impl FnOnce(&mut Program) -> bool,
+    ) -> bool {
+        let before = self.dump.then(|| self.program.to_string());
+        let changed = pass(self.program);
+        if let Err(err) = verify(self.program) {
+            panic!("{err} after {name}\n{}", self.program);
+        }
+        if let Some(before) = before.filter(|_| changed) {
+            eprintln!(
+                "--- before {name} ---\n{before}--- after {name} ---\n{}",
+                self.program
+            );
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::lower::lower_text;
+
+    const TEXT: &str = "
+fn main() -> i8 {
+    let x = answer() + 1_i8;
+    let y = x;
+    y * 3_i8
+}
+fn answer() -> i8 { 6_i8 * 7_i8 }
+";
+
+    #[test]
+    fn o1_folds_within_functions() {
+        assert_eq!(
+            lower_text(TEXT, OptLevel::O1).to_string(),
+            "\
+fn main() -> i8 {
+bb0:
+    v0: i8 = call answer()
+    v1: i8 = const 1
+    v2: i8 = v0 + v1 // x
+    v5: i8 = const 3
+    v6: i8 = v2 * v5
+    return v6
+}
+
+fn answer() -> i8 {
+bb0:
+    v2: i8 = const 42
+    return v2
+}
+"
+        );
+    }
+
+    #[test]
+    fn o2_inlines_small_functions() {
+        // 43 * 3 wraps around to -127 in an `i8`.
+        assert_eq!(
+            lower_text(TEXT, OptLevel::O2).to_string(),
+            "\
+fn main() -> i8 {
+bb0:
+    v6: i8 = const -127
+    return v6
+}
+
+fn answer() -> i8 {
+bb0:
+    v2: i8 = const 42
+    return v2
+}
+"
+        );
+    }
+
+    #[test]
+    fn functions_can_be_optimized_one_at_a_time() {
+        let mut program = lower_text(TEXT, OptLevel::O0);
+        for function in &mut program.functions {
+            optimize_function(Arc::make_mut(function), OptLevel::O2);
+        }
+        optimize_program(&mut program, OptLevel::O2);
+        assert_eq!(
+            program.to_string(),
+            lower_text(TEXT, OptLevel::O2).to_string()
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! Evaluates instructions whose operands are known at compile time and
+//! simplifies arithmetic identities such as `x + 0`. Branches on constants
+//! become jumps.
+
+use crate::{
+    ast::BinaryOperator,
+    ir::{cfg::reverse_postorder, Function, Instruction, Terminator, Value},
+    typ::Type,
+};
+use std::collections::HashMap;
+
+pub fn run(function: &mut Function) -> bool {
+    // Visiting blocks in reverse postorder means that the definitions of
+    // operands have been visited before their uses.
+    let order = reverse_postorder(function);
+    let Function { values, blocks, .. } = function;
+    let mut constants = HashMap::new();
+    let mut changed = false;
+
+    for id in order {
+        for instruction in &mut blocks[id.0].instructions {
+            let replacement = match *instruction {
+                Instruction::Const { dest, value } => {
+                    constants.insert(dest, value);
+                    continue;
+                }
+                Instruction::Copy { dest, source } => constants
+                    .get(&source)
+                    .map(|&value| Instruction::Const { dest, value }),
+                Instruction::Binary {
+                    dest,
+                    operator,
+                    left,
+                    right,
+                } => {
+                    let typ = values[dest.0].typ;
+                    match (constants.get(&left), constants.get(&right)) {
+                        (Some(&left), Some(&right)) => {
+                            fold(typ, operator, left, right)
+                                .map(|value| Instruction::Const { dest, value })
+                        }
+                        (None, Some(&constant)) => {
+                            simplify(operator, constant, false).map(|result| {
+                                result.into_instruction(dest, left)
+                            })
+                        }
+                        (Some(&constant), None) => {
+                            simplify(operator, constant, true).map(|result| {
+                                result.into_instruction(dest, right)
+                            })
+                        }
+                        (None, None) => None,
+                    }
+                }
+                Instruction::Cast { dest, source } => {
+                    constants.get(&source).map(|&value| {
+                        let value = values[source.0].typ.to_i128(value) as u64;
+                        Instruction::Const {
+                            dest,
+                            value: values[dest.0].typ.truncate(value),
+                        }
+                    })
+                }
+                Instruction::Call { .. }
+                | Instruction::Syscall { .. }
+                | Instruction::StackSlot { .. }
+                | Instruction::Load { .. }
+                | Instruction::Store { .. } => None,
+            };
+            if let Some(replacement) = replacement {
+                if let Instruction::Const { dest, value } = replacement {
+                    constants.insert(dest, value);
+                }
+                *instruction = replacement;
+                changed = true;
+            }
+        }
+        let terminator = &mut blocks[id.0].terminator;
+        if let Terminator::Branch {
+            value,
+            low,
+            high,
+            then,
+            otherwise,
+        } = *terminator
+        {
+            if let Some(&constant) = constants.get(&value) {
+                let typ = values[value.0].typ;
+                let value = typ.to_i128(constant);
+                let target = if (typ.to_i128(low)..=typ.to_i128(high))
+                    .contains(&value)
+                {
+                    then
+                } else {
+                    otherwise
+                };
+                *terminator = Terminator::Jump(target, Vec::new());
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Computes `left operator right` with wrapping arithmetic, or returns `None`
+/// if the result is undefined.
+fn fold(
+    typ: Type,
+    operator: BinaryOperator,
+    left: u64,
+    right: u64,
+) -> Option<u64> {
+    let bits = typ.bits()?;
+    let shift = right & u64::from(bits - 1);
+    let (signed_left, signed_right) = (typ.to_i128(left), typ.to_i128(right));
+    let overflows_division = typ.is_signed()
+        && signed_left == -(1 << (bits - 1))
+        && signed_right == -1;
+    let result = match operator {
+        BinaryOperator::Add => left.wrapping_add(right),
+        BinaryOperator::Sub => left.wrapping_sub(right),
+        BinaryOperator::Mul => left.wrapping_mul(right),
+        BinaryOperator::And => left & right,
+        BinaryOperator::Or => left | right,
+        BinaryOperator::Xor => left ^ right,
+        BinaryOperator::Shl => left << shift,
+        BinaryOperator::Shr if typ.is_signed() => (signed_left >> shift) as u64,
+        BinaryOperator::Shr => left >> shift,
+        BinaryOperator::Div | BinaryOperator::Rem
+            if right == 0 || overflows_division =>
+        {
+            return None;
+        }
+        BinaryOperator::Div if typ.is_signed() => {
+            (signed_left / signed_right) as u64
+        }
+        BinaryOperator::Div => left / right,
+        BinaryOperator::Rem if typ.is_signed() => {
+            (signed_left % signed_right) as u64
+        }
+        BinaryOperator::Rem => left % right,
+    };
+    Some(typ.truncate(result))
+}
+
+enum Simplified {
+    /// The result is the other operand.
+    Operand,
+    Const(u64),
+}
+
+impl Simplified {
+    fn into_instruction(self, dest: Value, operand: Value) -> Instruction {
+        match self {
+            Self::Operand => Instruction::Copy {
+                dest,
+                source: operand,
+            },
+            Self::Const(value) => Instruction::Const { dest, value },
+        }
+    }
+}
+
+/// Simplifies an operation where one operand is the given constant, which is
+/// on the left if `constant_is_left` is set.
+fn simplify(
+    operator: BinaryOperator,
+    constant: u64,
+    constant_is_left: bool,
+) -> Option<Simplified> {
+    use BinaryOperator::*;
+    match (operator, constant, constant_is_left) {
+        (Add | Or | Xor, 0, _)
+        | (Sub | Shl | Shr, 0, false)
+        | (Mul, 1, _)
+        | (Div, 1, false) => Some(Simplified::Operand),
+        (Mul | And, 0, _) | (Shl | Shr, 0, true) | (Rem, 1, false) => {
+            Some(Simplified::Const(0))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fold_signed(
+        typ: Type,
+        operator: BinaryOperator,
+        left: i64,
+        right: i64,
+    ) -> Option<i128> {
+        fold(
+            typ,
+            operator,
+            typ.truncate(left as u64),
+            typ.truncate(right as u64),
+        )
+        .map(|result| typ.to_i128(result))
+    }
+
+    #[test]
+    fn arithmetic_wraps_around() {
+        use BinaryOperator::*;
+        assert_eq!(fold_signed(Type::I8, Add, 127, 1), Some(-128));
+        assert_eq!(fold_signed(Type::U8, Sub, 0, 1), Some(255));
+        assert_eq!(fold_signed(Type::U16, Mul, 256, 256), Some(0));
+        assert_eq!(fold_signed(Type::I32, Mul, i32::MAX.into(), 2), Some(-2));
+        assert_eq!(fold_signed(Type::U64, Add, -1, 1), Some(0));
+        assert_eq!(
+            fold_signed(Type::I64, Sub, i64::MIN, 1),
+            Some(i64::MAX.into())
+        );
+    }
+
+    #[test]
+    fn shift_amounts_are_taken_modulo_the_width() {
+        use BinaryOperator::*;
+        assert_eq!(fold_signed(Type::U8, Shl, 1, 9), Some(2));
+        assert_eq!(fold_signed(Type::I8, Shr, -128, 7), Some(-1));
+        assert_eq!(fold_signed(Type::U8, Shr, 128, 7), Some(1));
+        assert_eq!(fold_signed(Type::U32, Shl, 1, 32), Some(1));
+    }
+
+    #[test]
+    fn undefined_divisions_are_left_alone() {
+        use BinaryOperator::*;
+        assert_eq!(fold_signed(Type::I8, Div, -7, 2), Some(-3));
+        assert_eq!(fold_signed(Type::I8, Rem, -7, 2), Some(-1));
+        assert_eq!(fold_signed(Type::U8, Div, 7, 0), None);
+        assert_eq!(fold_signed(Type::I8, Div, -128, -1), None);
+        assert_eq!(fold_signed(Type::I8, Rem, -128, -1), None);
+    }
+}
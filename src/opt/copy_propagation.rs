@@ -0,0 +1,48 @@
+//! Replaces uses of copied values with the originals and removes the copies.
+
+use crate::ir::{Function, Instruction, Value};
+use std::collections::BTreeMap;
+
+pub fn run(function: &mut Function) -> bool {
+    let mut sources = BTreeMap::new();
+    for block in &mut function.blocks {
+        block.instructions.retain(|instruction| match *instruction {
+            Instruction::Copy { dest, source } => {
+                sources.insert(dest, source);
+                false
+            }
+            _ => true,
+        });
+    }
+    if sources.is_empty() {
+        return false;
+    }
+
+    // Copies of copies are resolved to the first value in the chain.
+    let original = |mut value: Value| {
+        while let Some(&source) = sources.get(&value) {
+            value = source;
+        }
+        value
+    };
+
+    // Keep the names of `let` bindings around for debugging.
+    for &dest in sources.keys() {
+        let original = original(dest);
+        if function.values[original.0].name.is_none() {
+            function.values[original.0].name = function.values[dest.0].name;
+        }
+    }
+
+    for block in &mut function.blocks {
+        let operands = block
+            .instructions
+            .iter_mut()
+            .flat_map(Instruction::operands_mut)
+            .chain(block.terminator.operands_mut());
+        for operand in operands {
+            *operand = original(*operand);
+        }
+    }
+    true
+}
@@ -0,0 +1,101 @@
+//! Removes unreachable blocks, instructions whose results are unused and
+//! block parameters that are never read.
+
+use crate::ir::{cfg::reverse_postorder, BlockId, Function, Terminator, Value};
+
+pub fn run(function: &mut Function) -> bool {
+    let mut changed = remove_unreachable_blocks(function);
+    loop {
+        let uses = count_uses(function);
+        let is_unused = |value: Value| uses[value.0] == 0;
+        let mut removed_any = false;
+
+        for block in &mut function.blocks {
+            let len = block.instructions.len();
+            block.instructions.retain(|instruction| {
+                instruction.has_side_effects()
+                    || !instruction.dest().is_some_and(is_unused)
+            });
+            removed_any |= block.instructions.len() != len;
+        }
+
+        for id in function.block_ids().skip(1) {
+            let unused = function
+                .block(id)
+                .parameters
+                .iter()
+                .map(|&parameter| is_unused(parameter))
+                .collect::<Vec<_>>();
+            if !unused.contains(&true) {
+                continue;
+            }
+            retain_by_mask(&mut function.block_mut(id).parameters, &unused);
+            for block in &mut function.blocks {
+                if let Terminator::Jump(target, arguments) =
+                    &mut block.terminator
+                {
+                    if *target == id {
+                        retain_by_mask(arguments, &unused);
+                    }
+                }
+            }
+            removed_any = true;
+        }
+
+        if !removed_any {
+            return changed;
+        }
+        changed = true;
+    }
+}
+
+fn count_uses(function: &Function) -> Vec<usize> {
+    let mut uses = vec![0; function.values.len()];
+    for block in &function.blocks {
+        let operands = block
+            .instructions
+            .iter()
+            .flat_map(|instruction| instruction.operands())
+            .chain(block.terminator.operands());
+        for operand in operands {
+            uses[operand.0] += 1;
+        }
+    }
+    uses
+}
+
+/// Removes the elements for which `remove` is set.
+fn retain_by_mask<T>(items: &mut Vec<T>, remove: &[bool]) {
+    let mut remove = remove.iter();
+    items.retain(|_| !remove.next().expect("mask has the same length"));
+}
+
+/// Removes blocks that can't be reached from the entry block, keeping the
+/// order of the remaining blocks.
+pub fn remove_unreachable_blocks(function: &mut Function) -> bool {
+    let mut is_reachable = vec![false; function.blocks.len()];
+    for id in reverse_postorder(function) {
+        is_reachable[id.0] = true;
+    }
+    if !is_reachable.contains(&false) {
+        return false;
+    }
+
+    let mut new_ids = Vec::with_capacity(function.blocks.len());
+    let mut next_id = 0;
+    for &is_reachable in &is_reachable {
+        new_ids.push(BlockId(next_id));
+        next_id += usize::from(is_reachable);
+    }
+
+    let mut is_reachable = is_reachable.into_iter();
+    function
+        .blocks
+        .retain(|_| is_reachable.next().expect("one entry per block"));
+    for block in &mut function.blocks {
+        for target in block.terminator.successors_mut() {
+            *target = new_ids[target.0];
+        }
+    }
+    true
+}
@@ -0,0 +1,182 @@
+//! Replaces calls to small functions with copies of their bodies. Only leaf
+//! functions are inlined, so inlining can't go on forever on recursive
+//! functions.
+
+use crate::ir::{
+    Block, BlockId, Function, Instruction, Program, Terminator, Value,
+};
+use internment::Intern;
+use std::{collections::HashMap, sync::Arc};
+
+/// Functions with at most this many instructions are inlined.
+const MAX_INSTRUCTIONS: usize = 8;
+
+pub fn run(program: &mut Program) -> bool {
+    let inlinable = program
+        .functions
+        .iter()
+        .filter(|function| is_inlinable(function))
+        .map(|function| (function.name, Arc::clone(function)))
+        .collect::<HashMap<_, _>>();
+    let mut changed = false;
+
+    for function in &mut program.functions {
+        let find_call = |function: &Function| {
+            function.block_ids().find_map(|id| {
+                let index = function.block(id).instructions.iter().position(
+                    |instruction| {
+                        matches!(
+                            instruction,
+                            Instruction::Call { callee, .. }
+                                if inlinable.contains_key(callee)
+                        )
+                    },
+                )?;
+                Some((id, index))
+            })
+        };
+        if find_call(function).is_none() {
+            continue;
+        }
+        let function = Arc::make_mut(function);
+        while let Some((block, index)) = find_call(function) {
+            inline_call(function, block, index, &inlinable);
+        }
+        changed = true;
+    }
+    changed
+}
+
+fn is_inlinable(function: &Function) -> bool {
+    let instructions =
+        function.blocks.iter().flat_map(|block| &block.instructions);
+    instructions.clone().count() <= MAX_INSTRUCTIONS
+        && !instructions
+            .into_iter()
+            .any(|instruction| matches!(instruction, Instruction::Call { .. }))
+}
+
+/// Splits `block` at the call at `index` and replaces the call with the body
+/// of the callee. The rest of the block moves to a new block that receives the
+/// return value of the callee as its parameter.
+fn inline_call(
+    function: &mut Function,
+    block: BlockId,
+    index: usize,
+    inlinable: &HashMap<Intern<str>, Arc<Function>>,
+) {
+    let continuation = BlockId(function.blocks.len());
+    let first_callee_block = continuation.0 + 1;
+
+    let caller_block = function.block_mut(block);
+    let rest = caller_block.instructions.split_off(index + 1);
+    let Some(Instruction::Call {
+        dest,
+        callee,
+        arguments,
+    }) = caller_block.instructions.pop()
+    else {
+        unreachable!("the instruction at `index` is a call");
+    };
+    let callee = &inlinable[&callee];
+    let terminator = std::mem::replace(
+        &mut caller_block.terminator,
+        Terminator::Jump(BlockId(first_callee_block), Vec::new()),
+    );
+    function.blocks.push(Block {
+        parameters: dest.into_iter().collect(),
+        instructions: rest,
+        terminator,
+    });
+
+    let values = callee
+        .values
+        .iter()
+        .map(|info| function.new_value(info.typ, info.name))
+        .collect::<Vec<_>>();
+    let map_value = |value: Value| values[value.0];
+    let map_block = |id: BlockId| BlockId(first_callee_block + id.0);
+
+    function.block_mut(block).instructions.extend(
+        callee
+            .parameters
+            .iter()
+            .zip(arguments)
+            .map(|(&parameter, source)| Instruction::Copy {
+                dest: map_value(parameter),
+                source,
+            }),
+    );
+
+    for callee_block in &callee.blocks {
+        let mut instructions = callee_block.instructions.clone();
+        for instruction in &mut instructions {
+            match instruction {
+                Instruction::Const { dest, .. }
+                | Instruction::StackSlot { dest } => *dest = map_value(*dest),
+                Instruction::Copy { dest, source }
+                | Instruction::Cast { dest, source }
+                | Instruction::Load {
+                    dest,
+                    address: source,
+                } => {
+                    *dest = map_value(*dest);
+                    *source = map_value(*source);
+                }
+                Instruction::Binary {
+                    dest, left, right, ..
+                } => {
+                    *dest = map_value(*dest);
+                    *left = map_value(*left);
+                    *right = map_value(*right);
+                }
+                Instruction::Store { address, value } => {
+                    *address = map_value(*address);
+                    *value = map_value(*value);
+                }
+                Instruction::Syscall { dest, arguments } => {
+                    *dest = map_value(*dest);
+                    for argument in arguments {
+                        *argument = map_value(*argument);
+                    }
+                }
+                Instruction::Call { .. } => {
+                    unreachable!("only leaf functions are inlined")
+                }
+            }
+        }
+        let terminator = match &callee_block.terminator {
+            Terminator::Return(value) => Terminator::Jump(
+                continuation,
+                value.iter().copied().map(map_value).collect(),
+            ),
+            Terminator::Jump(target, arguments) => Terminator::Jump(
+                map_block(*target),
+                arguments.iter().copied().map(map_value).collect(),
+            ),
+            &Terminator::Branch {
+                value,
+                low,
+                high,
+                then,
+                otherwise,
+            } => Terminator::Branch {
+                value: map_value(value),
+                low,
+                high,
+                then: map_block(then),
+                otherwise: map_block(otherwise),
+            },
+        };
+        function.blocks.push(Block {
+            parameters: callee_block
+                .parameters
+                .iter()
+                .copied()
+                .map(map_value)
+                .collect(),
+            instructions,
+            terminator,
+        });
+    }
+}
@@ -0,0 +1,57 @@
+//! Merges blocks into their predecessor when that is the only way to reach
+//! them, turning block arguments into copies.
+
+use super::dead_code::remove_unreachable_blocks;
+use crate::ir::{
+    cfg::{predecessors, reverse_postorder},
+    Block, Function, Instruction, Terminator,
+};
+
+pub fn run(function: &mut Function) -> bool {
+    let predecessor_counts = predecessors(function)
+        .iter()
+        .map(Vec::len)
+        .collect::<Vec<_>>();
+    let mut changed = false;
+
+    for id in reverse_postorder(function) {
+        while let Terminator::Jump(target, _) = function.block(id).terminator {
+            let target_block = function.block(target);
+            if target == id
+                || predecessor_counts[target.0] != 1
+                || target_block.terminator.successors().contains(&id)
+            {
+                break;
+            }
+
+            let target_block = std::mem::replace(
+                function.block_mut(target),
+                Block {
+                    parameters: Vec::new(),
+                    instructions: Vec::new(),
+                    terminator: Terminator::Return(None),
+                },
+            );
+            let block = function.block_mut(id);
+            let Terminator::Jump(_, arguments) = std::mem::replace(
+                &mut block.terminator,
+                target_block.terminator,
+            ) else {
+                unreachable!("the terminator was matched above");
+            };
+            block.instructions.extend(
+                target_block
+                    .parameters
+                    .into_iter()
+                    .zip(arguments)
+                    .map(|(dest, source)| Instruction::Copy { dest, source }),
+            );
+            block.instructions.extend(target_block.instructions);
+            changed = true;
+        }
+    }
+
+    // The merged blocks are no longer reachable.
+    remove_unreachable_blocks(function);
+    changed
+}
@@ -3,7 +3,7 @@ use ropey::Rope;
 use std::borrow::Cow;
 use tree_sitter::{Node, Point};
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Debug, Default)]
 pub enum PositionEncoding {
     Utf8,
     #[default]
@@ -76,20 +76,11 @@ impl PositionEncoding {
     }
 
     pub fn position_to_point(self, text: &Rope, pos: Position) -> Point {
-        // FIXME: This gives incorrect results for characters with a width other
-        // than 1 cell. I think. The Tree-sitter documentation is not very clear
-        // about what a column is.
+        // Tree-sitter columns are byte offsets from the start of the line.
+        let row = pos.line as usize;
         Point {
-            row: pos.line as usize,
-            column: match self {
-                Self::Utf8 => text
-                    .line(pos.line as usize)
-                    .byte_to_char(pos.character as usize),
-                Self::Utf16 => text
-                    .line(pos.line as usize)
-                    .utf16_cu_to_char(pos.character as usize),
-                Self::Utf32 => pos.character as usize,
-            },
+            row,
+            column: self.position_to_byte(text, pos) - text.line_to_byte(row),
         }
     }
 
@@ -112,15 +103,64 @@ impl PositionEncoding {
 }
 
 pub fn byte_to_point(text: &Rope, byte: usize) -> Point {
-    // FIXME: This gives incorrect results for characters with a width other
-    // than 1 cell. I think. The Tree-sitter documentation is not very clear
-    // about what a column is.
     let row = text.byte_to_line(byte);
-    let line_start = text.line_to_byte(row);
-    let column = text.line(row).byte_to_char(byte - line_start);
-    Point { row, column }
+    Point {
+        row,
+        column: byte - text.line_to_byte(row),
+    }
 }
 
 pub fn node_text<'a>(node: Node, text: &'a Rope) -> Cow<'a, str> {
     text.byte_slice(node.byte_range()).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "fn äö() -> unit {\n    // 🦀 € 𝔤\n}\n\nx🦀y";
+
+    #[test]
+    fn positions_round_trip_through_bytes() {
+        let text = Rope::from(TEXT);
+        for pos_enc in [
+            PositionEncoding::Utf8,
+            PositionEncoding::Utf16,
+            PositionEncoding::Utf32,
+        ] {
+            for (byte, _) in TEXT.char_indices() {
+                let position = pos_enc.byte_to_position(&text, byte);
+                assert_eq!(
+                    pos_enc.position_to_byte(&text, position),
+                    byte,
+                    "{pos_enc:?} {position:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn points_use_byte_columns() {
+        let text = Rope::from(TEXT);
+        for (byte, _) in TEXT.char_indices() {
+            let line_start = TEXT[..byte].rfind('\n').map_or(0, |i| i + 1);
+            let expected = Point {
+                row: TEXT[..byte].matches('\n').count(),
+                column: byte - line_start,
+            };
+            assert_eq!(byte_to_point(&text, byte), expected);
+            for pos_enc in [
+                PositionEncoding::Utf8,
+                PositionEncoding::Utf16,
+                PositionEncoding::Utf32,
+            ] {
+                let position = pos_enc.byte_to_position(&text, byte);
+                assert_eq!(
+                    pos_enc.position_to_point(&text, position),
+                    expected,
+                    "{pos_enc:?} {position:?}"
+                );
+            }
+        }
+    }
+}
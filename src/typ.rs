@@ -1,8 +1,11 @@
 use crate::{ast::SyntaxError, text::node_text};
+use internment::Intern;
 use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use tree_sitter::Node;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum Type {
     Unit,
     I8,
@@ -13,10 +16,30 @@ pub enum Type {
     U16,
     U32,
     U64,
+    /// `*T`, or `*mut T` if the pointee can be written through the pointer.
+    /// Pointers are 64-bit addresses that behave like `u64` in arithmetic.
+    Pointer {
+        pointee: Intern<Type>,
+        is_mutable: bool,
+    },
+    /// A struct or an enum, which is defined elsewhere under this name.
+    Named(#[serde(with = "crate::cache::interned")] Intern<str>),
 }
 
 impl Type {
     pub fn parse(node: Node, text: &Rope) -> Result<Self, SyntaxError> {
+        if node.kind() == "pointer_type" {
+            let pointee = node
+                .child_by_field_name("pointee")
+                .ok_or(SyntaxError)
+                .and_then(|node| Self::parse(node, text))?;
+            let is_mutable =
+                node.child(1).is_some_and(|child| child.kind() == "mut");
+            return Ok(Self::pointer(pointee, is_mutable));
+        }
+        if node.kind() == "type_identifier" {
+            return Ok(Self::Named((&*node_text(node, text)).into()));
+        }
         if node.kind() != "primitive_type" {
             return Err(SyntaxError);
         }
@@ -33,4 +56,96 @@ impl Type {
             _ => Err(SyntaxError),
         }
     }
+
+    pub fn pointer(pointee: Type, is_mutable: bool) -> Self {
+        Self::Pointer {
+            pointee: Intern::new(pointee),
+            is_mutable,
+        }
+    }
+
+    /// The type that a pointer type points to.
+    pub fn pointee(self) -> Option<Type> {
+        match self {
+            Self::Pointer { pointee, .. } => Some(*pointee),
+            _ => None,
+        }
+    }
+
+    pub fn is_integer(self) -> bool {
+        !matches!(self, Self::Unit | Self::Pointer { .. } | Self::Named(_))
+    }
+
+    /// The width of values of this type in bits, or `None` for `unit`. Struct
+    /// and enum values are the 64-bit addresses of the memory that holds them.
+    pub fn bits(self) -> Option<u32> {
+        match self {
+            Self::Unit => None,
+            Self::I8 | Self::U8 => Some(8),
+            Self::I16 | Self::U16 => Some(16),
+            Self::I32 | Self::U32 => Some(32),
+            Self::I64 | Self::U64 | Self::Pointer { .. } | Self::Named(_) => {
+                Some(64)
+            }
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, Self::I8 | Self::I16 | Self::I32 | Self::I64)
+    }
+
+    /// The smallest and the largest value of an integer type.
+    pub fn range(self) -> Option<(i128, i128)> {
+        let bits = self.bits().filter(|_| self.is_integer())?;
+        Some(if self.is_signed() {
+            (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+        } else {
+            (0, (1 << bits) - 1)
+        })
+    }
+
+    /// Wraps `value` around to fit in this integer type, keeping only the low
+    /// bits.
+    pub fn truncate(self, value: u64) -> u64 {
+        match self.bits() {
+            Some(bits) if bits < 64 => value & ((1 << bits) - 1),
+            _ => value,
+        }
+    }
+
+    /// Interprets bits that have been truncated to this type as a number,
+    /// sign-extending them if the type is signed.
+    pub fn to_i128(self, value: u64) -> i128 {
+        match self.bits() {
+            Some(bits) if self.is_signed() => {
+                i128::from((value << (64 - bits)) as i64 >> (64 - bits))
+            }
+            _ => i128::from(value),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unit => f.write_str("unit"),
+            Self::I8 => f.write_str("i8"),
+            Self::I16 => f.write_str("i16"),
+            Self::I32 => f.write_str("i32"),
+            Self::I64 => f.write_str("i64"),
+            Self::U8 => f.write_str("u8"),
+            Self::U16 => f.write_str("u16"),
+            Self::U32 => f.write_str("u32"),
+            Self::U64 => f.write_str("u64"),
+            Self::Pointer {
+                pointee,
+                is_mutable: false,
+            } => write!(f, "*{pointee}"),
+            Self::Pointer {
+                pointee,
+                is_mutable: true,
+            } => write!(f, "*mut {pointee}"),
+            Self::Named(name) => f.write_str(name),
+        }
+    }
 }
@@ -1,6 +1,22 @@
 const comma_separated = rule =>
   optional(seq(rule, repeat(seq(",", rule)), optional(",")));
 
+// Binary operators from lowest to highest precedence.
+const binary_operators = [
+  ["|"],
+  ["^"],
+  ["&"],
+  ["<<", ">>"],
+  ["+", "-"],
+  ["*", "/", "%"],
+];
+
+// Casts bind tighter than binary operators, prefix operators bind tighter
+// than casts and field accesses bind tightest.
+const PREC_CAST = binary_operators.length + 2;
+const PREC_PREFIX = PREC_CAST + 1;
+const PREC_FIELD = PREC_PREFIX + 1;
+
 module.exports = grammar({
   name: "gneiss",
 
@@ -11,20 +27,70 @@ module.exports = grammar({
   rules: {
     source_file: $ => repeat($._statement),
 
+    // Functions marked `extern` are defined outside of Gneiss and end with
+    // `;` instead of a body. Functions marked `export` can be called from C.
     function_definition: $ =>
       seq(
+        optional(choice("extern", "export")),
         "fn",
         field("name", $.identifier),
         field("parameters", $.parameters),
         "->",
         field("return_type", $._type),
-        field("body", $.block)
+        choice(field("body", $.block), ";")
+      ),
+
+    parameters: $ => seq("(", comma_separated($.parameter), ")"),
+
+    // The fields of a `packed` struct are laid out without padding.
+    struct_definition: $ =>
+      seq(
+        optional("packed"),
+        "struct",
+        field("name", $.identifier),
+        field("fields", $.field_declarations)
+      ),
+
+    field_declarations: $ =>
+      seq("{", comma_separated($.field_declaration), "}"),
+
+    field_declaration: $ =>
+      seq(
+        field("name", alias($.identifier, $.field_identifier)),
+        ":",
+        field("type", $._type)
       ),
 
-    parameters: $ => seq("(", ")"),
+    enum_definition: $ =>
+      seq(
+        "enum",
+        field("name", $.identifier),
+        field("variants", $.variant_declarations)
+      ),
+
+    variant_declarations: $ =>
+      seq("{", comma_separated($.variant_declaration), "}"),
+
+    variant_declaration: $ =>
+      seq(
+        field("name", $.identifier),
+        optional(field("payload", $.payload_types))
+      ),
+
+    payload_types: $ => seq("(", comma_separated($._type), ")"),
+
+    parameter: $ =>
+      seq(field("pattern", $.identifier), ":", field("type", $._type)),
 
     _type: $ =>
-      choice($.primitive_type, alias($.identifier, $.type_identifier)),
+      choice(
+        $.primitive_type,
+        $.pointer_type,
+        alias($.identifier, $.type_identifier)
+      ),
+
+    pointer_type: $ =>
+      seq("*", optional("mut"), field("pointee", $._type)),
 
     block: $ =>
       seq(
@@ -37,7 +103,10 @@ module.exports = grammar({
     _statement: $ =>
       choice(
         $.function_definition,
+        $.struct_definition,
+        $.enum_definition,
         $.let_declaration,
+        $.assignment_statement,
         $.expression_statement,
         $.empty_statement
       ),
@@ -51,9 +120,22 @@ module.exports = grammar({
       ),
 
     _expression_requiring_semicolon: $ =>
-      choice($.function_call, $.identifier, $.number),
+      choice(
+        $.function_call,
+        $.identifier,
+        $.number,
+        $.binary_expression,
+        $.address_of_expression,
+        $.dereference_expression,
+        $.cast_expression,
+        $.struct_expression,
+        $.field_expression,
+        $.variant_expression,
+        $.parenthesized_expression
+      ),
 
-    _expression_not_requiring_semicolon: $ => $.block,
+    _expression_not_requiring_semicolon: $ =>
+      choice($.block, $.match_expression),
 
     let_declaration: $ =>
       seq(
@@ -64,6 +146,16 @@ module.exports = grammar({
         ";"
       ),
 
+    // Only `*pointer = value;` is valid, but the checker gives a better error
+    // for other targets than a syntax error would.
+    assignment_statement: $ =>
+      seq(
+        field("target", $._expression),
+        "=",
+        field("value", $._expression),
+        ";"
+      ),
+
     _expression: $ =>
       prec(
         1,
@@ -78,6 +170,107 @@ module.exports = grammar({
 
     arguments: $ => seq("(", comma_separated($._expression), ")"),
 
+    binary_expression: $ =>
+      choice(
+        ...binary_operators.flatMap((operators, i) =>
+          operators.map(operator =>
+            prec.left(
+              i + 2,
+              seq(
+                field("left", $._expression),
+                field("operator", operator),
+                field("right", $._expression)
+              )
+            )
+          )
+        )
+      ),
+
+    address_of_expression: $ =>
+      prec(
+        PREC_PREFIX,
+        seq("&", optional("mut"), field("value", $._expression))
+      ),
+
+    dereference_expression: $ =>
+      prec(PREC_PREFIX, seq("*", field("pointer", $._expression))),
+
+    cast_expression: $ =>
+      prec.left(
+        PREC_CAST,
+        seq(field("value", $._expression), "as", field("type", $._type))
+      ),
+
+    // In `match value {`, the `{` starts the arms rather than a struct
+    // expression.
+    struct_expression: $ =>
+      prec(
+        -1,
+        seq(
+          field("name", alias($.identifier, $.type_identifier)),
+          field("fields", $.field_initializers)
+        )
+      ),
+
+    field_initializers: $ =>
+      seq("{", comma_separated($.field_initializer), "}"),
+
+    field_initializer: $ =>
+      seq(
+        field("name", alias($.identifier, $.field_identifier)),
+        ":",
+        field("value", $._expression)
+      ),
+
+    field_expression: $ =>
+      prec(
+        PREC_FIELD,
+        seq(
+          field("value", $._expression),
+          ".",
+          field("field", alias($.identifier, $.field_identifier))
+        )
+      ),
+
+    variant_expression: $ =>
+      seq(
+        field("name", alias($.identifier, $.type_identifier)),
+        "::",
+        field("variant", $.identifier),
+        optional(field("payload", $.arguments))
+      ),
+
+    match_expression: $ =>
+      seq("match", field("value", $._expression), field("arms", $.match_arms)),
+
+    match_arms: $ => seq("{", comma_separated($.match_arm), "}"),
+
+    match_arm: $ =>
+      seq(
+        field("pattern", $._pattern),
+        "=>",
+        field("value", $._expression)
+      ),
+
+    // An identifier binds the value, except for `_`, which ignores it.
+    _pattern: $ =>
+      choice($.identifier, $.number, $.range_pattern, $.variant_pattern),
+
+    range_pattern: $ =>
+      seq(field("start", $.number), "..=", field("end", $.number)),
+
+    variant_pattern: $ =>
+      seq(
+        field("name", alias($.identifier, $.type_identifier)),
+        "::",
+        field("variant", $.identifier),
+        optional(field("bindings", $.bindings))
+      ),
+
+    bindings: $ => seq("(", comma_separated($.identifier), ")"),
+
+    parenthesized_expression: $ => seq("(", $._expression, ")"),
+
     primitive_type: $ => /[ui](8|16|32|64)|unit/,
 
     identifier: $ => /@?[\p{XID_Start}_][\p{XID_Continue}-]*/,
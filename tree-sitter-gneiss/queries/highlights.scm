@@ -3,11 +3,26 @@
 (number) @constant.numeric
 
 "fn" @keyword.function
+[
+  "extern"
+  "export"
+  "packed"
+] @keyword
+[
+  "struct"
+  "enum"
+] @keyword.storage.type
 "let" @keyword.storage
+"mut" @keyword.storage.modifier
+"as" @keyword.operator
+"match" @keyword.control.conditional
 
 [
   ";"
   ","
+  ":"
+  "::"
+  "."
 ] @punctuation.delimiter
 
 [
@@ -19,7 +34,19 @@
 
 [
   "->"
+  "=>"
+  "..="
   "="
+  "+"
+  "-"
+  "*"
+  "/"
+  "%"
+  "&"
+  "|"
+  "^"
+  "<<"
+  ">>"
 ] @operator
 
 (function_definition
@@ -28,5 +55,25 @@
 (function_call 
   name: (identifier) @function)
 
+(struct_definition
+  name: (identifier) @type)
+
+(enum_definition
+  name: (identifier) @type)
+
+(variant_declaration
+  name: (identifier) @constructor)
+
+(variant_expression
+  variant: (identifier) @constructor)
+
+(variant_pattern
+  variant: (identifier) @constructor)
+
+(parameter
+  pattern: (identifier) @variable.parameter)
+
 (primitive_type) @type.builtin
+(type_identifier) @type
+(field_identifier) @variable.other.member
 (identifier) @variable
@@ -4,5 +4,8 @@
 (arguments
   ((_) @parameter.inside . ","? @parameter.around) @parameter.around)
 
+(parameters
+  ((_) @parameter.inside . ","? @parameter.around) @parameter.around)
+
 (function_definition
   body: (_) @function.inside) @function.around